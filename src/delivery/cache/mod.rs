@@ -0,0 +1,227 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Clear the caches we keep under `~/.delivery`, for
+//! `delivery cache clear`:
+//!
+//! * tokens: `api-tokens` and the pending `device-codes.json`
+//! * artifacts: downloaded build cookbooks, in `cache/artifacts`
+//! * responses: configs fetched from the server, in `cache/config`
+//!
+//! A cache that isn't there is simply reported as empty.
+
+use std::fs;
+use std::path::PathBuf;
+use errors::DeliveryError;
+use utils;
+use utils::path_join_many::PathJoinMany;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheKind {
+    Tokens,
+    Artifacts,
+    Responses
+}
+
+/// Every cache, in the order they are cleared and reported.
+pub const ALL: [CacheKind; 3] = [CacheKind::Tokens, CacheKind::Artifacts, CacheKind::Responses];
+
+impl CacheKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CacheKind::Tokens => "tokens",
+            CacheKind::Artifacts => "artifacts",
+            CacheKind::Responses => "responses"
+        }
+    }
+
+    /// The files and directories making up this cache, in the
+    /// `.delivery` directory `dot_delivery`.
+    pub fn paths(&self, dot_delivery: &PathBuf) -> Vec<PathBuf> {
+        match *self {
+            CacheKind::Tokens => vec![dot_delivery.join("api-tokens"),
+                                      dot_delivery.join("device-codes.json")],
+            CacheKind::Artifacts => vec![dot_delivery.join_many(&["cache", "artifacts"])],
+            CacheKind::Responses => vec![dot_delivery.join_many(&["cache", "config"])]
+        }
+    }
+}
+
+/// What clearing one cache freed.
+#[derive(Debug, PartialEq)]
+pub struct Cleared {
+    pub kind: CacheKind,
+    pub bytes: u64,
+    /// Whether anything was there to remove.
+    pub existed: bool
+}
+
+/// The caches asked for by the flags, or all of them with none.
+pub fn selected(tokens: bool, artifacts: bool, responses: bool) -> Vec<CacheKind> {
+    if !tokens && !artifacts && !responses {
+        return ALL.to_vec();
+    }
+    let mut kinds = Vec::new();
+    if tokens { kinds.push(CacheKind::Tokens); }
+    if artifacts { kinds.push(CacheKind::Artifacts); }
+    if responses { kinds.push(CacheKind::Responses); }
+    kinds
+}
+
+/// The size of a file, or of everything under a directory.
+pub fn size_of(path: &PathBuf) -> u64 {
+    let meta = match fs::metadata(path) {
+        Ok(m) => m,
+        Err(_) => return 0
+    };
+    if !meta.is_dir() {
+        return meta.len();
+    }
+    match fs::read_dir(path) {
+        Ok(listing) => listing.filter_map(|e| e.ok()).map(|e| size_of(&e.path())).fold(0, |sum, s| sum + s),
+        Err(_) => 0
+    }
+}
+
+/// Remove each of `kinds` from `dot_delivery`.
+pub fn clear(dot_delivery: &PathBuf, kinds: &[CacheKind]) -> Result<Vec<Cleared>, DeliveryError> {
+    let mut cleared = Vec::new();
+    for kind in kinds.iter() {
+        let mut bytes = 0;
+        let mut existed = false;
+        for path in kind.paths(dot_delivery).iter() {
+            if fs::metadata(path).is_err() {
+                continue;
+            }
+            existed = true;
+            bytes += size_of(path);
+            if path.is_dir() {
+                try!(utils::remove_recursive(path));
+            } else {
+                try!(fs::remove_file(path));
+            }
+        }
+        cleared.push(Cleared{ kind: *kind, bytes: bytes, existed: existed });
+    }
+    Ok(cleared)
+}
+
+/// `bytes` in the largest unit that keeps it at least 1.
+pub fn format_bytes(bytes: u64) -> String {
+    let units = ["KB", "MB", "GB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size = size / 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, units[unit])
+}
+
+/// A line for each cache, and the total.
+pub fn report(cleared: &[Cleared]) -> Vec<String> {
+    let mut lines: Vec<String> = cleared.iter().map(|c| {
+        if c.existed {
+            format!("Cleared {}: {} freed", c.kind.name(), format_bytes(c.bytes))
+        } else {
+            format!("No {} cache to clear", c.kind.name())
+        }
+    }).collect();
+    let total = cleared.iter().fold(0, |sum, c| sum + c.bytes);
+    lines.push(format!("Freed {} in total", format_bytes(total)));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+    use tempdir::TempDir;
+    use utils;
+    use utils::path_join_many::PathJoinMany;
+
+    fn write(path: &PathBuf, bytes: usize) {
+        utils::mkdir_recursive(&path.parent().unwrap().to_path_buf()).unwrap();
+        File::create(path).unwrap().write_all(&vec![b'x'; bytes]).unwrap();
+    }
+
+    fn filled(dir: &TempDir) -> PathBuf {
+        let dot_delivery = dir.path().join(".delivery");
+        write(&dot_delivery.join("api-tokens"), 100);
+        write(&dot_delivery.join("cli.toml"), 10);
+        write(&dot_delivery.join_many(&["cache", "artifacts", "cookbook_1.0_abc", "artifact"]), 2048);
+        write(&dot_delivery.join_many(&["cache", "config", "https_config", "artifact"]), 300);
+        dot_delivery
+    }
+
+    #[test]
+    fn clear_each_kind_alone() {
+        for kind in ALL.iter() {
+            let tempdir = TempDir::new("cache").ok().expect("TempDir failed");
+            let dot_delivery = filled(&tempdir);
+            clear(&dot_delivery, &[*kind]).unwrap();
+            for other in ALL.iter() {
+                let present = other.paths(&dot_delivery)[0].exists();
+                assert_eq!(other != kind, present);
+            }
+            assert!(dot_delivery.join("cli.toml").exists());
+        }
+    }
+
+    #[test]
+    fn clear_reports_freed_space() {
+        let tempdir = TempDir::new("cache").ok().expect("TempDir failed");
+        let dot_delivery = filled(&tempdir);
+        let cleared = clear(&dot_delivery, &ALL).unwrap();
+        assert_eq!(vec![Cleared{ kind: CacheKind::Tokens, bytes: 100, existed: true },
+                        Cleared{ kind: CacheKind::Artifacts, bytes: 2048, existed: true },
+                        Cleared{ kind: CacheKind::Responses, bytes: 300, existed: true }],
+                   cleared);
+        assert_eq!(vec!["Cleared tokens: 100 B freed",
+                        "Cleared artifacts: 2.0 KB freed",
+                        "Cleared responses: 300 B freed",
+                        "Freed 2.4 KB in total"],
+                   report(&cleared));
+    }
+
+    #[test]
+    fn clear_missing_caches() {
+        let tempdir = TempDir::new("cache").ok().expect("TempDir failed");
+        let cleared = clear(&tempdir.path().join(".delivery"), &ALL).unwrap();
+        assert!(cleared.iter().all(|c| !c.existed && c.bytes == 0));
+        assert_eq!("No tokens cache to clear", report(&cleared)[0]);
+    }
+
+    #[test]
+    fn selected_defaults_to_all() {
+        assert_eq!(ALL.to_vec(), selected(false, false, false));
+        assert_eq!(vec![CacheKind::Artifacts, CacheKind::Responses], selected(false, true, true));
+    }
+
+    #[test]
+    fn format_bytes_test() {
+        assert_eq!("512 B", format_bytes(512));
+        assert_eq!("1.5 KB", format_bytes(1536));
+        assert_eq!("3.0 MB", format_bytes(3 * 1024 * 1024));
+    }
+}
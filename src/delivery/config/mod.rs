@@ -17,8 +17,10 @@
 
 pub use errors;
 use errors::{DeliveryError, Kind};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::default::Default;
+use std::env;
 use utils::say::{say, sayln};
 use rustc_serialize::Encodable;
 use std::path::PathBuf;
@@ -26,6 +28,9 @@ use toml;
 use utils::mkdir_recursive;
 use std::io::prelude::*;
 use utils::path_join_many::PathJoinMany;
+use regex::Regex;
+
+pub mod wizard;
 
 #[derive(RustcEncodable, Clone)]
 pub struct Config {
@@ -35,9 +40,34 @@ pub struct Config {
     pub organization: Option<String>,
     pub project: Option<String>,
     pub git_port: Option<String>,
-    pub pipeline: Option<String>
+    pub pipeline: Option<String>,
+    pub timeout: Option<String>,
+    pub pipeline_branch_pattern: Option<String>,
+    pub status_map: Option<BTreeMap<String, String>>,
+    pub skip_chown: Option<bool>,
+    pub proxy: Option<BTreeMap<String, String>>,
+    pub token_endpoint: Option<String>,
+    pub token_fields: Option<BTreeMap<String, String>>,
+    pub device_code_endpoint: Option<String>,
+    pub auto_push: Option<bool>,
+    pub help_base_url: Option<String>,
+    pub pool_idle_timeout: Option<String>,
+    pub git_path: Option<String>,
+    pub tls_min_version: Option<String>,
+    pub tls_ciphers: Option<String>,
+    pub tls_ca_cert: Option<String>,
+    pub review_description_max: Option<String>,
+    pub require_reviewers: Option<bool>,
+    pub allowed_servers: Option<Vec<String>>,
+    pub post_review_webhook: Option<String>,
+    /// Extra environment for job phases, from `[env.<phase>]` tables.
+    pub env: Option<BTreeMap<String, BTreeMap<String, String>>>,
+    pub api_base_path: Option<String>
 }
 
+/// The longest timeout, in seconds, we accept for a request.
+pub const MAX_TIMEOUT_SECS: u64 = 3600;
+
 impl Default for Config {
     fn default() -> Config {
         Config{
@@ -47,7 +77,28 @@ impl Default for Config {
             project: None,
             user: None,
             git_port: Some(String::from_str("8989")),
-            pipeline: Some(String::from_str("master"))
+            pipeline: Some(String::from_str("master")),
+            timeout: None,
+            pipeline_branch_pattern: None,
+            status_map: None,
+            skip_chown: None,
+            proxy: None,
+            token_endpoint: None,
+            token_fields: None,
+            device_code_endpoint: None,
+            auto_push: None,
+            help_base_url: None,
+            pool_idle_timeout: None,
+            git_path: None,
+            tls_min_version: None,
+            tls_ciphers: None,
+            tls_ca_cert: None,
+            review_description_max: None,
+            require_reviewers: None,
+            allowed_servers: None,
+            post_review_webhook: None,
+            env: None,
+            api_base_path: None
         }
     }
 }
@@ -62,6 +113,26 @@ macro_rules! config_accessor_for {
                 }
             }
 
+            pub fn $set_name(mut self, $name: &str) -> Config {
+                if !$name.is_empty() {
+                    self.$name = Some(String::from_str($name));
+                }
+                self
+            }
+        }
+    );
+    ($name:ident, $set_name:ident, $err_msg:expr, $check:ident) => (
+        impl Config {
+            pub fn $name(self) -> Result<String, DeliveryError> {
+                match self.$name {
+                    Some(v) => {
+                        try!($check(&v));
+                        Ok(v.clone())
+                    },
+                    None => Err(DeliveryError{ kind: Kind::MissingConfig, detail: Some(String::from_str($err_msg)) })
+                }
+            }
+
             pub fn $set_name(mut self, $name: &str) -> Config {
                 if !$name.is_empty() {
                     self.$name = Some(String::from_str($name));
@@ -72,16 +143,167 @@ macro_rules! config_accessor_for {
     )
 }
 
-config_accessor_for!(server, set_server, "Server not set; try --server");
 config_accessor_for!(user, set_user, "User not set; try --user");
 config_accessor_for!(enterprise, set_enterprise, "Enterprise not set; try --ent");
-config_accessor_for!(organization, set_organization, "Organization not set; try --org");
+config_accessor_for!(organization, set_organization, "Organization not set; try --org", validate_org_name);
 config_accessor_for!(project, set_project, "Project not set; try --project");
 config_accessor_for!(git_port, set_git_port, "Git Port not set");
 config_accessor_for!(pipeline, set_pipeline, "Pipeline not set; try --for");
+config_accessor_for!(timeout, set_timeout, "Timeout not set");
+config_accessor_for!(token_endpoint, set_token_endpoint, "Token endpoint not set; try --token-endpoint");
+
+impl Config {
+    /// The server, which has to be one of `allowed_servers` when
+    /// that is set.
+    pub fn server(self) -> Result<String, DeliveryError> {
+        match self.server {
+            Some(ref v) => {
+                try!(check_server_allowed(v, &self.allowed_servers));
+                Ok(v.clone())
+            },
+            None => Err(DeliveryError{ kind: Kind::MissingConfig, detail: Some(String::from_str("Server not set; try --server")) })
+        }
+    }
+
+    pub fn set_server(mut self, server: &str) -> Config {
+        if !server.is_empty() {
+            self.server = Some(String::from_str(server));
+        }
+        self
+    }
+}
+
+/// Return `Kind::ServerNotAllowed` unless `server` is in `allowed`,
+/// ignoring case. With no `allowed_servers` every server is allowed.
+pub fn check_server_allowed(server: &str, allowed: &Option<Vec<String>>) -> Result<(), DeliveryError> {
+    let list = match *allowed {
+        Some(ref l) => l,
+        None => return Ok(())
+    };
+    let wanted = server.trim().to_lowercase();
+    if list.iter().any(|s| s.trim().to_lowercase() == wanted) {
+        Ok(())
+    } else {
+        Err(DeliveryError{
+            kind: Kind::ServerNotAllowed,
+            detail: Some(format!("'{}' is not one of the allowed_servers: {}", server, list.connect(", ")))
+        })
+    }
+}
+
+/// Organization names go into request paths, so only letters,
+/// numbers, '-', '_' and '.' are allowed.
+pub fn validate_org_name(org: &str) -> Result<(), DeliveryError> {
+    let ok = !org.is_empty() && org.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if ok {
+        Ok(())
+    } else {
+        Err(DeliveryError{
+            kind: Kind::InvalidOrgName,
+            detail: Some(format!("'{}' is not a valid organization name; use only letters, numbers, '-', '_' and '.'", org))
+        })
+    }
+}
+
+/// Parse a timeout given in seconds, rejecting anything that isn't a
+/// positive whole number no larger than `MAX_TIMEOUT_SECS`.
+pub fn parse_timeout(value: &str) -> Result<u64, DeliveryError> {
+    let invalid = |why: &str| {
+        DeliveryError{ kind: Kind::InvalidTimeoutValue,
+                       detail: Some(format!("'{}' {}", value, why)) }
+    };
+    let secs = match value.trim().parse::<i64>() {
+        Ok(s) => s,
+        Err(_) => return Err(invalid("is not a number"))
+    };
+    if secs <= 0 {
+        return Err(invalid("must be greater than zero"));
+    }
+    if secs as u64 > MAX_TIMEOUT_SECS {
+        return Err(invalid(&format!("is more than the maximum of {}", MAX_TIMEOUT_SECS)));
+    }
+    Ok(secs as u64)
+}
+
+/// Extract a pipeline name from `branch` using the regex `pattern`
+/// from `pipeline_branch_pattern`. The first capture group is the
+/// pipeline; a branch that doesn't match gives `None`.
+pub fn pipeline_from_branch(pattern: &str, branch: &str) -> Result<Option<String>, DeliveryError> {
+    let r = match Regex::new(pattern) {
+        Ok(r) => r,
+        Err(e) => return Err(DeliveryError{
+            kind: Kind::ConfigValidation,
+            detail: Some(format!("Invalid pipeline_branch_pattern '{}': {:?}", pattern, e))
+        })
+    };
+    match r.captures(branch).and_then(|caps| caps.at(1)) {
+        Some(pipeline) if !pipeline.is_empty() => Ok(Some(pipeline.to_string())),
+        _ => Ok(None)
+    }
+}
 
 impl Config {
+    /// Set the pipeline from `for_pipeline` if given. Otherwise, if a
+    /// `pipeline_branch_pattern` is configured and `branch` matches
+    /// it, use the pipeline it names; failing that, keep the default.
+    pub fn set_pipeline_for_branch(self, for_pipeline: &str, branch: &str) -> Result<Config, DeliveryError> {
+        if !for_pipeline.is_empty() {
+            return Ok(self.set_pipeline(for_pipeline));
+        }
+        let resolved = match self.pipeline_branch_pattern {
+            Some(ref pattern) => try!(pipeline_from_branch(pattern, branch)),
+            None => None
+        };
+        match resolved {
+            Some(p) => Ok(self.set_pipeline(&p)),
+            None => Ok(self)
+        }
+    }
+
+    /// The configured timeout in seconds, if there is one.
+    pub fn timeout_secs(&self) -> Result<Option<u64>, DeliveryError> {
+        match self.timeout {
+            Some(ref t) => Ok(Some(try!(parse_timeout(t)))),
+            None => Ok(None)
+        }
+    }
+
+    /// How long, in seconds, an idle keep-alive connection is kept
+    /// open, if `pool_idle_timeout` is set.
+    pub fn pool_idle_timeout_secs(&self) -> Result<Option<u64>, DeliveryError> {
+        match self.pool_idle_timeout {
+            Some(ref t) => Ok(Some(try!(parse_timeout(t)))),
+            None => Ok(None)
+        }
+    }
+
+    /// The longest review description, in characters, the server
+    /// accepts, if `review_description_max` is set.
+    pub fn review_description_max_len(&self) -> Result<Option<usize>, DeliveryError> {
+        match self.review_description_max {
+            Some(ref m) => match m.trim().parse::<usize>() {
+                Ok(n) if n > 0 => Ok(Some(n)),
+                _ => Err(DeliveryError{
+                    kind: Kind::ConfigValidation,
+                    detail: Some(format!("review_description_max '{}' must be a positive whole number", m))
+                })
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Load the config from the nearest `.delivery/cli.toml`, with the
+    /// `DELIVERY_*` environment variables applied over it. Config that
+    /// is to be written back should come from `load_config_file`, so
+    /// the environment doesn't end up saved in cli.toml.
     pub fn load_config(cwd: &PathBuf) -> Result<Config, DeliveryError> {
+        let vars: Vec<(String, String)> = env::vars().collect();
+        Ok(try!(Config::load_config_file(cwd)).with_env(&vars))
+    }
+
+    /// Load the config from the nearest `.delivery/cli.toml` as it is
+    /// saved, without the environment.
+    pub fn load_config_file(cwd: &PathBuf) -> Result<Config, DeliveryError> {
         let have_config = Config::have_dot_delivery_cli(cwd);
         match have_config.as_ref() {
             Some(path) => {
@@ -95,21 +317,61 @@ impl Config {
         }
     }
 
+    /// Override the server, enterprise, user and organization with
+    /// `DELIVERY_SERVER`, `DELIVERY_ENTERPRISE`, `DELIVERY_USER` and
+    /// `DELIVERY_ORGANIZATION` from `vars`. Command line flags are set
+    /// afterwards, so they still win.
+    pub fn with_env(self, vars: &[(String, String)]) -> Config {
+        let get = |name: &str| {
+            vars.iter()
+                .find(|&&(ref k, _)| *k == name)
+                .map(|&(_, ref v)| v.clone())
+                .unwrap_or(String::new())
+        };
+        self.set_server(&get("DELIVERY_SERVER"))
+            .set_enterprise(&get("DELIVERY_ENTERPRISE"))
+            .set_user(&get("DELIVERY_USER"))
+            .set_organization(&get("DELIVERY_ORGANIZATION"))
+    }
+
+    /// Whether `.delivery/cli.toml` under `path` already holds this
+    /// config. A file that will not read or parse never does.
+    pub fn same_as_file(&self, path: &PathBuf) -> bool {
+        let file_path = path.join_many(&[".delivery", "cli.toml"]);
+        match Config::read_file(&file_path).and_then(|t| Config::parse_config(&t)) {
+            Ok(existing) => toml::encode_str(&existing) == toml::encode_str(self),
+            Err(_) => false
+        }
+    }
+
+    /// Write the config to `.delivery/cli.toml` under `path`. Any
+    /// failure is a `Kind::ConfigWriteFailed` naming the file.
     pub fn write_file(&self, path: &PathBuf) -> Result<(), DeliveryError> {
         let write_dir = path.join_many(&[".delivery"]);
+        let write_path = path.join_many(&[".delivery", "cli.toml"]);
+        let write_failed = |e: DeliveryError| {
+            DeliveryError{
+                kind: Kind::ConfigWriteFailed,
+                detail: Some(format!("{}: {}", write_path.display(),
+                                     e.detail.unwrap_or(String::new())))
+            }
+        };
         if !write_dir.is_dir() {
-            try!(mkdir_recursive(&write_dir));
+            try!(mkdir_recursive(&write_dir).map_err(|e| write_failed(e)));
         }
-        let write_path = path.join_many(&[".delivery", "cli.toml"]);
         say("white", "Writing configuration to ");
         sayln("yellow", &format!("{}", write_path.display()));
-        let mut f = try!(File::create(&write_path));
         let toml_string = toml::encode_str(self);
         sayln("magenta", "New configuration");
         sayln("magenta", "-----------------");
         say("white", &toml_string);
-        try!(f.write_all(toml_string.as_bytes()));
-        Ok(())
+        let written = File::create(&write_path)
+            .and_then(|mut f| f.write_all(toml_string.as_bytes()));
+        match written {
+            Ok(_) => Ok(()),
+            Err(e) => Err(write_failed(DeliveryError{ kind: Kind::IoError,
+                                                      detail: Some(format!("{}", e)) }))
+        }
     }
 
     pub fn parse_config(toml: &str) -> Result<Config, DeliveryError> {
@@ -133,6 +395,27 @@ impl Config {
         config.organization = Config::stringify_values(table.get("organization"));
         config.user = Config::stringify_values(table.get("user"));
         config.git_port = Config::stringify_values(table.get("git_port"));
+        config.timeout = Config::stringify_values(table.get("timeout"));
+        config.pipeline_branch_pattern = Config::stringify_values(table.get("pipeline_branch_pattern"));
+        config.status_map = Config::stringify_table(table.get("status_map"));
+        config.skip_chown = Config::boolify_values(table.get("skip_chown"));
+        config.proxy = Config::stringify_table(table.get("proxy"));
+        config.token_endpoint = Config::stringify_values(table.get("token_endpoint"));
+        config.token_fields = Config::stringify_table(table.get("token_fields"));
+        config.device_code_endpoint = Config::stringify_values(table.get("device_code_endpoint"));
+        config.auto_push = Config::boolify_values(table.get("auto_push"));
+        config.help_base_url = Config::stringify_values(table.get("help_base_url"));
+        config.pool_idle_timeout = Config::stringify_values(table.get("pool_idle_timeout"));
+        config.git_path = Config::stringify_values(table.get("git_path"));
+        config.tls_min_version = Config::stringify_values(table.get("tls_min_version"));
+        config.tls_ciphers = Config::stringify_values(table.get("tls_ciphers"));
+        config.tls_ca_cert = Config::stringify_values(table.get("tls_ca_cert"));
+        config.review_description_max = Config::stringify_values(table.get("review_description_max"));
+        config.require_reviewers = Config::boolify_values(table.get("require_reviewers"));
+        config.allowed_servers = Config::stringify_array(table.get("allowed_servers"));
+        config.post_review_webhook = Config::stringify_values(table.get("post_review_webhook"));
+        config.env = Config::stringify_tables(table.get("env"));
+        config.api_base_path = Config::stringify_values(table.get("api_base_path"));
         return Ok(config);
     }
 
@@ -158,6 +441,68 @@ impl Config {
         }
     }
 
+    fn boolify_values(toml_value: Option<&toml::Value>) -> Option<bool> {
+        match toml_value {
+            Some(value) => {
+                match value.as_bool() {
+                    Some(b) => Some(b),
+                    None => value.as_str().map(|s| s == "true")
+                }
+            },
+            None => None
+        }
+    }
+
+    fn stringify_table(toml_value: Option<&toml::Value>) -> Option<BTreeMap<String, String>> {
+        match toml_value.and_then(|v| v.as_table()) {
+            Some(table) => {
+                let mut map = BTreeMap::new();
+                for (k, v) in table.iter() {
+                    match v.as_str() {
+                        Some(vstr) => { map.insert(k.clone(), String::from_str(vstr)); },
+                        None => debug!("Ignoring non-string value for {}", k)
+                    }
+                }
+                Some(map)
+            },
+            None => None
+        }
+    }
+
+    /// A table of tables of strings, like `[env.unit]`. Values that
+    /// aren't tables are ignored.
+    fn stringify_tables(toml_value: Option<&toml::Value>) -> Option<BTreeMap<String, BTreeMap<String, String>>> {
+        match toml_value.and_then(|v| v.as_table()) {
+            Some(table) => {
+                let mut map = BTreeMap::new();
+                for (k, v) in table.iter() {
+                    match Config::stringify_table(Some(v)) {
+                        Some(inner) => { map.insert(k.clone(), inner); },
+                        None => debug!("Ignoring non-table value for {}", k)
+                    }
+                }
+                Some(map)
+            },
+            None => None
+        }
+    }
+
+    fn stringify_array(toml_value: Option<&toml::Value>) -> Option<Vec<String>> {
+        match toml_value.and_then(|v| v.as_slice()) {
+            Some(values) => {
+                let mut list = Vec::new();
+                for v in values.iter() {
+                    match v.as_str() {
+                        Some(vstr) => list.push(String::from_str(vstr)),
+                        None => debug!("Ignoring non-string value {}", v)
+                    }
+                }
+                Some(list)
+            },
+            None => None
+        }
+    }
+
     fn check_dot_delivery_cli(path: PathBuf) -> Option<PathBuf> {
         let dot_git = path.join_many(&[".delivery", "cli.toml"]);
         debug!("Checking {}", dot_git.display());
@@ -185,7 +530,14 @@ impl Config {
 
 #[cfg(test)]
 mod tests {
-    use super::Config;
+    use super::{Config, parse_timeout, pipeline_from_branch, validate_org_name};
+    use std::default::Default;
+    use errors::Kind;
+    use std::fs::{self, File};
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+    use tempdir::TempDir;
+    use utils::{self, privileged_process};
 
     #[test]
     fn parse_config() {
@@ -206,4 +558,213 @@ mod tests {
             }
         }
     }
+
+    fn assert_invalid_timeout(value: &str) {
+        match parse_timeout(value) {
+            Err(e) => match e.kind {
+                Kind::InvalidTimeoutValue => assert!(e.detail.unwrap().contains(value)),
+                _ => panic!("Expected InvalidTimeoutValue, got {:?}", e)
+            },
+            Ok(t) => panic!("Expected '{}' to be rejected, got {}", value, t)
+        }
+    }
+
+    fn env(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn with_env_sets_values() {
+        let vars = env(&[("DELIVERY_SERVER", "delivery.example.com"),
+                         ("DELIVERY_ENTERPRISE", "family"),
+                         ("DELIVERY_USER", "cavalera"),
+                         ("DELIVERY_ORGANIZATION", "sepultura")]);
+        let config = Config::default().set_server("from-file.example.com").with_env(&vars);
+        assert_eq!("delivery.example.com", config.clone().server().unwrap());
+        assert_eq!("family", config.clone().enterprise().unwrap());
+        assert_eq!("cavalera", config.clone().user().unwrap());
+        assert_eq!("sepultura", config.organization().unwrap());
+    }
+
+    #[test]
+    fn with_env_overridden_by_flags() {
+        let vars = env(&[("DELIVERY_SERVER", "delivery.example.com"),
+                         ("DELIVERY_ENTERPRISE", "family"),
+                         ("DELIVERY_USER", "cavalera"),
+                         ("DELIVERY_ORGANIZATION", "sepultura")]);
+        let config = Config::default().with_env(&vars)
+            .set_server("flag.example.com")
+            .set_enterprise("flag-ent")
+            .set_user("flag-user")
+            .set_organization("flag-org");
+        assert_eq!("flag.example.com", config.clone().server().unwrap());
+        assert_eq!("flag-ent", config.clone().enterprise().unwrap());
+        assert_eq!("flag-user", config.clone().user().unwrap());
+        assert_eq!("flag-org", config.organization().unwrap());
+    }
+
+    #[test]
+    fn with_env_ignores_unset_and_empty() {
+        let vars = env(&[("DELIVERY_SERVER", ""), ("HOME", "/root")]);
+        let config = Config::default().set_server("from-file.example.com").with_env(&vars);
+        assert_eq!("from-file.example.com", config.clone().server().unwrap());
+        assert!(config.user().is_err());
+    }
+
+    #[test]
+    fn validate_org_name_valid() {
+        assert!(validate_org_name("chef-sw_1.0").is_ok());
+        let config = Config::default().set_organization("chef");
+        assert_eq!("chef", config.organization().unwrap());
+    }
+
+    #[test]
+    fn validate_org_name_with_slash() {
+        let config = Config::default().set_organization("chef/../admin");
+        match config.organization() {
+            Err(e) => match e.kind {
+                Kind::InvalidOrgName => assert!(e.detail.unwrap().starts_with("'chef/../admin' is not")),
+                _ => panic!("Expected InvalidOrgName, got {:?}", e)
+            },
+            Ok(o) => panic!("Expected '{}' to be rejected", o)
+        }
+    }
+
+    #[test]
+    fn server_in_allowed_servers() {
+        let config = Config::parse_config("server = \"Delivery.example.com\"\nallowed_servers = [\"delivery.example.com\", \"staging.example.com\"]").unwrap();
+        assert_eq!(Some(vec!["delivery.example.com".to_string(), "staging.example.com".to_string()]),
+                   config.allowed_servers);
+        assert_eq!("Delivery.example.com", config.clone().server().unwrap());
+        assert_eq!("staging.example.com", config.set_server("staging.example.com").server().unwrap());
+        assert!(Config::default().set_server("anywhere.example.com").server().is_ok());
+    }
+
+    #[test]
+    fn server_not_in_allowed_servers() {
+        let config = Config::parse_config("allowed_servers = [\"delivery.example.com\"]").unwrap();
+        match config.set_server("prod.example.com").server() {
+            Err(e) => match e.kind {
+                Kind::ServerNotAllowed => assert_eq!("'prod.example.com' is not one of the allowed_servers: delivery.example.com",
+                                                     e.detail.unwrap()),
+                _ => panic!("Expected ServerNotAllowed, got {:?}", e)
+            },
+            Ok(s) => panic!("Expected {} to be refused", s)
+        }
+    }
+
+    #[test]
+    fn review_description_max_len_test() {
+        let config = Config::parse_config("review_description_max = \"500\"").unwrap();
+        assert_eq!(Some(500), config.review_description_max_len().unwrap());
+        assert_eq!(None, Config::default().review_description_max_len().unwrap());
+        let bad = Config::parse_config("review_description_max = \"lots\"").unwrap();
+        assert!(bad.review_description_max_len().is_err());
+    }
+
+    #[test]
+    fn pool_idle_timeout_secs_test() {
+        let config = Config::parse_config("pool_idle_timeout = \"45\"").unwrap();
+        assert_eq!(Some(45), config.pool_idle_timeout_secs().unwrap());
+        assert_eq!(None, Config::default().pool_idle_timeout_secs().unwrap());
+    }
+
+    #[test]
+    fn parse_timeout_valid() {
+        assert_eq!(30, parse_timeout("30").unwrap());
+    }
+
+    #[test]
+    fn parse_timeout_invalid() {
+        assert_invalid_timeout("-5");
+        assert_invalid_timeout("0");
+        assert_invalid_timeout("86400");
+        assert_invalid_timeout("soon");
+    }
+
+    #[test]
+    fn pipeline_from_matching_branch() {
+        let pattern = r"^([^/]+)/.+$";
+        assert_eq!(Some("acceptance".to_string()),
+                   pipeline_from_branch(pattern, "acceptance/new-feature").unwrap());
+    }
+
+    #[test]
+    fn pipeline_from_non_matching_branch() {
+        let pattern = r"^([^/]+)/.+$";
+        assert_eq!(None, pipeline_from_branch(pattern, "new-feature").unwrap());
+        let config = Config::parse_config("pipeline_branch_pattern = '^([^/]+)/.+$'").unwrap();
+        let config = config.set_pipeline_for_branch("", "new-feature").unwrap();
+        assert_eq!(Some("master".to_string()), config.pipeline);
+    }
+
+    #[test]
+    fn parse_config_status_map() {
+        let toml = r#"
+            server = "127.0.0.1"
+
+            [status_map]
+            403 = "AuthenticationFailed"
+"#;
+        let config = Config::parse_config(toml).unwrap();
+        let map = config.status_map.unwrap();
+        assert_eq!(Some(&"AuthenticationFailed".to_string()), map.get("403"));
+    }
+
+    #[test]
+    fn parse_config_env_tables() {
+        let toml = r#"
+            server = "127.0.0.1"
+
+            [env.unit]
+            RUBYOPT = "-W0"
+
+            [env.lint]
+            http_proxy = "http://proxy:3128"
+"#;
+        let config = Config::parse_config(toml).unwrap();
+        let env = config.env.unwrap();
+        assert_eq!(Some(&"-W0".to_string()), env.get("unit").unwrap().get("RUBYOPT"));
+        assert_eq!(None, env.get("unit").unwrap().get("http_proxy"));
+        assert_eq!(Some(&"http://proxy:3128".to_string()), env.get("lint").unwrap().get("http_proxy"));
+    }
+
+    fn assert_write_failed(config: &Config, path: &PathBuf) {
+        match config.write_file(path) {
+            Err(e) => match e.kind {
+                Kind::ConfigWriteFailed => assert!(e.detail.unwrap().contains("cli.toml")),
+                _ => panic!("Expected ConfigWriteFailed, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected the write to fail")
+        }
+    }
+
+    #[test]
+    fn write_file_test() {
+        let tempdir = TempDir::new("config").ok().expect("TempDir failed");
+        let path = tempdir.path().to_path_buf();
+        let config = Config::parse_config("server = \"127.0.0.1\"").unwrap();
+        config.write_file(&path).unwrap();
+        let mut toml = String::new();
+        File::open(&path.join(".delivery").join("cli.toml")).unwrap().read_to_string(&mut toml).unwrap();
+        assert!(toml.contains("server = \"127.0.0.1\""));
+    }
+
+    #[test]
+    fn write_file_permission_denied() {
+        let tempdir = TempDir::new("config").ok().expect("TempDir failed");
+        let path = tempdir.path().to_path_buf();
+        let config = Config::parse_config("server = \"127.0.0.1\"").unwrap();
+        fs::create_dir(&path.join(".delivery")).unwrap();
+        // root can write whatever the mode says, so only check the
+        // mode when we aren't root
+        if !privileged_process() {
+            utils::chmod(&path.join(".delivery"), "0555").unwrap();
+            assert_write_failed(&config, &path);
+            utils::chmod(&path.join(".delivery"), "0755").unwrap();
+        }
+        // A directory in the way fails for everyone
+        fs::create_dir(&path.join(".delivery").join("cli.toml")).unwrap();
+        assert_write_failed(&config, &path);
+    }
 }
@@ -0,0 +1,213 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Prompt for the values `delivery setup` needs, for people who don't
+//! have them all to hand as flags.
+//!
+//! Reading and writing go through `BufRead` and `Write`, so the tests
+//! can answer the questions themselves.
+
+use std::io::prelude::*;
+use errors::{DeliveryError, Kind};
+use config::{check_server_allowed, validate_org_name, Config};
+
+pub type Validator = fn(&str) -> Result<(), String>;
+
+/// A server is a host name, optionally with a port; no scheme or path.
+pub fn validate_server(value: &str) -> Result<(), String> {
+    if value.contains("://") {
+        return Err("Give just the host name, without http:// or https://".to_string());
+    }
+    if value.contains("/") || value.contains(" ") {
+        return Err("A server can't contain '/' or spaces".to_string());
+    }
+    Ok(())
+}
+
+/// Enterprise, organization, user and pipeline names, which are held
+/// to the same rule as `validate_org_name`.
+pub fn validate_name(value: &str) -> Result<(), String> {
+    match validate_org_name(value) {
+        Ok(_) => Ok(()),
+        Err(_) => Err("Use only letters, numbers, '-', '_' and '.'".to_string())
+    }
+}
+
+/// An email address: something before and after an '@', and no
+/// spaces.
+pub fn validate_email(value: &str) -> Result<(), String> {
+    match value.find('@') {
+        Some(i) if i > 0 && i + 1 < value.len() && !value.contains(" ") => Ok(()),
+        _ => Err("Give an email address, like alice@example.com".to_string())
+    }
+}
+
+/// Ask for one value. An empty answer takes `default`; an answer that
+/// fails `validate` is explained and the question asked again.
+pub fn ask<R: BufRead, W: Write>(input: &mut R, output: &mut W,
+                                 prompt: &str, default: Option<String>,
+                                 validate: Validator) -> Result<String, DeliveryError> {
+    loop {
+        match default {
+            Some(ref d) => try!(write!(output, "{} [{}]: ", prompt, d)),
+            None => try!(write!(output, "{}: ", prompt))
+        }
+        try!(output.flush());
+        let mut line = String::new();
+        if try!(input.read_line(&mut line)) == 0 {
+            return Err(DeliveryError{
+                kind: Kind::MissingConfig,
+                detail: Some(format!("No answer given for '{}'", prompt))
+            });
+        }
+        let answer = line.trim().to_string();
+        let answer = if answer.is_empty() {
+            match default {
+                Some(ref d) => d.clone(),
+                None => {
+                    try!(writeln!(output, "A value is required"));
+                    continue;
+                }
+            }
+        } else {
+            answer
+        };
+        match validate(&answer) {
+            Ok(_) => return Ok(answer),
+            Err(why) => try!(writeln!(output, "{}", why))
+        }
+    }
+}
+
+/// Ask for the server until the answer is one of the
+/// `allowed_servers` in `config`.
+fn ask_server<R: BufRead, W: Write>(input: &mut R, output: &mut W,
+                                    config: &Config) -> Result<String, DeliveryError> {
+    loop {
+        let server = try!(ask(input, output, "Delivery server", config.server.clone(), validate_server));
+        match check_server_allowed(&server, &config.allowed_servers) {
+            Ok(_) => return Ok(server),
+            Err(e) => try!(writeln!(output, "{}", e.detail.unwrap_or(String::new())))
+        }
+    }
+}
+
+/// Ask for each of the setup values, offering what `config` already
+/// has as the default, and return the config with the answers set.
+pub fn collect<R: BufRead, W: Write>(config: Config, input: &mut R,
+                                     output: &mut W) -> Result<Config, DeliveryError> {
+    let server = try!(ask_server(input, output, &config));
+    let ent = try!(ask(input, output, "Enterprise", config.enterprise.clone(), validate_name));
+    let org = try!(ask(input, output, "Organization", config.organization.clone(), validate_name));
+    let user = try!(ask(input, output, "User", config.user.clone(), validate_name));
+    let pipeline = try!(ask(input, output, "Pipeline", config.pipeline.clone(), validate_name));
+    Ok(config.set_server(&server)
+       .set_enterprise(&ent)
+       .set_organization(&org)
+       .set_user(&user)
+       .set_pipeline(&pipeline))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+    use std::default::Default;
+    use std::io::Cursor;
+
+    fn answers(text: &str) -> Cursor<Vec<u8>> {
+        Cursor::new(text.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn collect_answers() {
+        let mut input = answers("delivery.example.com\nChef\nacme\nalice\n\n");
+        let mut output: Vec<u8> = Vec::new();
+        let config = collect(Default::default(), &mut input, &mut output).unwrap();
+        assert_eq!(Some("delivery.example.com".to_string()), config.server);
+        assert_eq!(Some("Chef".to_string()), config.enterprise);
+        assert_eq!(Some("acme".to_string()), config.organization);
+        assert_eq!(Some("alice".to_string()), config.user);
+        // The default pipeline is master
+        assert_eq!(Some("master".to_string()), config.pipeline);
+        let prompts = String::from_utf8(output).unwrap();
+        assert!(prompts.starts_with("Delivery server: Enterprise: "));
+        assert!(prompts.ends_with("Pipeline [master]: "));
+    }
+
+    #[test]
+    fn collect_asks_again_for_a_server_not_allowed() {
+        let mut existing: Config = Default::default();
+        existing.allowed_servers = Some(vec!["delivery.example.com".to_string()]);
+        let mut input = answers("prod.example.com\ndelivery.example.com\nChef\nacme\nalice\n\n");
+        let mut output: Vec<u8> = Vec::new();
+        let config = collect(existing, &mut input, &mut output).unwrap();
+        assert_eq!(Some("delivery.example.com".to_string()), config.server);
+        let prompts = String::from_utf8(output).unwrap();
+        assert!(prompts.contains("'prod.example.com' is not one of the allowed_servers"));
+    }
+
+    #[test]
+    fn validate_email_test() {
+        assert!(validate_email("alice@example.com").is_ok());
+        for bad in ["alice", "@example.com", "alice@", "alice smith@example.com"].iter() {
+            assert!(validate_email(bad).is_err());
+        }
+    }
+
+    #[test]
+    fn collect_defaults_from_config() {
+        let existing = Config::parse_config(r#"
+            server = "delivery.example.com"
+            enterprise = "Chef"
+            organization = "acme"
+            user = "alice"
+"#).unwrap();
+        let mut input = answers("\n\nnewco\n\n\n");
+        let mut output: Vec<u8> = Vec::new();
+        let config = collect(existing, &mut input, &mut output).unwrap();
+        assert_eq!(Some("delivery.example.com".to_string()), config.server);
+        assert_eq!(Some("newco".to_string()), config.organization);
+        assert_eq!(Some("alice".to_string()), config.user);
+    }
+
+    #[test]
+    fn ask_reprompts_until_valid() {
+        let mut input = answers("\nhttps://delivery.example.com\ndelivery.example.com\n");
+        let mut output: Vec<u8> = Vec::new();
+        let server = ask(&mut input, &mut output, "Delivery server", None, validate_server).unwrap();
+        assert_eq!("delivery.example.com", server);
+        let prompts = String::from_utf8(output).unwrap();
+        assert!(prompts.contains("A value is required\n"));
+        assert!(prompts.contains("without http:// or https://\n"));
+    }
+
+    #[test]
+    fn ask_fails_at_end_of_input() {
+        let mut input = answers("");
+        let mut output: Vec<u8> = Vec::new();
+        assert!(ask(&mut input, &mut output, "User", None, validate_name).is_err());
+    }
+
+    #[test]
+    fn validate_name_test() {
+        assert!(validate_name("acme-corp_1.0").is_ok());
+        assert!(validate_name("acme corp").is_err());
+        assert!(validate_name("acme/corp").is_err());
+    }
+}
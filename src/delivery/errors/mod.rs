@@ -19,6 +19,8 @@ use rustc_serialize::json;
 use std::error::{self, Error};
 use std::io;
 use std::fmt;
+use std::num;
+use std::string;
 use hyper;
 use hyper::HttpError;
 
@@ -60,7 +62,229 @@ pub enum Kind {
     ApiError(hyper::status::StatusCode, Result<String, io::Error>),
     JsonParseError,
     OpenFailed,
-    NoToken
+    NoToken,
+    MultipleReposFound,
+    ServerVersionMismatch,
+    ServerVersionMismatchWarning,
+    NoReviewersAvailable,
+    InvalidTimeoutValue,
+    DuplicateConfigKey,
+    InvalidDate,
+    GitDetachedFromRemote,
+    BuildCookbookCircularDependency,
+    InvalidUtf8InGitOutput,
+    ServerTimeSkew,
+    CookbookTooLarge,
+    BuildCookbookSourceConflict,
+    StaleLockfile,
+    InsufficientDiskSpace,
+    TokenRevoked,
+    ConfigWriteFailed,
+    ReviewBranchNotPushed,
+    InvalidJsonField,
+    NoDefaultBranchOnRemote,
+    ChefClientVersionUnsupported,
+    ArtifactCacheFailed,
+    InvalidOrgName,
+    ConfigSchemaUnknownField,
+    ReviewTargetAncestor,
+    UnsupportedOutputFormat,
+    BerksfileNotFound,
+    InvalidTokenEntry,
+    GitWorkingTreeDirty,
+    InvalidChangeIdentifier,
+    DownloadFailed,
+    TokenCacheVersionMismatch,
+    UnsignedCommits,
+    ApiResponseMissingField,
+    PasswordPromptFailed,
+    Timeout,
+    MissingExecutable,
+    ReviewQuotaExceeded,
+    ProxyError,
+    ConfigCircularInclude,
+    TlsError,
+    ReviewCommentTooLong,
+    BuildCookbookChecksumMissing,
+    BuildCookbookChecksumMismatch,
+    ServerNotAllowed,
+    ParseError,
+    ReviewMissingTitle,
+    InvalidBuildCookbookVersion,
+    GitConfigMissingUser,
+    UnsupportedConfigFormat,
+    ReservedJobEnv,
+    ReviewAbortedByUser,
+    CertFingerprintChanged,
+    TooManyPages,
+    NotInProject,
+    ConfirmationNeedsTerminal,
+    ForeignPageLink
+}
+
+/// Where the help pages for errors live, unless `help_base_url` in
+/// cli.toml says otherwise.
+pub const DEFAULT_HELP_BASE_URL: &'static str = "https://docs.chef.io/delivery/cli/errors";
+
+/// Exit codes, by group of `Kind`. They are part of the interface for
+/// scripts that run delivery, so a variant may join a group but the
+/// numbers don't change.
+pub const EXIT_GENERAL: i32 = 1;
+/// Bad arguments: no such command, or a flag that doesn't parse.
+pub const EXIT_USAGE: i32 = 2;
+/// The user said no when asked to confirm; nothing went wrong.
+pub const EXIT_ABORTED: i32 = 3;
+/// Reading, writing or running something on the local machine.
+pub const EXIT_IO: i32 = 10;
+/// Logging in, and the API token.
+pub const EXIT_AUTH: i32 = 20;
+/// git, and the checks made before a review.
+pub const EXIT_GIT: i32 = 30;
+/// cli.toml and the values in it.
+pub const EXIT_CONFIG: i32 = 40;
+/// Talking to the Delivery server.
+pub const EXIT_HTTP: i32 = 50;
+/// Build cookbooks and running job phases.
+pub const EXIT_JOB: i32 = 60;
+
+impl Kind {
+    /// The name of the variant, without any fields: `ApiError`, not
+    /// `ApiError(500, ..)`.
+    pub fn name(&self) -> String {
+        let debug = format!("{:?}", self);
+        debug.split('(').next().unwrap_or("").to_string()
+    }
+
+    /// A link to the help page for the errors people most often hit
+    /// (authentication, configuration and the network), under `base`.
+    /// The page is the variant name in kebab case, so
+    /// `AuthenticationFailed` is `base/authentication-failed`.
+    pub fn help_url(&self, base: &str) -> Option<String> {
+        match *self {
+            Kind::AuthenticationFailed | Kind::NoToken | Kind::TokenRevoked |
+            Kind::ConfigParse | Kind::MissingConfig | Kind::ConfigValidation |
+            Kind::ConfigWriteFailed | Kind::HttpError(_) | Kind::ApiError(_, _) |
+            Kind::ServerTimeSkew | Kind::ServerVersionMismatch => {
+                Some(format!("{}/{}", base.trim_right_matches('/'), kebab_case(&self.name())))
+            },
+            _ => None
+        }
+    }
+
+    /// The process exit code for this kind of error; see the
+    /// `EXIT_*` constants. Every variant is listed, so a new `Kind`
+    /// has to be given a group before it compiles.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Kind::IoError |
+            Kind::NoHomedir |
+            Kind::OpenFailed |
+            Kind::CopyFailed |
+            Kind::MoveFailed |
+            Kind::ChmodFailed |
+            Kind::ChownFailed |
+            Kind::FailedToExecute |
+            Kind::MissingExecutable |
+            Kind::InsufficientDiskSpace |
+            Kind::StaleLockfile |
+            Kind::ArtifactCacheFailed |
+            Kind::TarFailed => EXIT_IO,
+            Kind::AuthenticationFailed |
+            Kind::NoToken |
+            Kind::TokenRevoked |
+            Kind::InvalidTokenEntry |
+            Kind::TokenCacheVersionMismatch |
+            Kind::PasswordPromptFailed => EXIT_AUTH,
+            Kind::NotOnABranch |
+            Kind::CannotReviewSameBranch |
+            Kind::PushFailed |
+            Kind::BadGitOutputMatch |
+            Kind::GitFailed |
+            Kind::GitSetupFailed |
+            Kind::MultipleReposFound |
+            Kind::GitDetachedFromRemote |
+            Kind::InvalidUtf8InGitOutput |
+            Kind::ReviewBranchNotPushed |
+            Kind::NoDefaultBranchOnRemote |
+            Kind::ReviewTargetAncestor |
+            Kind::GitWorkingTreeDirty |
+            Kind::UnsignedCommits |
+            Kind::ReviewQuotaExceeded |
+            Kind::ReviewCommentTooLong |
+            Kind::GitConfigMissingUser |
+            Kind::ReviewMissingTitle => EXIT_GIT,
+            Kind::NoConfig |
+            Kind::ConfigParse |
+            Kind::MissingConfig |
+            Kind::ConfigValidation |
+            Kind::DuplicateConfigKey |
+            Kind::ConfigWriteFailed |
+            Kind::InvalidOrgName |
+            Kind::ConfigSchemaUnknownField |
+            Kind::ConfigCircularInclude |
+            Kind::InvalidTimeoutValue |
+            Kind::UnsupportedConfigFormat |
+            Kind::ReservedJobEnv |
+            Kind::NotInProject |
+            Kind::ServerNotAllowed => EXIT_CONFIG,
+            Kind::UnsupportedHttpMethod |
+            Kind::HttpError(_) |
+            Kind::ApiError(_, _) |
+            Kind::ServerVersionMismatch |
+            Kind::ServerVersionMismatchWarning |
+            Kind::NoReviewersAvailable |
+            Kind::ServerTimeSkew |
+            Kind::ApiResponseMissingField |
+            Kind::Timeout |
+            Kind::ProxyError |
+            Kind::TlsError |
+            Kind::CertFingerprintChanged |
+            Kind::TooManyPages |
+            Kind::ForeignPageLink |
+            Kind::DownloadFailed => EXIT_HTTP,
+            Kind::NoBuildCookbook |
+            Kind::NoValidBuildCookbook |
+            Kind::MissingBuildCookbookName |
+            Kind::MissingBuildCookbookField |
+            Kind::BerksFailed |
+            Kind::BerksfileNotFound |
+            Kind::SupermarketFailed |
+            Kind::ChefServerFailed |
+            Kind::ChefFailed |
+            Kind::ChefClientVersionUnsupported |
+            Kind::BuildCookbookCircularDependency |
+            Kind::CookbookTooLarge |
+            Kind::BuildCookbookSourceConflict |
+            Kind::BuildCookbookChecksumMissing |
+            Kind::BuildCookbookChecksumMismatch |
+            Kind::ExpectedJsonString |
+            Kind::InvalidBuildCookbookVersion |
+            Kind::InvalidJsonField => EXIT_JOB,
+            Kind::NoMatchingCommand |
+            Kind::InvalidDate |
+            Kind::UnsupportedOutputFormat |
+            Kind::ConfirmationNeedsTerminal |
+            Kind::InvalidChangeIdentifier => EXIT_USAGE,
+            Kind::JsonError |
+            Kind::JsonEncode |
+            Kind::JsonParseError |
+            Kind::ParseError => EXIT_GENERAL,
+            Kind::ReviewAbortedByUser => EXIT_ABORTED
+        }
+    }
+
+    /// Look up a `Kind` by its variant name, for the ones that make
+    /// sense to configure (see `status_map` in cli.toml).
+    pub fn from_name(name: &str) -> Option<Kind> {
+        match name {
+            "AuthenticationFailed" => Some(Kind::AuthenticationFailed),
+            "NoToken" => Some(Kind::NoToken),
+            "NoReviewersAvailable" => Some(Kind::NoReviewersAvailable),
+            "ServerVersionMismatch" => Some(Kind::ServerVersionMismatch),
+            "TokenRevoked" => Some(Kind::TokenRevoked),
+            _ => None
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -73,6 +297,95 @@ impl DeliveryError {
     pub fn detail(&self) -> Option<String> {
         self.detail.clone()
     }
+
+    /// The code to exit with for this error: see `Kind::exit_code`.
+    pub fn exit_code(&self) -> i32 {
+        self.kind.exit_code()
+    }
+
+    /// For an `ApiError`, what the server had to say: the `message`
+    /// of a `{"error": .., "message": ..}` body, or the body as it is
+    /// when it isn't JSON like that. None for other kinds, and when
+    /// the body couldn't be read or was empty.
+    pub fn api_message(&self) -> Option<String> {
+        let body = match self.kind {
+            Kind::ApiError(_, Ok(ref body)) if !body.trim().is_empty() => body,
+            _ => return None
+        };
+        let message = json::Json::from_str(body).ok()
+            .and_then(|j| j.find("message").and_then(|m| m.as_string()).map(|m| m.to_string()));
+        Some(message.unwrap_or(body.trim().to_string()))
+    }
+
+    /// The error as one line of JSON, for tools that run delivery:
+    /// `{"kind":"ApiError","code":422,"message":..,"detail":..,"cause":..}`.
+    /// `kind` is the variant name, and `message` the description.
+    /// `code` is the HTTP status of an `ApiError`, and `cause` what the
+    /// server said, or the error underneath an `HttpError`; both are
+    /// null for other kinds, as `detail` is when there isn't one.
+    pub fn to_json(&self) -> String {
+        let (code, cause) = match self.kind {
+            Kind::ApiError(ref status, Ok(_)) => (Some(status.to_u16()), self.api_message()),
+            Kind::ApiError(ref status, Err(ref e)) => (Some(status.to_u16()), Some(chain(e))),
+            Kind::HttpError(ref e) => (None, Some(chain(e))),
+            _ => (None, None)
+        };
+        let out = ErrorJson{
+            kind: self.kind.name(),
+            code: code,
+            message: self.description().to_string(),
+            detail: self.detail.clone(),
+            cause: cause
+        };
+        json::encode(&out).unwrap()
+    }
+
+    /// The description and detail, followed by each error in the
+    /// `cause()` chain, so wrapped I/O and HTTP errors aren't lost.
+    pub fn chain(&self) -> String {
+        let top = format!("{}", self);
+        match self.cause() {
+            Some(c) => format!("{} caused by: {}", top, chain(c)),
+            None => top
+        }
+    }
+}
+
+/// What `DeliveryError::to_json` writes, in this order.
+#[derive(RustcEncodable)]
+struct ErrorJson {
+    kind: String,
+    code: Option<u16>,
+    message: String,
+    detail: Option<String>,
+    cause: Option<String>
+}
+
+fn kebab_case(name: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                out.push('-');
+            }
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Render `err` and everything in its `cause()` chain, outermost
+/// first.
+pub fn chain(err: &Error) -> String {
+    let mut out = format!("{}", err);
+    let mut cause = err.cause();
+    while let Some(c) = cause {
+        out.push_str(&format!(" caused by: {}", c));
+        cause = c.cause();
+    }
+    out
 }
 
 impl error::Error for DeliveryError {
@@ -84,7 +397,7 @@ impl error::Error for DeliveryError {
             Kind::FailedToExecute => "Tried to fork a process, and failed",
             Kind::PushFailed => "Git Push failed!",
             Kind::GitFailed => "Git command failed!",
-            Kind::GitSetupFailed => "Setup failed; you have already set up delivery.",
+            Kind::GitSetupFailed => "Setting up the git repository failed",
             Kind::BadGitOutputMatch => "A line of git porcelain did not match!",
             Kind::NoConfig => "Cannot find a .git/config file",
             Kind::ConfigParse => "Failed to parse the cli config file",
@@ -114,7 +427,64 @@ impl error::Error for DeliveryError {
             Kind::JsonParseError => "Attempted to parse invalid JSON",
             Kind::OpenFailed => "Open command failed",
             Kind::AuthenticationFailed => "Authentication failed",
-            Kind::NoToken => "Missing API token. Try `delivery token` to create one"
+            Kind::NoToken => "Missing API token. Try `delivery token` to create one",
+            Kind::MultipleReposFound => "Found more than one git repository; run delivery from the root of the repository you mean",
+            Kind::ServerVersionMismatch => "The Delivery server version is incompatible with this CLI",
+            Kind::ServerVersionMismatchWarning => "The Delivery server version differs from this CLI; proceeding anyway",
+            Kind::NoReviewersAvailable => "No eligible reviewers are set up for this project",
+            Kind::InvalidTimeoutValue => "Timeouts must be a whole number of seconds, greater than zero",
+            Kind::DuplicateConfigKey => "A key appears more than once in .delivery/config.json",
+            Kind::InvalidDate => "Dates must be given as YYYY-MM-DD",
+            Kind::GitDetachedFromRemote => "The current branch has no upstream; try `git push -u delivery <branch>`",
+            Kind::BuildCookbookCircularDependency => "The build cookbook's dependencies form a cycle",
+            Kind::InvalidUtf8InGitOutput => "Git printed output that is not valid UTF-8",
+            Kind::ServerTimeSkew => "The server clock differs too much from the local clock",
+            Kind::CookbookTooLarge => "The cookbook is larger than the configured size limit",
+            Kind::BuildCookbookSourceConflict => "The build_cookbook names more than one source",
+            Kind::StaleLockfile => "Another delivery run holds the lock; use --force-unlock if it crashed",
+            Kind::InsufficientDiskSpace => "There is not enough free disk space for the download",
+            Kind::TokenRevoked => "Your API token was revoked; run 'delivery token' to log in again",
+            Kind::ConfigWriteFailed => "Failed to write the configuration file",
+            Kind::ReviewBranchNotPushed => "The branch under review has not been pushed to the delivery remote",
+            Kind::InvalidJsonField => "A field in the config has an invalid value",
+            Kind::NoDefaultBranchOnRemote => "The remote has no default branch (HEAD) set",
+            Kind::ChefClientVersionUnsupported => "The installed chef-client is older than this job requires",
+            Kind::ArtifactCacheFailed => "Failed to read or write the artifact cache",
+            Kind::InvalidOrgName => "The organization name contains characters not allowed in a URL path",
+            Kind::ConfigSchemaUnknownField => "The config has a key we don't know about",
+            Kind::ReviewTargetAncestor => "The review branch is behind its target and adds nothing to it",
+            Kind::UnsupportedOutputFormat => "The output format is not one we know",
+            Kind::BerksfileNotFound => "The build cookbook uses Berkshelf but has no Berksfile",
+            Kind::InvalidTokenEntry => "A token file entry is malformed",
+            Kind::GitWorkingTreeDirty => "The working tree has uncommitted changes",
+            Kind::InvalidChangeIdentifier => "The change identifier is not a change id or branch name",
+            Kind::DownloadFailed => "Failed to download a file",
+            Kind::TokenCacheVersionMismatch => "The token cache is in a format this version does not understand",
+            Kind::UnsignedCommits => "Some commits in the review are not signed",
+            Kind::ApiResponseMissingField => "The API response is missing a required field",
+            Kind::PasswordPromptFailed => "Unable to read a password from the terminal",
+            Kind::Timeout => "The Delivery server did not respond in time",
+            Kind::MissingExecutable => "The configured executable does not exist",
+            Kind::ReviewQuotaExceeded => "You have too many open changes to submit another review",
+            Kind::ProxyError => "The proxy refused to connect us to the Delivery server",
+            Kind::ConfigCircularInclude => "The config_url includes form a cycle",
+            Kind::TlsError => "The TLS connection to the Delivery server failed",
+            Kind::ReviewCommentTooLong => "The review description is longer than the server allows",
+            Kind::BuildCookbookChecksumMissing => "The build cookbook has no checksum to verify it against",
+            Kind::BuildCookbookChecksumMismatch => "The build cookbook does not match its checksum",
+            Kind::ServerNotAllowed => "The server is not in allowed_servers in .delivery/cli.toml",
+            Kind::ParseError => "Expected a number",
+            Kind::ReviewMissingTitle => "The review has no title",
+            Kind::InvalidBuildCookbookVersion => "The build_cookbook version is not a valid version or constraint",
+            Kind::GitConfigMissingUser => "git has no user.name or user.email to commit with",
+            Kind::UnsupportedConfigFormat => "The config file is not in a format we can read",
+            Kind::ReservedJobEnv => "A phase environment sets a variable the job itself controls",
+            Kind::ReviewAbortedByUser => "The review was not confirmed, so nothing was pushed",
+            Kind::CertFingerprintChanged => "The server's certificate is not the one we saw before",
+            Kind::TooManyPages => "The server kept sending more pages than we will follow",
+            Kind::NotInProject => "Not inside a Delivery project",
+            Kind::ConfirmationNeedsTerminal => "There is no terminal to ask for confirmation on",
+            Kind::ForeignPageLink => "A list page linked to another server"
         }
     }
 
@@ -133,8 +503,23 @@ impl error::Error for DeliveryError {
 }
 
 impl fmt::Display for DeliveryError {
+    /// The description, then the API status and body for `ApiError`,
+    /// then the detail if there is one.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.description().fmt(f)
+        try!(self.description().fmt(f));
+        match self.kind {
+            Kind::ApiError(ref status, Ok(ref body)) => {
+                try!(write!(f, ": {} {}", status, body));
+            },
+            Kind::ApiError(ref status, Err(ref e)) => {
+                try!(write!(f, ": {} {}", status, e));
+            },
+            _ => {}
+        }
+        match self.detail {
+            Some(ref d) => write!(f, ": {}", d),
+            None => Ok(())
+        }
     }
 }
 
@@ -165,10 +550,35 @@ impl error::FromError<io::Error> for DeliveryError {
     }
 }
 
+/// Where a syntax error is, as `line 7, column 3: invalid syntax`.
 impl error::FromError<json::ParserError> for DeliveryError {
     fn from_error(err: json::ParserError) -> DeliveryError {
+        let detail = match err {
+            json::ParserError::SyntaxError(code, line, col) =>
+                format!("line {}, column {}: {}", line, col, json::error_str(code)),
+            json::ParserError::IoError(ref e) =>
+                format!("{}: {}", err.description(), e)
+        };
         DeliveryError{
             kind: Kind::JsonError,
+            detail: Some(detail)
+        }
+    }
+}
+
+impl error::FromError<num::ParseIntError> for DeliveryError {
+    fn from_error(err: num::ParseIntError) -> DeliveryError {
+        DeliveryError{
+            kind: Kind::ParseError,
+            detail: Some(err.description().to_string())
+        }
+    }
+}
+
+impl error::FromError<string::FromUtf8Error> for DeliveryError {
+    fn from_error(err: string::FromUtf8Error) -> DeliveryError {
+        DeliveryError{
+            kind: Kind::InvalidUtf8InGitOutput,
             detail: Some(err.description().to_string())
         }
     }
@@ -182,3 +592,200 @@ impl error::FromError<hyper::HttpError> for DeliveryError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::kebab_case;
+    use hyper;
+    use hyper::status::StatusCode;
+    use rustc_serialize::json;
+    use std::error::{self, Error};
+    use std::fmt;
+    use std::fs::File;
+
+    #[derive(Debug)]
+    struct Inner;
+
+    #[derive(Debug)]
+    struct Outer {
+        inner: Inner
+    }
+
+    impl Error for Inner {
+        fn description(&self) -> &str { "I/O error: connection reset" }
+    }
+
+    impl fmt::Display for Inner {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.description().fmt(f)
+        }
+    }
+
+    impl Error for Outer {
+        fn description(&self) -> &str { "HTTP error: request failed" }
+        fn cause(&self) -> Option<&Error> { Some(&self.inner) }
+    }
+
+    impl fmt::Display for Outer {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.description().fmt(f)
+        }
+    }
+
+    #[test]
+    fn chain_renders_every_level() {
+        let err = Outer { inner: Inner };
+        assert_eq!("HTTP error: request failed caused by: I/O error: connection reset",
+                   chain(&err));
+    }
+
+    #[test]
+    fn help_url_for_common_kinds() {
+        let base = "https://docs.example.com/errors/";
+        assert_eq!(Some("https://docs.example.com/errors/authentication-failed".to_string()),
+                   Kind::AuthenticationFailed.help_url(base));
+        assert_eq!(Some("https://docs.example.com/errors/config-parse".to_string()),
+                   Kind::ConfigParse.help_url(base));
+        assert_eq!(Some(format!("{}/no-token", DEFAULT_HELP_BASE_URL)),
+                   Kind::NoToken.help_url(DEFAULT_HELP_BASE_URL));
+        assert_eq!(None, Kind::CannotReviewSameBranch.help_url(base));
+    }
+
+    #[test]
+    fn kind_name_drops_fields() {
+        assert_eq!("NoToken", Kind::NoToken.name());
+        let err = hyper::HttpError::HttpMethodError;
+        assert_eq!("HttpError", Kind::HttpError(err).name());
+        assert_eq!("http-error", kebab_case("HttpError"));
+    }
+
+    #[test]
+    fn delivery_error_chain_without_cause() {
+        let err = DeliveryError{ kind: Kind::NoToken,
+                                 detail: Some("server: 127.0.0.1".to_string()) };
+        assert_eq!(format!("{}: server: 127.0.0.1", err.description()), err.chain());
+    }
+
+    #[test]
+    fn json_parser_error_has_position() {
+        let malformed = "{\n  \"version\": \"1\",\n  \"build_cookbook\": \"delivery-truck\",\n}\n";
+        let err: DeliveryError = match json::Json::from_str(malformed) {
+            Err(e) => error::FromError::from_error(e),
+            Ok(j) => panic!("Expected a parse error, got {}", j)
+        };
+        match err.kind {
+            Kind::JsonError => {},
+            _ => panic!("Expected JsonError, got {:?}", err)
+        }
+        assert!(err.detail.unwrap().starts_with("line 4, column "));
+    }
+
+    #[test]
+    fn display_includes_detail() {
+        let err = DeliveryError{ kind: Kind::IoError,
+                                 detail: Some("permission denied".to_string()) };
+        assert_eq!(format!("{}: permission denied", err.description()),
+                   format!("{}", err));
+        let bare = DeliveryError{ kind: Kind::IoError, detail: None };
+        assert_eq!(bare.description(), &format!("{}", bare)[..]);
+    }
+
+    #[test]
+    fn display_includes_api_status_and_body() {
+        let body = "{\"error\":\"name is required\"}".to_string();
+        let err = DeliveryError{ kind: Kind::ApiError(StatusCode::UnprocessableEntity,
+                                                      Ok(body.clone())),
+                                 detail: None };
+        assert_eq!(format!("{}: {} {}", err.description(),
+                           StatusCode::UnprocessableEntity, body),
+                   format!("{}", err));
+    }
+
+    #[test]
+    fn api_message_from_json_body() {
+        let body = "{\"error\":\"conflict\",\"message\":\"Project fun already exists\"}".to_string();
+        let err = DeliveryError{ kind: Kind::ApiError(StatusCode::Conflict, Ok(body)),
+                                 detail: None };
+        assert_eq!(Some("Project fun already exists".to_string()), err.api_message());
+    }
+
+    #[test]
+    fn api_message_from_plain_body() {
+        let err = DeliveryError{ kind: Kind::ApiError(StatusCode::BadGateway,
+                                                      Ok("Bad Gateway\n".to_string())),
+                                 detail: None };
+        assert_eq!(Some("Bad Gateway".to_string()), err.api_message());
+        let no_message = DeliveryError{ kind: Kind::ApiError(StatusCode::Forbidden,
+                                                             Ok("{\"error\":\"forbidden\"}".to_string())),
+                                        detail: None };
+        assert_eq!(Some("{\"error\":\"forbidden\"}".to_string()), no_message.api_message());
+    }
+
+    #[test]
+    fn api_message_from_unreadable_body() {
+        let read_error = File::open("/nonexistent/delivery/body").err().unwrap();
+        let err = DeliveryError{ kind: Kind::ApiError(StatusCode::InternalServerError, Err(read_error)),
+                                 detail: None };
+        assert_eq!(None, err.api_message());
+        let other = DeliveryError{ kind: Kind::NoToken, detail: Some("server: 127.0.0.1".to_string()) };
+        assert_eq!(None, other.api_message());
+    }
+
+    #[test]
+    fn parse_int_error_converts() {
+        let err: DeliveryError = match "12a".parse::<u32>() {
+            Err(e) => error::FromError::from_error(e),
+            Ok(n) => panic!("Expected a parse error, got {}", n)
+        };
+        match err.kind {
+            Kind::ParseError => {},
+            _ => panic!("Expected ParseError, got {:?}", err)
+        }
+        assert_eq!(Some("invalid digit found in string".to_string()), err.detail);
+    }
+
+    #[test]
+    fn from_utf8_error_converts() {
+        let err: DeliveryError = match String::from_utf8(vec![b'o', b'k', 0xff]) {
+            Err(e) => error::FromError::from_error(e),
+            Ok(s) => panic!("Expected a UTF-8 error, got {}", s)
+        };
+        match err.kind {
+            Kind::InvalidUtf8InGitOutput => {},
+            _ => panic!("Expected InvalidUtf8InGitOutput, got {:?}", err)
+        }
+        assert!(err.detail.unwrap().len() > 0);
+    }
+
+    #[test]
+    fn exit_code_by_group() {
+        let code = |kind: Kind| DeliveryError{ kind: kind, detail: None }.exit_code();
+        assert_eq!(EXIT_IO, code(Kind::OpenFailed));
+        assert_eq!(EXIT_AUTH, code(Kind::AuthenticationFailed));
+        assert_eq!(EXIT_GIT, code(Kind::NotOnABranch));
+        assert_eq!(EXIT_CONFIG, code(Kind::ConfigParse));
+        assert_eq!(EXIT_HTTP, code(Kind::ApiError(StatusCode::BadGateway, Ok(String::new()))));
+        assert_eq!(EXIT_JOB, code(Kind::NoBuildCookbook));
+        assert_eq!(EXIT_USAGE, code(Kind::NoMatchingCommand));
+        assert_eq!(EXIT_GENERAL, code(Kind::JsonParseError));
+        assert_eq!(EXIT_ABORTED, code(Kind::ReviewAbortedByUser));
+        assert_eq!((10, 20, 30, 40, 50), (EXIT_IO, EXIT_AUTH, EXIT_GIT, EXIT_CONFIG, EXIT_HTTP));
+    }
+
+    #[test]
+    fn to_json_io_error() {
+        let err = DeliveryError{ kind: Kind::IoError, detail: Some("Failed to read \"Berksfile\"".to_string()) };
+        assert_eq!("{\"kind\":\"IoError\",\"code\":null,\"message\":\"An I/O Error occurred\",\"detail\":\"Failed to read \\\"Berksfile\\\"\",\"cause\":null}",
+                   err.to_json());
+    }
+
+    #[test]
+    fn to_json_api_error() {
+        let body = "{\"error\":\"conflict\",\"message\":\"Project fun already exists\"}".to_string();
+        let err = DeliveryError{ kind: Kind::ApiError(StatusCode::UnprocessableEntity, Ok(body)),
+                                 detail: Some("POST orgs/acme/projects".to_string()) };
+        assert_eq!("{\"kind\":\"ApiError\",\"code\":422,\"message\":\"An API Error occured\",\"detail\":\"POST orgs/acme/projects\",\"cause\":\"Project fun already exists\"}",
+                   err.to_json());
+    }
+}
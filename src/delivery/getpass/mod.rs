@@ -23,11 +23,34 @@
 use libc::types::os::arch::c95::c_char;
 use std::ffi::{CString, CStr};
 use std::str;
+use errors::{DeliveryError, Kind};
 
 extern {
     fn getpass(pass: *const c_char) -> *const c_char;
 }
 
+/// Like `read`, but `Kind::PasswordPromptFailed` when getpass fails,
+/// which it does when there is no terminal to open, or when what was
+/// typed is not valid UTF-8.
+pub fn try_read(prompt: &str) -> Result<String, DeliveryError> {
+    let cprompt = CString::new(prompt.as_bytes()).unwrap();
+    let cresult = unsafe { getpass(cprompt.as_ptr()) };
+    if cresult.is_null() {
+        return Err(DeliveryError{
+            kind: Kind::PasswordPromptFailed,
+            detail: Some("Could not open the terminal; pipe the password on stdin instead".to_string())
+        });
+    }
+    let bytes = unsafe { CStr::from_ptr(cresult).to_bytes() };
+    match str::from_utf8(bytes) {
+        Ok(s) => Ok(s.to_string()),
+        Err(_) => Err(DeliveryError{
+            kind: Kind::PasswordPromptFailed,
+            detail: Some("The password is not valid UTF-8".to_string())
+        })
+    }
+}
+
 pub fn read(prompt: &str) -> String {
     let cprompt = CString::new(prompt.as_bytes()).unwrap();
     let cresult = unsafe { getpass(cprompt.as_ptr()) };
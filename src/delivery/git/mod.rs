@@ -20,11 +20,40 @@ pub use errors;
 use std::process::Command;
 use utils::say::{say, sayln, Spinner};
 use errors::{DeliveryError, Kind};
+use config::check_server_allowed;
 use std::env;
 use std::path::{Path, PathBuf};
 use std::convert::AsRef;
 use std::error;
 use std::fs::PathExt;
+use std::mem;
+use std::sync::{Once, ONCE_INIT};
+
+static mut show_git_output: bool = false;
+
+static GIT_PATH_INIT: Once = ONCE_INIT;
+static mut chosen_git: *const String = 0 as *const String;
+
+/// Print the complete stdout and stderr of every git command we run,
+/// not just what we make of it.
+pub fn turn_on_git_output() {
+    unsafe {
+        show_git_output = true;
+    }
+}
+
+/// The raw output of `git args`, as printed under `--show-git-output`.
+pub fn format_git_output(args: &[&str], stdout: &str, stderr: &str) -> String {
+    format!("git {}\nSTDOUT: {}\nSTDERR: {}\n", args.connect(" "), stdout, stderr)
+}
+
+fn say_git_output(args: &[&str], stdout: &str, stderr: &str) {
+    unsafe {
+        if show_git_output {
+            say("white", &format_git_output(args, stdout, stderr));
+        }
+    }
+}
 
 fn cwd() -> PathBuf {
     env::current_dir().unwrap()
@@ -47,6 +76,12 @@ fn parse_get_head(stdout: &str) -> Result<String, DeliveryError> {
         let token = caps.at(1).unwrap();
         if token == "*" {
             let branch = caps.at(2).unwrap();
+            // A detached HEAD is listed as `(HEAD detached at 1a2b3c4)`
+            if branch.starts_with("(") {
+                return Err(DeliveryError{ kind: Kind::NotOnABranch,
+                                          detail: Some(format!("{}; check out a branch to review",
+                                                               branch.trim_matches(&['(', ')'][..]))) });
+            }
             return Ok(String::from_str(branch));
         }
     }
@@ -74,6 +109,152 @@ fn test_parse_get_head() {
     };
 }
 
+#[test]
+fn test_parse_get_head_detached() {
+    match parse_get_head("* (HEAD detached at 1a2b3c4)\n  master\n") {
+        Err(e) => match e.kind {
+            Kind::NotOnABranch => assert!(e.detail.unwrap().starts_with("HEAD detached at 1a2b3c4;")),
+            _ => panic!("Expected NotOnABranch, got {:?}", e)
+        },
+        Ok(b) => panic!("Expected a detached HEAD to be refused, got {}", b)
+    }
+}
+
+/// Check `head` can be reviewed against the `target` branch: it must
+/// be a branch, and not `target` itself.
+pub fn check_review_branches(head: &str, target: &str) -> Result<(), DeliveryError> {
+    if head.is_empty() || head == "HEAD" {
+        return Err(DeliveryError{ kind: Kind::NotOnABranch, detail: None });
+    }
+    if head == target {
+        return Err(DeliveryError{
+            kind: Kind::CannotReviewSameBranch,
+            detail: Some(format!("You are on {}; create a feature branch, or pass --for with another target", head))
+        });
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_review_branches_same_branch() {
+    match check_review_branches("acceptance", "acceptance") {
+        Err(e) => match e.kind {
+            Kind::CannotReviewSameBranch => assert!(e.detail.unwrap().starts_with("You are on acceptance")),
+            _ => panic!("Expected CannotReviewSameBranch, got {:?}", e)
+        },
+        Ok(_) => panic!("Expected a review of acceptance for acceptance to be refused")
+    }
+}
+
+#[test]
+fn test_check_review_branches_other_target() {
+    assert!(check_review_branches("feature", "acceptance").is_ok());
+    assert!(check_review_branches("feature", "master").is_ok());
+    assert_eq!("_for/acceptance/feature", review_branch_name("feature", "acceptance"));
+    match check_review_branches("HEAD", "master") {
+        Err(e) => match e.kind {
+            Kind::NotOnABranch => {},
+            _ => panic!("Expected NotOnABranch, got {:?}", e)
+        },
+        Ok(_) => panic!("Expected a detached HEAD to be refused")
+    }
+}
+
+/// The upstream the current branch in `path` tracks, e.g.
+/// `delivery/master`, or `Kind::GitDetachedFromRemote` if it tracks
+/// nothing.
+pub fn get_upstream(path: &PathBuf) -> Result<String, DeliveryError> {
+    let gitr = try!(git_command(&["status", "--porcelain", "--branch"], path));
+    parse_upstream(&gitr.stdout)
+}
+
+/// Pull the upstream out of the `## branch...upstream` header that
+/// `git status --porcelain --branch` prints. A branch with no
+/// upstream only shows `## branch`.
+fn parse_upstream(stdout: &str) -> Result<String, DeliveryError> {
+    let header = match stdout.lines_any().next() {
+        Some(l) if l.starts_with("## ") => &l[3..],
+        _ => return Err(DeliveryError{ kind: Kind::BadGitOutputMatch,
+                                       detail: Some(format!("Failed to match: {}", stdout)) })
+    };
+    let r = regex!(r"^(.+?)\.\.\.([^ ]+)");
+    match r.captures(header) {
+        Some(caps) => Ok(String::from_str(caps.at(2).unwrap())),
+        None => {
+            let branch = header.split(' ').next().unwrap_or(header);
+            Err(DeliveryError{ kind: Kind::GitDetachedFromRemote,
+                               detail: Some(format!("Branch '{}' is not tracking a remote branch", branch)) })
+        }
+    }
+}
+
+#[test]
+fn test_parse_upstream() {
+    let stdout = "## adam/feature...delivery/adam/feature [ahead 1]
+ M src/main.rs";
+    assert_eq!("delivery/adam/feature", parse_upstream(stdout).unwrap());
+}
+
+#[test]
+fn test_parse_upstream_without_upstream() {
+    let stdout = "## adam/feature
+?? new_file";
+    match parse_upstream(stdout) {
+        Err(e) => {
+            match e.kind {
+                Kind::GitDetachedFromRemote => {},
+                _ => panic!("Expected GitDetachedFromRemote, got {:?}", e)
+            }
+            assert!(e.detail.unwrap().contains("adam/feature"));
+        },
+        Ok(u) => panic!("Expected no upstream, got {}", u)
+    }
+}
+
+/// The default branch of `remote`, which is whatever its HEAD points
+/// at.
+pub fn get_remote_head(remote: &str, path: &PathBuf) -> Result<String, DeliveryError> {
+    let gitr = try!(git_command(&["ls-remote", "--symref", remote, "HEAD"], path));
+    parse_remote_head(remote, &gitr.stdout)
+}
+
+/// Find `ref: refs/heads/<branch>\tHEAD` in `git ls-remote --symref`
+/// output.
+fn parse_remote_head(remote: &str, stdout: &str) -> Result<String, DeliveryError> {
+    for line in stdout.lines_any() {
+        if line.starts_with("ref: refs/heads/") && line.ends_with("\tHEAD") {
+            let branch = &line["ref: refs/heads/".len()..line.len() - "\tHEAD".len()];
+            return Ok(branch.to_string());
+        }
+    }
+    Err(DeliveryError{
+        kind: Kind::NoDefaultBranchOnRemote,
+        detail: Some(format!("Remote '{}' has no HEAD; name the base branch with --for", remote))
+    })
+}
+
+#[test]
+fn test_parse_remote_head() {
+    assert_eq!("main",
+               parse_remote_head("delivery", "ref: refs/heads/main\tHEAD\n4f1b2c3d\tHEAD\n").unwrap());
+}
+
+#[test]
+fn test_parse_remote_head_missing() {
+    for stdout in ["", "4f1b2c3d\trefs/heads/master\n"].iter() {
+        match parse_remote_head("delivery", stdout) {
+            Err(e) => {
+                match e.kind {
+                    Kind::NoDefaultBranchOnRemote => {},
+                    _ => panic!("Expected NoDefaultBranchOnRemote, got {:?}", e)
+                }
+                assert!(e.detail.unwrap().contains("--for"));
+            },
+            Ok(b) => panic!("Expected no default branch, got {}", b)
+        }
+    }
+}
+
 pub struct GitResult {
     pub stdout: String,
     pub stderr: String
@@ -84,39 +265,280 @@ pub struct GitResult {
 // Where <P: ?Sized> == Any Type (Sized or Unsized)
 // Where P: AsRef<Path> == Any type that implements the AsRef<Path> trait
 pub fn git_command<P: ?Sized>(args: &[&str], c: &P) -> Result<GitResult, DeliveryError> where P: AsRef<Path> {
+    let git = try!(git_binary());
+    git_command_with(&git, args, c)
+}
+
+/// Run the git at `path`, for `--git-path` and the `git_path` setting
+/// in cli.toml. Only the first call counts. It is kept in this process
+/// rather than set in our environment, which chef-client and every
+/// other command we start would inherit.
+pub fn use_git_binary(path: &str) {
+    let path = path.to_string();
+    GIT_PATH_INIT.call_once(move || unsafe {
+        chosen_git = mem::transmute(Box::new(path));
+    });
+}
+
+/// The path given to `use_git_binary`, if any.
+fn chosen_git_binary() -> Option<String> {
+    unsafe {
+        if chosen_git.is_null() { None } else { Some((*chosen_git).clone()) }
+    }
+}
+
+/// The git we run: the one given to `use_git_binary`, or else
+/// `DELIVERY_GIT` if it is set, or else `git` from the `PATH`.
+pub fn git_binary() -> Result<PathBuf, DeliveryError> {
+    git_binary_from(chosen_git_binary().or(env::var("DELIVERY_GIT").ok()))
+}
+
+/// The git binary for a configured `value`. A bare name is looked up
+/// on the `PATH` when we run it; a path has to exist, or it is
+/// `Kind::MissingExecutable`.
+fn git_binary_from(value: Option<String>) -> Result<PathBuf, DeliveryError> {
+    let value = match value {
+        Some(ref v) if !v.trim().is_empty() => v.trim().to_string(),
+        _ => return Ok(PathBuf::from("git"))
+    };
+    let path = PathBuf::from(&value);
+    if path.components().count() > 1 && !path.is_file() {
+        return Err(DeliveryError{
+            kind: Kind::MissingExecutable,
+            detail: Some(format!("git not found at {}; check --git-path, DELIVERY_GIT or git_path in cli.toml", value))
+        });
+    }
+    Ok(path)
+}
+
+/// Like `git_command`, for output we only pass on to the user, such as
+/// a diff: bytes that aren't UTF-8, from binary or Latin-1 files, are
+/// shown as U+FFFD rather than failing the command.
+pub fn git_command_lossy<P: ?Sized>(args: &[&str], c: &P) -> Result<GitResult, DeliveryError> where P: AsRef<Path> {
+    let git = try!(git_binary());
+    run_git(&git, args, c, true)
+}
+
+/// Like `git_command`, running the git at `git`.
+pub fn git_command_with<P: ?Sized>(git: &Path, args: &[&str],
+                                   c: &P) -> Result<GitResult, DeliveryError> where P: AsRef<Path> {
+    run_git(git, args, c, false)
+}
+
+fn run_git<P: ?Sized>(git: &Path, args: &[&str], c: &P,
+                      lossy: bool) -> Result<GitResult, DeliveryError> where P: AsRef<Path> {
     let cwd = c.as_ref();
     let spinner = Spinner::start();
-    let mut command = Command::new("git");
+    let mut command = Command::new(git);
     command.args(args);
     command.current_dir(cwd);
     debug!("Git command: {:?}", command);
     let output = match command.output() {
         Ok(o) => o,
-        Err(e) => { spinner.stop(); return Err(DeliveryError{ kind: Kind::FailedToExecute, detail: Some(format!("failed to execute git: {}", error::Error::description(&e)))}) },
+        Err(e) => { spinner.stop(); return Err(DeliveryError{ kind: Kind::FailedToExecute, detail: Some(format!("failed to execute {}: {}", git.display(), error::Error::description(&e)))}) },
     };
     debug!("Git exited: {}", output.status);
     spinner.stop();
+    say_git_output(args, &String::from_utf8_lossy(&output.stdout), &String::from_utf8_lossy(&output.stderr));
     if !output.status.success() {
         return Err(DeliveryError{ kind: Kind::GitFailed, detail: Some(format!("STDOUT: {}\nSTDERR: {}\n", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr)))});
     }
-    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let stdout = if lossy {
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    } else {
+        try!(decode_git_output(&output.stdout, args))
+    };
     debug!("Git stdout: {}", stdout);
     let stderr = String::from_utf8_lossy(&output.stderr).to_string();
     debug!("Git stderr: {}", stderr);
     Ok(GitResult{ stdout: stdout, stderr: stderr })
 }
 
+/// Walk up from `start` looking for the root of the git repository.
+///
+/// A `.git` file (rather than a directory) marks a submodule. When we
+/// start inside a submodule that lives in another repository, we can't
+/// tell which one the user meant, so we return a
+/// `Kind::MultipleReposFound` listing the candidates instead of
+/// guessing.
+pub fn find_repo_root(start: &PathBuf) -> Result<PathBuf, DeliveryError> {
+    let mut path = start.clone();
+    let mut candidates: Vec<PathBuf> = Vec::new();
+    loop {
+        if path.join(".git").exists() {
+            candidates.push(path.clone());
+        }
+        if !path.pop() { break; }
+    }
+    if candidates.is_empty() {
+        return Err(DeliveryError{ kind: Kind::NoConfig,
+                                  detail: Some(format!("No git repository found at or above {}", start.display())) });
+    }
+    let innermost = candidates[0].clone();
+    if candidates.len() > 1 && innermost.join(".git").is_file() {
+        let names: Vec<String> = candidates.iter()
+            .map(|c| format!("{}", c.display()))
+            .collect();
+        return Err(DeliveryError{ kind: Kind::MultipleReposFound,
+                                  detail: Some(format!("Candidates: {}; pass --repo with the one you mean", names.connect(", "))) });
+    }
+    Ok(innermost)
+}
+
+/// Make sure `path`, as given with `--repo`, is the root of a git
+/// repository.
+pub fn check_repo_root(path: &PathBuf) -> Result<(), DeliveryError> {
+    if path.join(".git").exists() {
+        Ok(())
+    } else {
+        Err(DeliveryError{ kind: Kind::NoConfig,
+                           detail: Some(format!("{} is not the root of a git repository", path.display())) })
+    }
+}
+
+/// The remote branch a review of `branch` targeted for the `target`
+/// pipeline gets pushed to.
+pub fn review_branch_name(branch: &str, target: &str) -> String {
+    format!("_for/{}/{}", target, branch)
+}
+
+/// Decode the stdout of a git command. We parse this output, so
+/// rather than quietly swapping bad bytes for U+FFFD and failing to
+/// match later, report it as `Kind::InvalidUtf8InGitOutput`.
+fn decode_git_output(bytes: &[u8], args: &[&str]) -> Result<String, DeliveryError> {
+    match String::from_utf8(bytes.to_vec()) {
+        Ok(s) => Ok(s),
+        Err(e) => {
+            let msg = format!("git {}: {}; output was: {}",
+                              args.connect(" "), e,
+                              String::from_utf8_lossy(bytes));
+            Err(DeliveryError{ kind: Kind::InvalidUtf8InGitOutput,
+                               detail: Some(msg) })
+        }
+    }
+}
+
+#[test]
+fn test_format_git_output() {
+    assert_eq!("git push delivery master\nSTDOUT: To delivery\n\nSTDERR: \n",
+               format_git_output(&["push", "delivery", "master"], "To delivery\n", ""));
+}
+
+#[test]
+fn test_parse_remote_project() {
+    let expect = Some(("acme".to_string(), "frob".to_string()));
+    assert_eq!(expect, parse_remote_project("ssh://adam@Chef@delivery.example.com:8989/Chef/acme/frob"));
+    assert_eq!(expect, parse_remote_project("ssh://adam@Chef@delivery.example.com:8989/Chef/acme/frob.git\n"));
+    assert_eq!(expect, parse_remote_project("https://delivery.example.com/Chef/acme/frob/"));
+    assert_eq!(expect, parse_remote_project("adam@Chef@delivery.example.com:Chef/acme/frob"));
+}
+
+#[test]
+fn test_parse_remote_project_unmatched() {
+    assert_eq!(None, parse_remote_project("/tmp/delivery/Chef/acme/frob"));
+    assert_eq!(None, parse_remote_project("https://delivery.example.com/acme/frob"));
+    assert_eq!(None, parse_remote_project("https://delivery.example.com"));
+}
+
+#[test]
+fn test_remote_host() {
+    let expect = Some("delivery.example.com".to_string());
+    assert_eq!(expect, remote_host("ssh://adam@Chef@delivery.example.com:8989/Chef/acme/frob"));
+    assert_eq!(expect, remote_host("https://delivery.example.com/Chef/acme/frob/"));
+    assert_eq!(expect, remote_host("adam@Chef@delivery.example.com:Chef/acme/frob"));
+    assert_eq!(None, remote_host("/tmp/delivery/Chef/acme/frob"));
+}
+
+#[test]
+fn test_decode_git_output() {
+    assert_eq!("* master\n", decode_git_output(b"* master\n", &["branch"]).unwrap());
+    let bad = b"?? caf\xe9.txt\n";
+    match decode_git_output(bad, &["status", "--porcelain"]) {
+        Err(e) => {
+            match e.kind {
+                Kind::InvalidUtf8InGitOutput => {},
+                _ => panic!("Expected InvalidUtf8InGitOutput, got {:?}", e)
+            }
+            assert!(e.detail.unwrap().starts_with("git status --porcelain"));
+        },
+        Ok(s) => panic!("Expected invalid UTF-8 to be reported, got {}", s)
+    }
+}
+
+/// Push `branch` for review against `target`. A `title` given on the
+/// command line goes to the server as a `title` push option; without
+/// one it takes the newest commit's subject, as `review_title` does.
+/// A server that doesn't take push options refuses the push before
+/// anything is sent, so then we warn and push again without it.
 pub fn git_push_review(branch: &str,
-                       target: &str) -> Result<ReviewResult, DeliveryError> {
-    let gitr = try!(git_command(&["push",
-                                  "--porcelain", "--progress",
-                                  "--verbose", "delivery",
-                                  &format!("{}:_for/{}/{}",
-                                           branch, target, branch)],
-                                &cwd()));
+                       target: &str,
+                       title: Option<&str>) -> Result<ReviewResult, DeliveryError> {
+    git_push_review_in(branch, target, title, &cwd())
+}
+
+fn git_push_review_in(branch: &str, target: &str, title: Option<&str>,
+                      path: &PathBuf) -> Result<ReviewResult, DeliveryError> {
+    let title_option = title.map(|t| format!("--push-option=title={}", t));
+    let refspec = format!("{}:{}", branch, review_branch_name(branch, target));
+    let mut args = vec!["push", "--porcelain", "--progress", "--verbose"];
+    match title_option {
+        Some(ref o) => args.push(&o[..]),
+        None => {}
+    }
+    args.push("delivery");
+    args.push(&refspec[..]);
+    let result = git_command(&args, path);
+    let gitr = match result {
+        Ok(r) => r,
+        Err(ref e) if title.is_some() && push_options_unsupported(e) => {
+            sayln("yellow", "The server doesn't take a review title; it will use the newest commit's subject");
+            return git_push_review_in(branch, target, None, path);
+        },
+        Err(e) => {
+            let quota = match e.detail {
+                Some(ref d) => review_quota_error(d),
+                None => None
+            };
+            return Err(quota.unwrap_or(e));
+        }
+    };
     parse_git_push_output(&gitr.stdout, &gitr.stderr)
 }
 
+/// Whether a failed push was turned away for its push options.
+fn push_options_unsupported(e: &DeliveryError) -> bool {
+    match e.detail {
+        Some(ref d) => d.contains("does not support push options"),
+        None => false
+    }
+}
+
+/// When the server turned a review away because we have too many open
+/// changes, it says so on a remote line, like
+///
+/// ```text
+/// remote: Review quota exceeded: 12 open changes, limit 10
+/// ```
+///
+/// That is `Kind::ReviewQuotaExceeded`, with the count and limit if
+/// the server gave them.
+pub fn review_quota_error(push_error: &str) -> Option<DeliveryError> {
+    let quota = regex!(r"(?i)quota exceeded");
+    let line = match push_error.lines_any().find(|l| quota.is_match(l)) {
+        Some(l) => l,
+        None => return None
+    };
+    let open = regex!(r"(\d+) open change").captures(line).and_then(|c| c.at(1));
+    let limit = regex!(r"(?i)limit\D*(\d+)").captures(line).and_then(|c| c.at(1));
+    let detail = match (open, limit) {
+        (Some(o), Some(l)) => format!("You have {} open changes and the limit is {}; merge or abandon some before submitting another review",
+                                      o, l),
+        _ => format!("Merge or abandon some of your open changes before submitting another review ({})",
+                     line.trim())
+    };
+    Some(DeliveryError{ kind: Kind::ReviewQuotaExceeded, detail: Some(detail) })
+}
+
 /// Output via `sayln` results of a git push.
 pub fn say_push_results(results: Vec<PushResult>) {
     for result in results.iter() {
@@ -240,6 +662,113 @@ pub fn delivery_ssh_url(user: &str, server: &str, ent: &str, org: &str, proj: &s
     format!("ssh://{}@{}@{}:8989/{}/{}/{}", user, ent, server, ent, org, proj)
 }
 
+/// The organization and project named by a delivery remote URL, which
+/// ends in `ent/org/project`. Both `ssh://` and `https://` URLs work,
+/// as does the scp-like `user@host:path` form; anything else (a local
+/// path, say) returns `None`.
+pub fn parse_remote_project(url: &str) -> Option<(String, String)> {
+    let url = url.trim();
+    let path = match url.find("://") {
+        Some(i) => {
+            let rest = &url[i + 3..];
+            match rest.find('/') {
+                Some(j) => &rest[j..],
+                None => return None
+            }
+        },
+        None => {
+            if url.starts_with("/") || !url.contains("@") {
+                return None;
+            }
+            match url.rfind(':') {
+                Some(i) => &url[i + 1..],
+                None => return None
+            }
+        }
+    };
+    let path = path.trim_right_matches('/');
+    let path = if path.ends_with(".git") { &path[..path.len() - 4] } else { path };
+    let parts: Vec<&str> = path.split('/').filter(|p| !p.is_empty()).collect();
+    if parts.len() < 3 {
+        return None;
+    }
+    let n = parts.len();
+    Some((parts[n - 2].to_string(), parts[n - 1].to_string()))
+}
+
+/// The host a remote URL points at, without any user or port. Like
+/// `parse_remote_project`, this takes `ssh://`, `https://` and
+/// `user@host:path` URLs, and returns `None` for anything else.
+pub fn remote_host(url: &str) -> Option<String> {
+    let url = url.trim();
+    let authority = match url.find("://") {
+        Some(i) => {
+            let rest = &url[i + 3..];
+            let authority = match rest.find('/') {
+                Some(j) => &rest[..j],
+                None => rest
+            };
+            match authority.rfind(':') {
+                Some(j) => &authority[..j],
+                None => authority
+            }
+        },
+        None => {
+            if url.starts_with("/") || !url.contains("@") {
+                return None;
+            }
+            match url.find(':') {
+                Some(i) => &url[..i],
+                None => return None
+            }
+        }
+    };
+    let host = match authority.rfind('@') {
+        Some(i) => &authority[i + 1..],
+        None => authority
+    };
+    if host.is_empty() {
+        None
+    } else {
+        Some(host.to_string())
+    }
+}
+
+/// Return `Kind::ServerNotAllowed` unless the `delivery` remote of
+/// the repository at `path` is on one of the `allowed` servers. A
+/// remote we can't find a host in is only allowed when every server
+/// is; a missing remote is left for the push to report.
+pub fn check_delivery_remote(path: &PathBuf, allowed: &Option<Vec<String>>) -> Result<(), DeliveryError> {
+    let url = match delivery_remote_url(path) {
+        Some(u) => u,
+        None => return Ok(())
+    };
+    let host = remote_host(&url).unwrap_or(url.clone());
+    match check_server_allowed(&host, allowed) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(DeliveryError{
+            kind: Kind::ServerNotAllowed,
+            detail: Some(format!("The git remote delivery is {}; {}", url, e.detail.unwrap_or(String::new())))
+        })
+    }
+}
+
+/// The url of the `delivery` remote of the repository at `path`, if
+/// it has one.
+pub fn delivery_remote_url(path: &PathBuf) -> Option<String> {
+    match git_command(&["config", "--get", "remote.delivery.url"], path) {
+        Ok(r) => Some(r.stdout.trim().to_string()),
+        Err(_) => None
+    }
+}
+
+/// The project name from the `delivery` remote of the repository at
+/// `path`, if it has one we can parse.
+pub fn project_from_remote(path: &PathBuf) -> Option<String> {
+    delivery_remote_url(path)
+        .and_then(|url| parse_remote_project(&url).map(|(_, proj)| proj))
+}
+
 pub fn init_repo(path: &PathBuf) -> Result<(), DeliveryError> {
     say("white", "Is ");
     say("magenta", &format!("{} ", path.display()));
@@ -294,6 +823,43 @@ pub fn config_repo(user: &str, server: &str, ent: &str, org: &str, proj: &str, p
     }
 }
 
+/// A change is named by its numeric id or by its branch, such as
+/// `adam/feature`: path segments of letters, numbers, '-', '_' and
+/// '.', none of them empty, starting with '-' or '.', or ending with
+/// `.lock`. Anything else is `Kind::InvalidChangeIdentifier`.
+pub fn validate_change(change: &str) -> Result<(), DeliveryError> {
+    let valid_segment = |seg: &str| {
+        !seg.is_empty() && !seg.starts_with("-") && !seg.starts_with(".") &&
+            !seg.ends_with(".lock") &&
+            seg.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
+    };
+    if !change.contains("..") && change.split('/').all(|seg| valid_segment(seg)) {
+        Ok(())
+    } else {
+        Err(DeliveryError{
+            kind: Kind::InvalidChangeIdentifier,
+            detail: Some(format!("'{}' is not a change id or branch name", change))
+        })
+    }
+}
+
+#[test]
+fn test_validate_change() {
+    for good in ["42", "feature", "adam/feature", "rust/test-1.2_b"].iter() {
+        assert!(validate_change(good).is_ok(), "expected '{}' to be valid", good);
+    }
+    for bad in ["", "adam//feature", "/feature", "feature/", "../etc", "a..b",
+                "-rf", "feature.lock", "has space", "semi;colon", "a~1"].iter() {
+        match validate_change(bad) {
+            Err(e) => match e.kind {
+                Kind::InvalidChangeIdentifier => assert!(e.detail.unwrap().contains(*bad)),
+                _ => panic!("Expected InvalidChangeIdentifier, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected '{}' to be rejected", bad)
+        }
+    }
+}
+
 pub fn checkout_branch_name(change: &str, patchset: &str) -> String {
     if patchset == "latest" {
         return String::from_str(change);
@@ -303,38 +869,40 @@ pub fn checkout_branch_name(change: &str, patchset: &str) -> String {
 }
 
 pub fn diff(change: &str, patchset: &str, pipeline: &str, local: &bool) -> Result<(), DeliveryError> {
-    try!(git_command(&["fetch", "delivery"], &cwd()));
+    try!(validate_change(change));
+    try!(git_command_lossy(&["fetch", "delivery"], &cwd()));
     let mut first_branch = format!("delivery/{}", pipeline);
     if *local {
         first_branch = String::from_str("HEAD");
     }
-    let diff = try!(git_command(&["diff", "--color=always", &first_branch, &format!("delivery/_reviews/{}/{}/{}", pipeline, change, patchset)], &cwd()));
+    let diff = try!(git_command_lossy(&["diff", "--color=always", &first_branch, &format!("delivery/_reviews/{}/{}/{}", pipeline, change, patchset)], &cwd()));
     say("white", "\n");
     sayln("white", &diff.stdout);
     Ok(())
 }
 
 pub fn clone(project: &str, git_url: &str) -> Result<(), DeliveryError> {
-    try!(git_command(&["clone", git_url, project], &cwd()));
+    try!(git_command_lossy(&["clone", git_url, project], &cwd()));
     Ok(())
 }
 
 pub fn checkout_review(change: &str, patchset: &str, pipeline: &str) -> Result<(), DeliveryError> {
-    try!(git_command(&["fetch", "delivery"], &cwd()));
+    try!(validate_change(change));
+    try!(git_command_lossy(&["fetch", "delivery"], &cwd()));
     let branchname = checkout_branch_name(change, patchset);
-    let result = git_command(&["branch", "--track", &branchname, &format!("delivery/_reviews/{}/{}/{}", pipeline, change, patchset)], &cwd());
+    let result = git_command_lossy(&["branch", "--track", &branchname, &format!("delivery/_reviews/{}/{}/{}", pipeline, change, patchset)], &cwd());
     match result {
         Ok(_) => {
-            try!(git_command(&["checkout", &branchname], &cwd()));
+            try!(git_command_lossy(&["checkout", &branchname], &cwd()));
             return Ok(())
         },
         Err(e) => {
             match e.detail {
                 Some(msg) => {
                     if msg.contains("already exists.") {
-                        try!(git_command(&["checkout", &branchname], &cwd()));
+                        try!(git_command_lossy(&["checkout", &branchname], &cwd()));
                         sayln("white", "Branch already exists, checking it out.");
-                        let r = try!(git_command(&["status"], &cwd()));
+                        let r = try!(git_command_lossy(&["status"], &cwd()));
                         sayln("white", &r.stdout);
                         return Ok(())
                     } else {
@@ -349,6 +917,46 @@ pub fn checkout_review(change: &str, patchset: &str, pipeline: &str) -> Result<(
     }
 }
 
+/// Whether `branch` exists on `remote`, according to `git ls-remote`.
+pub fn remote_branch_exists(remote: &str, branch: &str,
+                            path: &PathBuf) -> Result<bool, DeliveryError> {
+    let refname = format!("refs/heads/{}", branch);
+    let result = try!(git_command(&["ls-remote", remote, &refname], path));
+    Ok(result.stdout.lines_any().any(|l| l.ends_with(&refname[..])))
+}
+
+/// Make sure `branch` is on the delivery remote before we review it.
+/// With `auto_push` we push it ourselves, tracking it from then on;
+/// without, a missing branch is `Kind::GitDetachedFromRemote` if it
+/// tracks nothing at all, or else `Kind::ReviewBranchNotPushed`.
+pub fn ensure_branch_pushed(branch: &str, auto_push: bool,
+                            path: &PathBuf) -> Result<(), DeliveryError> {
+    if try!(remote_branch_exists("delivery", branch, path)) {
+        return Ok(());
+    }
+    if !auto_push {
+        match get_upstream(path) {
+            Err(DeliveryError{ kind: Kind::GitDetachedFromRemote, detail }) => {
+                return Err(DeliveryError{
+                    kind: Kind::GitDetachedFromRemote,
+                    detail: Some(format!("{}; push it with `git push -u delivery {}`, or set auto_push = true in .delivery/cli.toml",
+                                         detail.unwrap_or(String::new()), branch))
+                });
+            },
+            _ => {}
+        }
+        return Err(DeliveryError{
+            kind: Kind::ReviewBranchNotPushed,
+            detail: Some(format!("Push it with `git push delivery {0}`, or set auto_push = true in .delivery/cli.toml", branch))
+        });
+    }
+    say("white", "Pushing ");
+    say("yellow", branch);
+    sayln("white", " to delivery");
+    try!(git_command(&["push", "--set-upstream", "delivery", &format!("{0}:refs/heads/{0}", branch)], path));
+    Ok(())
+}
+
 pub fn server_content() -> bool {
     match git_command(&["ls-remote", "delivery", "refs/heads/master"], &cwd()) {
         Ok(msg) => {
@@ -391,3 +999,681 @@ pub fn git_push_master() -> Result<(), DeliveryError> {
         }
     }
 }
+
+/// How many commits `branch` has that `target` doesn't, and how many
+/// `target` has that `branch` doesn't.
+pub fn ahead_behind(branch: &str, target: &str,
+                    path: &PathBuf) -> Result<(u64, u64), DeliveryError> {
+    let range = format!("{}...{}", branch, target);
+    let result = try!(git_command(&["rev-list", "--left-right", "--count", &range], path));
+    parse_ahead_behind(&result.stdout)
+}
+
+/// Parse the `AHEAD\tBEHIND` printed by `git rev-list --left-right --count`.
+fn parse_ahead_behind(stdout: &str) -> Result<(u64, u64), DeliveryError> {
+    let counts: Vec<Option<u64>> = stdout.split(|c: char| c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<u64>().ok())
+        .collect();
+    if counts.len() == 2 {
+        match (counts[0], counts[1]) {
+            (Some(ahead), Some(behind)) => return Ok((ahead, behind)),
+            _ => {}
+        }
+    }
+    Err(DeliveryError{
+        kind: Kind::BadGitOutputMatch,
+        detail: Some(format!("Expected ahead and behind counts, got: {}", stdout.trim()))
+    })
+}
+
+/// The target branch as last seen on the delivery remote, if we have
+/// it.
+fn target_ref(target: &str, path: &PathBuf) -> Option<String> {
+    let candidate = format!("refs/remotes/delivery/{}", target);
+    match git_command(&["rev-parse", "--verify", "-q", &candidate], path) {
+        Ok(_) => Some(candidate),
+        Err(_) => None
+    }
+}
+
+/// Refuse to review `branch` when it is strictly behind `target`: the
+/// target has commits the branch lacks, and the branch adds nothing.
+/// That is `Kind::ReviewTargetAncestor`. If we haven't seen the
+/// target on the delivery remote there is nothing to compare, and the
+/// check passes.
+pub fn check_review_target(branch: &str, target: &str,
+                           path: &PathBuf) -> Result<(), DeliveryError> {
+    let tref = match target_ref(target, path) {
+        Some(t) => t,
+        None => return Ok(())
+    };
+    let (ahead, behind) = try!(ahead_behind(branch, &tref, path));
+    if ahead == 0 && behind > 0 {
+        return Err(DeliveryError{
+            kind: Kind::ReviewTargetAncestor,
+            detail: Some(format!("{} is {} commit(s) behind {} and has none of its own; rebase it with `git rebase delivery/{}`",
+                                 branch, behind, target, target))
+        });
+    }
+    Ok(())
+}
+
+/// The files `git status --porcelain` reports as changed, ignoring
+/// untracked ones, which can't surprise anyone by being left out.
+fn parse_dirty_files(stdout: &str) -> Vec<String> {
+    stdout.lines_any()
+        .filter(|l| l.len() > 3 && !l.starts_with("??"))
+        .map(|l| l[3..].to_string())
+        .collect()
+}
+
+/// Return `Kind::GitWorkingTreeDirty`, listing the files, if the
+/// working tree at `path` has uncommitted changes to tracked files.
+/// A review only pushes what is committed.
+pub fn check_clean_working_tree(path: &PathBuf) -> Result<(), DeliveryError> {
+    let result = try!(git_command(&["status", "--porcelain"], path));
+    let dirty = parse_dirty_files(&result.stdout);
+    if dirty.is_empty() {
+        Ok(())
+    } else {
+        Err(DeliveryError{
+            kind: Kind::GitWorkingTreeDirty,
+            detail: Some(format!("Commit or stash your changes, or pass --allow-dirty. Modified: {}",
+                                 dirty.connect(", ")))
+        })
+    }
+}
+
+/// The abbreviated commits on `branch` that aren't on `target`, newest
+/// first. We compare against the target as last seen on the delivery
+/// remote, falling back to the local branch of that name.
+pub fn review_commits(branch: &str, target: &str,
+                      path: &PathBuf) -> Result<Vec<String>, DeliveryError> {
+    let tref = target_ref(target, path).unwrap_or(target.to_string());
+    let range = format!("{}..{}", tref, branch);
+    let result = try!(git_command(&["rev-list", "--abbrev-commit", &range], path));
+    Ok(result.stdout.lines_any()
+       .filter(|l| !l.trim().is_empty())
+       .map(|l| l.trim().to_string())
+       .collect())
+}
+
+/// Return `Kind::UnsignedCommits`, listing them, if any commit on
+/// `branch` that isn't on `target` fails `git verify-commit`.
+pub fn check_signed_commits(branch: &str, target: &str,
+                            path: &PathBuf) -> Result<(), DeliveryError> {
+    let commits = try!(review_commits(branch, target, path));
+    let unsigned: Vec<String> = commits.into_iter()
+        .filter(|c| git_command(&["verify-commit", &c[..]], path).is_err())
+        .collect();
+    if unsigned.is_empty() {
+        Ok(())
+    } else {
+        Err(DeliveryError{
+            kind: Kind::UnsignedCommits,
+            detail: Some(format!("Sign them with `git commit -S --amend` or `git rebase -S`. Unsigned: {}",
+                                 unsigned.connect(", ")))
+        })
+    }
+}
+
+/// The title a review of `branch` gets: the subject of its newest
+/// commit, or None if that is empty.
+pub fn review_title(branch: &str, path: &PathBuf) -> Result<Option<String>, DeliveryError> {
+    let result = try!(git_command(&["log", "-1", "--format=%s", branch], path));
+    let subject = result.stdout.trim();
+    if subject.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(subject.to_string()))
+    }
+}
+
+/// The `given` title if there is one, or else the `derived` one. With
+/// neither, the review would have no title, which is
+/// `Kind::ReviewMissingTitle`.
+pub fn choose_review_title(given: &str, derived: Option<String>) -> Result<String, DeliveryError> {
+    if !given.trim().is_empty() {
+        return Ok(given.trim().to_string());
+    }
+    match derived {
+        Some(t) => Ok(t),
+        None => Err(DeliveryError{
+            kind: Kind::ReviewMissingTitle,
+            detail: Some("The newest commit has no subject; reword it with `git commit --amend`, or pass --title".to_string())
+        })
+    }
+}
+
+/// The description a review of `branch` goes up with: the body of its
+/// newest commit message, without the subject line.
+pub fn review_description(branch: &str, path: &PathBuf) -> Result<String, DeliveryError> {
+    let result = try!(git_command(&["log", "-1", "--format=%b", branch], path));
+    Ok(result.stdout.trim().to_string())
+}
+
+/// Return `Kind::ReviewCommentTooLong` if `description` has more than
+/// `max` characters, so we find out before the server turns it away.
+pub fn check_review_description(description: &str, max: usize) -> Result<(), DeliveryError> {
+    let len = description.chars().count();
+    if len <= max {
+        Ok(())
+    } else {
+        Err(DeliveryError{
+            kind: Kind::ReviewCommentTooLong,
+            detail: Some(format!("The description is {} characters and the limit is {}; shorten the commit message with `git commit --amend`",
+                                 len, max))
+        })
+    }
+}
+
+/// The value of `key` in the git config for `path`, or None when it
+/// isn't set (or is empty).
+fn git_config_value(key: &str, path: &PathBuf) -> Option<String> {
+    match git_command(&["config", "--get", key], path) {
+        Ok(r) if !r.stdout.trim().is_empty() => Some(r.stdout.trim().to_string()),
+        _ => None
+    }
+}
+
+/// The `user.name` and `user.email` git would commit with in `path`.
+pub fn git_user(path: &PathBuf) -> (Option<String>, Option<String>) {
+    (git_config_value("user.name", path), git_config_value("user.email", path))
+}
+
+/// Return `Kind::GitConfigMissingUser`, naming the settings that are
+/// missing, unless both a `name` and an `email` are set.
+pub fn check_git_user_values(name: &Option<String>, email: &Option<String>) -> Result<(), DeliveryError> {
+    let mut missing = Vec::new();
+    if name.is_none() {
+        missing.push("user.name");
+    }
+    if email.is_none() {
+        missing.push("user.email");
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(DeliveryError{
+        kind: Kind::GitConfigMissingUser,
+        detail: Some(format!("Set {} with `git config`, or pass --set-git-user to use your Delivery user",
+                             missing.connect(" and ")))
+    })
+}
+
+/// Return `Kind::GitConfigMissingUser` if git can't commit in `path`
+/// for want of an identity.
+pub fn check_git_user(path: &PathBuf) -> Result<(), DeliveryError> {
+    let (name, email) = git_user(path);
+    check_git_user_values(&name, &email)
+}
+
+/// Fill in whichever of `user.name` and `user.email` is missing in the
+/// repository's own git config: the name from the Delivery `user`, and
+/// the email from `email`, which the user has to have given us. With
+/// no `email` a missing one stays missing.
+pub fn set_git_user(user: &str, email: Option<&str>, path: &PathBuf) -> Result<(), DeliveryError> {
+    let (current_name, current_email) = git_user(path);
+    if current_name.is_none() {
+        try!(git_command(&["config", "user.name", user], path));
+    }
+    match (current_email, email) {
+        (None, Some(e)) => { try!(git_command(&["config", "user.email", e], path)); },
+        _ => {}
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_git_user_values() {
+    assert!(check_git_user_values(&Some("Alice".to_string()), &Some("alice@example.com".to_string())).is_ok());
+    for &(ref name, ref email, missing) in [(None, Some("alice@example.com".to_string()), "user.name"),
+                                            (Some("Alice".to_string()), None, "user.email"),
+                                            (None, None, "user.name and user.email")].iter() {
+        match check_git_user_values(name, email) {
+            Err(e) => match e.kind {
+                Kind::GitConfigMissingUser => {
+                    assert_eq!(format!("Set {} with `git config`, or pass --set-git-user to use your Delivery user", missing),
+                               e.detail.unwrap());
+                },
+                _ => panic!("Expected GitConfigMissingUser, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected {} to be missing", missing)
+        }
+    }
+}
+
+#[test]
+fn test_parse_dirty_files() {
+    let status = " M README.md\nA  new.rb\n?? scratch.txt\nR  old.rb -> moved.rb\n";
+    assert_eq!(vec!["README.md".to_string(), "new.rb".to_string(), "old.rb -> moved.rb".to_string()],
+               parse_dirty_files(status));
+    assert!(parse_dirty_files("").is_empty());
+}
+
+#[test]
+fn test_choose_review_title() {
+    assert_eq!("Add the frobnicator", choose_review_title("", Some("Add the frobnicator".to_string())).unwrap());
+    assert_eq!("Frobnicate better", choose_review_title(" Frobnicate better ", Some("wip".to_string())).unwrap());
+    assert_eq!("Frobnicate better", choose_review_title("Frobnicate better", None).unwrap());
+    match choose_review_title(" ", None) {
+        Err(e) => match e.kind {
+            Kind::ReviewMissingTitle => assert!(e.detail.unwrap().contains("--title")),
+            _ => panic!("Expected ReviewMissingTitle, got {:?}", e)
+        },
+        Ok(t) => panic!("Expected no title, got {}", t)
+    }
+}
+
+#[test]
+fn test_check_review_description_under_limit() {
+    assert!(check_review_description("Fix the frobnicator", 100).is_ok());
+    assert!(check_review_description("12345", 5).is_ok());
+    assert!(check_review_description("", 1).is_ok());
+}
+
+#[test]
+fn test_check_review_description_over_limit() {
+    match check_review_description("123456", 5) {
+        Err(e) => match e.kind {
+            Kind::ReviewCommentTooLong => assert!(e.detail.unwrap().starts_with("The description is 6 characters and the limit is 5")),
+            _ => panic!("Expected ReviewCommentTooLong, got {:?}", e)
+        },
+        Ok(_) => panic!("Expected a description over the limit to be refused")
+    }
+}
+
+#[test]
+fn test_review_quota_error() {
+    let stderr = "Pushing to ssh://alice@Chef@delivery:8989/Chef/acme/fun\nremote: Review quota exceeded: 12 open changes, limit 10\nerror: failed to push some refs\n";
+    let e = review_quota_error(stderr).unwrap();
+    match e.kind {
+        Kind::ReviewQuotaExceeded => {},
+        _ => panic!("Expected ReviewQuotaExceeded, got {:?}", e)
+    }
+    assert!(e.detail.unwrap().starts_with("You have 12 open changes and the limit is 10"));
+    let vague = review_quota_error("remote: Quota exceeded\n").unwrap();
+    assert!(vague.detail.unwrap().ends_with("(remote: Quota exceeded)"));
+    assert!(review_quota_error("remote: Permission denied\n").is_none());
+}
+
+#[test]
+fn test_git_binary_from() {
+    assert_eq!(PathBuf::from("git"), git_binary_from(None).unwrap());
+    assert_eq!(PathBuf::from("git"), git_binary_from(Some(" ".to_string())).unwrap());
+    assert_eq!(PathBuf::from("git2"), git_binary_from(Some("git2".to_string())).unwrap());
+    match git_binary_from(Some("/nonexistent/bin/git".to_string())) {
+        Err(e) => match e.kind {
+            Kind::MissingExecutable => assert!(e.detail.unwrap().contains("/nonexistent/bin/git")),
+            _ => panic!("Expected MissingExecutable, got {:?}", e)
+        },
+        Ok(p) => panic!("Expected a missing git to be reported, got {:?}", p)
+    }
+}
+
+#[test]
+fn test_parse_ahead_behind() {
+    assert_eq!((2, 0), parse_ahead_behind("2\t0\n").unwrap());
+    assert!(parse_ahead_behind("fatal: bad revision\n").is_err());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_repo_root, check_repo_root, review_branch_name, git_command,
+                remote_branch_exists, ensure_branch_pushed, get_upstream,
+                check_review_target, ahead_behind, check_clean_working_tree,
+                check_signed_commits, review_commits, git_binary_from,
+                git_command_with, review_title, set_git_user, git_user,
+                check_git_user, git_command_lossy, check_delivery_remote,
+                git_push_review_in, get_remote_head};
+    use errors::Kind;
+    use std::path::PathBuf;
+    use std::fs::File;
+    use std::process::Command;
+    use std::io::prelude::*;
+    use tempdir::TempDir;
+    use utils::{mkdir_recursive, chmod};
+    use utils::path_join_many::PathJoinMany;
+
+    #[test]
+    fn git_command_lossy_passes_latin1_through() {
+        let tempdir = TempDir::new("lossy").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        git_command(&["init"], &root).unwrap();
+        File::create(&root.join("menu.txt")).unwrap().write_all(b"caf\xe9\n").unwrap();
+        git_command(&["add", "menu.txt"], &root).unwrap();
+        git_command(&["-c", "user.name=Test", "-c", "user.email=test@example.com",
+                      "commit", "-m", "menu"], &root).unwrap();
+        assert!(git_command(&["show", "HEAD:menu.txt"], &root).is_err());
+        assert_eq!("caf\u{FFFD}\n", git_command_lossy(&["show", "HEAD:menu.txt"], &root).unwrap().stdout);
+    }
+
+    #[test]
+    fn find_repo_root_from_subdirectory() {
+        let tempdir = TempDir::new("repo-root").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        mkdir_recursive(&root.join_many(&[".git"])).unwrap();
+        mkdir_recursive(&root.join_many(&["cookbooks", "foo"])).unwrap();
+        let found = find_repo_root(&root.join_many(&["cookbooks", "foo"])).unwrap();
+        assert_eq!(root, found);
+    }
+
+    #[test]
+    fn find_repo_root_in_nested_submodule_is_ambiguous() {
+        let tempdir = TempDir::new("repo-root").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        let submodule = root.join_many(&["vendor", "sub"]);
+        mkdir_recursive(&root.join_many(&[".git"])).unwrap();
+        mkdir_recursive(&submodule.join_many(&["recipes"])).unwrap();
+        File::create(&submodule.join(".git")).unwrap();
+        let result = find_repo_root(&submodule.join_many(&["recipes"]));
+        match result {
+            Err(e) => {
+                match e.kind {
+                    Kind::MultipleReposFound => {},
+                    _ => panic!("Expected MultipleReposFound, got {:?}", e)
+                }
+                let detail = e.detail().unwrap();
+                assert!(detail.contains(submodule.to_str().unwrap()));
+                assert!(detail.contains(root.to_str().unwrap()));
+                assert!(detail.contains("--repo"));
+            },
+            Ok(p) => panic!("Expected an ambiguity error, got {:?}", p)
+        }
+    }
+
+    #[test]
+    fn check_repo_root_test() {
+        let tempdir = TempDir::new("repo-root").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        mkdir_recursive(&root.join_many(&["vendor", "sub"])).unwrap();
+        File::create(&root.join_many(&["vendor", "sub", ".git"])).unwrap();
+        assert!(check_repo_root(&root.join_many(&["vendor", "sub"])).is_ok());
+        match check_repo_root(&root.join("vendor")) {
+            Err(e) => match e.kind {
+                Kind::NoConfig => {},
+                _ => panic!("Expected NoConfig, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected vendor not to be a repository")
+        }
+    }
+
+    #[test]
+    fn review_branch_name_test() {
+        assert_eq!("_for/master/adam/feature",
+                   review_branch_name("adam/feature", "master"));
+        assert_eq!("_for/acceptance/fix",
+                   review_branch_name("fix", "acceptance"));
+    }
+
+    /// A local repository on branch `feature`, with a bare `delivery`
+    /// remote that only has master.
+    fn setup_remote(root: &PathBuf) -> PathBuf {
+        let remote = root.join("remote.git");
+        let local = root.join("local");
+        mkdir_recursive(&local).unwrap();
+        git_command(&["init", "--bare", remote.to_str().unwrap()], root).unwrap();
+        git_command(&["init"], &local).unwrap();
+        git_command(&["symbolic-ref", "HEAD", "refs/heads/master"], &local).unwrap();
+        File::create(&local.join("README.md")).unwrap();
+        git_command(&["add", "README.md"], &local).unwrap();
+        git_command(&["-c", "user.name=Test", "-c", "user.email=test@example.com",
+                      "commit", "-m", "first"], &local).unwrap();
+        git_command(&["remote", "add", "delivery", remote.to_str().unwrap()], &local).unwrap();
+        git_command(&["push", "delivery", "master"], &local).unwrap();
+        git_command(&["checkout", "-b", "feature"], &local).unwrap();
+        local
+    }
+
+    #[test]
+    fn ensure_branch_pushed_with_pushed_branch() {
+        let tempdir = TempDir::new("remote-branch").ok().expect("TempDir failed");
+        let local = setup_remote(&tempdir.path().to_path_buf());
+        git_command(&["push", "delivery", "feature"], &local).unwrap();
+        assert!(ensure_branch_pushed("feature", false, &local).is_ok());
+    }
+
+    #[test]
+    fn get_remote_head_follows_remote_default_branch() {
+        let tempdir = TempDir::new("remote-head").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        let local = setup_remote(&root);
+        git_command(&["push", "delivery", "feature:trunk"], &local).unwrap();
+        git_command(&["symbolic-ref", "HEAD", "refs/heads/trunk"], &root.join("remote.git")).unwrap();
+        assert_eq!("trunk", get_remote_head("delivery", &local).unwrap());
+    }
+
+    #[test]
+    fn git_push_review_without_push_options() {
+        let tempdir = TempDir::new("push-options").ok().expect("TempDir failed");
+        let local = setup_remote(&tempdir.path().to_path_buf());
+        // The bare remote doesn't advertise push options
+        let review = git_push_review_in("feature", "master", Some("A title"), &local);
+        assert!(review.is_ok());
+        assert!(git_command(&["ls-remote", "--exit-code", "delivery", "_for/master/feature"], &local).is_ok());
+    }
+
+    #[test]
+    fn check_delivery_remote_against_allowed_servers() {
+        let tempdir = TempDir::new("remote-host").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        git_command(&["init"], &root).unwrap();
+        let allowed = Some(vec!["delivery.example.com".to_string()]);
+        // No remote yet is left to the push
+        assert!(check_delivery_remote(&root, &allowed).is_ok());
+        git_command(&["remote", "add", "delivery",
+                      "ssh://adam@Chef@Delivery.example.com:8989/Chef/acme/frob"], &root).unwrap();
+        assert!(check_delivery_remote(&root, &allowed).is_ok());
+        git_command(&["remote", "set-url", "delivery",
+                      "ssh://adam@Chef@evil.example.com:8989/Chef/acme/frob"], &root).unwrap();
+        match check_delivery_remote(&root, &allowed) {
+            Err(e) => match e.kind {
+                Kind::ServerNotAllowed => assert!(e.detail.unwrap().contains("evil.example.com")),
+                _ => panic!("Expected ServerNotAllowed, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected the remote to be refused")
+        }
+        assert!(check_delivery_remote(&root, &None).is_ok());
+    }
+
+    #[test]
+    fn ensure_branch_pushed_with_unpushed_branch() {
+        let tempdir = TempDir::new("remote-branch").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        let local = setup_remote(&root);
+        // Tracking a branch somewhere else isn't enough
+        let origin = root.join("origin.git");
+        git_command(&["init", "--bare", origin.to_str().unwrap()], &root).unwrap();
+        git_command(&["remote", "add", "origin", origin.to_str().unwrap()], &local).unwrap();
+        git_command(&["push", "-u", "origin", "feature"], &local).unwrap();
+        match ensure_branch_pushed("feature", false, &local) {
+            Err(e) => match e.kind {
+                Kind::ReviewBranchNotPushed => assert!(e.detail.unwrap().contains("git push delivery feature")),
+                _ => panic!("Expected ReviewBranchNotPushed, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected the unpushed branch to be reported")
+        }
+        ensure_branch_pushed("feature", true, &local).unwrap();
+        assert!(remote_branch_exists("delivery", "feature", &local).unwrap());
+        assert_eq!("delivery/feature", get_upstream(&local).unwrap());
+    }
+
+    #[test]
+    fn ensure_branch_pushed_without_upstream() {
+        let tempdir = TempDir::new("remote-branch").ok().expect("TempDir failed");
+        let local = setup_remote(&tempdir.path().to_path_buf());
+        match ensure_branch_pushed("feature", false, &local) {
+            Err(e) => match e.kind {
+                Kind::GitDetachedFromRemote => assert!(e.detail.unwrap().contains("git push -u delivery feature")),
+                _ => panic!("Expected GitDetachedFromRemote, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected the untracked branch to be reported")
+        }
+    }
+
+    fn commit(local: &PathBuf, file: &str) {
+        File::create(&local.join(file)).unwrap();
+        git_command(&["add", file], local).unwrap();
+        git_command(&["-c", "user.name=Test", "-c", "user.email=test@example.com",
+                      "commit", "-m", file], local).unwrap();
+    }
+
+    #[test]
+    fn review_title_from_newest_commit() {
+        let tempdir = TempDir::new("review-title").ok().expect("TempDir failed");
+        let local = setup_remote(&tempdir.path().to_path_buf());
+        commit(&local, "frobnicator.rb");
+        assert_eq!(Some("frobnicator.rb".to_string()), review_title("feature", &local).unwrap());
+    }
+
+    #[test]
+    fn review_title_with_empty_subject() {
+        let tempdir = TempDir::new("review-title").ok().expect("TempDir failed");
+        let local = setup_remote(&tempdir.path().to_path_buf());
+        File::create(&local.join("quiet.rb")).unwrap();
+        git_command(&["add", "quiet.rb"], &local).unwrap();
+        git_command(&["-c", "user.name=Test", "-c", "user.email=test@example.com",
+                      "commit", "--allow-empty-message", "-m", ""], &local).unwrap();
+        assert_eq!(None, review_title("feature", &local).unwrap());
+    }
+
+    #[test]
+    fn check_review_target_branch_behind_target() {
+        let tempdir = TempDir::new("review-target").ok().expect("TempDir failed");
+        let local = setup_remote(&tempdir.path().to_path_buf());
+        git_command(&["checkout", "master"], &local).unwrap();
+        commit(&local, "second");
+        git_command(&["push", "delivery", "master"], &local).unwrap();
+        git_command(&["checkout", "feature"], &local).unwrap();
+        assert_eq!((0, 1), ahead_behind("feature", "master", &local).unwrap());
+        match check_review_target("feature", "master", &local) {
+            Err(e) => match e.kind {
+                Kind::ReviewTargetAncestor => assert!(e.detail.unwrap().contains("rebase")),
+                _ => panic!("Expected ReviewTargetAncestor, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected a branch behind master to be refused")
+        }
+    }
+
+    #[test]
+    fn check_review_target_branch_ahead_of_target() {
+        let tempdir = TempDir::new("review-target").ok().expect("TempDir failed");
+        let local = setup_remote(&tempdir.path().to_path_buf());
+        commit(&local, "feature.txt");
+        assert_eq!((1, 0), ahead_behind("feature", "master", &local).unwrap());
+        assert!(check_review_target("feature", "master", &local).is_ok());
+    }
+
+    #[test]
+    fn check_clean_working_tree_clean() {
+        let tempdir = TempDir::new("working-tree").ok().expect("TempDir failed");
+        let local = setup_remote(&tempdir.path().to_path_buf());
+        File::create(&local.join("untracked.txt")).unwrap();
+        assert!(check_clean_working_tree(&local).is_ok());
+    }
+
+    #[test]
+    fn check_clean_working_tree_dirty() {
+        let tempdir = TempDir::new("working-tree").ok().expect("TempDir failed");
+        let local = setup_remote(&tempdir.path().to_path_buf());
+        File::create(&local.join("README.md")).unwrap().write_all(b"changed").unwrap();
+        match check_clean_working_tree(&local) {
+            Err(e) => match e.kind {
+                Kind::GitWorkingTreeDirty => assert!(e.detail.unwrap().ends_with("Modified: README.md")),
+                _ => panic!("Expected GitWorkingTreeDirty, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected the dirty working tree to be reported")
+        }
+    }
+
+    /// A throwaway GPG key in `root`, with the repository at `local`
+    /// told to sign and verify with it. `None` if gpg isn't installed.
+    fn gpg_signing(root: &PathBuf, local: &PathBuf) -> Option<()> {
+        let home = root.join("gnupg");
+        mkdir_recursive(&home).unwrap();
+        chmod(&home, "0700").unwrap();
+        let generated = Command::new("gpg")
+            .args(&["--homedir", home.to_str().unwrap(), "--batch", "--passphrase", "",
+                    "--quick-gen-key", "Test <test@example.com>"])
+            .output();
+        match generated {
+            Ok(ref o) if o.status.success() => {},
+            _ => return None
+        }
+        let wrapper = root.join("gpg.sh");
+        File::create(&wrapper).unwrap()
+            .write_all(format!("#!/bin/sh\nexec gpg --homedir {} \"$@\"\n",
+                               home.to_str().unwrap()).as_bytes()).unwrap();
+        chmod(&wrapper, "0755").unwrap();
+        git_command(&["config", "gpg.program", wrapper.to_str().unwrap()], local).unwrap();
+        git_command(&["config", "user.signingkey", "test@example.com"], local).unwrap();
+        Some(())
+    }
+
+    #[test]
+    fn check_signed_commits_with_unsigned_commit() {
+        let tempdir = TempDir::new("signed-commits").ok().expect("TempDir failed");
+        let local = setup_remote(&tempdir.path().to_path_buf());
+        commit(&local, "feature.txt");
+        let commits = review_commits("feature", "master", &local).unwrap();
+        assert_eq!(1, commits.len());
+        match check_signed_commits("feature", "master", &local) {
+            Err(e) => match e.kind {
+                Kind::UnsignedCommits => assert!(e.detail.unwrap().ends_with(&format!("Unsigned: {}", commits[0]))),
+                _ => panic!("Expected UnsignedCommits, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected the unsigned commit to be reported")
+        }
+    }
+
+    #[test]
+    fn check_signed_commits_with_signed_commit() {
+        let tempdir = TempDir::new("signed-commits").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        let local = setup_remote(&root);
+        if gpg_signing(&root, &local).is_none() {
+            return;
+        }
+        File::create(&local.join("feature.txt")).unwrap();
+        git_command(&["add", "feature.txt"], &local).unwrap();
+        git_command(&["-c", "user.name=Test", "-c", "user.email=test@example.com",
+                      "commit", "-S", "-m", "signed"], &local).unwrap();
+        assert!(check_signed_commits("feature", "master", &local).is_ok());
+        commit(&local, "unsigned.txt");
+        assert!(check_signed_commits("feature", "master", &local).is_err());
+    }
+
+    #[test]
+    fn git_command_runs_configured_binary() {
+        let tempdir = TempDir::new("git-path").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        let fake = root.join("fake-git");
+        File::create(&fake).unwrap()
+            .write_all(b"#!/bin/sh\necho \"fake-git $@\"\n").unwrap();
+        chmod(&fake, "0755").unwrap();
+        let git = git_binary_from(Some(fake.to_str().unwrap().to_string())).unwrap();
+        assert_eq!(fake, git);
+        let result = git_command_with(&git, &["status", "--porcelain"], &root).unwrap();
+        assert_eq!("fake-git status --porcelain\n", result.stdout);
+    }
+
+    #[test]
+    fn set_git_user_writes_repo_config() {
+        let tempdir = TempDir::new("git-user").ok().expect("TempDir failed");
+        let local = setup_remote(&tempdir.path().to_path_buf());
+        git_command(&["config", "user.name", "Alice"], &local).unwrap();
+        // An empty email here hides any from the global config
+        git_command(&["config", "user.email", ""], &local).unwrap();
+        assert!(check_git_user(&local).is_err());
+        set_git_user("alice", None, &local).unwrap();
+        assert!(check_git_user(&local).is_err());
+        set_git_user("alice", Some("alice@delivery.example.com"), &local).unwrap();
+        let (name, email) = git_user(&local);
+        assert_eq!(Some("Alice".to_string()), name);
+        assert_eq!(Some("alice@delivery.example.com".to_string()), email);
+        let local_email = git_command(&["config", "--local", "--get", "user.email"], &local).unwrap();
+        assert_eq!("alice@delivery.example.com", local_email.stdout.trim());
+        assert!(check_git_user(&local).is_ok());
+    }
+}
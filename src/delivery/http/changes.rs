@@ -0,0 +1,128 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! List the changes on a project.
+//!
+//! Every page of changes is fetched; see `http::pages`. A `since`
+//! date is sent to the server as a query parameter. Older
+//! servers ignore it, so we also drop any change whose `submit_at`
+//! is before that date on our side.
+
+use errors::{DeliveryError, Kind};
+use http::APIClient;
+use rustc_serialize::json::Json;
+use time;
+
+/// The fields of a change shown by `delivery changes`.
+pub const CHANGE_COLUMNS: [&'static str; 6] = ["id", "title", "target", "state", "submitter", "submit_at"];
+
+/// Check that `since` is a `YYYY-MM-DD` date, returning it
+/// normalized.
+pub fn parse_since(since: &str) -> Result<String, DeliveryError> {
+    let invalid = DeliveryError{ kind: Kind::InvalidDate,
+                                 detail: Some(format!("Invalid --since date: '{}'", since)) };
+    let tm = match time::strptime(since.trim(), "%Y-%m-%d") {
+        Ok(tm) => tm,
+        Err(_) => return Err(invalid)
+    };
+    match time::strftime("%Y-%m-%d", &tm) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(invalid)
+    }
+}
+
+/// The API path listing changes on `proj`, limited to those since
+/// `since` when it isn't empty.
+pub fn changes_path(org: &str, proj: &str, since: &str) -> Result<String, DeliveryError> {
+    let path = format!("orgs/{}/projects/{}/changes", org, proj);
+    if since.is_empty() {
+        Ok(path)
+    } else {
+        let date = try!(parse_since(since));
+        Ok(format!("{}?since={}", path, date))
+    }
+}
+
+/// Keep the changes submitted on or after `since`. Changes without a
+/// `submit_at` timestamp are kept, since we can't tell.
+pub fn filter_since(changes: Vec<Json>, since: &str) -> Vec<Json> {
+    changes.into_iter().filter(|c| {
+        match c.find("submit_at").and_then(|s| s.as_string()) {
+            // ISO 8601 timestamps sort as strings
+            Some(submitted) => submitted >= since,
+            None => true
+        }
+    }).collect()
+}
+
+impl APIClient {
+    pub fn list_changes(&self, org: &str, proj: &str,
+                        since: &str) -> Result<Vec<Json>, DeliveryError> {
+        let path = try!(changes_path(org, proj, since));
+        let changes = try!(self.get_all(&path));
+        if since.is_empty() {
+            Ok(changes)
+        } else {
+            let date = try!(parse_since(since));
+            Ok(filter_since(changes, &date))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Kind;
+    use rustc_serialize::json::Json;
+
+    #[test]
+    fn parse_since_test() {
+        assert_eq!("2015-04-01", parse_since("2015-04-01").unwrap());
+        for bad in &["yesterday", "04/01/2015", ""] {
+            match parse_since(bad) {
+                Err(e) => match e.kind {
+                    Kind::InvalidDate => {},
+                    _ => panic!("Expected InvalidDate, got {:?}", e)
+                },
+                Ok(d) => panic!("Expected '{}' to be rejected, got {}", bad, d)
+            }
+        }
+    }
+
+    #[test]
+    fn changes_path_test() {
+        assert_eq!("orgs/acme/projects/fun/changes",
+                   changes_path("acme", "fun", "").unwrap());
+        assert_eq!("orgs/acme/projects/fun/changes?since=2015-04-01",
+                   changes_path("acme", "fun", "2015-04-01").unwrap());
+        assert!(changes_path("acme", "fun", "whenever").is_err());
+    }
+
+    #[test]
+    fn filter_since_test() {
+        let changes = vec![
+            Json::from_str(r#"{"id":"old","submit_at":"2015-03-30 10:00:00"}"#).unwrap(),
+            Json::from_str(r#"{"id":"new","submit_at":"2015-04-02 10:00:00"}"#).unwrap(),
+            Json::from_str(r#"{"id":"unknown"}"#).unwrap()
+        ];
+        let ids: Vec<String> = filter_since(changes, "2015-04-01").iter()
+            .map(|c| c.find("id").unwrap().as_string().unwrap().to_string())
+            .collect();
+        assert_eq!(vec!["new".to_string(), "unknown".to_string()], ids);
+    }
+}
@@ -0,0 +1,94 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Pull required fields out of a decoded API response.
+//!
+//! Older and newer servers don't always send the same fields. Rather
+//! than let a missing one turn into an opaque decode error, we check
+//! for them up front and name every field that isn't there.
+
+use errors::{DeliveryError, Kind};
+use rustc_serialize::json::Json;
+
+/// The values of `names` in the object `json`, in the same order. If
+/// any are missing, or `json` isn't an object at all, that is
+/// `Kind::ApiResponseMissingField` naming each of them.
+pub fn require<'a>(json: &'a Json, names: &[&str]) -> Result<Vec<&'a Json>, DeliveryError> {
+    let mut found = Vec::new();
+    let mut missing = Vec::new();
+    for name in names.iter() {
+        match json.find(name) {
+            Some(v) => found.push(v),
+            None => missing.push(*name)
+        }
+    }
+    if missing.is_empty() {
+        Ok(found)
+    } else {
+        Err(DeliveryError{
+            kind: Kind::ApiResponseMissingField,
+            detail: Some(format!("Missing from the server's response: {}",
+                                 missing.connect(", ")))
+        })
+    }
+}
+
+/// The string value of the required field `name`. A value that isn't
+/// a string is as good as missing.
+pub fn require_string(json: &Json, name: &str) -> Result<String, DeliveryError> {
+    let values = try!(require(json, &[name]));
+    match values[0].as_string() {
+        Some(s) => Ok(s.to_string()),
+        None => Err(DeliveryError{
+            kind: Kind::ApiResponseMissingField,
+            detail: Some(format!("Expected '{}' in the server's response to be a string", name))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Kind;
+    use rustc_serialize::json::Json;
+
+    #[test]
+    fn require_present_fields() {
+        let json = Json::from_str(r#"{"id":"abc","title":"Fix it","state":"open"}"#).unwrap();
+        let values = require(&json, &["title", "id"]).unwrap();
+        assert_eq!(Some("Fix it"), values[0].as_string());
+        assert_eq!(Some("abc"), values[1].as_string());
+        assert_eq!("open", require_string(&json, "state").unwrap());
+    }
+
+    #[test]
+    fn require_missing_fields() {
+        let json = Json::from_str(r#"{"id":"abc"}"#).unwrap();
+        match require(&json, &["id", "title", "submitter"]) {
+            Err(e) => match e.kind {
+                Kind::ApiResponseMissingField => assert!(e.detail.unwrap().ends_with("title, submitter")),
+                _ => panic!("Expected ApiResponseMissingField, got {:?}", e)
+            },
+            Ok(v) => panic!("Expected missing fields, got {:?}", v)
+        }
+        let not_an_object = Json::from_str("[1, 2]").unwrap();
+        assert!(require(&not_an_object, &["id"]).is_err());
+        let wrong_type = Json::from_str(r#"{"id":42}"#).unwrap();
+        assert!(require_string(&wrong_type, "id").is_err());
+    }
+}
@@ -0,0 +1,330 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Count the API requests we make, for `--metrics-file`.
+//!
+//! Every `APIClient` request is recorded in one `Metrics` for the
+//! process: how many were made by method and status, the kinds of
+//! error the failed ones ended in, and how long they took. On exit
+//! `write` puts them in the metrics file, as JSON or in the Prometheus
+//! text exposition format, for whatever wraps the CLI to collect.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Mutex, Once, ONCE_INIT};
+use hyper::client::response::Response as HyperResponse;
+use rustc_serialize::json::Json;
+use errors::{DeliveryError, Kind};
+
+pub const METRICS_FORMATS: [&'static str; 2] = ["json", "prometheus"];
+
+/// The upper bounds, in seconds, of the request latency histogram.
+pub const LATENCY_BUCKETS: [f64; 8] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricsFormat {
+    Json,
+    Prometheus
+}
+
+/// The format for `--metrics-format=<name>`; empty means JSON.
+pub fn format(name: &str) -> Result<MetricsFormat, DeliveryError> {
+    match name {
+        "" | "json" => Ok(MetricsFormat::Json),
+        "prometheus" => Ok(MetricsFormat::Prometheus),
+        _ => Err(DeliveryError{
+            kind: Kind::UnsupportedOutputFormat,
+            detail: Some(format!("Unknown metrics format '{}'; use one of: {}",
+                                 name, METRICS_FORMATS.connect(", ")))
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metrics {
+    requests: BTreeMap<(String, String), u64>,
+    errors: BTreeMap<String, u64>,
+    // Not cumulative; the last is for anything over the largest bound
+    buckets: Vec<u64>,
+    latency_sum: f64
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            requests: BTreeMap::new(),
+            errors: BTreeMap::new(),
+            buckets: vec![0; LATENCY_BUCKETS.len() + 1],
+            latency_sum: 0.0
+        }
+    }
+
+    /// Count a request that took `secs`. `status` is the HTTP status
+    /// it got, or None if it failed without one.
+    pub fn record_request(&mut self, method: &str, status: Option<u16>, secs: f64) {
+        let status = match status {
+            Some(s) => s.to_string(),
+            None => "error".to_string()
+        };
+        increment(&mut self.requests, (method.to_string(), status));
+        let bucket = LATENCY_BUCKETS.iter().position(|b| secs <= *b)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.latency_sum += secs;
+    }
+
+    /// Count a request that failed with `kind`.
+    pub fn record_error(&mut self, kind: &Kind) {
+        increment(&mut self.errors, kind.name());
+    }
+
+    pub fn request_count(&self) -> u64 {
+        self.buckets.iter().fold(0, |sum, n| sum + n)
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP delivery_requests_total Requests made to the Delivery API.\n");
+        out.push_str("# TYPE delivery_requests_total counter\n");
+        for (&(ref method, ref status), count) in self.requests.iter() {
+            out.push_str(&format!("delivery_requests_total{{method=\"{}\",status=\"{}\"}} {}\n",
+                                  escape_label(method), escape_label(status), count));
+        }
+        out.push_str("# HELP delivery_request_errors_total Requests that failed, by kind of error.\n");
+        out.push_str("# TYPE delivery_request_errors_total counter\n");
+        for (kind, count) in self.errors.iter() {
+            out.push_str(&format!("delivery_request_errors_total{{kind=\"{}\"}} {}\n",
+                                  escape_label(kind), count));
+        }
+        out.push_str("# HELP delivery_request_duration_seconds How long requests took.\n");
+        out.push_str("# TYPE delivery_request_duration_seconds histogram\n");
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += *count;
+            out.push_str(&format!("delivery_request_duration_seconds_bucket{{le=\"{}\"}} {}\n",
+                                  bound, cumulative));
+        }
+        out.push_str(&format!("delivery_request_duration_seconds_bucket{{le=\"+Inf\"}} {}\n",
+                              self.request_count()));
+        out.push_str(&format!("delivery_request_duration_seconds_sum {}\n", self.latency_sum));
+        out.push_str(&format!("delivery_request_duration_seconds_count {}\n", self.request_count()));
+        out
+    }
+
+    /// The metrics as a JSON object; bucket counts are cumulative, as
+    /// in the Prometheus format.
+    pub fn to_json(&self) -> Json {
+        let requests = self.requests.iter().map(|(&(ref method, ref status), count)| {
+            let mut r = BTreeMap::new();
+            r.insert("method".to_string(), Json::String(method.clone()));
+            r.insert("status".to_string(), Json::String(status.clone()));
+            r.insert("count".to_string(), Json::U64(*count));
+            Json::Object(r)
+        }).collect();
+        let errors = self.errors.iter()
+            .map(|(k, n)| (k.clone(), Json::U64(*n)))
+            .collect();
+        let mut buckets = Vec::new();
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += *count;
+            let mut b = BTreeMap::new();
+            b.insert("le".to_string(), Json::F64(*bound));
+            b.insert("count".to_string(), Json::U64(cumulative));
+            buckets.push(Json::Object(b));
+        }
+        let mut latency = BTreeMap::new();
+        latency.insert("buckets".to_string(), Json::Array(buckets));
+        latency.insert("sum".to_string(), Json::F64(self.latency_sum));
+        latency.insert("count".to_string(), Json::U64(self.request_count()));
+        let mut top = BTreeMap::new();
+        top.insert("requests".to_string(), Json::Array(requests));
+        top.insert("errors".to_string(), Json::Object(errors));
+        top.insert("latency_seconds".to_string(), Json::Object(latency));
+        Json::Object(top)
+    }
+
+    pub fn render(&self, format: MetricsFormat) -> String {
+        match format {
+            MetricsFormat::Json => format!("{}\n", self.to_json().pretty()),
+            MetricsFormat::Prometheus => self.to_prometheus()
+        }
+    }
+}
+
+fn increment<K: Ord>(counts: &mut BTreeMap<K, u64>, key: K) {
+    let current = counts.get(&key).map(|n| *n).unwrap_or(0);
+    counts.insert(key, current + 1);
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
+
+static INIT: Once = ONCE_INIT;
+static mut GLOBAL: *const Mutex<Metrics> = 0 as *const Mutex<Metrics>;
+
+/// The metrics for this process.
+fn global() -> &'static Mutex<Metrics> {
+    unsafe {
+        INIT.call_once(|| {
+            GLOBAL = mem::transmute(Box::new(Mutex::new(Metrics::new())));
+        });
+        &*GLOBAL
+    }
+}
+
+/// Record the outcome of a request that took `secs` in the metrics
+/// for this process.
+pub fn observe(method: &str, result: &Result<HyperResponse, DeliveryError>, secs: f64) {
+    let mut metrics = match global().lock() {
+        Ok(m) => m,
+        Err(_) => return
+    };
+    match *result {
+        Ok(ref res) => metrics.record_request(method, Some(res.status.to_u16()), secs),
+        Err(ref e) => {
+            metrics.record_request(method, None, secs);
+            metrics.record_error(&e.kind);
+        }
+    }
+}
+
+/// Write the metrics for this process to `path` in `format`.
+pub fn write(path: &PathBuf, format: MetricsFormat) -> Result<(), DeliveryError> {
+    let rendered = match global().lock() {
+        Ok(m) => m.render(format),
+        Err(_) => Metrics::new().render(format)
+    };
+    let mut f = try!(File::create(path));
+    try!(f.write_all(rendered.as_bytes()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Kind;
+    use hyper::status::StatusCode;
+    use regex::Regex;
+    use std::collections::BTreeMap;
+
+    /// Check `text` is exposition format as Prometheus reads it: every
+    /// sample belongs to a family declared by a `# TYPE` line before
+    /// it, has well-formed labels and a number for a value. Returns
+    /// the samples by name and labels.
+    fn parse_exposition(text: &str) -> BTreeMap<String, f64> {
+        let comment = Regex::new(r"^# (HELP|TYPE) ([a-zA-Z_:][a-zA-Z0-9_:]*) (.+)$").unwrap();
+        let sample = Regex::new(r#"^([a-zA-Z_:][a-zA-Z0-9_:]*)(\{([a-zA-Z_][a-zA-Z0-9_]*="([^"\\]|\\.)*",?)*\})? (\S+)$"#).unwrap();
+        let mut families: BTreeMap<String, String> = BTreeMap::new();
+        let mut samples = BTreeMap::new();
+        assert!(text.ends_with("\n"));
+        for line in text.lines() {
+            if line.starts_with("#") {
+                let caps = comment.captures(line).expect(&format!("bad comment line: {}", line));
+                if caps.at(1) == Some("TYPE") {
+                    let kind = caps.at(3).unwrap();
+                    assert!(["counter", "gauge", "histogram", "summary", "untyped"].contains(&kind),
+                            "bad type: {}", line);
+                    families.insert(caps.at(2).unwrap().to_string(), kind.to_string());
+                }
+                continue;
+            }
+            let caps = sample.captures(line).expect(&format!("bad sample line: {}", line));
+            let name = caps.at(1).unwrap();
+            let family = ["_bucket", "_sum", "_count"].iter()
+                .filter(|s| name.ends_with(*s))
+                .map(|s| &name[..name.len() - s.len()])
+                .find(|f| families.get(*f).map(|k| &k[..]) == Some("histogram"))
+                .unwrap_or(name);
+            assert!(families.contains_key(family), "no TYPE before {}", line);
+            let value = caps.at(5).unwrap();
+            let parsed = match value {
+                "+Inf" => ::std::f64::INFINITY,
+                _ => value.parse::<f64>().ok().expect(&format!("bad value: {}", line))
+            };
+            let key = format!("{}{}", name, caps.at(2).unwrap_or(""));
+            assert!(samples.insert(key, parsed).is_none(), "duplicate sample: {}", line);
+        }
+        samples
+    }
+
+    fn recorded() -> Metrics {
+        let mut m = Metrics::new();
+        m.record_request("GET", Some(200), 0.02);
+        m.record_request("GET", Some(200), 0.3);
+        m.record_request("POST", Some(500), 1.5);
+        m.record_request("GET", None, 12.0);
+        m.record_error(&Kind::Timeout);
+        m
+    }
+
+    #[test]
+    fn format_test() {
+        assert_eq!(MetricsFormat::Json, format("").unwrap());
+        assert_eq!(MetricsFormat::Prometheus, format("prometheus").unwrap());
+        match format("statsd") {
+            Err(e) => match e.kind {
+                Kind::UnsupportedOutputFormat => assert!(e.detail.unwrap().contains("json, prometheus")),
+                _ => panic!("Expected UnsupportedOutputFormat, got {:?}", e)
+            },
+            Ok(f) => panic!("Expected statsd to be refused, got {:?}", f)
+        }
+    }
+
+    #[test]
+    fn to_prometheus_parses_test() {
+        parse_exposition(&Metrics::new().to_prometheus());
+        parse_exposition(&recorded().to_prometheus());
+    }
+
+    #[test]
+    fn to_prometheus_reflects_recorded_events_test() {
+        let samples = parse_exposition(&recorded().to_prometheus());
+        assert_eq!(Some(&2.0), samples.get("delivery_requests_total{method=\"GET\",status=\"200\"}"));
+        assert_eq!(Some(&1.0), samples.get("delivery_requests_total{method=\"POST\",status=\"500\"}"));
+        assert_eq!(Some(&1.0), samples.get("delivery_requests_total{method=\"GET\",status=\"error\"}"));
+        assert_eq!(Some(&1.0), samples.get("delivery_request_errors_total{kind=\"Timeout\"}"));
+        assert_eq!(Some(&1.0), samples.get("delivery_request_duration_seconds_bucket{le=\"0.05\"}"));
+        assert_eq!(Some(&2.0), samples.get("delivery_request_duration_seconds_bucket{le=\"0.5\"}"));
+        assert_eq!(Some(&3.0), samples.get("delivery_request_duration_seconds_bucket{le=\"10\"}"));
+        assert_eq!(Some(&4.0), samples.get("delivery_request_duration_seconds_bucket{le=\"+Inf\"}"));
+        assert_eq!(Some(&4.0), samples.get("delivery_request_duration_seconds_count"));
+        let sum = samples.get("delivery_request_duration_seconds_sum").unwrap();
+        assert!((sum - 13.82).abs() < 1e-9);
+    }
+
+    #[test]
+    fn record_error_drops_payload_test() {
+        let mut m = Metrics::new();
+        m.record_error(&Kind::ApiError(StatusCode::NotFound, Ok("gone".to_string())));
+        assert!(m.to_prometheus().contains("delivery_request_errors_total{kind=\"ApiError\"} 1\n"));
+    }
+
+    #[test]
+    fn to_json_test() {
+        let json = recorded().to_json();
+        assert_eq!(Some(4), json.find_path(&["latency_seconds", "count"]).and_then(|c| c.as_u64()));
+        assert_eq!(Some(1), json.find_path(&["errors", "Timeout"]).and_then(|c| c.as_u64()));
+        assert_eq!(3, json.find("requests").and_then(|r| r.as_array()).unwrap().len());
+    }
+}
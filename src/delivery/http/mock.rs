@@ -0,0 +1,104 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! A canned-response HTTP server for tests.
+//!
+//! It listens on a random local port and answers each connection with
+//! the next response in the list, recording the raw requests it got.
+//! Point an `APIClient::new_http` at `addr()` to use it.
+
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub struct MockServer {
+    addr: String,
+    requests: Arc<Mutex<Vec<String>>>
+}
+
+/// Build a raw HTTP/1.1 response with a JSON body.
+pub fn response(status: &str, body: &str) -> String {
+    response_with_headers(status, &[], body)
+}
+
+pub fn response_with_headers(status: &str, headers: &[&str], body: &str) -> String {
+    let mut res = format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+                          status, body.len());
+    for h in headers.iter() {
+        res.push_str(&format!("{}\r\n", h));
+    }
+    res.push_str("\r\n");
+    res.push_str(body);
+    res
+}
+
+impl MockServer {
+    pub fn start(responses: Vec<String>) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        thread::spawn(move || {
+            for res in responses.into_iter() {
+                let mut stream = match listener.accept() {
+                    Ok((s, _)) => s,
+                    Err(_) => return
+                };
+                let request = MockServer::read_request(&mut stream);
+                seen.lock().unwrap().push(request);
+                let _ = stream.write_all(res.as_bytes());
+            }
+        });
+        MockServer{ addr: addr, requests: requests }
+    }
+
+    fn read_request<R: Read>(stream: &mut R) -> String {
+        let mut reader = BufReader::new(stream);
+        let mut request = String::new();
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                break;
+            }
+            if line.to_lowercase().starts_with("content-length:") {
+                content_length = line[15..].trim().parse::<usize>().unwrap_or(0);
+            }
+            request.push_str(&line);
+            if line == "\r\n" {
+                break;
+            }
+        }
+        let mut body = vec![0u8; content_length];
+        let _ = reader.read(&mut body);
+        request.push_str(&String::from_utf8_lossy(&body));
+        request
+    }
+
+    /// The `host:port` the server listens on.
+    pub fn addr(&self) -> String {
+        self.addr.clone()
+    }
+
+    /// The raw requests received so far.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
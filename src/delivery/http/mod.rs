@@ -15,8 +15,10 @@
 // limitations under the License.
 //
 
+use std::collections::BTreeMap;
 use std::fmt;
 use std::env;
+use std::io;
 use hyper;
 use hyper::status::StatusCode;
 use hyper::client::response::Response as HyperResponse;
@@ -27,11 +29,36 @@ use errors::Kind as DelivError;
 use std::error;
 use std::io::prelude::*;
 use errors::{DeliveryError, Kind};
+use config::{check_server_allowed, Config};
 use token::TokenStore;
 use utils::say::{sayln};
+use utils::disk;
+use std::fs::File;
+use std::path::PathBuf;
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, TryRecvError};
+use std::thread;
+use std::time::duration::Duration;
+use time;
 
 mod headers;
+#[cfg(test)]
+pub mod mock;
+pub mod pages;
+pub mod pins;
+pub mod changes;
+pub mod fields;
+pub mod metrics;
+pub mod pool;
+pub mod proxy;
+pub mod record;
+pub mod retry;
+pub mod skew;
+pub mod tls;
 pub mod token;
+pub mod trace;
+pub mod version;
+pub mod webhook;
 
 #[derive(Debug)]
 enum HProto {
@@ -64,9 +91,95 @@ pub struct APIClient {
     proto: HProto,
     host: String,
     enterprise: String,
-    auth: Option<APIAuth>
+    auth: Option<APIAuth>,
+    status_map: BTreeMap<u16, String>,
+    proxy: proxy::ProxyConfig,
+    tls: tls::TlsPolicy,
+    /// hyper clients kept between requests to the same server.
+    pool: pool::SharedPool<hyper::Client>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    retries: u32,
+    trace: Option<trace::Sink>,
+    request_log: Option<PathBuf>,
+    /// The script to add each request to as a `curl` command; see
+    /// `http::record`.
+    record_script: Option<PathBuf>,
+    /// Where to keep the fingerprints of servers' certificates; None
+    /// doesn't check them.
+    cert_pins: Option<PathBuf>,
+    accept_new_cert: bool,
+    /// Where the server is mounted, for one not at the root; empty
+    /// for the root.
+    base_path: String,
+    /// The hosts `get_url` may fetch from; None allows any.
+    allowed_servers: Option<Vec<String>>
+}
+
+/// What it takes to open a connection to the server, apart from the
+/// client, so that it can be done on the thread that is timed.
+#[derive(Clone)]
+struct Connector {
+    proxy: proxy::ProxyConfig,
+    tls: tls::TlsPolicy,
+    /// Where to keep the fingerprints of servers' certificates; None
+    /// doesn't check them.
+    cert_pins: Option<PathBuf>,
+    accept_new_cert: bool
+}
+
+impl Connector {
+    /// The TLS session to the server for `url`, through the proxy
+    /// unless it is bypassed, if it is HTTPS. We always do the
+    /// handshake ourselves, as hyper doesn't check certificates;
+    /// plain HTTP requests are left to hyper, and always go direct.
+    fn tls_stream(&self, url: &str) -> Result<Option<tls::TlsStream>, DeliveryError> {
+        let (scheme, host, port) = match proxy::host_port(url) {
+            Some(t) => t,
+            None => return Ok(None)
+        };
+        if scheme != "https" {
+            return Ok(None);
+        }
+        let stream = if !self.proxy.bypass(&host) {
+            try!(self.proxy.open_tunnel(&host, port))
+        } else {
+            match TcpStream::connect(&(&host[..], port)) {
+                Ok(s) => s,
+                Err(e) => return Err(error::FromError::from_error(HttpError::HttpIoError(e)))
+            }
+        };
+        let tls = try!(self.tls.start_tls(stream, &host));
+        match self.cert_pins {
+            Some(ref path) => try!(self.check_cert_pin(path, &host, port, &tls)),
+            None => {}
+        }
+        Ok(Some(tls))
+    }
+
+    fn check_cert_pin(&self, path: &PathBuf, host: &str, port: u16,
+                      tls: &tls::TlsStream) -> Result<(), DeliveryError> {
+        let fingerprint = match tls.fingerprint() {
+            Some(f) => f,
+            None => {
+                debug!("No certificate from {}:{} to check", host, port);
+                return Ok(());
+            }
+        };
+        let mut known = try!(pins::CertPins::from_file(path));
+        match try!(known.check(host, port, &fingerprint, self.accept_new_cert)) {
+            pins::PinCheck::Recorded => debug!("Keeping the certificate fingerprint for {}:{}", host, port),
+            pins::PinCheck::Replaced => sayln("yellow", &format!("Accepted the new certificate for {}:{}", host, port)),
+            pins::PinCheck::Matched => {}
+        }
+        Ok(())
+    }
 }
 
+/// The first retry waits this long, and each one after twice as long
+/// as the last (plus jitter).
+pub const RETRY_BASE_MS: u64 = 200;
+
 impl APIClient {
 
     /// Create a new `APIClient` using HTTP attached to the enterprise
@@ -86,17 +199,362 @@ impl APIClient {
             proto: proto,
             host: String::from_str(host),
             enterprise: String::from_str(ent),
-            auth: None
+            auth: None,
+            status_map: BTreeMap::new(),
+            proxy: proxy::ProxyConfig::none(),
+            tls: tls::TlsPolicy::default_policy(),
+            pool: pool::SharedPool::new(pool::IdlePool::new()),
+            connect_timeout: None,
+            read_timeout: None,
+            retries: 0,
+            trace: trace::from_env(),
+            request_log: None,
+            record_script: None,
+            cert_pins: None,
+            accept_new_cert: false,
+            base_path: String::new(),
+            allowed_servers: None
+        }
+    }
+
+    /// Retry a request up to `count` more times, backing off
+    /// exponentially, when the server answers with a 5xx or the
+    /// connection is reset. Other responses, 4xx included, are
+    /// returned at once. By default we try once.
+    pub fn with_retries(mut self, count: u32) -> APIClient {
+        self.retries = count;
+        self
+    }
+
+    /// Give up with `Kind::Timeout` if connecting and sending a
+    /// request takes longer than `timeout`. By default we wait
+    /// forever.
+    pub fn with_timeout(mut self, timeout: Duration) -> APIClient {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up with `Kind::Timeout` if the server takes longer than
+    /// `timeout` to respond once the request is sent.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> APIClient {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// How long to wait for a response, if we aren't to wait forever.
+    /// hyper can't tell us when it has connected, so the connect and
+    /// read timeouts are spent together.
+    fn response_timeout(&self) -> Option<Duration> {
+        match (self.connect_timeout, self.read_timeout) {
+            (Some(c), Some(r)) => Some(c + r),
+            (Some(c), None) => Some(c),
+            (None, Some(r)) => Some(r),
+            (None, None) => None
+        }
+    }
+
+    /// Create a new HTTPS `APIClient` for the server and enterprise in
+    /// `config`, with any client settings from cli.toml applied.
+    pub fn from_config(config: &Config) -> Result<APIClient, DeliveryError> {
+        let server = try!(config.clone().server());
+        let ent = try!(config.clone().enterprise());
+        let mut client = try!(APIClient::with_connection_settings(&server, &ent, config));
+        match config.status_map {
+            Some(ref map) => try!(client.set_status_map(map)),
+            None => {}
+        }
+        client.set_cert_pins_from_home();
+        match config.api_base_path {
+            Some(ref base) => client.set_base_path(base),
+            None => {}
+        }
+        client.allowed_servers = config.allowed_servers.clone();
+        match trace::log_path() {
+            Some(path) => {
+                try!(trace::trim_log(&path, trace::LOG_LINES));
+                client.request_log = Some(path);
+            },
+            None => {}
+        }
+        client.record_script = record::script_path();
+        Ok(client)
+    }
+
+    /// A client for a URL that isn't the Delivery server's, such as a
+    /// chat webhook: the proxy, TLS and timeout settings in `config`
+    /// apply, but not certificate pins, `allowed_servers`, the request
+    /// log or `--record-script`, which are all about our server.
+    pub fn for_webhook(config: &Config) -> Result<APIClient, DeliveryError> {
+        APIClient::with_connection_settings("", "", config)
+    }
+
+    /// An HTTPS client with the proxy, TLS policy and timeout from
+    /// `config`.
+    fn with_connection_settings(host: &str, ent: &str,
+                                config: &Config) -> Result<APIClient, DeliveryError> {
+        let mut client = APIClient::new_https(host, ent);
+        let vars: Vec<(String, String)> = env::vars().collect();
+        client.set_proxy(proxy::ProxyConfig::resolve(config, &vars));
+        client.set_tls_policy(try!(tls::TlsPolicy::from_config(config)));
+        match try!(config.pool_idle_timeout_secs()) {
+            Some(secs) => client.set_pool_idle_timeout(secs),
+            None => {}
+        }
+        match try!(config.timeout_secs()) {
+            Some(secs) => client = client.with_timeout(Duration::seconds(secs as i64)),
+            None => {}
+        }
+        Ok(client)
+    }
+
+    /// Check the server's certificate against the fingerprint kept in
+    /// `path`, keeping it there the first time; see `http::pins`.
+    /// `accept_new` replaces a fingerprint that changed.
+    pub fn set_cert_pins(&mut self, path: &PathBuf, accept_new: bool) {
+        self.cert_pins = Some(path.clone());
+        self.accept_new_cert = accept_new;
+    }
+
+    /// Send every request under `base`, for a server mounted at a
+    /// path like `/delivery` rather than at the root.
+    pub fn set_base_path(&mut self, base: &str) {
+        self.base_path = base.to_string();
+    }
+
+    /// `set_cert_pins` with `~/.delivery/known-certs.json`, accepting a
+    /// new certificate if `--accept-new-cert` was given.
+    pub fn set_cert_pins_from_home(&mut self) {
+        match pins::CertPins::home_path() {
+            Some(path) => self.set_cert_pins(&path, pins::accept_new()),
+            None => {}
+        }
+    }
+
+    /// Log each request and its response status to stderr; see
+    /// `http::trace`.
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.trace = if verbose { Some(trace::Sink::Stderr) } else { None };
+    }
+
+    /// Send the request log to `sink` instead of stderr.
+    pub fn set_trace_sink(&mut self, sink: trace::Sink) {
+        self.trace = Some(sink);
+    }
+
+    pub fn set_proxy(&mut self, proxy: proxy::ProxyConfig) {
+        self.proxy = proxy;
+    }
+
+    pub fn set_tls_policy(&mut self, policy: tls::TlsPolicy) {
+        self.tls = policy;
+    }
+
+    /// Trust the CA certificates in the PEM bundle at `path` as well
+    /// as the system's, for servers with certificates from an
+    /// internal CA. Plain HTTP clients are left as they are.
+    pub fn with_ca_cert(mut self, path: &PathBuf) -> APIClient {
+        if let HProto::HTTPS = self.proto {
+            self.tls.ca_cert = Some(path.clone());
+        }
+        self
+    }
+
+    /// Skip checking the server's certificate altogether when
+    /// `accept` is true. This is for test servers with throwaway
+    /// certificates only: anyone in the middle can read the token.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> APIClient {
+        if let HProto::HTTPS = self.proto {
+            self.tls.verify_certs = !accept;
+        }
+        self
+    }
+
+    /// Send HTTPS requests through the proxy at `host` and `port`,
+    /// whatever the environment says.
+    pub fn with_proxy(mut self, host: &str, port: u16) -> APIClient {
+        self.proxy = self.proxy.with_address(host, port);
+        self
+    }
+
+    pub fn proxy(&self) -> &proxy::ProxyConfig {
+        &self.proxy
+    }
+
+    /// Close connections left idle for longer than `secs`, rather
+    /// than the default `pool::DEFAULT_IDLE_TIMEOUT_SECS`. Any kept
+    /// so far are closed now.
+    pub fn set_pool_idle_timeout(&mut self, secs: u64) {
+        self.pool = pool::SharedPool::new(pool::IdlePool::with_idle_timeout(secs));
+    }
+
+    /// How many connections are kept idle for the next request.
+    pub fn idle_connections(&self) -> usize {
+        self.pool.len()
+    }
+
+    /// Override which `Kind` a response status maps to. Keys are
+    /// status codes and values are `Kind` names, as in the
+    /// `[status_map]` table of cli.toml.
+    pub fn set_status_map(&mut self,
+                          map: &BTreeMap<String, String>) -> Result<(), DeliveryError> {
+        for (status, kind) in map.iter() {
+            let code = match status.parse::<u16>() {
+                Ok(c) => c,
+                Err(_) => return Err(DeliveryError{
+                    kind: Kind::ConfigValidation,
+                    detail: Some(format!("status_map: '{}' is not an HTTP status", status))
+                })
+            };
+            if Kind::from_name(kind).is_none() {
+                return Err(DeliveryError{
+                    kind: Kind::ConfigValidation,
+                    detail: Some(format!("status_map: unknown error kind '{}'", kind))
+                });
+            }
+            self.status_map.insert(code, kind.clone());
+        }
+        Ok(())
+    }
+
+    /// The `DeliveryError` for an unsuccessful response. A mapping
+    /// from `set_status_map` wins; otherwise a revoked token is
+    /// `TokenRevoked`, any other 401 an authentication failure, and
+    /// anything else an `ApiError`.
+    pub fn error_for_status(&self,
+                            status: StatusCode,
+                            body: Result<String, io::Error>) -> DeliveryError {
+        let detail = match body {
+            Ok(ref b) => Some(format!("{} {}", status, b)),
+            Err(ref e) => Some(format!("{} {}", status, e))
+        };
+        let mapped = self.status_map.get(&status.to_u16())
+            .and_then(|name| Kind::from_name(name));
+        let revoked = match body {
+            Ok(ref b) => is_revoked(status, b),
+            Err(_) => false
+        };
+        match mapped {
+            Some(kind) => DeliveryError{ kind: kind, detail: detail },
+            None if revoked => DeliveryError{
+                kind: Kind::TokenRevoked,
+                detail: Some(format!("The token for {} was revoked by {}",
+                                     self.auth.as_ref().map(|a| a.user()).unwrap_or("this user".to_string()),
+                                     self.host))
+            },
+            None => match status {
+                StatusCode::Unauthorized => DeliveryError{
+                    kind: Kind::AuthenticationFailed,
+                    detail: detail
+                },
+                // Display already shows an ApiError's status and body
+                _ => DeliveryError{ kind: Kind::ApiError(status, body),
+                                    detail: None }
+            }
         }
     }
 
-    pub fn get_auth_from_home(&mut self, server: &str, ent: &str, user: &str) -> Result<APIAuth, DeliveryError> {
-        match TokenStore::from_home() {
-            Ok(tstore) => {
-                APIAuth::from_token_store(tstore, server, ent, user)
+    /// Compare the response's `Date` header with the local clock,
+    /// returning `Kind::ServerTimeSkew` if they are too far apart. A
+    /// missing or unparseable header is ignored.
+    pub fn check_server_time(res: &HyperResponse) -> Result<(), DeliveryError> {
+        let date = match res.headers.get_raw("Date") {
+            Some(raw) if !raw.is_empty() => String::from_utf8_lossy(&raw[0]).into_owned(),
+            _ => return Ok(())
+        };
+        let now = time::get_time();
+        match skew::clock_skew(&date, now) {
+            Ok(_) => skew::check_clock_skew(&date, now),
+            Err(_) => Ok(())
+        }
+    }
+
+    /// Check the version the server reports in its
+    /// `chef-delivery-version` header against ours, with
+    /// `version::negotiate`. A missing header is ignored.
+    pub fn check_server_version(res: &HyperResponse) -> Result<(), DeliveryError> {
+        match res.headers.get_raw("chef-delivery-version") {
+            Some(raw) if !raw.is_empty() => {
+                try!(version::negotiate(&String::from_utf8_lossy(&raw[0])));
+                Ok(())
             },
+            _ => Ok(())
+        }
+    }
+
+    /// Pass a successful response through, or turn it into the
+    /// matching `DeliveryError`. Clock skew is only a warning here,
+    /// but a server of another major version is an error.
+    pub fn check_response(&self,
+                          mut res: HyperResponse) -> Result<HyperResponse, DeliveryError> {
+        match APIClient::check_server_time(&res) {
+            Err(e) => sayln("yellow", &format!("{}", e)),
+            Ok(_) => {}
+        }
+        try!(APIClient::check_server_version(&res));
+        if res.status.is_success() {
+            return Ok(res);
+        }
+        let mut body = String::new();
+        let body_result = match res.read_to_string(&mut body) {
+            Ok(_) => Ok(body),
             Err(e) => Err(e)
+        };
+        let err = self.error_for_status(res.status, body_result);
+        match err.kind {
+            Kind::TokenRevoked => self.forget_token(),
+            _ => {}
         }
+        Err(err)
+    }
+
+    /// Drop our token from the token store, since it is no use any
+    /// more. Failing to do so is only worth a debug message; the
+    /// revoked token error is what the user needs to see.
+    fn forget_token(&self) {
+        match TokenStore::from_home().and_then(|mut tstore| self.forget_token_in(&mut tstore)) {
+            Ok(_) => {},
+            Err(e) => debug!("Failed to remove revoked token: {:?}", e)
+        }
+    }
+
+    /// `forget_token` against `tstore`. Only the token the server
+    /// revoked is removed, so one we were given in `DELIVERY_TOKEN`
+    /// leaves whatever the store has alone.
+    fn forget_token_in(&self, tstore: &mut TokenStore) -> Result<(), DeliveryError> {
+        let auth = match self.auth {
+            Some(ref a) => a,
+            None => return Ok(())
+        };
+        let stored = tstore.lookup(&self.host, &self.enterprise, &auth.user).map(|t| t.clone());
+        if stored == Some(auth.token()) {
+            try!(tstore.remove_token(&self.host, &self.enterprise, &auth.user));
+        }
+        Ok(())
+    }
+
+    /// GET `path` and parse the response body as JSON, failing on an
+    /// unsuccessful status.
+    pub fn get_json(&self, path: &str) -> Result<json::Json, DeliveryError> {
+        let res = try!(self.get(path));
+        let res = try!(self.check_response(res));
+        APIClient::parse_json(Ok(res))
+    }
+
+    /// GET `url`, as `get_url` does, and write the body to `dest`.
+    /// When the server sends a `Content-Length` we first check it will
+    /// fit, returning `Kind::InsufficientDiskSpace` before writing
+    /// anything.
+    pub fn download(&self, url: &str, dest: &PathBuf) -> Result<u64, DeliveryError> {
+        let res = try!(self.get_url(url));
+        let mut res = try!(self.check_response(res));
+        let size = res.headers.get::<hyper::header::ContentLength>().map(|l| l.0);
+        let dir = match dest.parent() {
+            Some(d) => d.to_path_buf(),
+            None => PathBuf::from(".")
+        };
+        try!(disk::preflight(&dir, size));
+        let mut f = try!(File::create(dest));
+        Ok(try!(io::copy(&mut res, &mut f)))
     }
 
     pub fn set_auth(&mut self, auth: APIAuth) {
@@ -104,8 +562,13 @@ impl APIClient {
     }
     
     pub fn api_url(&self, path: &str) -> String {
-        format!("{}://{}/api/v0/e/{}/{}",
-                self.proto, self.host, self.enterprise, path)
+        let api = join_path(&format!("api/v0/e/{}", self.enterprise), path);
+        self.server_url(&join_path(&self.base_path, &api))
+    }
+
+    /// `path` on the server itself, outside any base path.
+    fn server_url(&self, path: &str) -> String {
+        format!("{}://{}{}", self.proto, self.host, join_path("/", path))
     }
 
     pub fn project_exists(&self,
@@ -125,7 +588,7 @@ impl APIClient {
                 }
             },
             Err(e) => {
-                sayln("red", &format!("project_exists: HttpError: {:?}", e));
+                sayln("red", &format!("project_exists: {}", e));
                 return false;
             }
         }
@@ -153,7 +616,7 @@ impl APIClient {
                     }
                 }
             },
-            Err(e) => Err(DelivError::HttpError(e))
+            Err(e) => Err(e.kind)
         }
     }
 
@@ -182,43 +645,274 @@ impl APIClient {
                     }
                 }
             },
-            Err(e) => Err(DelivError::HttpError(e))
+            Err(e) => Err(e.kind)
         }
     }
 
-    pub fn get(&self, path: &str) -> Result<HyperResponse, HttpError> {
-        let url = self.api_url(path);
-        let mut client = hyper::Client::new();
-        let req = client.get(url.as_ref());
-        let req = req.header(self.json_content());
-        let req = match self.auth {
-            Some(ref auth) => {
-                let (deliv_user, deliv_token) = auth.auth_headers();
-                req.header(deliv_user).header(deliv_token)
-            },
-            None => req
+    /// Fetch the users eligible to review changes to `proj`. An empty
+    /// list is returned as `Kind::NoReviewersAvailable`.
+    pub fn reviewers(&self,
+                     org: &str,
+                     proj: &str) -> Result<Vec<String>, DeliveryError> {
+        let path = format!("orgs/{}/projects/{}/reviewers", org, proj);
+        let json = try!(self.get_json(&path));
+        APIClient::reviewers_from_json(&json, org, proj)
+    }
+
+    pub fn reviewers_from_json(json: &json::Json,
+                               org: &str,
+                               proj: &str) -> Result<Vec<String>, DeliveryError> {
+        let values = try!(fields::require(json, &["reviewers"]));
+        let list = match values[0].as_array() {
+            Some(l) => l,
+            None => return Err(DeliveryError{
+                kind: Kind::JsonParseError,
+                detail: Some("Expected a 'reviewers' array".to_string())
+            })
         };
-        req.send()
+        let reviewers: Vec<String> = list.iter()
+            .filter_map(|r| r.as_string())
+            .map(|r| r.to_string())
+            .collect();
+        if reviewers.is_empty() {
+            let msg = format!("Add reviewers to {}/{} on the Delivery server",
+                              org, proj);
+            return Err(DeliveryError{ kind: Kind::NoReviewersAvailable,
+                                      detail: Some(msg) });
+        }
+        Ok(reviewers)
+    }
+
+    pub fn get(&self, path: &str) -> Result<HyperResponse, DeliveryError> {
+        let url = self.api_url(path);
+        self.send(hyper::method::Method::Get, &url, "")
+    }
+
+    /// The URL for an endpoint given in full: a complete URL is used
+    /// as is, and a path is taken from the root of the server (under
+    /// the base path, if there is one) rather than from the
+    /// enterprise API. Endpoints get a password, so an `http://` URL
+    /// is `Kind::ConfigValidation`.
+    pub fn endpoint_url(&self, endpoint: &str) -> Result<String, DeliveryError> {
+        if endpoint.starts_with("http://") {
+            return Err(DeliveryError{
+                kind: Kind::ConfigValidation,
+                detail: Some(format!("The token endpoint {} has to be https; the password would be sent in the clear", endpoint))
+            });
+        }
+        if endpoint.starts_with("https://") {
+            return Ok(endpoint.to_string());
+        }
+        Ok(self.server_url(&join_path(&self.base_path, endpoint)))
     }
 
     pub fn post(&self,
                 path: &str,
-                payload: &str) -> Result<HyperResponse, HttpError> {
+                payload: &str) -> Result<HyperResponse, DeliveryError> {
+        let url = self.api_url(path);
+        self.post_url(&url, payload)
+    }
+
+    /// POST `payload` to `url`, which is used verbatim.
+    pub fn post_url(&self,
+                    url: &str,
+                    payload: &str) -> Result<HyperResponse, DeliveryError> {
+        self.send(hyper::method::Method::Post, url, payload)
+    }
+
+    /// GET `url`, which is used verbatim. Its host has to be one of
+    /// the `allowed_servers`, as the server's does.
+    pub fn get_url(&self, url: &str) -> Result<HyperResponse, DeliveryError> {
+        match proxy::host_port(url) {
+            Some((_, host, port)) => {
+                let with_port = format!("{}:{}", host, port);
+                if check_server_allowed(&with_port, &self.allowed_servers).is_err() {
+                    try!(check_server_allowed(&host, &self.allowed_servers));
+                }
+            },
+            None => {}
+        }
+        self.send(hyper::method::Method::Get, url, "")
+    }
+
+    /// PUT `payload` to `path`, as `post` does.
+    pub fn put(&self,
+               path: &str,
+               payload: &str) -> Result<HyperResponse, DeliveryError> {
         let url = self.api_url(path);
-        let mut client = hyper::Client::new();
-        let req = client.post(url.as_ref());
-        let req = req.header(self.json_content());
-        let req = match self.auth {
-            Some(ref auth) => {
-                let (deliv_user, deliv_token) = auth.auth_headers();
-                req.header(deliv_user).header(deliv_token)
+        self.send(hyper::method::Method::Put, &url, payload)
+    }
+
+    /// DELETE `path`; the request carries no body.
+    pub fn delete(&self, path: &str) -> Result<HyperResponse, DeliveryError> {
+        let url = self.api_url(path);
+        self.send(hyper::method::Method::Delete, &url, "")
+    }
+
+    /// Send a request with `send_once`, retrying transient failures as
+    /// many times as `with_retries` allows.
+    fn send(&self, method: hyper::method::Method,
+            url: &str, payload: &str) -> Result<HyperResponse, DeliveryError> {
+        let mut backoff = retry::Backoff::new(RETRY_BASE_MS);
+        let mut attempt = 0;
+        loop {
+            let result = self.send_once(method.clone(), url, payload);
+            if attempt >= self.retries || !APIClient::is_transient(&result) {
+                return result;
+            }
+            let delay = backoff.delay_ms(attempt);
+            debug!("Retrying {} {} in {}ms", method, url, delay);
+            thread::sleep(Duration::milliseconds(delay as i64));
+            attempt += 1;
+        }
+    }
+
+    /// Whether a retry might go better: the server had a 5xx, or the
+    /// connection was reset or refused under us.
+    fn is_transient(result: &Result<HyperResponse, DeliveryError>) -> bool {
+        match *result {
+            Ok(ref res) => res.status.is_server_error(),
+            Err(DeliveryError{ kind: Kind::HttpError(HttpError::HttpIoError(ref e)), .. }) => {
+                match e.kind() {
+                    io::ErrorKind::ConnectionReset |
+                    io::ErrorKind::ConnectionAborted |
+                    io::ErrorKind::ConnectionRefused => true,
+                    _ => false
+                }
             },
-            None => req
+            Err(_) => false
+        }
+    }
+
+    /// Send a request to `url`, with our JSON content type and auth
+    /// headers and `payload` as the body unless it is empty. With a
+    /// timeout set the request, connecting and the TLS handshake
+    /// included, is made on its own thread, so we can stop waiting
+    /// for it.
+    fn send_once(&self, method: hyper::method::Method,
+                 url: &str, payload: &str) -> Result<HyperResponse, DeliveryError> {
+        let target = url.to_string();
+        let body = payload.to_string();
+        let content = self.json_content();
+        let auth = self.auth.as_ref().map(|a| a.auth_headers());
+        let method_name = format!("{}", method);
+        let mut headers = vec![("Content-Type".to_string(), format!("{}", content.0))];
+        match auth {
+            Some((ref user, ref token)) => {
+                headers.push(("chef-delivery-user".to_string(), user.0.clone()));
+                headers.push(("chef-delivery-token".to_string(), token.0.clone()));
+            },
+            None => {}
+        }
+        match self.record_script {
+            Some(ref script) => try!(record::record(script, &method_name, url, &headers, payload)),
+            None => {}
+        }
+        self.trace_lines(&trace::request_lines(&method_name, url, &headers));
+        let connector = self.connector();
+        let pool = self.pool.clone();
+        let origin = match proxy::host_port(url) {
+            Some((scheme, host, port)) => format!("{}://{}:{}", scheme, host, port),
+            None => url.to_string()
         };
-        if !payload.is_empty() {
-            req.body(payload).send()
-        } else {
-            req.send()
+        let request = move || -> Result<HyperResponse, DeliveryError> {
+            // A tunnel through a proxy is good for one request only
+            let tunnel = try!(connector.tls_stream(&target));
+            let reusable = tunnel.is_none();
+            let mut client = match tunnel {
+                Some(t) => hyper::Client::with_connector(tls::PreparedConnector::new(t)),
+                None => pool.checkout(&origin).unwrap_or_else(|| hyper::Client::new())
+            };
+            let sent = {
+                let req = client.request(method, &target[..]).header(content);
+                let req = match auth {
+                    Some((deliv_user, deliv_token)) => req.header(deliv_user).header(deliv_token),
+                    None => req
+                };
+                if !body.is_empty() {
+                    req.body(&body[..]).send()
+                } else {
+                    req.send()
+                }
+            };
+            if reusable && sent.is_ok() {
+                pool.checkin(&origin, client);
+            }
+            Ok(try!(sent))
+        };
+        let started = time::precise_time_s();
+        let result = match self.response_timeout() {
+            Some(limit) => APIClient::send_within(limit, url, request),
+            None => request()
+        };
+        metrics::observe(&method_name, &result, time::precise_time_s() - started);
+        let status = match result {
+            Ok(ref res) => Ok(format!("{}", res.status)),
+            Err(ref e) => Err(format!("{}", e))
+        };
+        self.trace_lines(&[trace::response_line(status)]);
+        result
+    }
+
+    /// Hand `lines` to the trace sink and the request log, whichever
+    /// are set.
+    fn trace_lines(&self, lines: &[String]) {
+        match self.trace {
+            Some(ref sink) => {
+                for line in lines.iter() {
+                    sink.write(line);
+                }
+            },
+            None => {}
+        }
+        match self.request_log {
+            Some(ref path) => trace::append_log(path, lines),
+            None => {}
+        }
+    }
+
+    fn connector(&self) -> Connector {
+        Connector {
+            proxy: self.proxy.clone(),
+            tls: self.tls.clone(),
+            cert_pins: self.cert_pins.clone(),
+            accept_new_cert: self.accept_new_cert
+        }
+    }
+
+    /// Run `request` on another thread, returning `Kind::Timeout` if
+    /// it hasn't finished within `limit`. We can't cancel a request
+    /// hyper is blocked in, so on a timeout the thread is left to
+    /// finish or fail on its own: it holds its connection until the
+    /// server answers or closes it, or until we exit. A timeout isn't
+    /// retried and fails the command, so at most one such thread is
+    /// left behind.
+    fn send_within<F>(limit: Duration, url: &str,
+                      request: F) -> Result<HyperResponse, DeliveryError>
+        where F: FnOnce() -> Result<HyperResponse, DeliveryError> + Send + 'static {
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let _ = tx.send(request());
+        });
+        let deadline = time::precise_time_ns() + limit.num_milliseconds() as u64 * 1_000_000;
+        loop {
+            match rx.try_recv() {
+                Ok(result) => return result,
+                Err(TryRecvError::Disconnected) => return Err(DeliveryError{
+                    kind: Kind::FailedToExecute,
+                    detail: Some(format!("The request to {} stopped without a response", url))
+                }),
+                Err(TryRecvError::Empty) => {
+                    if time::precise_time_ns() > deadline {
+                        return Err(DeliveryError{
+                            kind: Kind::Timeout,
+                            detail: Some(format!("No response from {} within {}ms",
+                                                 url, limit.num_milliseconds()))
+                        });
+                    }
+                    thread::sleep(Duration::milliseconds(10i64));
+                }
+            }
         }
     }
 
@@ -270,6 +964,29 @@ impl APIClient {
 
 }
 
+/// Whether a response says our token was revoked, rather than merely
+/// wrong or expired: a 401 whose JSON body has `"error":
+/// "token_revoked"`.
+pub fn is_revoked(status: StatusCode, body: &str) -> bool {
+    if status != StatusCode::Unauthorized {
+        return false;
+    }
+    match json::Json::from_str(body) {
+        Ok(j) => j.find("error").and_then(|e| e.as_string()) == Some("token_revoked"),
+        Err(_) => false
+    }
+}
+
+/// `a` and `b` joined with exactly one `/` between them, whether or
+/// not `a` ends with one or `b` starts with one. An empty `a` leaves
+/// `b` as it is.
+pub fn join_path(a: &str, b: &str) -> String {
+    if a.is_empty() {
+        return b.to_string();
+    }
+    format!("{}/{}", a.trim_right_matches('/'), b.trim_left_matches('/'))
+}
+
 #[derive(Debug)]
 pub struct APIAuth {
     user: String,
@@ -277,6 +994,10 @@ pub struct APIAuth {
 }
 
 impl APIAuth {
+    pub fn new(user: &str, token: &str) -> APIAuth {
+        APIAuth { user: String::from_str(user), token: String::from_str(token) }
+    }
+
     pub fn from_env() -> APIAuth {
         let token = env::var("TOKEN").ok().expect("env missing TOKEN");
         let user = env::var("DEL_USER").ok().expect("env missing DEL_USER");
@@ -286,18 +1007,8 @@ impl APIAuth {
     pub fn from_token_store(tstore: TokenStore,
                             server: &str, ent: &str,
                             user: &str) -> Result<APIAuth, DeliveryError> {
-        match tstore.lookup(server, ent, user) {
-            Some(token) => {
-                Ok(APIAuth{ user: String::from_str(user),
-                             token: String::from_str(token)})
-            },
-            None => {
-                let msg = format!("server: {}, ent: {}, user: {}",
-                                  server, ent, user);
-                Err(DeliveryError{ kind: Kind::NoToken,
-                                   detail: Some(msg)})
-            }
-        }
+        let token = try!(tstore.read_token(server, ent, user));
+        Ok(APIAuth{ user: String::from_str(user), token: token })
     }
 
     pub fn user(&self) -> String {
@@ -318,11 +1029,24 @@ impl APIAuth {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use config::Config;
+    use errors::Kind;
+    use hyper::status::StatusCode;
+    use rustc_serialize::json::Json;
+    use std::collections::BTreeMap;
     use token::TokenStore;
     use std::env;
+    use std::sync::{Arc, Mutex};
     use tempdir::TempDir;
     use utils::path_join_many::PathJoinMany;
-    
+    use http::{mock, pool};
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::time::duration::Duration;
+    use time;
+
     #[test]
     fn api_auth() {
         fake_test_env();
@@ -333,6 +1057,21 @@ mod tests {
         assert!(auth.token.len() > 4);
     }
 
+    #[test]
+    fn forget_token_only_forgets_the_stored_token() {
+        let tempdir = TempDir::new("forget").ok().expect("TempDir failed");
+        let mut tstore = TokenStore::from_file(&tempdir.path().join("api-tokens")).unwrap();
+        tstore.write_token("localhost", "Chef", "alice", "stored").unwrap();
+        let mut client = APIClient::new_http("localhost", "Chef");
+        // A token from DELIVERY_TOKEN, say, isn't the store's to forget
+        client.set_auth(APIAuth::new("alice", "from-env"));
+        client.forget_token_in(&mut tstore).unwrap();
+        assert_eq!(Some(&"stored".to_string()), tstore.lookup("localhost", "Chef", "alice"));
+        client.set_auth(APIAuth::new("alice", "stored"));
+        client.forget_token_in(&mut tstore).unwrap();
+        assert_eq!(None, tstore.lookup("localhost", "Chef", "alice"));
+    }
+
     #[test]
     fn http_api_url_test() {
         let mut client = APIClient::new_http("localhost:4343",
@@ -355,6 +1094,139 @@ mod tests {
         assert_eq!("https://localhost:4343/api/v0/e/Chef/foo/bar", url)
     }
 
+    #[test]
+    fn reviewers_from_json_test() {
+        let json = Json::from_str(r#"{"reviewers":["alice","bob"]}"#).unwrap();
+        let reviewers = APIClient::reviewers_from_json(&json, "acme", "fun").unwrap();
+        assert_eq!(vec!["alice".to_string(), "bob".to_string()], reviewers);
+    }
+
+    #[test]
+    fn reviewers_from_empty_json_test() {
+        let json = Json::from_str(r#"{"reviewers":[]}"#).unwrap();
+        let result = APIClient::reviewers_from_json(&json, "acme", "fun");
+        match result {
+            Err(e) => match e.kind {
+                Kind::NoReviewersAvailable => {
+                    assert_eq!("Add reviewers to acme/fun on the Delivery server",
+                               e.detail().unwrap());
+                },
+                _ => panic!("Expected NoReviewersAvailable, got {:?}", e)
+            },
+            Ok(r) => panic!("Expected an error, got {:?}", r)
+        }
+    }
+
+    #[test]
+    fn error_for_status_uses_status_map() {
+        let mut client = APIClient::new_https("localhost", "Chef");
+        let mut map = BTreeMap::new();
+        map.insert("403".to_string(), "AuthenticationFailed".to_string());
+        client.set_status_map(&map).unwrap();
+        let e = client.error_for_status(StatusCode::Forbidden, Ok("nope".to_string()));
+        match e.kind {
+            Kind::AuthenticationFailed => {},
+            _ => panic!("Expected AuthenticationFailed, got {:?}", e)
+        }
+    }
+
+    #[test]
+    fn error_for_status_falls_back_to_defaults() {
+        let mut client = APIClient::new_https("localhost", "Chef");
+        let mut map = BTreeMap::new();
+        map.insert("403".to_string(), "AuthenticationFailed".to_string());
+        client.set_status_map(&map).unwrap();
+        match client.error_for_status(StatusCode::Unauthorized, Ok(String::new())).kind {
+            Kind::AuthenticationFailed => {},
+            k => panic!("Expected AuthenticationFailed, got {:?}", k)
+        }
+        match client.error_for_status(StatusCode::InternalServerError, Ok(String::new())).kind {
+            Kind::ApiError(StatusCode::InternalServerError, _) => {},
+            k => panic!("Expected ApiError, got {:?}", k)
+        }
+    }
+
+    #[test]
+    fn error_for_status_shows_the_body_once() {
+        let client = APIClient::new_https("localhost", "Chef");
+        let shown = format!("{}", client.error_for_status(StatusCode::InternalServerError,
+                                                          Ok("boom".to_string())));
+        assert_eq!(2, shown.split("boom").count());
+        assert!(shown.ends_with(": 500 Internal Server Error boom"));
+    }
+
+    #[test]
+    fn download_writes_body() {
+        let tempdir = TempDir::new("download").ok().expect("TempDir failed");
+        let dest = tempdir.path().join("build_cookbook.tgz");
+        let server = mock::MockServer::start(vec![mock::response("200 OK", "cookies")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        let url = format!("http://{}/artifacts/1", server.addr());
+        assert_eq!(7, client.download(&url, &dest).unwrap());
+        let mut body = String::new();
+        File::open(&dest).unwrap().read_to_string(&mut body).unwrap();
+        assert_eq!("cookies", body);
+    }
+
+    #[test]
+    fn get_json_refuses_a_server_of_another_major_version() {
+        let server = mock::MockServer::start(vec![mock::response_with_headers(
+            "200 OK", &["chef-delivery-version: 99.0.0"], "{}")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        match client.get_json("orgs") {
+            Err(e) => match e.kind {
+                Kind::ServerVersionMismatch => {},
+                _ => panic!("Expected ServerVersionMismatch, got {:?}", e)
+            },
+            Ok(j) => panic!("Expected the version check to fail, got {}", j)
+        }
+    }
+
+    #[test]
+    fn error_for_status_revoked_token() {
+        let client = APIClient::new_https("localhost", "Chef");
+        let body = r#"{"error":"token_revoked"}"#.to_string();
+        match client.error_for_status(StatusCode::Unauthorized, Ok(body)).kind {
+            Kind::TokenRevoked => {},
+            k => panic!("Expected TokenRevoked, got {:?}", k)
+        }
+        let body = r#"{"error":"token_denied"}"#.to_string();
+        match client.error_for_status(StatusCode::Unauthorized, Ok(body)).kind {
+            Kind::AuthenticationFailed => {},
+            k => panic!("Expected AuthenticationFailed, got {:?}", k)
+        }
+        assert!(!is_revoked(StatusCode::Forbidden, r#"{"error":"token_revoked"}"#));
+        assert!(!is_revoked(StatusCode::Unauthorized, "Unauthorized"));
+    }
+
+    #[test]
+    fn set_status_map_rejects_unknown_kind() {
+        let mut client = APIClient::new_https("localhost", "Chef");
+        let mut map = BTreeMap::new();
+        map.insert("403".to_string(), "Banana".to_string());
+        assert!(client.set_status_map(&map).is_err());
+    }
+
+    #[test]
+    fn pool_uses_configured_idle_timeout() {
+        let mut client = APIClient::new_https("localhost", "Chef");
+        assert_eq!(pool::DEFAULT_IDLE_TIMEOUT_SECS, client.pool.idle_timeout_secs());
+        client.set_pool_idle_timeout(5);
+        assert_eq!(5, client.pool.idle_timeout_secs());
+    }
+
+    #[test]
+    fn pool_keeps_connection_for_next_request() {
+        let server = mock::MockServer::start(vec![mock::response("200 OK", "{}"),
+                                                  mock::response("200 OK", "{}")]);
+        let client = APIClient::new_http(&server.addr(), "Chef");
+        assert_eq!(0, client.idle_connections());
+        assert!(client.get_url(&format!("http://{}/one", server.addr())).is_ok());
+        assert_eq!(1, client.idle_connections());
+        assert!(client.get_url(&format!("http://{}/two", server.addr())).is_ok());
+        assert_eq!(1, client.idle_connections());
+    }
+
     fn fake_test_env() {
         env::set_var("DEL_USER", "pete");
         env::set_var("TOKEN", "deadbeefcafe");
@@ -394,4 +1266,199 @@ mod tests {
                        Ok(a)
                    }).is_ok());
     }
+
+    #[test]
+    fn get_times_out_when_server_hangs() {
+        // Accepts connections, but never answers them
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("{}", listener.local_addr().unwrap());
+        let client = APIClient::new_http(&addr, "acme")
+            .with_timeout(Duration::milliseconds(100))
+            .with_read_timeout(Duration::milliseconds(200));
+        let started = time::precise_time_s();
+        match client.get("orgs") {
+            Err(e) => match e.kind {
+                Kind::Timeout => assert!(e.detail.unwrap().ends_with("within 300ms")),
+                k => panic!("Expected Timeout, got {:?}", k)
+            },
+            Ok(res) => panic!("Expected a timeout, got {}", res.status)
+        }
+        let elapsed = time::precise_time_s() - started;
+        assert!(elapsed >= 0.3 && elapsed < 2.0, "timed out after {}s", elapsed);
+        drop(listener);
+    }
+
+    #[test]
+    fn https_times_out_during_handshake() {
+        // Accepts connections, but never starts TLS
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("{}", listener.local_addr().unwrap());
+        let client = APIClient::new_https(&addr, "acme")
+            .with_timeout(Duration::milliseconds(100))
+            .with_read_timeout(Duration::milliseconds(200));
+        match client.get("orgs") {
+            Err(e) => match e.kind {
+                Kind::Timeout => {},
+                k => panic!("Expected Timeout, got {:?}", k)
+            },
+            Ok(res) => panic!("Expected a timeout, got {}", res.status)
+        }
+        drop(listener);
+    }
+
+    #[test]
+    fn https_connection_refused_is_transient() {
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("{}", listener.local_addr().unwrap())
+        };
+        let client = APIClient::new_https(&addr, "acme")
+            .with_timeout(Duration::milliseconds(500));
+        let result = client.get("orgs");
+        assert!(APIClient::is_transient(&result));
+        match result {
+            Err(e) => match e.kind {
+                Kind::HttpError(_) => {},
+                k => panic!("Expected HttpError, got {:?}", k)
+            },
+            Ok(res) => panic!("Expected the connection to be refused, got {}", res.status)
+        }
+    }
+
+    #[test]
+    fn get_retries_server_errors() {
+        let server = mock::MockServer::start(vec![mock::response("503 Service Unavailable", ""),
+                                                  mock::response("503 Service Unavailable", ""),
+                                                  mock::response("200 OK", "{\"orgs\":[]}")]);
+        let client = APIClient::new_http(&server.addr(), "acme").with_retries(2);
+        let res = client.get("orgs").unwrap();
+        assert_eq!(StatusCode::Ok, res.status);
+        assert_eq!(3, server.requests().len());
+    }
+
+    #[test]
+    fn get_without_retries_is_single_shot() {
+        let server = mock::MockServer::start(vec![mock::response("503 Service Unavailable", ""),
+                                                  mock::response("200 OK", "{}")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        assert_eq!(StatusCode::ServiceUnavailable, client.get("orgs").unwrap().status);
+        assert_eq!(1, server.requests().len());
+    }
+
+    #[test]
+    fn post_never_retries_client_errors() {
+        let server = mock::MockServer::start(vec![mock::response("422 Unprocessable Entity", "{}"),
+                                                  mock::response("200 OK", "{}")]);
+        let client = APIClient::new_http(&server.addr(), "acme").with_retries(3);
+        assert_eq!(StatusCode::UnprocessableEntity, client.post("orgs", "{}").unwrap().status);
+        assert_eq!(1, server.requests().len());
+    }
+
+    #[test]
+    fn put_sends_payload_and_headers() {
+        let server = mock::MockServer::start(vec![mock::response("200 OK", "{}")]);
+        let tempdir = TempDir::new("t1").ok().expect("TempDir failed");
+        let mut tstore = TokenStore::from_file(&tempdir.path().join("api-tokens")).unwrap();
+        tstore.write_token(&server.addr(), "acme", "alice", "beefbeef").unwrap();
+        let mut client = APIClient::new_http(&server.addr(), "acme");
+        client.set_auth(APIAuth::from_token_store(tstore, &server.addr(), "acme", "alice").unwrap());
+        let payload = "{\"name\":\"master\",\"base\":\"master\"}";
+        let res = client.put("orgs/acme/projects/fun/pipelines/master", payload).unwrap();
+        assert_eq!(StatusCode::Ok, res.status);
+        let request = server.requests()[0].clone();
+        assert!(request.starts_with("PUT /api/v0/e/acme/orgs/acme/projects/fun/pipelines/master HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Type: application/json\r\n"));
+        assert!(request.contains("chef-delivery-user: alice\r\n"));
+        assert!(request.contains("chef-delivery-token: beefbeef\r\n"));
+        assert!(request.ends_with(payload));
+    }
+
+    #[test]
+    fn https_verifies_certificates_by_default() {
+        let ca = PathBuf::from("/etc/pki/internal-ca.pem");
+        let client = APIClient::new_https("delivery.example.com", "acme");
+        assert!(client.tls.verify_certs);
+        assert_eq!(None, client.tls.ca_cert);
+        let custom = APIClient::new_https("delivery.example.com", "acme").with_ca_cert(&ca);
+        assert_eq!(Some(ca.clone()), custom.tls.ca_cert);
+        assert!(custom.tls.verify_certs);
+        let reckless = APIClient::new_https("delivery.example.com", "acme")
+            .danger_accept_invalid_certs(true);
+        assert!(!reckless.tls.verify_certs);
+    }
+
+    #[test]
+    fn from_config_applies_timeout() {
+        let config = Config::parse_config("server = \"delivery.example.com\"\nenterprise = \"acme\"\ntimeout = \"5\"").unwrap();
+        let client = APIClient::from_config(&config).unwrap();
+        assert_eq!(Some(Duration::seconds(5)), client.response_timeout());
+        let config = Config::parse_config("server = \"delivery.example.com\"\nenterprise = \"acme\"").unwrap();
+        assert_eq!(None, APIClient::from_config(&config).unwrap().response_timeout());
+    }
+
+    #[test]
+    fn for_webhook_keeps_connection_settings_only() {
+        let config = Config::parse_config("server = \"delivery.example.com\"\nenterprise = \"acme\"\ntimeout = \"5\"\nallowed_servers = [\"delivery.example.com\"]").unwrap();
+        let client = APIClient::for_webhook(&config).unwrap();
+        assert_eq!(Some(Duration::seconds(5)), client.response_timeout());
+        assert_eq!(None, client.cert_pins);
+        assert_eq!(None, client.allowed_servers);
+        assert_eq!(None, client.record_script);
+        assert_eq!(None, client.request_log);
+    }
+
+    #[test]
+    fn certificate_options_leave_http_alone() {
+        let server = mock::MockServer::start(vec![mock::response("200 OK", "{}")]);
+        let client = APIClient::new_http(&server.addr(), "acme")
+            .with_ca_cert(&PathBuf::from("/etc/pki/internal-ca.pem"))
+            .danger_accept_invalid_certs(true);
+        assert_eq!(tls::TlsPolicy::default_policy(), client.tls);
+        assert_eq!(StatusCode::Ok, client.get("orgs").unwrap().status);
+    }
+
+    #[test]
+    fn delete_sends_request_line() {
+        let server = mock::MockServer::start(vec![mock::response("204 No Content", "")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        assert_eq!(StatusCode::NoContent, client.delete("orgs/acme/projects/fun").unwrap().status);
+        assert!(server.requests()[0].starts_with("DELETE /api/v0/e/acme/orgs/acme/projects/fun HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn trace_redacts_token_and_skips_body() {
+        let server = mock::MockServer::start(vec![mock::response("200 OK", "{\"token\":\"abc123\"}")]);
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let mut client = APIClient::new_http(&server.addr(), "acme");
+        client.set_auth(APIAuth::new("alice", "beefbeef"));
+        client.set_trace_sink(trace::Sink::Capture(lines.clone()));
+        let payload = token::TokenRequest::payload("alice", "sesame123").unwrap();
+        client.post("users/alice/get-token", &payload).unwrap();
+        let logged = lines.lock().unwrap().clone();
+        let url = format!("> POST http://{}/api/v0/e/acme/users/alice/get-token", server.addr());
+        assert_eq!(url, logged[0]);
+        assert!(logged.contains(&"> chef-delivery-user: alice".to_string()));
+        assert!(logged.contains(&"> chef-delivery-token: ***".to_string()));
+        assert_eq!("< 200 OK", logged[logged.len() - 1]);
+        assert!(!logged.iter().any(|l| l.contains("beefbeef") || l.contains("sesame123")));
+    }
+
+    #[test]
+    fn join_path_collapses_slashes() {
+        assert_eq!("delivery/api", join_path("delivery/", "/api"));
+        assert_eq!("delivery/api", join_path("delivery", "api"));
+        assert_eq!("/api", join_path("", "/api"));
+    }
+
+    #[test]
+    fn api_url_with_base_path() {
+        let mut client = APIClient::new_https("delivery.example.com", "acme");
+        assert_eq!("https://delivery.example.com/api/v0/e/acme/users/alice/get-token",
+                   client.api_url("users/alice/get-token"));
+        client.set_base_path("/delivery/");
+        assert_eq!("https://delivery.example.com/delivery/api/v0/e/acme/users/alice/get-token",
+                   client.api_url("users/alice/get-token"));
+        assert_eq!("https://delivery.example.com/delivery/gateway/token", client.endpoint_url("/gateway/token").unwrap());
+        assert_eq!("https://auth.example.com/token", client.endpoint_url("https://auth.example.com/token").unwrap());
+    }
 }
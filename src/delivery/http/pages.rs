@@ -0,0 +1,204 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Follow the pages of a list endpoint.
+//!
+//! List endpoints answer with a bare JSON array, so there is nowhere
+//! in the body for a `next` field. The next page is given the way
+//! GitHub does it, in a `Link` header:
+//!
+//! ```text
+//! Link: </api/v0/e/acme/orgs/o/projects/p/changes?page=2>; rel="next"
+//! ```
+//!
+//! The last page has no `next` link. The URL may be complete, or a
+//! path from the root of the server, base path included. A complete
+//! URL has to be on the client's own scheme, host and port, since we
+//! send our credentials with every page; anything else is
+//! `Kind::ForeignPageLink`.
+
+use errors::{DeliveryError, Kind};
+use http::{proxy, APIClient};
+use hyper;
+use rustc_serialize::json::Json;
+
+/// The most pages `get_all` follows before giving up.
+pub const MAX_PAGES: usize = 100;
+
+/// The `rel="next"` URL in a `Link` header, if there is one.
+pub fn next_link(header: &str) -> Option<String> {
+    for link in header.split(',') {
+        let mut parts = link.split(';');
+        let target = parts.next().unwrap_or("").trim();
+        if !(target.starts_with("<") && target.ends_with(">")) {
+            continue;
+        }
+        let is_next = parts.any(|p| {
+            let p = p.trim();
+            p == "rel=\"next\"" || p == "rel=next"
+        });
+        if is_next {
+            return Some(target[1..target.len() - 1].to_string());
+        }
+    }
+    None
+}
+
+impl APIClient {
+    /// GET `path` and every page after it, up to `MAX_PAGES`, and
+    /// return their items in order. More pages than that is
+    /// `Kind::TooManyPages`.
+    pub fn get_all(&self, path: &str) -> Result<Vec<Json>, DeliveryError> {
+        self.get_pages(path, MAX_PAGES)
+    }
+
+    pub fn get_pages(&self, path: &str, max_pages: usize) -> Result<Vec<Json>, DeliveryError> {
+        let mut items = Vec::new();
+        let mut url = self.api_url(path);
+        let mut pages = 0;
+        loop {
+            if pages == max_pages {
+                return Err(DeliveryError{
+                    kind: Kind::TooManyPages,
+                    detail: Some(format!("Stopped after {} pages of {}; the next was {}", max_pages, path, url))
+                });
+            }
+            let res = try!(self.send(hyper::method::Method::Get, &url, ""));
+            let res = try!(self.check_response(res));
+            let next = match res.headers.get_raw("Link") {
+                Some(raw) if !raw.is_empty() => next_link(&String::from_utf8_lossy(&raw[0])),
+                _ => None
+            };
+            let page = try!(APIClient::parse_json(Ok(res)));
+            match page.as_array() {
+                Some(a) => items.extend(a.iter().cloned()),
+                None => return Err(DeliveryError{
+                    kind: Kind::JsonParseError,
+                    detail: Some(format!("Expected page {} of {} to be a list", pages + 1, path))
+                })
+            }
+            pages += 1;
+            match next {
+                Some(ref n) if n.starts_with("http://") || n.starts_with("https://") => {
+                    try!(self.check_same_server(n));
+                    url = n.clone();
+                },
+                Some(ref n) => url = self.server_url(n),
+                None => return Ok(items)
+            }
+        }
+    }
+
+    /// Fail with `Kind::ForeignPageLink` unless `link` is on the same
+    /// scheme, host and port as this client.
+    fn check_same_server(&self, link: &str) -> Result<(), DeliveryError> {
+        let ours = proxy::host_port(&self.server_url("/"));
+        let theirs = proxy::host_port(link);
+        let same = match (ours, theirs) {
+            (Some((s1, h1, p1)), Some((s2, h2, p2))) =>
+                s1 == s2 && h1.to_lowercase() == h2.to_lowercase() && p1 == p2,
+            _ => false
+        };
+        if same {
+            Ok(())
+        } else {
+            Err(DeliveryError{
+                kind: Kind::ForeignPageLink,
+                detail: Some(format!("Not following the next page link to {}, as it isn't on {}",
+                                     link, self.server_url("/")))
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Kind;
+    use http::APIClient;
+    use http::mock::{self, MockServer};
+    use rustc_serialize::json::Json;
+
+    #[test]
+    fn next_link_test() {
+        assert_eq!(Some("/api/v0/e/acme/changes?page=2".to_string()),
+                   next_link("</api/v0/e/acme/changes?page=2>; rel=\"next\""));
+        assert_eq!(Some("https://delivery/changes?page=3".to_string()),
+                   next_link("<https://delivery/changes?page=1>; rel=\"prev\", <https://delivery/changes?page=3>; rel=\"next\""));
+        assert_eq!(None, next_link("<https://delivery/changes?page=1>; rel=\"prev\""));
+        assert_eq!(None, next_link(""));
+    }
+
+    #[test]
+    fn get_all_merges_pages() {
+        let server = MockServer::start(vec![
+            mock::response_with_headers("200 OK", &["Link: </api/v0/e/acme/changes?page=2>; rel=\"next\""], "[{\"id\":\"a\"},{\"id\":\"b\"}]"),
+            mock::response("200 OK", "[{\"id\":\"c\"}]")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        let items = client.get_all("changes").unwrap();
+        let expected = vec![Json::from_str("{\"id\":\"a\"}").unwrap(),
+                            Json::from_str("{\"id\":\"b\"}").unwrap(),
+                            Json::from_str("{\"id\":\"c\"}").unwrap()];
+        assert_eq!(expected, items);
+        let requests = server.requests();
+        assert!(requests[0].starts_with("GET /api/v0/e/acme/changes HTTP/1.1"));
+        assert!(requests[1].starts_with("GET /api/v0/e/acme/changes?page=2 HTTP/1.1"));
+    }
+
+    #[test]
+    fn check_same_server_test() {
+        let client = APIClient::new_https("delivery.example.com", "acme");
+        assert!(client.check_same_server("https://delivery.example.com/api/v0/e/acme/changes?page=2").is_ok());
+        assert!(client.check_same_server("https://Delivery.Example.com:443/changes").is_ok());
+        assert!(client.check_same_server("http://delivery.example.com/changes").is_err());
+        assert!(client.check_same_server("https://delivery.example.com.evil.com/changes").is_err());
+        assert!(client.check_same_server("https://delivery.example.com:8443/changes").is_err());
+    }
+
+    #[test]
+    fn get_all_refuses_links_to_other_servers() {
+        for link in ["Link: <http://evil.example.com/steal>; rel=\"next\"",
+                     "Link: <https://127.0.0.1:1/changes?page=2>; rel=\"next\""].iter() {
+            let server = MockServer::start(vec![mock::response_with_headers("200 OK", &[link], "[1]")]);
+            let client = APIClient::new_http(&server.addr(), "acme");
+            match client.get_all("changes") {
+                Err(e) => match e.kind {
+                    Kind::ForeignPageLink => assert!(e.detail.unwrap().starts_with("Not following the next page link")),
+                    _ => panic!("Expected ForeignPageLink, got {:?}", e)
+                },
+                Ok(items) => panic!("Expected {} to be refused, got {:?}", link, items)
+            }
+            assert_eq!(1, server.requests().len());
+        }
+    }
+
+    #[test]
+    fn get_pages_stops_at_the_cap() {
+        let next = "Link: </api/v0/e/acme/changes?page=2>; rel=\"next\"";
+        let server = MockServer::start(vec![mock::response_with_headers("200 OK", &[next], "[1]"),
+                                            mock::response_with_headers("200 OK", &[next], "[2]")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        match client.get_pages("changes", 2) {
+            Err(e) => match e.kind {
+                Kind::TooManyPages => assert!(e.detail.unwrap().starts_with("Stopped after 2 pages of changes")),
+                _ => panic!("Expected TooManyPages, got {:?}", e)
+            },
+            Ok(items) => panic!("Expected the cap to be hit, got {:?}", items)
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Trust on first use for the server's certificate.
+//!
+//! The first time we talk HTTPS to a server, the SHA-256 fingerprint
+//! of its certificate is kept in `~/.delivery/known-certs.json`, by
+//! host and port. After that a different certificate is
+//! `Kind::CertFingerprintChanged`, even one the CAs would accept,
+//! until `--accept-new-cert` says the change is expected and the new
+//! fingerprint replaces the old.
+
+use errors::{DeliveryError, Kind};
+use rustc_serialize::json;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use utils;
+use utils::path_join_many::PathJoinMany;
+
+static mut accept_new_cert: bool = false;
+
+/// Replace a pin rather than fail on it, for `--accept-new-cert`.
+pub fn turn_on_accept_new() {
+    unsafe {
+        accept_new_cert = true;
+    }
+}
+
+/// What `CertPins::check` did.
+#[derive(Debug, PartialEq)]
+pub enum PinCheck {
+    /// We hadn't seen this server before, and now we have.
+    Recorded,
+    Matched,
+    /// The certificate changed, and we were told to accept it.
+    Replaced
+}
+
+/// Whether `--accept-new-cert` was given.
+pub fn accept_new() -> bool {
+    unsafe { accept_new_cert }
+}
+
+/// The fingerprints we have seen, by `host:port`, backed by a JSON
+/// file that is rewritten on every change.
+#[derive(Debug)]
+pub struct CertPins {
+    pins: BTreeMap<String, String>,
+    path: PathBuf
+}
+
+impl CertPins {
+    /// Where the pins are kept, if we have a home directory.
+    pub fn home_path() -> Option<PathBuf> {
+        utils::home_dir().map(|home| home.join_many(&[".delivery", "known-certs.json"]))
+    }
+
+    /// The pins in `path`; a missing file has none.
+    pub fn from_file(path: &PathBuf) -> Result<CertPins, DeliveryError> {
+        let mut pins = BTreeMap::new();
+        if path.is_file() {
+            let mut body = String::new();
+            try!(try!(File::open(path)).read_to_string(&mut body));
+            if !body.trim().is_empty() {
+                pins = try!(json::decode(&body));
+            }
+        }
+        Ok(CertPins{ pins: pins, path: path.clone() })
+    }
+
+    pub fn lookup(&self, host: &str, port: u16) -> Option<&String> {
+        self.pins.get(&CertPins::key(host, port))
+    }
+
+    /// Compare `fingerprint` with the one we have for the server,
+    /// keeping it if we have none. A different one is
+    /// `Kind::CertFingerprintChanged`, unless `accept_new` is set, when
+    /// it replaces the old one.
+    pub fn check(&mut self, host: &str, port: u16, fingerprint: &str,
+                 accept_new: bool) -> Result<PinCheck, DeliveryError> {
+        let key = CertPins::key(host, port);
+        let result = match self.pins.get(&key) {
+            None => PinCheck::Recorded,
+            Some(known) if &known[..] == fingerprint => return Ok(PinCheck::Matched),
+            Some(known) if !accept_new => {
+                return Err(DeliveryError{
+                    kind: Kind::CertFingerprintChanged,
+                    detail: Some(format!("{} has sent a different certificate (SHA-256 {}, we saw {} before). If you expected it to change, run again with --accept-new-cert",
+                                         key, fingerprint, known))
+                });
+            },
+            Some(_) => PinCheck::Replaced
+        };
+        self.pins.insert(key, fingerprint.to_string());
+        try!(self.write());
+        Ok(result)
+    }
+
+    fn key(host: &str, port: u16) -> String {
+        format!("{}:{}", host, port)
+    }
+
+    fn write(&self) -> Result<(), DeliveryError> {
+        match self.path.parent() {
+            Some(dir) => try!(utils::mkdir_recursive(&dir.to_path_buf())),
+            None => {}
+        }
+        let mut file = try!(File::create(&self.path));
+        try!(file.write_all(try!(json::encode(&self.pins)).as_bytes()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Kind;
+    use tempdir::TempDir;
+
+    #[test]
+    fn first_connection_records() {
+        let tempdir = TempDir::new("pins").ok().expect("TempDir failed");
+        let path = tempdir.path().join("known-certs.json");
+        let mut pins = CertPins::from_file(&path).unwrap();
+        assert_eq!(None, pins.lookup("delivery.example.com", 443));
+        assert_eq!(PinCheck::Recorded, pins.check("delivery.example.com", 443, "aa11", false).unwrap());
+        let reread = CertPins::from_file(&path).unwrap();
+        assert_eq!(Some(&"aa11".to_string()), reread.lookup("delivery.example.com", 443));
+        assert_eq!(None, reread.lookup("delivery.example.com", 8443));
+    }
+
+    #[test]
+    fn changed_fingerprint_fails() {
+        let tempdir = TempDir::new("pins").ok().expect("TempDir failed");
+        let path = tempdir.path().join("known-certs.json");
+        let mut pins = CertPins::from_file(&path).unwrap();
+        pins.check("delivery.example.com", 443, "aa11", false).unwrap();
+        assert_eq!(PinCheck::Matched, pins.check("delivery.example.com", 443, "aa11", false).unwrap());
+        match pins.check("delivery.example.com", 443, "bb22", false) {
+            Err(e) => match e.kind {
+                Kind::CertFingerprintChanged => assert!(e.detail.unwrap().contains("--accept-new-cert")),
+                _ => panic!("Expected CertFingerprintChanged, got {:?}", e)
+            },
+            Ok(c) => panic!("Expected the new certificate to be refused, got {:?}", c)
+        }
+        assert_eq!(Some(&"aa11".to_string()), CertPins::from_file(&path).unwrap().lookup("delivery.example.com", 443));
+    }
+
+    #[test]
+    fn accept_new_replaces() {
+        let tempdir = TempDir::new("pins").ok().expect("TempDir failed");
+        let path = tempdir.path().join("known-certs.json");
+        let mut pins = CertPins::from_file(&path).unwrap();
+        pins.check("delivery.example.com", 443, "aa11", false).unwrap();
+        assert_eq!(PinCheck::Replaced, pins.check("delivery.example.com", 443, "bb22", true).unwrap());
+        let mut reread = CertPins::from_file(&path).unwrap();
+        assert_eq!(PinCheck::Matched, reread.check("delivery.example.com", 443, "bb22", false).unwrap());
+    }
+}
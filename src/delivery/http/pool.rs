@@ -0,0 +1,186 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! A keep-alive pool for connections the client has finished with.
+//!
+//! Connections are kept per host. One that has sat unused for longer
+//! than the idle timeout is closed (dropped) rather than handed out
+//! again, since the server has most likely given up on it by then.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use time;
+
+/// How long, in seconds, an idle connection is kept.
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 90;
+
+#[derive(Debug)]
+pub struct IdlePool<C> {
+    idle_timeout_secs: u64,
+    idle: BTreeMap<String, Vec<(i64, C)>>
+}
+
+impl<C> IdlePool<C> {
+    pub fn new() -> IdlePool<C> {
+        IdlePool::with_idle_timeout(DEFAULT_IDLE_TIMEOUT_SECS)
+    }
+
+    pub fn with_idle_timeout(secs: u64) -> IdlePool<C> {
+        IdlePool { idle_timeout_secs: secs, idle: BTreeMap::new() }
+    }
+
+    pub fn idle_timeout_secs(&self) -> u64 {
+        self.idle_timeout_secs
+    }
+
+    /// Hand back a connection to `host` once a request is done with it.
+    pub fn checkin(&mut self, host: &str, conn: C) {
+        self.checkin_at(host, conn, time::get_time().sec)
+    }
+
+    /// A connection to `host` that hasn't been idle too long, if we
+    /// have one.
+    pub fn checkout(&mut self, host: &str) -> Option<C> {
+        self.checkout_at(host, time::get_time().sec)
+    }
+
+    pub fn checkin_at(&mut self, host: &str, conn: C, now: i64) {
+        if !self.idle.contains_key(host) {
+            self.idle.insert(host.to_string(), Vec::new());
+        }
+        self.idle.get_mut(host).unwrap().push((now, conn));
+    }
+
+    /// As `checkout`, at time `now`. Expired connections are closed
+    /// along the way. The most recently used connection is preferred.
+    pub fn checkout_at(&mut self, host: &str, now: i64) -> Option<C> {
+        self.close_idle_at(now);
+        match self.idle.get_mut(host) {
+            Some(conns) => conns.pop().map(|(_, c)| c),
+            None => None
+        }
+    }
+
+    /// Close every connection idle for longer than the timeout.
+    pub fn close_idle_at(&mut self, now: i64) {
+        let limit = self.idle_timeout_secs as i64;
+        for conns in self.idle.values_mut() {
+            conns.retain(|&(since, _)| now - since <= limit);
+        }
+    }
+
+    /// How many idle connections we are holding.
+    pub fn len(&self) -> usize {
+        self.idle.values().fold(0, |n, conns| n + conns.len())
+    }
+}
+
+/// An `IdlePool` shared between a client and the threads its
+/// requests are made on.
+pub struct SharedPool<C> {
+    inner: Arc<Mutex<IdlePool<C>>>
+}
+
+impl<C> SharedPool<C> {
+    pub fn new(pool: IdlePool<C>) -> SharedPool<C> {
+        SharedPool { inner: Arc::new(Mutex::new(pool)) }
+    }
+
+    pub fn idle_timeout_secs(&self) -> u64 {
+        self.inner.lock().unwrap().idle_timeout_secs()
+    }
+
+    pub fn checkin(&self, host: &str, conn: C) {
+        self.inner.lock().unwrap().checkin(host, conn)
+    }
+
+    pub fn checkout(&self, host: &str) -> Option<C> {
+        self.inner.lock().unwrap().checkout(host)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len()
+    }
+}
+
+impl<C> Clone for SharedPool<C> {
+    fn clone(&self) -> SharedPool<C> {
+        SharedPool { inner: self.inner.clone() }
+    }
+}
+
+impl<C> fmt::Debug for SharedPool<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedPool {{ idle_timeout_secs: {}, idle: {} }}",
+               self.idle_timeout_secs(), self.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// Counts how many times it has been closed.
+    struct Conn {
+        closed: Rc<Cell<u32>>
+    }
+
+    impl Drop for Conn {
+        fn drop(&mut self) {
+            self.closed.set(self.closed.get() + 1);
+        }
+    }
+
+    #[test]
+    fn reuses_connection_within_timeout() {
+        let closed = Rc::new(Cell::new(0));
+        let mut pool = IdlePool::with_idle_timeout(30);
+        pool.checkin_at("delivery.example.com", Conn { closed: closed.clone() }, 1000);
+        assert!(pool.checkout_at("delivery.example.com", 1030).is_some());
+        assert!(pool.checkout_at("other.example.com", 1030).is_none());
+    }
+
+    #[test]
+    fn drops_idle_connection_after_timeout() {
+        let closed = Rc::new(Cell::new(0));
+        let mut pool = IdlePool::with_idle_timeout(30);
+        pool.checkin_at("delivery.example.com", Conn { closed: closed.clone() }, 1000);
+        assert_eq!(1, pool.len());
+        assert!(pool.checkout_at("delivery.example.com", 1031).is_none());
+        assert_eq!(0, pool.len());
+        assert_eq!(1, closed.get());
+    }
+
+    #[test]
+    fn shared_pool_is_one_pool() {
+        let pool = SharedPool::new(IdlePool::with_idle_timeout(30));
+        pool.clone().checkin("delivery.example.com", 1);
+        assert_eq!(1, pool.len());
+        assert_eq!(Some(1), pool.checkout("delivery.example.com"));
+        assert_eq!(0, pool.len());
+    }
+
+    #[test]
+    fn default_idle_timeout() {
+        let pool: IdlePool<Conn> = IdlePool::new();
+        assert_eq!(DEFAULT_IDLE_TIMEOUT_SECS, pool.idle_timeout_secs());
+    }
+}
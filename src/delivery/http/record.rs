@@ -0,0 +1,175 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Record the requests we make as a shell script of `curl` commands.
+//!
+//! With `--record-script <file>`, each `APIClient` request is appended
+//! to the script, so support can replay exactly what the CLI did.
+//! Secrets never go in the file: the token header and any password or
+//! token in a JSON body become references to environment variables,
+//! `DELIVERY_TOKEN` and the like, to be set by whoever runs it.
+
+use std::mem;
+use std::sync::{Once, ONCE_INIT};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use regex::Captures;
+use errors::DeliveryError;
+use utils;
+
+static SCRIPT_INIT: Once = ONCE_INIT;
+static mut recording_to: *const PathBuf = 0 as *const PathBuf;
+
+/// The script we are recording to, if `start` was called.
+pub fn script_path() -> Option<PathBuf> {
+    unsafe {
+        if recording_to.is_null() { None } else { Some((*recording_to).clone()) }
+    }
+}
+
+/// Start a new, executable script at `path`, replacing any old one,
+/// and record the requests of every client made with
+/// `APIClient::from_config` from now on to it. Only the first call
+/// sets the script.
+pub fn start(path: &PathBuf) -> Result<(), DeliveryError> {
+    try!(create_script(path));
+    let path = path.clone();
+    SCRIPT_INIT.call_once(move || unsafe {
+        recording_to = mem::transmute(Box::new(path));
+    });
+    Ok(())
+}
+
+fn create_script(path: &PathBuf) -> Result<(), DeliveryError> {
+    let mut f = try!(File::create(path));
+    try!(f.write_all(b"#!/bin/sh\n# Requests made by delivery. Set DELIVERY_TOKEN, and\n# DELIVERY_PASSWORD for token requests, before running it.\n"));
+    try!(utils::chmod(path, "0755"));
+    Ok(())
+}
+
+/// Append the `curl` command for a request to the script at `path`,
+/// starting the script if it isn't there yet.
+pub fn record(path: &PathBuf, method: &str, url: &str,
+              headers: &[(String, String)], body: &str) -> Result<(), DeliveryError> {
+    if !path.exists() {
+        try!(create_script(path));
+    }
+    let mut f = try!(OpenOptions::new().write(true).append(true).open(path));
+    try!(f.write_all(format!("{}\n", curl_command(method, url, headers, body)).as_bytes()));
+    Ok(())
+}
+
+/// `s` in single quotes, for the shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace("'", "'\\''"))
+}
+
+/// Where the value of a secret would be, leave the shell quote and
+/// read it from the variable instead.
+fn placeholder(var: &str) -> String {
+    format!("'\"${{{}}}\"'", var)
+}
+
+/// The `curl` command making the same request, with secrets replaced
+/// by environment variables.
+pub fn curl_command(method: &str, url: &str,
+                    headers: &[(String, String)], body: &str) -> String {
+    let mut cmd = format!("curl -X {} {}", method, shell_quote(url));
+    for &(ref name, ref value) in headers.iter() {
+        let header = if name == "chef-delivery-token" {
+            format!("'{}: {}'", name, placeholder("DELIVERY_TOKEN"))
+        } else {
+            shell_quote(&format!("{}: {}", name, value))
+        };
+        cmd.push_str(&format!(" -H {}", header));
+    }
+    if !body.is_empty() {
+        cmd.push_str(&format!(" --data {}", redact_body(body)));
+    }
+    cmd
+}
+
+/// The shell-quoted `body` with any password, token or secret value
+/// read from `DELIVERY_PASSWORD`, `DELIVERY_TOKEN` or
+/// `DELIVERY_SECRET`.
+fn redact_body(body: &str) -> String {
+    let secret = regex!(r#"(?i)"(token|password|secret)"(\s*:\s*)"(?:[^"\\]|\\.)*""#);
+    let quoted = shell_quote(body);
+    secret.replace_all(&quoted, |caps: &Captures| {
+        let name = caps.at(1).unwrap_or("");
+        format!("\"{}\"{}\"{}\"", name, caps.at(2).unwrap_or(":"),
+                placeholder(&format!("DELIVERY_{}", name.to_uppercase())))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use tempdir::TempDir;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn curl_command_for_get() {
+        let cmd = curl_command("GET", "https://delivery.example.com/api/v0/e/Chef/orgs",
+                               &headers(&[("Content-Type", "application/json"),
+                                          ("chef-delivery-user", "alice"),
+                                          ("chef-delivery-token", "beefbeef")]), "");
+        assert_eq!("curl -X GET 'https://delivery.example.com/api/v0/e/Chef/orgs' -H 'Content-Type: application/json' -H 'chef-delivery-user: alice' -H 'chef-delivery-token: '\"${DELIVERY_TOKEN}\"''",
+                   cmd);
+        assert!(!cmd.contains("beefbeef"));
+    }
+
+    #[test]
+    fn curl_command_redacts_password() {
+        let cmd = curl_command("POST", "https://delivery.example.com/api/v0/e/Chef/users/alice/get-token",
+                               &[], r#"{"username":"alice","password":"sesame123"}"#);
+        assert!(cmd.starts_with("curl -X POST 'https://delivery.example.com/api/v0/e/Chef/users/alice/get-token' --data "));
+        assert!(cmd.ends_with(r#"--data '{"username":"alice","password":"'"${DELIVERY_PASSWORD}"'"}'"#));
+        assert!(!cmd.contains("sesame123"));
+    }
+
+    #[test]
+    fn curl_command_redacts_password_with_escaped_quote() {
+        let cmd = curl_command("POST", "https://delivery.example.com/api/v0/e/Chef/users/alice/get-token",
+                               &[], r#"{"password":"ses\"ame\\123","username":"alice"}"#);
+        assert!(cmd.ends_with(r#"--data '{"password":"'"${DELIVERY_PASSWORD}"'","username":"alice"}'"#));
+        assert!(!cmd.contains("123"));
+    }
+
+    #[test]
+    fn record_appends_to_script() {
+        let tempdir = TempDir::new("record").ok().expect("TempDir failed");
+        let script = tempdir.path().join("replay.sh");
+        // Not `start`, which would have every client in the tests record
+        record(&script, "GET", "http://127.0.0.1/api/v0/e/Chef/orgs", &[], "").unwrap();
+        record(&script, "POST", "http://127.0.0.1/api/v0/e/Chef/orgs", &[], "{}").unwrap();
+        let mut content = String::new();
+        File::open(&script).unwrap().read_to_string(&mut content).unwrap();
+        assert!(content.starts_with("#!/bin/sh\n"));
+        let commands: Vec<&str> = content.lines_any().filter(|l| l.starts_with("curl")).collect();
+        assert_eq!(vec!["curl -X GET 'http://127.0.0.1/api/v0/e/Chef/orgs'",
+                        "curl -X POST 'http://127.0.0.1/api/v0/e/Chef/orgs' --data '{}'"],
+                   commands);
+    }
+}
@@ -0,0 +1,105 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Exponential backoff with jitter, for retrying API requests.
+//!
+//! The jitter comes from a small xorshift generator. It is seeded from
+//! the clock, unless `DELIVERY_RETRY_SEED` is set (or `with_seed` is
+//! used), in which case the delays are the same on every run.
+
+use std::env;
+use time;
+
+/// A tiny xorshift64* generator; we only need it for jitter.
+#[derive(Debug, Clone)]
+struct XorShift {
+    state: u64
+}
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        // Zero is a fixed point for xorshift
+        XorShift { state: if seed == 0 { 0x9E3779B97F4A7C15 } else { seed } }
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.state;
+        x = x ^ (x >> 12);
+        x = x ^ (x << 25);
+        x = x ^ (x >> 27);
+        self.state = x;
+        x.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base_ms: u64,
+    rng: XorShift
+}
+
+impl Backoff {
+    /// A backoff starting at `base_ms`, seeded from
+    /// `DELIVERY_RETRY_SEED` if it is set and from the clock if not.
+    pub fn new(base_ms: u64) -> Backoff {
+        let seed = match env::var("DELIVERY_RETRY_SEED").ok().and_then(|s| s.parse::<u64>().ok()) {
+            Some(s) => s,
+            None => time::precise_time_ns()
+        };
+        Backoff::with_seed(base_ms, seed)
+    }
+
+    pub fn with_seed(base_ms: u64, seed: u64) -> Backoff {
+        Backoff { base_ms: base_ms, rng: XorShift::new(seed) }
+    }
+
+    /// How long to wait before retry number `attempt` (starting at
+    /// 0): `base_ms * 2^attempt`, plus up to `base_ms` of jitter.
+    pub fn delay_ms(&mut self, attempt: u32) -> u64 {
+        let exp = self.base_ms.saturating_mul(1u64 << attempt.min(16));
+        let jitter = if self.base_ms == 0 { 0 } else { self.rng.next() % self.base_ms };
+        exp.saturating_add(jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delays(backoff: &mut Backoff) -> Vec<u64> {
+        (0..4).map(|a| backoff.delay_ms(a)).collect()
+    }
+
+    #[test]
+    fn same_seed_same_delays() {
+        let mut one = Backoff::with_seed(200, 42);
+        let mut two = Backoff::with_seed(200, 42);
+        assert_eq!(delays(&mut one), delays(&mut two));
+    }
+
+    #[test]
+    fn delays_grow_exponentially() {
+        let mut backoff = Backoff::with_seed(200, 7);
+        let d = delays(&mut backoff);
+        for (attempt, delay) in d.iter().enumerate() {
+            let floor = 200 * (1 << attempt);
+            assert!(*delay >= floor && *delay < floor + 200,
+                    "attempt {} delay {} not in [{}, {})", attempt, delay, floor, floor + 200);
+        }
+    }
+}
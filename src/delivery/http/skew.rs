@@ -0,0 +1,94 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Compare the server's `Date` header with the local clock. A large
+//! difference makes tokens look expired (or not yet valid), so we
+//! would rather say so than leave the user guessing.
+
+use errors::{DeliveryError, Kind};
+use time;
+
+/// How far apart the clocks may be, in seconds, before we complain.
+pub const MAX_CLOCK_SKEW_SECS: i64 = 300;
+
+/// Seconds the server clock is ahead of `now`, parsed from an HTTP
+/// `Date` header. Negative when the server is behind; an error when
+/// the header can't be parsed.
+pub fn clock_skew(date: &str, now: time::Timespec) -> Result<i64, DeliveryError> {
+    let tm = match time::strptime(date.trim(), "%a, %d %b %Y %H:%M:%S GMT") {
+        Ok(t) => t,
+        Err(_) => return Err(DeliveryError{
+            kind: Kind::InvalidDate,
+            detail: Some(format!("Unparseable Date header '{}'", date))
+        })
+    };
+    Ok(tm.to_timespec().sec - now.sec)
+}
+
+/// `Kind::ServerTimeSkew` if the clocks are further apart than
+/// `MAX_CLOCK_SKEW_SECS`.
+pub fn check_clock_skew(date: &str, now: time::Timespec) -> Result<(), DeliveryError> {
+    let skew = try!(clock_skew(date, now));
+    if skew.abs() > MAX_CLOCK_SKEW_SECS {
+        let dir = if skew > 0 { "ahead of" } else { "behind" };
+        return Err(DeliveryError{
+            kind: Kind::ServerTimeSkew,
+            detail: Some(format!("Server clock is {}s {} this machine (limit {}s)",
+                                 skew.abs(), dir, MAX_CLOCK_SKEW_SECS))
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Kind;
+    use time;
+
+    fn now() -> time::Timespec {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        time::Timespec::new(784111777, 0)
+    }
+
+    #[test]
+    fn skew_within_threshold() {
+        assert_eq!(0, clock_skew("Sun, 06 Nov 1994 08:49:37 GMT", now()).unwrap());
+        assert_eq!(-60, clock_skew("Sun, 06 Nov 1994 08:48:37 GMT", now()).unwrap());
+        assert!(check_clock_skew("Sun, 06 Nov 1994 08:53:37 GMT", now()).is_ok());
+    }
+
+    #[test]
+    fn skew_beyond_threshold() {
+        match check_clock_skew("Sun, 06 Nov 1994 09:49:37 GMT", now()) {
+            Err(e) => match e.kind {
+                Kind::ServerTimeSkew => {
+                    assert_eq!("Server clock is 3600s ahead of this machine (limit 300s)",
+                               e.detail().unwrap());
+                },
+                _ => panic!("Expected ServerTimeSkew, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected an hour of skew to fail")
+        }
+    }
+
+    #[test]
+    fn skew_with_bad_header() {
+        assert!(clock_skew("yesterday", now()).is_err());
+    }
+}
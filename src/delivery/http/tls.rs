@@ -0,0 +1,550 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! The TLS policy for talking to the Delivery server.
+//!
+//! We open HTTPS connections and do the handshake ourselves, then
+//! hand the finished `TlsStream` to hyper through a
+//! `PreparedConnector`; hyper's own connector doesn't check the
+//! server's certificate. The policy can set a minimum version, a
+//! cipher policy and a CA bundle to trust as well as the system's:
+//!
+//! ```toml
+//! tls_min_version = "1.2"
+//! tls_ciphers = "modern"
+//! tls_ca_cert = "/etc/pki/internal-ca.pem"
+//! ```
+//!
+//! A server that can't meet the policy is `Kind::TlsError`. We send
+//! the host name (SNI) in the handshake, and the certificate has to
+//! be for that host as well as signed by a CA we trust; a certificate
+//! that fails either check is a `Kind::HttpError` that says so.
+
+use std::io;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use config::Config;
+use errors::{DeliveryError, Kind};
+use std::ascii::AsciiExt;
+use hyper::HttpError;
+use hyper::net::{NetworkConnector, NetworkStream};
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::hex::ToHex;
+use openssl::crypto::hash::Type;
+use openssl::nid::Nid;
+use openssl::ssl::{Ssl, SslContext, SslMethod, SslStream, SSL_VERIFY_NONE, SSL_VERIFY_PEER};
+use openssl::x509::X509;
+use openssl::ssl::{SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3, SSL_OP_NO_TLSV1, SSL_OP_NO_TLSV1_1};
+
+/// The versions a minimum can be set to.
+pub const TLS_VERSIONS: [&'static str; 3] = ["1.0", "1.1", "1.2"];
+
+/// Forward-secret AEAD ciphers only.
+const MODERN_CIPHERS: &'static str = "ECDHE+AESGCM:!aNULL:!eNULL:!MD5:!RC4:!DES:!3DES";
+
+/// Also allows CBC modes and DHE, for older servers.
+const INTERMEDIATE_CIPHERS: &'static str = "ECDHE+AESGCM:ECDHE+AES:DHE+AESGCM:DHE+AES:!aNULL:!eNULL:!MD5:!RC4:!DES:!3DES";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsPolicy {
+    pub min_version: Option<String>,
+    pub ciphers: Option<String>,
+    /// A CA bundle to trust, on top of the system's.
+    pub ca_cert: Option<PathBuf>,
+    /// Only ever false for test servers with throwaway certificates.
+    pub verify_certs: bool
+}
+
+impl TlsPolicy {
+    /// OpenSSL's versions and ciphers, checking the server's
+    /// certificate against the system's CAs.
+    pub fn default_policy() -> TlsPolicy {
+        TlsPolicy { min_version: None, ciphers: None, ca_cert: None, verify_certs: true }
+    }
+
+    /// The policy from `tls_min_version` and `tls_ciphers` in
+    /// `config`. A version or cipher policy we don't know is
+    /// `Kind::ConfigValidation`.
+    pub fn from_config(config: &Config) -> Result<TlsPolicy, DeliveryError> {
+        let policy = TlsPolicy { min_version: config.tls_min_version.clone(),
+                                 ciphers: config.tls_ciphers.clone(),
+                                 ca_cert: config.tls_ca_cert.as_ref().map(|p| PathBuf::from(p)),
+                                 verify_certs: true };
+        match policy.min_version {
+            Some(ref v) if !TLS_VERSIONS.iter().any(|k| *k == &v[..]) => {
+                return Err(DeliveryError{
+                    kind: Kind::ConfigValidation,
+                    detail: Some(format!("tls_min_version: '{}' is not one of {}", v, TLS_VERSIONS.connect(", ")))
+                });
+            },
+            _ => {}
+        }
+        match policy.ciphers {
+            Some(ref c) if c != "modern" && c != "intermediate" => {
+                return Err(DeliveryError{
+                    kind: Kind::ConfigValidation,
+                    detail: Some(format!("tls_ciphers: '{}' is not modern or intermediate", c))
+                });
+            },
+            _ => {}
+        }
+        match policy.ca_cert {
+            Some(ref p) if !p.is_file() => {
+                return Err(DeliveryError{
+                    kind: Kind::ConfigValidation,
+                    detail: Some(format!("tls_ca_cert: {} is not a file", p.display()))
+                });
+            },
+            _ => {}
+        }
+        Ok(policy)
+    }
+
+    /// The protocols we refuse to negotiate.
+    pub fn disabled_protocols(&self) -> Vec<&'static str> {
+        let mut disabled = vec!["SSLv2", "SSLv3"];
+        match self.min_version {
+            Some(ref v) if &v[..] == "1.1" => disabled.push("TLSv1.0"),
+            Some(ref v) if &v[..] == "1.2" => {
+                disabled.push("TLSv1.0");
+                disabled.push("TLSv1.1");
+            },
+            _ => {}
+        }
+        disabled
+    }
+
+    /// The OpenSSL cipher list for the cipher policy, if there is one.
+    pub fn cipher_list(&self) -> Option<&'static str> {
+        match self.ciphers {
+            Some(ref c) if &c[..] == "modern" => Some(MODERN_CIPHERS),
+            Some(ref c) if &c[..] == "intermediate" => Some(INTERMEDIATE_CIPHERS),
+            _ => None
+        }
+    }
+
+    /// A short description, for errors.
+    pub fn describe(&self) -> String {
+        format!("TLS {} or newer, {} ciphers",
+                self.min_version.clone().unwrap_or("1.0".to_string()),
+                self.ciphers.clone().unwrap_or("default".to_string()))
+    }
+
+    fn context(&self) -> Result<SslContext, DeliveryError> {
+        let mut ctx = try!(SslContext::new(SslMethod::Sslv23).map_err(|e| self.error(&format!("{:?}", e))));
+        let mut options = SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3;
+        for p in self.disabled_protocols().iter() {
+            match *p {
+                "TLSv1.0" => options = options | SSL_OP_NO_TLSV1,
+                "TLSv1.1" => options = options | SSL_OP_NO_TLSV1_1,
+                _ => {}
+            }
+        }
+        ctx.set_options(options);
+        match self.cipher_list() {
+            Some(list) => try!(ctx.set_cipher_list(list).map_err(|e| self.error(&format!("{:?}", e)))),
+            None => {}
+        }
+        if self.verify_certs {
+            ctx.set_verify(SSL_VERIFY_PEER, None);
+            try!(ctx.set_default_verify_paths().map_err(|e| self.error(&format!("{:?}", e))));
+            match self.ca_cert {
+                Some(ref p) => try!(ctx.set_CA_file(p).map_err(|e| {
+                    self.error(&format!("Unable to load tls_ca_cert {}: {:?}", p.display(), e))
+                })),
+                None => {}
+            }
+        } else {
+            ctx.set_verify(SSL_VERIFY_NONE, None);
+        }
+        Ok(ctx)
+    }
+
+    fn error(&self, why: &str) -> DeliveryError {
+        DeliveryError{
+            kind: Kind::TlsError,
+            detail: Some(format!("{} (we require {}; see tls_min_version and tls_ciphers in cli.toml)",
+                                 why, self.describe()))
+        }
+    }
+
+    /// The error for a failed handshake, given OpenSSL's account of
+    /// it. An untrusted certificate says so, since the fix is to add
+    /// the CA rather than to loosen the policy.
+    fn handshake_error(&self, why: &str) -> DeliveryError {
+        if why.contains("certificate verify failed") {
+            let ca = match self.ca_cert {
+                Some(ref p) => format!("neither the system's CAs nor {}", p.display()),
+                None => "none of the system's CAs".to_string()
+            };
+            return certificate_error(format!("The server's certificate isn't trusted: it is signed by {}. If it comes from an internal CA, set tls_ca_cert in cli.toml to that CA's certificate ({})",
+                                             ca, why));
+        }
+        self.error(&format!("TLS handshake failed: {}", why))
+    }
+
+    /// Start TLS over `stream` to `host` under this policy, sending
+    /// `host` as the server name. A server that can't meet the policy
+    /// fails the handshake, which is `Kind::TlsError`; a certificate we
+    /// don't trust, or one for another host, is `Kind::HttpError`.
+    pub fn start_tls(&self, stream: TcpStream, host: &str) -> Result<TlsStream, DeliveryError> {
+        let ctx = try!(self.context());
+        let ssl = try!(Ssl::new(&ctx).map_err(|e| self.error(&format!("{:?}", e))));
+        try!(ssl.set_hostname(host).map_err(|e| self.error(&format!("Unable to send the server name {}: {:?}", host, e))));
+        let tls = match SslStream::new_from(ssl, TlsSocket(stream)) {
+            Ok(s) => TlsStream(s),
+            Err(e) => return Err(self.handshake_error(&format!("{:?}", e)))
+        };
+        if self.verify_certs {
+            let names = match tls.0.get_peer_certificate() {
+                Some(cert) => certificate_names(&cert),
+                None => Vec::new()
+            };
+            try!(check_host(host, &names));
+        }
+        Ok(tls)
+    }
+}
+
+/// A problem with the server's certificate, as a `Kind::HttpError`.
+fn certificate_error(detail: String) -> DeliveryError {
+    DeliveryError{
+        kind: Kind::HttpError(HttpError::HttpIoError(io::Error::new(io::ErrorKind::Other, detail.clone()))),
+        detail: Some(detail)
+    }
+}
+
+/// The host names `cert` is for: its DNS and IP subject alternative
+/// names, or its subject common name when it has none.
+fn certificate_names(cert: &X509) -> Vec<String> {
+    let mut pem = Vec::new();
+    let sans = match cert.write_pem(&mut pem) {
+        Ok(_) => pem_to_der(&String::from_utf8_lossy(&pem)).map(|der| subject_alt_names(&der)),
+        Err(_) => None
+    };
+    match sans {
+        Some(ref names) if !names.is_empty() => names.clone(),
+        _ => cert.subject_name().text_by_nid(Nid::CN).map(|cn| vec![cn.to_string()]).unwrap_or(Vec::new())
+    }
+}
+
+fn pem_to_der(pem: &str) -> Option<Vec<u8>> {
+    let body: String = pem.lines()
+        .filter(|l| !l.starts_with("-----"))
+        .collect::<Vec<&str>>()
+        .concat();
+    body.from_base64().ok()
+}
+
+/// The length of the DER element whose length starts at `at`, and
+/// where its contents start.
+fn der_length(der: &[u8], at: usize) -> Option<(usize, usize)> {
+    let first = match der.get(at) {
+        Some(b) => *b as usize,
+        None => return None
+    };
+    if first < 0x80 {
+        return Some((first, at + 1));
+    }
+    let count = first & 0x7f;
+    if count == 0 || count > 4 || at + 1 + count > der.len() {
+        return None;
+    }
+    let len = der[at + 1..at + 1 + count].iter().fold(0, |n, b| (n << 8) | *b as usize);
+    Some((len, at + 1 + count))
+}
+
+/// The DNS names and IPv4 addresses in the subject alternative name
+/// extension of the DER certificate `der`.
+pub fn subject_alt_names(der: &[u8]) -> Vec<String> {
+    // The extension's OID, 2.5.29.17
+    let oid = [0x06u8, 0x03, 0x55, 0x1d, 0x11];
+    let mut names = Vec::new();
+    let start = match der.windows(oid.len()).position(|w| w == &oid[..]) {
+        Some(i) => i + oid.len(),
+        None => return names
+    };
+    let mut at = start;
+    // Skip the critical flag, if it is there
+    if der.get(at) == Some(&0x01) {
+        match der_length(der, at + 1) {
+            Some((len, next)) => at = next + len,
+            None => return names
+        }
+    }
+    // The OCTET STRING wrapping a SEQUENCE of GeneralNames
+    if der.get(at) != Some(&0x04) {
+        return names;
+    }
+    at = match der_length(der, at + 1) {
+        Some((_, next)) => next,
+        None => return names
+    };
+    if der.get(at) != Some(&0x30) {
+        return names;
+    }
+    let (seq_len, mut at) = match der_length(der, at + 1) {
+        Some(l) => l,
+        None => return names
+    };
+    let end = at + seq_len;
+    if end > der.len() {
+        return names;
+    }
+    while at < end {
+        let tag = der[at];
+        let (len, next) = match der_length(der, at + 1) {
+            Some(l) => l,
+            None => return names
+        };
+        if next + len > end {
+            return names;
+        }
+        let value = &der[next..next + len];
+        match tag {
+            0x82 => names.push(String::from_utf8_lossy(value).into_owned()),
+            0x87 if len == 4 => names.push(format!("{}.{}.{}.{}", value[0], value[1], value[2], value[3])),
+            _ => {}
+        }
+        at = next + len;
+    }
+    names
+}
+
+/// Whether `pattern`, a name from a certificate, covers `host`. A
+/// leading `*.` stands for exactly one label.
+pub fn host_matches(host: &str, pattern: &str) -> bool {
+    let host = host.trim_right_matches('.');
+    let pattern = pattern.trim_right_matches('.');
+    if pattern.starts_with("*.") {
+        match host.find('.') {
+            Some(i) => i > 0 && host[i..].eq_ignore_ascii_case(&pattern[1..]),
+            None => false
+        }
+    } else {
+        host.eq_ignore_ascii_case(pattern)
+    }
+}
+
+/// Fail with `Kind::HttpError` unless one of `names`, from the
+/// server's certificate, covers `host`.
+pub fn check_host(host: &str, names: &[String]) -> Result<(), DeliveryError> {
+    if names.iter().any(|n| host_matches(host, n)) {
+        return Ok(());
+    }
+    let named = if names.is_empty() { "no host at all".to_string() } else { names.connect(", ") };
+    Err(certificate_error(format!("The server's certificate is for {}, not {}; check the server name in cli.toml",
+                                  named, host)))
+}
+
+/// The socket under a TLS session. Like hyper's own, cloning it
+/// clones the handle, not the connection.
+pub struct TlsSocket(TcpStream);
+
+impl Clone for TlsSocket {
+    fn clone(&self) -> TlsSocket {
+        TlsSocket(self.0.try_clone().unwrap())
+    }
+}
+
+impl Read for TlsSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for TlsSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// A TLS session with the Delivery server, directly or through a
+/// proxy tunnel.
+#[derive(Clone)]
+pub struct TlsStream(SslStream<TlsSocket>);
+
+impl TlsStream {
+    /// The SHA-256 fingerprint of the server's certificate, in hex.
+    pub fn fingerprint(&self) -> Option<String> {
+        self.0.get_peer_certificate()
+            .and_then(|cert| cert.fingerprint(Type::SHA256))
+            .map(|f| f.to_hex())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl NetworkStream for TlsStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.0.get_mut().0.peer_addr()
+    }
+}
+
+/// Hands hyper a connection we have already opened, whatever host it
+/// asks for; `APIClient` makes one for each request.
+pub struct PreparedConnector {
+    stream: TlsStream
+}
+
+impl PreparedConnector {
+    pub fn new(stream: TlsStream) -> PreparedConnector {
+        PreparedConnector { stream: stream }
+    }
+}
+
+impl NetworkConnector for PreparedConnector {
+    type Stream = TlsStream;
+
+    fn connect(&mut self, _host: &str, _port: u16, _scheme: &str) -> io::Result<TlsStream> {
+        Ok(self.stream.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+    use errors::Kind;
+    use std::default::Default;
+    use std::path::PathBuf;
+
+    #[test]
+    fn policy_from_config() {
+        let config = Config::parse_config("tls_min_version = \"1.2\"\ntls_ciphers = \"modern\"").unwrap();
+        let policy = TlsPolicy::from_config(&config).unwrap();
+        assert!(policy.verify_certs);
+        assert_eq!(vec!["SSLv2", "SSLv3", "TLSv1.0", "TLSv1.1"], policy.disabled_protocols());
+        assert!(policy.cipher_list().unwrap().starts_with("ECDHE+AESGCM"));
+        assert_eq!(TlsPolicy::default_policy(), TlsPolicy::from_config(&Config::default()).unwrap());
+    }
+
+    #[test]
+    fn policy_rejects_unknown_settings() {
+        for toml in ["tls_min_version = \"1.3.1\"", "tls_ciphers = \"export\"",
+                     "tls_ca_cert = \"/nonexistent/ca.pem\""].iter() {
+            let config = Config::parse_config(toml).unwrap();
+            match TlsPolicy::from_config(&config) {
+                Err(e) => match e.kind {
+                    Kind::ConfigValidation => {},
+                    _ => panic!("Expected ConfigValidation, got {:?}", e)
+                },
+                Ok(p) => panic!("Expected '{}' to be rejected, got {:?}", toml, p)
+            }
+        }
+    }
+
+    #[test]
+    fn handshake_error_names_the_policy() {
+        // We have no TLS server to test against, so check the error a
+        // server below the minimum gets
+        let policy = TlsPolicy { min_version: Some("1.2".to_string()),
+                                 ..TlsPolicy::default_policy() };
+        let e = policy.handshake_error("SslError(OpenSslErrors([UnknownError { library: \"SSL routines\", function: \"SSL23_GET_SERVER_HELLO\", reason: \"unsupported protocol\" }]))");
+        match e.kind {
+            Kind::TlsError => assert!(e.detail.unwrap().contains("we require TLS 1.2 or newer, default ciphers")),
+            _ => panic!("Expected TlsError, got {:?}", e)
+        }
+    }
+
+    #[test]
+    fn handshake_error_for_untrusted_certificate() {
+        let openssl = "SslError(OpenSslErrors([UnknownError { library: \"SSL routines\", function: \"SSL3_GET_SERVER_CERTIFICATE\", reason: \"certificate verify failed\" }]))";
+        let policy = TlsPolicy { ca_cert: Some(PathBuf::from("/etc/pki/internal-ca.pem")),
+                                 ..TlsPolicy::default_policy() };
+        let e = policy.handshake_error(openssl);
+        match e.kind {
+            Kind::HttpError(_) => {},
+            _ => panic!("Expected HttpError, got {:?}", e)
+        }
+        let detail = e.detail.unwrap();
+        assert!(detail.starts_with("The server's certificate isn't trusted: it is signed by neither the system's CAs nor /etc/pki/internal-ca.pem"));
+        assert!(detail.contains("set tls_ca_cert"));
+        let other = TlsPolicy::default_policy().handshake_error("SslSessionClosed");
+        match other.kind {
+            Kind::TlsError => {},
+            _ => panic!("Expected TlsError, got {:?}", other)
+        }
+        assert!(other.detail.unwrap().starts_with("TLS handshake failed: SslSessionClosed"));
+    }
+
+    #[test]
+    fn subject_alt_names_from_der() {
+        // The SAN extension, marked critical, naming delivery.example.com,
+        // *.example.com and 10.0.0.1
+        let mut der = vec![0x30, 0x37, 0x06, 0x03, 0x55, 0x1d, 0x11, 0x01, 0x01, 0xff,
+                           0x04, 0x2d, 0x30, 0x2b];
+        der.push(0x82);
+        der.push(20);
+        der.extend(b"delivery.example.com".iter().cloned());
+        der.push(0x82);
+        der.push(13);
+        der.extend(b"*.example.com".iter().cloned());
+        der.extend([0x87u8, 4, 10, 0, 0, 1].iter().cloned());
+        assert_eq!(vec!["delivery.example.com".to_string(), "*.example.com".to_string(),
+                        "10.0.0.1".to_string()],
+                   subject_alt_names(&der));
+        assert!(subject_alt_names(&[0x30, 0x00]).is_empty());
+        // Cut short, we stop rather than read past the end
+        assert!(subject_alt_names(&der[..20]).is_empty());
+    }
+
+    #[test]
+    fn host_matches_names_and_wildcards() {
+        assert!(host_matches("delivery.example.com", "Delivery.Example.com"));
+        assert!(host_matches("delivery.example.com", "*.example.com"));
+        assert!(!host_matches("a.delivery.example.com", "*.example.com"));
+        assert!(!host_matches("example.com", "*.example.com"));
+        assert!(!host_matches("evil.com", "delivery.example.com"));
+    }
+
+    #[test]
+    fn check_host_rejects_a_certificate_for_another_host() {
+        let names = vec!["other.example.com".to_string()];
+        assert!(check_host("other.example.com", &names).is_ok());
+        for (host, names) in vec![("delivery.example.com", names.clone()),
+                                  ("delivery.example.com", Vec::new())].into_iter() {
+            match check_host(host, &names) {
+                Err(e) => match e.kind {
+                    Kind::HttpError(_) => assert!(e.detail.unwrap().contains("not delivery.example.com")),
+                    _ => panic!("Expected HttpError, got {:?}", e)
+                },
+                Ok(_) => panic!("Expected {} to be rejected for {:?}", host, names)
+            }
+        }
+    }
+}
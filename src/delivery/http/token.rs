@@ -16,10 +16,22 @@
 //
 
 use errors::{DeliveryError, Kind};
+use config::Config;
 use http::*;
 use hyper::status::StatusCode;
 use rustc_serialize::json;
+use getpass;
+use libc::funcs::posix88::unistd;
+use std::collections::BTreeMap;
+use std::io;
 use std::io::prelude::*;
+use std::path::PathBuf;
+use std::thread;
+use std::time::duration::Duration;
+use time;
+use token::TokenStore;
+use token::device::{self, DeviceCodeStore, PendingDeviceCode};
+use utils::say::{say, sayln};
 
 
 #[derive(RustcEncodable, RustcDecodable, Debug, Clone)]
@@ -28,6 +40,9 @@ pub struct TokenRequest {
     password: String
 }
 
+/// Fields the extra fields of a token request may not replace.
+pub const RESERVED_FIELDS: [&'static str; 2] = ["username", "password"];
+
 impl TokenRequest {
     pub fn payload(user: &str, pass: &str) -> Result<String, DeliveryError> {
         let treq = TokenRequest{  username: String::from_str(user),
@@ -35,34 +50,169 @@ impl TokenRequest {
         let payload = try!(json::encode(&treq));
         Ok(payload)
     }
+
+    /// Like `payload`, with `fields` added alongside the username and
+    /// password. Trying to set either of those is an error.
+    pub fn payload_with_fields(user: &str, pass: &str,
+                               fields: &BTreeMap<String, String>) -> Result<String, DeliveryError> {
+        let mut payload = try!(TokenRequest::payload(user, pass));
+        if fields.is_empty() {
+            return Ok(payload);
+        }
+        // Drop the closing brace and append the extra fields
+        payload.pop();
+        for (k, v) in fields.iter() {
+            if RESERVED_FIELDS.iter().any(|r| *r == &k[..]) {
+                return Err(DeliveryError{
+                    kind: Kind::ConfigValidation,
+                    detail: Some(format!("'{}' can't be set as an extra token request field", k))
+                });
+            }
+            payload.push_str(&format!(",{}:{}", try!(json::encode(k)), try!(json::encode(v))));
+        }
+        payload.push('}');
+        Ok(payload)
+    }
+}
+
+/// How to ask for a token: `endpoint` replaces the usual
+/// `users/{user}/get-token` path, for servers behind a gateway, and is
+/// used verbatim (see `APIClient::endpoint_url`); `fields` are sent
+/// along with the username and password. Both are under the client's
+/// base path when the server isn't mounted at the root. With
+/// `device_endpoint`, tokens come from the device flow there instead
+/// of a password; see `request_with_device_code`.
+#[derive(Debug, Clone, Default)]
+pub struct TokenOptions {
+    pub endpoint: Option<String>,
+    pub fields: BTreeMap<String, String>,
+    pub device_endpoint: Option<String>
 }
 
+impl TokenOptions {
+    /// The `token_endpoint`, `[token_fields]` and
+    /// `device_code_endpoint` from cli.toml.
+    pub fn from_config(config: &Config) -> TokenOptions {
+        TokenOptions {
+            endpoint: config.token_endpoint.clone(),
+            fields: config.token_fields.clone().unwrap_or(BTreeMap::new()),
+            device_endpoint: config.device_code_endpoint.clone()
+        }
+    }
+
+    /// Send `code` as the `mfa_code` field, unless it is empty.
+    pub fn with_mfa(mut self, code: &str) -> TokenOptions {
+        if !code.is_empty() {
+            self.fields.insert("mfa_code".to_string(), code.to_string());
+        }
+        self
+    }
+}
+
+/// A get-token response. Newer servers also say how many seconds the
+/// token is good for in `ttl`; older ones leave it out.
 #[derive(RustcEncodable, RustcDecodable, Debug, Clone)]
 pub struct TokenResponse {
-    token: String
+    pub token: String,
+    pub ttl: Option<i64>
 }
 
 impl TokenResponse {
+    /// The whole response. A `ttl` that is missing, or isn't a whole
+    /// number, is `None`.
+    pub fn parse(response: &str) -> Result<TokenResponse, DeliveryError> {
+        let body = try!(json::Json::from_str(response));
+        let token = try!(fields::require_string(&body, "token"));
+        let ttl = body.find("ttl").and_then(|t| t.as_i64());
+        Ok(TokenResponse{ token: token, ttl: ttl })
+    }
+
     pub fn parse_token(response: &str) -> Result<String, DeliveryError> {
-        let tresponse: TokenResponse = try!(json::decode(response));
-        Ok(tresponse.token)
+        TokenResponse::parse(response).map(|r| r.token)
     }
 }
 
-/// Request an API token for a user from a Delivery server. HTTPS is
-/// used since the specified password will be sent plain.
-pub fn request(server: &str, ent: &str,
-               user: &str, pass: &str) -> Result<String, DeliveryError> {
-    let client = APIClient::new_https(&server, &ent);
-    let payload = try!(TokenRequest::payload(&user, &pass));
-    let path = format!("users/{}/get-token", &user);
-    let mut result = try!(client.post(&path, &payload));
+/// Ask for the password on the terminal, without echoing it. When
+/// stdin isn't a terminal, as in CI, the first line piped in is the
+/// password. Failing either way is `Kind::PasswordPromptFailed`.
+pub fn prompt_password() -> Result<String, DeliveryError> {
+    if unsafe { unistd::isatty(0) } == 1 {
+        getpass::try_read("Delivery password: ")
+    } else {
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        read_password_line(&mut input)
+    }
+}
+
+/// The first line of `input`, without its line ending. Anything else
+/// is kept, since spaces can be part of a password.
+pub fn read_password_line<R: BufRead>(input: &mut R) -> Result<String, DeliveryError> {
+    let mut line = String::new();
+    if try!(input.read_line(&mut line)) == 0 {
+        return Err(DeliveryError{
+            kind: Kind::PasswordPromptFailed,
+            detail: Some("stdin is not a terminal and had no password on it".to_string())
+        });
+    }
+    if line.ends_with("\n") {
+        line.pop();
+        if line.ends_with("\r") {
+            line.pop();
+        }
+    }
+    Ok(line)
+}
+
+/// Check that `user` and `pass` can get a token, then throw the
+/// token away. Nothing is written to the token store.
+pub fn check_with_client(client: &APIClient,
+                         user: &str, pass: &str,
+                         opts: &TokenOptions) -> Result<(), DeliveryError> {
+    try!(request_with_client(client, user, pass, opts));
+    Ok(())
+}
+
+/// Request an API token for `user` and, if `save` is set, write it to
+/// the token store under `home`. Returns the token and where it was
+/// saved; without `save` the store is never opened.
+pub fn fetch_token(client: &APIClient, home: &PathBuf, save: bool,
+                   server: &str, ent: &str, user: &str, pass: &str,
+                   opts: &TokenOptions) -> Result<(String, Option<PathBuf>), DeliveryError> {
+    let token = try!(request_with_client(client, user, pass, opts));
+    if !save {
+        return Ok((token, None))
+    }
+    let mut tstore = try!(TokenStore::from_home_dir(home));
+    try!(tstore.write_token(server, ent, user, &token));
+    Ok((token, Some(tstore.path())))
+}
+
+/// Request an API token for a user from the Delivery server `client`
+/// talks to. The password is sent plain, so that should be over HTTPS.
+pub fn request_with_client(client: &APIClient,
+                           user: &str, pass: &str,
+                           opts: &TokenOptions) -> Result<String, DeliveryError> {
+    request_response_with_client(client, user, pass, opts).map(|r| r.token)
+}
+
+/// Request an API token for a user, keeping the `ttl` the server
+/// sent along with it.
+pub fn request_response_with_client(client: &APIClient,
+                                    user: &str, pass: &str,
+                                    opts: &TokenOptions) -> Result<TokenResponse, DeliveryError> {
+    let payload = try!(TokenRequest::payload_with_fields(&user, &pass, &opts.fields));
+    let mut result = match opts.endpoint {
+        Some(ref e) => try!(client.post_url(&try!(client.endpoint_url(e)), &payload)),
+        None => try!(client.post(&format!("users/{}/get-token", &user), &payload))
+    };
+    // A token minted against a skewed clock looks expired straight away
+    try!(APIClient::check_server_time(&result));
     match result.status {
         StatusCode::Ok => {
             let mut body_string = String::new();
             let _x = try!(result.read_to_string(&mut body_string));
-            let token = try!(TokenResponse::parse_token(&body_string));
-            Ok(token)
+            TokenResponse::parse(&body_string)
         },
         StatusCode::Unauthorized => {
             let msg = "token request returned 401".to_string();
@@ -78,9 +228,176 @@ pub fn request(server: &str, ent: &str,
     }
 }
 
+/// How often to poll for a device token when the server doesn't say.
+pub const DEFAULT_DEVICE_INTERVAL_SECS: u64 = 5;
+
+#[derive(RustcEncodable, Debug)]
+struct DeviceCodeRequest {
+    username: String
+}
+
+#[derive(RustcEncodable, Debug)]
+struct DeviceTokenRequest {
+    username: String,
+    device_code: String
+}
+
+/// A device code response, as the code is good for from `now`.
+pub fn parse_device_code(response: &str, now: i64) -> Result<PendingDeviceCode, DeliveryError> {
+    let body = try!(json::Json::from_str(response));
+    let device_code = try!(fields::require_string(&body, "device_code"));
+    let user_code = try!(fields::require_string(&body, "user_code"));
+    let verification_uri = try!(fields::require_string(&body, "verification_uri"));
+    let expires_in = match try!(fields::require(&body, &["expires_in"]))[0].as_i64() {
+        Some(e) => e,
+        None => return Err(DeliveryError{
+            kind: Kind::ApiResponseMissingField,
+            detail: Some("Expected 'expires_in' in the server's response to be a number".to_string())
+        })
+    };
+    Ok(PendingDeviceCode{
+        device_code: device_code,
+        user_code: user_code,
+        verification_uri: verification_uri,
+        interval: body.find("interval").and_then(|i| i.as_u64()).unwrap_or(DEFAULT_DEVICE_INTERVAL_SECS),
+        expires_at: now + expires_in
+    })
+}
+
+/// Ask `endpoint` for a new device code for `user`.
+pub fn request_device_code(client: &APIClient, user: &str, endpoint: &str,
+                           now: i64) -> Result<PendingDeviceCode, DeliveryError> {
+    let payload = try!(json::encode(&DeviceCodeRequest{ username: user.to_string() }));
+    let mut result = try!(client.post_url(&try!(client.endpoint_url(endpoint)), &payload));
+    let mut body = String::new();
+    try!(result.read_to_string(&mut body));
+    match result.status {
+        StatusCode::Ok => parse_device_code(&body, now),
+        error_code @ _ => Err(DeliveryError{
+            kind: Kind::AuthenticationFailed,
+            detail: Some(format!("device code request returned {}", error_code))
+        })
+    }
+}
+
+/// What one poll for a device token came back with.
+#[derive(Debug, PartialEq)]
+pub enum DevicePoll {
+    Token(String),
+    /// The user hasn't authorized the code yet.
+    Pending,
+    /// As `Pending`, and we are to poll less often.
+    SlowDown
+}
+
+/// Ask once for the token `code` stands for, where a password would
+/// be sent otherwise. A server error is `Kind::ApiError`; any other
+/// answer but a token, `authorization_pending` or `slow_down` is
+/// `Kind::AuthenticationFailed`.
+pub fn poll_device_token(client: &APIClient, user: &str, code: &PendingDeviceCode,
+                         opts: &TokenOptions) -> Result<DevicePoll, DeliveryError> {
+    let payload = try!(json::encode(&DeviceTokenRequest{ username: user.to_string(),
+                                                         device_code: code.device_code.clone() }));
+    let mut result = match opts.endpoint {
+        Some(ref e) => try!(client.post_url(&try!(client.endpoint_url(e)), &payload)),
+        None => try!(client.post(&format!("users/{}/get-token", &user), &payload))
+    };
+    let mut body = String::new();
+    try!(result.read_to_string(&mut body));
+    if result.status == StatusCode::Ok {
+        return TokenResponse::parse_token(&body).map(|t| DevicePoll::Token(t));
+    }
+    if result.status.is_server_error() {
+        return Err(DeliveryError{ kind: Kind::ApiError(result.status, Ok(body)), detail: None });
+    }
+    let error = json::Json::from_str(&body).ok()
+        .and_then(|j| j.find("error").and_then(|e| e.as_string()).map(|e| e.to_string()))
+        .unwrap_or(String::new());
+    match &error[..] {
+        "authorization_pending" => Ok(DevicePoll::Pending),
+        "slow_down" => Ok(DevicePoll::SlowDown),
+        _ => Err(DeliveryError{
+            kind: Kind::AuthenticationFailed,
+            detail: Some(format!("device token request returned {} {}", result.status, error))
+        })
+    }
+}
+
+/// Get a token for `user` with the OAuth device flow at `endpoint`:
+/// tell the user the code to authorize and where, then poll until they
+/// have. The code is kept in `codes` meanwhile, so a run that is
+/// interrupted is picked up by the next one, which polls the same code
+/// until it expires. A code that has expired, been refused, or come
+/// back with a token is discarded.
+pub fn request_with_device_code(client: &APIClient, codes: &mut DeviceCodeStore,
+                                server: &str, ent: &str, user: &str, endpoint: &str,
+                                opts: &TokenOptions) -> Result<String, DeliveryError> {
+    let now = time::get_time().sec;
+    let (code, resumed) = try!(device::resume_or_start(codes, server, ent, user, now, || {
+        request_device_code(client, user, endpoint, now)
+    }));
+    if resumed {
+        say("white", "Still waiting for you to enter code ");
+    } else {
+        say("white", "To authorize the CLI, enter code ");
+    }
+    say("yellow", &code.user_code);
+    say("white", " at ");
+    sayln("magenta", &code.verification_uri);
+    let mut interval = code.interval;
+    loop {
+        if code.is_expired(time::get_time().sec) {
+            try!(codes.discard(server, ent, user));
+            return Err(DeliveryError{
+                kind: Kind::AuthenticationFailed,
+                detail: Some(format!("Code {} expired before it was entered; run again for a new one",
+                                     code.user_code))
+            });
+        }
+        match poll_device_token(client, user, &code, opts) {
+            Ok(DevicePoll::Token(t)) => {
+                try!(codes.discard(server, ent, user));
+                return Ok(t);
+            },
+            Ok(DevicePoll::Pending) => {},
+            Ok(DevicePoll::SlowDown) => interval += DEFAULT_DEVICE_INTERVAL_SECS,
+            Err(e) => {
+                // The server is done with a code it refused; one we
+                // couldn't reach it about is still good next time
+                match e.kind {
+                    Kind::AuthenticationFailed => { try!(codes.discard(server, ent, user)); },
+                    _ => {}
+                }
+                return Err(e);
+            }
+        }
+        thread::sleep(Duration::seconds(interval as i64));
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use errors::Kind;
+    use http::APIClient;
+    use http::mock::{self, MockServer};
+    use std::collections::BTreeMap;
+    use std::io::Cursor;
+    use tempdir::TempDir;
+    use time;
+    use token::TokenStore;
+    use token::device::{DeviceCodeStore, PendingDeviceCode};
+    use utils::path_join_many::PathJoinMany;
+
+    fn pending_code(device_code: &str, expires_at: i64) -> PendingDeviceCode {
+        PendingDeviceCode{
+            device_code: device_code.to_string(),
+            user_code: "WDJB-MJHT".to_string(),
+            verification_uri: "https://delivery.example.com/device".to_string(),
+            interval: 0,
+            expires_at: expires_at
+        }
+    }
 
     #[test]
     fn token_request_payload_test() {
@@ -89,10 +406,264 @@ mod tests {
         assert_eq!(expect, payload.unwrap());
     }
 
+    #[test]
+    fn token_request_payload_with_fields_test() {
+        let opts = TokenOptions::default().with_mfa("123456");
+        let mut fields = opts.fields.clone();
+        fields.insert("client_id".to_string(), "ci-runner".to_string());
+        let payload = TokenRequest::payload_with_fields("alice", "sesame123", &fields);
+        let expect = "{\"username\":\"alice\",\"password\":\"sesame123\",\"client_id\":\"ci-runner\",\"mfa_code\":\"123456\"}";
+        assert_eq!(expect, payload.unwrap());
+        let none = TokenRequest::payload_with_fields("alice", "sesame123", &BTreeMap::new());
+        assert_eq!(TokenRequest::payload("alice", "sesame123").unwrap(), none.unwrap());
+    }
+
+    #[test]
+    fn token_request_payload_reserved_fields_test() {
+        let mut fields = BTreeMap::new();
+        fields.insert("password".to_string(), "hunter2".to_string());
+        match TokenRequest::payload_with_fields("alice", "sesame123", &fields) {
+            Err(e) => match e.kind {
+                Kind::ConfigValidation => {},
+                _ => panic!("Expected ConfigValidation, got {:?}", e)
+            },
+            Ok(p) => panic!("Expected the password not to be overridden, got {}", p)
+        }
+    }
+
     #[test]
     fn token_response_parse_token_test() {
         let response = "{\"token\":\"abc123\"}";
         let token = TokenResponse::parse_token(response).unwrap();
         assert_eq!("abc123", token);
     }
+
+    #[test]
+    fn token_response_parse_ttl_test() {
+        let with_ttl = TokenResponse::parse("{\"token\":\"abc123\",\"ttl\":3600}").unwrap();
+        assert_eq!("abc123", with_ttl.token);
+        assert_eq!(Some(3600), with_ttl.ttl);
+        let without_ttl = TokenResponse::parse("{\"token\":\"abc123\"}").unwrap();
+        assert_eq!("abc123", without_ttl.token);
+        assert_eq!(None, without_ttl.ttl);
+    }
+
+    #[test]
+    fn read_password_line_from_pipe_test() {
+        let mut piped = Cursor::new(b"sesame 123\r\nsomething else\n".to_vec());
+        assert_eq!("sesame 123", read_password_line(&mut piped).unwrap());
+        let mut empty = Cursor::new(Vec::new());
+        match read_password_line(&mut empty) {
+            Err(e) => match e.kind {
+                Kind::PasswordPromptFailed => {},
+                _ => panic!("Expected PasswordPromptFailed, got {:?}", e)
+            },
+            Ok(p) => panic!("Expected no password, got {}", p)
+        }
+    }
+
+    #[test]
+    fn token_response_without_token_test() {
+        match TokenResponse::parse_token("{\"ttl\":3600}") {
+            Err(e) => match e.kind {
+                Kind::ApiResponseMissingField => assert!(e.detail.unwrap().ends_with("token")),
+                _ => panic!("Expected ApiResponseMissingField, got {:?}", e)
+            },
+            Ok(t) => panic!("Expected the missing token to be reported, got {}", t)
+        }
+    }
+
+    #[test]
+    fn check_with_valid_credentials_test() {
+        let tempdir = TempDir::new("home").ok().expect("TempDir failed");
+        let server = MockServer::start(vec![mock::response("200 OK", "{\"token\":\"abc123\"}")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        let (token, saved) = fetch_token(&client, &tempdir.path().to_path_buf(), false,
+                                         &server.addr(), "acme", "alice", "sesame123",
+                                         &TokenOptions::default()).unwrap();
+        assert_eq!("abc123", token);
+        assert!(saved.is_none());
+        assert!(server.requests()[0].starts_with("POST /api/v0/e/acme/users/alice/get-token"));
+        assert!(!tempdir.path().join_many(&[".delivery", "api-tokens"]).exists());
+    }
+
+    #[test]
+    fn fetch_token_saves_under_home_test() {
+        let tempdir = TempDir::new("home").ok().expect("TempDir failed");
+        let server = MockServer::start(vec![mock::response("200 OK", "{\"token\":\"abc123\"}")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        let (_, saved) = fetch_token(&client, &tempdir.path().to_path_buf(), true,
+                                     &server.addr(), "acme", "alice", "sesame123",
+                                     &TokenOptions::default()).unwrap();
+        let tfile = tempdir.path().join_many(&[".delivery", "api-tokens"]);
+        assert_eq!(Some(tfile.clone()), saved);
+        let tstore = TokenStore::from_file(&tfile).unwrap();
+        assert_eq!("abc123", tstore.read_token(&server.addr(), "acme", "alice").unwrap());
+    }
+
+    #[test]
+    fn check_with_invalid_credentials_test() {
+        let server = MockServer::start(vec![mock::response("401 Unauthorized", "")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        match check_with_client(&client, "alice", "wrong", &TokenOptions::default()) {
+            Err(e) => match e.kind {
+                Kind::AuthenticationFailed => {},
+                _ => panic!("Expected AuthenticationFailed, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected bad credentials to fail")
+        }
+    }
+
+    #[test]
+    fn request_with_skewed_server_clock_test() {
+        let server = MockServer::start(vec![mock::response_with_headers(
+            "200 OK", &["Date: Sun, 06 Nov 1994 08:49:37 GMT"], "{\"token\":\"abc123\"}")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        match request_with_client(&client, "alice", "sesame123", &TokenOptions::default()) {
+            Err(e) => match e.kind {
+                Kind::ServerTimeSkew => {},
+                _ => panic!("Expected ServerTimeSkew, got {:?}", e)
+            },
+            Ok(t) => panic!("Expected a skewed clock to fail, got {}", t)
+        }
+    }
+
+    #[test]
+    fn request_with_endpoint_override_test() {
+        let server = MockServer::start(vec![mock::response("200 OK", "{\"token\":\"abc123\"}")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        let opts = TokenOptions{ endpoint: Some("/gateway/auth/token?user=alice".to_string()),
+                                 ..TokenOptions::default() };
+        let token = request_with_client(&client, "alice", "sesame123", &opts).unwrap();
+        assert_eq!("abc123", token);
+        let requests = server.requests();
+        assert!(requests[0].starts_with("POST /gateway/auth/token?user=alice HTTP/1.1"));
+        assert!(requests[0].ends_with("{\"username\":\"alice\",\"password\":\"sesame123\"}"));
+    }
+
+    #[test]
+    fn request_under_base_path_test() {
+        let server = MockServer::start(vec![mock::response("200 OK", "{\"token\":\"abc123\"}")]);
+        let mut client = APIClient::new_http(&server.addr(), "acme");
+        client.set_base_path("/delivery/");
+        let token = request_with_client(&client, "alice", "sesame123", &TokenOptions::default()).unwrap();
+        assert_eq!("abc123", token);
+        assert!(server.requests()[0].starts_with("POST /delivery/api/v0/e/acme/users/alice/get-token HTTP/1.1"));
+    }
+
+    #[test]
+    fn endpoint_url_test() {
+        let client = APIClient::new_https("delivery.example.com", "acme");
+        assert_eq!("https://delivery.example.com/gateway/token", client.endpoint_url("/gateway/token").unwrap());
+        assert_eq!("https://delivery.example.com/gateway/token", client.endpoint_url("gateway/token").unwrap());
+        assert_eq!("https://auth.example.com/token", client.endpoint_url("https://auth.example.com/token").unwrap());
+    }
+
+    #[test]
+    fn plain_http_endpoint_is_refused_test() {
+        let server = MockServer::start(vec![mock::response("200 OK", "{\"token\":\"abc123\"}")]);
+        let client = APIClient::new_https("delivery.example.com", "acme");
+        let opts = TokenOptions{ endpoint: Some(format!("http://{}/token", server.addr())),
+                                 ..TokenOptions::default() };
+        match request_with_client(&client, "alice", "sesame123", &opts) {
+            Err(e) => match e.kind {
+                Kind::ConfigValidation => {},
+                _ => panic!("Expected ConfigValidation, got {:?}", e)
+            },
+            Ok(t) => panic!("Expected a plain http endpoint to be refused, got {}", t)
+        }
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn parse_device_code_test() {
+        let code = parse_device_code("{\"device_code\":\"dc1\",\"user_code\":\"WDJB-MJHT\",\"verification_uri\":\"https://delivery.example.com/device\",\"expires_in\":600}", 1000).unwrap();
+        assert_eq!("dc1", code.device_code);
+        assert_eq!(DEFAULT_DEVICE_INTERVAL_SECS, code.interval);
+        assert_eq!(1600, code.expires_at);
+        match parse_device_code("{\"device_code\":\"dc1\"}", 1000) {
+            Err(e) => match e.kind {
+                Kind::ApiResponseMissingField => {},
+                _ => panic!("Expected ApiResponseMissingField, got {:?}", e)
+            },
+            Ok(c) => panic!("Expected the missing fields to be reported, got {:?}", c)
+        }
+    }
+
+    #[test]
+    fn device_flow_resumes_pending_code_test() {
+        let tempdir = TempDir::new("device-codes").ok().expect("TempDir failed");
+        let path = tempdir.path().join("device-codes.json");
+        let server = MockServer::start(vec![mock::response("400 Bad Request", "{\"error\":\"authorization_pending\"}"),
+                                            mock::response("200 OK", "{\"token\":\"abc123\"}")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        let mut codes = DeviceCodeStore::from_file(&path).unwrap();
+        // An earlier run asked for this code and was interrupted
+        codes.save(&server.addr(), "acme", "alice", &pending_code("pending", time::get_time().sec + 600)).unwrap();
+        let token = request_with_device_code(&client, &mut codes, &server.addr(), "acme", "alice",
+                                             "/device", &TokenOptions::default()).unwrap();
+        assert_eq!("abc123", token);
+        let requests = server.requests();
+        assert_eq!(2, requests.len());
+        for request in requests.iter() {
+            assert!(request.starts_with("POST /api/v0/e/acme/users/alice/get-token"));
+            assert!(request.ends_with("{\"username\":\"alice\",\"device_code\":\"pending\"}"));
+        }
+        let codes = DeviceCodeStore::from_file(&path).unwrap();
+        assert!(codes.lookup(&server.addr(), "acme", "alice").is_none());
+    }
+
+    #[test]
+    fn device_flow_discards_expired_code_test() {
+        let tempdir = TempDir::new("device-codes").ok().expect("TempDir failed");
+        let path = tempdir.path().join("device-codes.json");
+        let server = MockServer::start(vec![
+            mock::response("200 OK", "{\"device_code\":\"fresh\",\"user_code\":\"QPLK-ZTRW\",\"verification_uri\":\"https://delivery.example.com/device\",\"interval\":0,\"expires_in\":600}"),
+            mock::response("200 OK", "{\"token\":\"abc123\"}")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        let mut codes = DeviceCodeStore::from_file(&path).unwrap();
+        codes.save(&server.addr(), "acme", "alice", &pending_code("stale", 1000)).unwrap();
+        let token = request_with_device_code(&client, &mut codes, &server.addr(), "acme", "alice",
+                                             "/device", &TokenOptions::default()).unwrap();
+        assert_eq!("abc123", token);
+        let requests = server.requests();
+        assert!(requests[0].starts_with("POST /device HTTP/1.1"));
+        assert!(requests[1].ends_with("\"device_code\":\"fresh\"}"));
+    }
+
+    #[test]
+    fn device_flow_keeps_code_when_interrupted_test() {
+        let tempdir = TempDir::new("device-codes").ok().expect("TempDir failed");
+        let path = tempdir.path().join("device-codes.json");
+        // The server falls over while the user has yet to authorize
+        let server = MockServer::start(vec![
+            mock::response("200 OK", "{\"device_code\":\"fresh\",\"user_code\":\"QPLK-ZTRW\",\"verification_uri\":\"https://delivery.example.com/device\",\"interval\":0,\"expires_in\":600}"),
+            mock::response("400 Bad Request", "{\"error\":\"authorization_pending\"}"),
+            mock::response("503 Service Unavailable", "")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        let mut codes = DeviceCodeStore::from_file(&path).unwrap();
+        assert!(request_with_device_code(&client, &mut codes, &server.addr(), "acme", "alice",
+                                         "/device", &TokenOptions::default()).is_err());
+        let codes = DeviceCodeStore::from_file(&path).unwrap();
+        assert_eq!("fresh", codes.lookup(&server.addr(), "acme", "alice").unwrap().device_code);
+    }
+
+    #[test]
+    fn device_flow_discards_refused_code_test() {
+        let tempdir = TempDir::new("device-codes").ok().expect("TempDir failed");
+        let path = tempdir.path().join("device-codes.json");
+        let server = MockServer::start(vec![mock::response("400 Bad Request", "{\"error\":\"access_denied\"}")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        let mut codes = DeviceCodeStore::from_file(&path).unwrap();
+        codes.save(&server.addr(), "acme", "alice", &pending_code("pending", time::get_time().sec + 600)).unwrap();
+        match request_with_device_code(&client, &mut codes, &server.addr(), "acme", "alice",
+                                       "/device", &TokenOptions::default()) {
+            Err(e) => match e.kind {
+                Kind::AuthenticationFailed => assert!(e.detail.unwrap().contains("access_denied")),
+                _ => panic!("Expected AuthenticationFailed, got {:?}", e)
+            },
+            Ok(t) => panic!("Expected a refused code to fail, got {}", t)
+        }
+        assert!(DeviceCodeStore::from_file(&path).unwrap().lookup(&server.addr(), "acme", "alice").is_none());
+    }
 }
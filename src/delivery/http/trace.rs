@@ -0,0 +1,166 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Log each request an `APIClient` makes, for debugging a call that
+//! fails: the method and URL, the headers with the token shown as
+//! `***`, and the status that came back. Bodies are never logged, so
+//! the password in a token request can't end up in the output.
+//!
+//! The log goes to stderr, leaving stdout to whatever a command
+//! prints for scripts. Turn it on with `APIClient::set_verbose`, or
+//! for every client by setting `DELIVERY_TRACE_HTTP`.
+//!
+//! The same lines for the most recent requests are also kept in
+//! `~/.delivery/requests.log`, for `delivery support-bundle`.
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use errors::DeliveryError;
+use utils;
+use utils::path_join_many::PathJoinMany;
+
+/// Set (to anything but an empty string) to log every client's
+/// requests.
+pub const TRACE_VAR: &'static str = "DELIVERY_TRACE_HTTP";
+
+/// Where the log lines go.
+#[derive(Debug, Clone)]
+pub enum Sink {
+    Stderr,
+    /// Kept in memory, for tests.
+    Capture(Arc<Mutex<Vec<String>>>)
+}
+
+impl Sink {
+    pub fn write(&self, line: &str) {
+        match *self {
+            Sink::Stderr => {
+                let _ = writeln!(&mut io::stderr(), "{}", line);
+            },
+            Sink::Capture(ref lines) => lines.lock().unwrap().push(line.to_string())
+        }
+    }
+}
+
+/// The sink `DELIVERY_TRACE_HTTP` asks for, if any.
+pub fn from_env() -> Option<Sink> {
+    match env::var(TRACE_VAR) {
+        Ok(ref v) if !v.is_empty() => Some(Sink::Stderr),
+        _ => None
+    }
+}
+
+/// The lines for a request about to be sent. The token header's
+/// value is replaced with `***`.
+pub fn request_lines(method: &str, url: &str, headers: &[(String, String)]) -> Vec<String> {
+    let mut lines = vec![format!("> {} {}", method, url)];
+    for &(ref name, ref value) in headers.iter() {
+        let shown = if name == "chef-delivery-token" { "***" } else { &value[..] };
+        lines.push(format!("> {}: {}", name, shown));
+    }
+    lines
+}
+
+/// The line for the response, or for the request failing.
+pub fn response_line(status: Result<String, String>) -> String {
+    match status {
+        Ok(s) => format!("< {}", s),
+        Err(e) => format!("< failed: {}", e)
+    }
+}
+
+/// How many lines of the request log `trim_log` keeps.
+pub const LOG_LINES: usize = 500;
+
+/// Where clients made with `APIClient::from_config` log their
+/// requests.
+pub fn log_path() -> Option<PathBuf> {
+    utils::home_dir().map(|h| h.join_many(&[".delivery", "requests.log"]))
+}
+
+/// Add `lines` to the end of the request log at `path`. Failing to is
+/// only worth a debug message; the request matters more.
+pub fn append_log(path: &PathBuf, lines: &[String]) {
+    let result = OpenOptions::new().append(true).create(true).open(path)
+        .and_then(|mut f| f.write_all(format!("{}\n", lines.connect("\n")).as_bytes()));
+    match result {
+        Ok(_) => {},
+        Err(e) => debug!("Failed to write the request log {}: {}", path.display(), e)
+    }
+}
+
+/// Cut the request log at `path` down to its last `keep` lines. A log
+/// that isn't there yet is left alone.
+pub fn trim_log(path: &PathBuf, keep: usize) -> Result<(), DeliveryError> {
+    let mut content = String::new();
+    match File::open(path) {
+        Ok(mut f) => { try!(f.read_to_string(&mut content)); },
+        Err(_) => return Ok(())
+    }
+    let lines: Vec<&str> = content.lines_any().collect();
+    if lines.len() <= keep {
+        return Ok(());
+    }
+    let kept = &lines[lines.len() - keep..];
+    let mut f = try!(File::create(path));
+    try!(f.write_all(format!("{}\n", kept.connect("\n")).as_bytes()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn request_lines_redact_token() {
+        let headers = vec![("Content-Type".to_string(), "application/json".to_string()),
+                           ("chef-delivery-user".to_string(), "alice".to_string()),
+                           ("chef-delivery-token".to_string(), "beefbeef".to_string())];
+        let lines = request_lines("GET", "https://delivery/api/v0/e/acme/orgs", &headers);
+        assert_eq!(vec!["> GET https://delivery/api/v0/e/acme/orgs",
+                        "> Content-Type: application/json",
+                        "> chef-delivery-user: alice",
+                        "> chef-delivery-token: ***"], lines);
+    }
+
+    #[test]
+    fn response_line_test() {
+        assert_eq!("< 200 OK", response_line(Ok("200 OK".to_string())));
+        assert_eq!("< failed: connection refused", response_line(Err("connection refused".to_string())));
+    }
+
+    #[test]
+    fn append_and_trim_log() {
+        let tempdir = TempDir::new("trace").ok().expect("TempDir failed");
+        let path = tempdir.path().join("requests.log");
+        for i in 0..5 {
+            append_log(&path, &[format!("> GET /{}", i), "< 200 OK".to_string()]);
+        }
+        trim_log(&path, 3).unwrap();
+        let mut content = String::new();
+        File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!("< 200 OK\n> GET /4\n< 200 OK\n", content);
+    }
+}
@@ -0,0 +1,114 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Compare the version a Delivery server reports with our own.
+//!
+//! Differences in the major version are an error. A different minor
+//! version is probably fine, so we warn and carry on.
+
+use errors::{DeliveryError, Kind};
+use utils::say::sayln;
+
+#[derive(Debug, PartialEq)]
+pub enum Compatibility {
+    Compatible,
+    Warn,
+    Incompatible
+}
+
+/// The version of this CLI, as cargo knows it.
+pub fn cli_version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+fn parse(version: &str) -> Option<(u64, u64)> {
+    let r = regex!(r"^v?(\d+)\.(\d+)");
+    match r.captures(version.trim()) {
+        Some(caps) => {
+            let major = caps.at(1).unwrap().parse::<u64>().ok();
+            let minor = caps.at(2).unwrap().parse::<u64>().ok();
+            match (major, minor) {
+                (Some(ma), Some(mi)) => Some((ma, mi)),
+                _ => None
+            }
+        },
+        None => None
+    }
+}
+
+/// Classify the skew between the `cli` and `server` versions. A
+/// version we can't parse is treated as incompatible.
+pub fn classify(cli: &str, server: &str) -> Compatibility {
+    match (parse(cli), parse(server)) {
+        (Some((cli_major, cli_minor)), Some((server_major, server_minor))) => {
+            if cli_major != server_major {
+                Compatibility::Incompatible
+            } else if cli_minor != server_minor {
+                Compatibility::Warn
+            } else {
+                Compatibility::Compatible
+            }
+        },
+        _ => Compatibility::Incompatible
+    }
+}
+
+/// Check the `server` version against ours. Minor skew is reported
+/// with a `Kind::ServerVersionMismatchWarning` on the terminal, and a
+/// major difference returns `Kind::ServerVersionMismatch`.
+pub fn negotiate(server: &str) -> Result<Compatibility, DeliveryError> {
+    let detail = format!("cli: {}, server: {}", cli_version(), server);
+    match classify(cli_version(), server) {
+        Compatibility::Incompatible => {
+            Err(DeliveryError{ kind: Kind::ServerVersionMismatch,
+                               detail: Some(detail) })
+        },
+        Compatibility::Warn => {
+            let warning = DeliveryError{
+                kind: Kind::ServerVersionMismatchWarning,
+                detail: Some(detail)
+            };
+            sayln("yellow", &format!("{}", warning));
+            Ok(Compatibility::Warn)
+        },
+        Compatibility::Compatible => Ok(Compatibility::Compatible)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_compatible_test() {
+        assert_eq!(Compatibility::Compatible, classify("1.2.0", "1.2.7"));
+        assert_eq!(Compatibility::Compatible, classify("1.2.0", "v1.2"));
+    }
+
+    #[test]
+    fn classify_warn_test() {
+        assert_eq!(Compatibility::Warn, classify("1.2.0", "1.3.0"));
+        assert_eq!(Compatibility::Warn, classify("1.4.1", "1.3.9"));
+    }
+
+    #[test]
+    fn classify_incompatible_test() {
+        assert_eq!(Compatibility::Incompatible, classify("1.2.0", "2.0.0"));
+        assert_eq!(Compatibility::Incompatible, classify("1.2.0", "banana"));
+    }
+}
@@ -0,0 +1,115 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Tell a chat service (Slack, Teams, ..) about a review once it has
+//! been pushed, by POSTing to the `post_review_webhook` in cli.toml.
+
+use errors::DeliveryError;
+use http::APIClient;
+use rustc_serialize::json;
+use utils::say::sayln;
+
+/// What we send the webhook about a review.
+#[derive(RustcEncodable, Clone, Debug, PartialEq)]
+pub struct ReviewNotice {
+    pub change_url: String,
+    pub title: String,
+    pub author: String
+}
+
+impl ReviewNotice {
+    /// The JSON body for the webhook.
+    pub fn payload(&self) -> Result<String, DeliveryError> {
+        Ok(try!(json::encode(self)))
+    }
+}
+
+/// POST `notice` to `webhook`, failing on an unsuccessful status.
+/// `client` should carry no auth: the webhook is not the Delivery
+/// server, and has no business seeing our token.
+pub fn notify(client: &APIClient, webhook: &str, notice: &ReviewNotice) -> Result<(), DeliveryError> {
+    let payload = try!(notice.payload());
+    let res = try!(client.post_url(webhook, &payload));
+    try!(client.check_response(res));
+    Ok(())
+}
+
+/// Call `webhook`, if there is one, about a `review` that went
+/// through; a review that failed isn't announced. A webhook that
+/// fails is only a warning. Returns whether the webhook took it.
+pub fn after_review(client: &APIClient, webhook: Option<&str>,
+                    review: &Result<ReviewNotice, DeliveryError>) -> bool {
+    let (url, notice) = match (webhook, review) {
+        (Some(u), &Ok(ref n)) if !u.is_empty() => (u, n),
+        _ => return false
+    };
+    match notify(client, url, notice) {
+        Ok(_) => true,
+        Err(e) => {
+            sayln("yellow", &format!("Failed to call post_review_webhook: {}", e));
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::{DeliveryError, Kind};
+    use http::{mock, APIClient};
+
+    fn notice() -> ReviewNotice {
+        ReviewNotice{
+            change_url: "https://delivery.example.com/e/acme/#/organizations/eng/projects/fun/changes/c0ffee".to_string(),
+            title: "Add a frobnicator".to_string(),
+            author: "alice".to_string()
+        }
+    }
+
+    #[test]
+    fn after_review_posts_payload() {
+        let server = mock::MockServer::start(vec![mock::response("200 OK", "ok")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        let url = format!("http://{}/hooks/review", server.addr());
+        assert!(after_review(&client, Some(&url), &Ok(notice())));
+        let request = server.requests()[0].clone();
+        assert!(request.starts_with("POST /hooks/review HTTP/1.1\r\n"));
+        assert!(!request.contains("chef-delivery-token"));
+        assert!(request.ends_with("{\"change_url\":\"https://delivery.example.com/e/acme/#/organizations/eng/projects/fun/changes/c0ffee\",\"title\":\"Add a frobnicator\",\"author\":\"alice\"}"));
+    }
+
+    #[test]
+    fn after_review_skips_failed_review() {
+        let server = mock::MockServer::start(vec![mock::response("200 OK", "ok")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        let url = format!("http://{}/hooks/review", server.addr());
+        let failed = Err(DeliveryError{ kind: Kind::PushFailed, detail: None });
+        assert!(!after_review(&client, Some(&url), &failed));
+        assert!(!after_review(&client, None, &Ok(notice())));
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn after_review_warns_on_webhook_failure() {
+        let server = mock::MockServer::start(vec![mock::response("500 Internal Server Error", "oops")]);
+        let client = APIClient::new_http(&server.addr(), "acme");
+        let url = format!("http://{}/hooks/review", server.addr());
+        assert!(!after_review(&client, Some(&url), &Ok(notice())));
+        assert_eq!(1, server.requests().len());
+    }
+}
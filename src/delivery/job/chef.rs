@@ -0,0 +1,144 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use errors::{DeliveryError, Kind};
+use rustc_serialize::json::Json;
+use std::error;
+use std::process::Command;
+
+/// Pull the version out of `chef-client --version`, which prints
+/// something like `Chef: 12.4.1`. Returns the dotted numbers, or None
+/// if no version is there.
+pub fn parse_version(output: &str) -> Option<Vec<u64>> {
+    for word in output.split(|c: char| c.is_whitespace()).filter(|s| !s.is_empty()) {
+        let parts: Vec<Option<u64>> = word.split('.').map(|p| p.parse::<u64>().ok()).collect();
+        if parts.len() > 1 && parts.iter().all(|p| p.is_some()) {
+            return Some(parts.into_iter().map(|p| p.unwrap()).collect());
+        }
+    }
+    None
+}
+
+/// Compare two versions part by part; missing parts count as zero, so
+/// `12.4` is the same as `12.4.0`.
+pub fn at_least(found: &[u64], required: &[u64]) -> bool {
+    let len = if found.len() > required.len() { found.len() } else { required.len() };
+    for i in 0..len {
+        let f = found.get(i).map(|v| *v).unwrap_or(0);
+        let r = required.get(i).map(|v| *v).unwrap_or(0);
+        if f != r {
+            return f > r;
+        }
+    }
+    true
+}
+
+/// Return `Kind::ChefClientVersionUnsupported` if the version in
+/// `output` is older than `required`.
+pub fn check_version(output: &str, required: &str) -> Result<(), DeliveryError> {
+    let want = try!(parse_version(required).ok_or(DeliveryError{
+        kind: Kind::ConfigValidation,
+        detail: Some(format!("'{}' is not a chef-client version", required))
+    }));
+    let found = try!(parse_version(output).ok_or(DeliveryError{
+        kind: Kind::ChefClientVersionUnsupported,
+        detail: Some(format!("Could not find a version in chef-client output: {}", output.trim()))
+    }));
+    if at_least(&found, &want) {
+        Ok(())
+    } else {
+        Err(DeliveryError{
+            kind: Kind::ChefClientVersionUnsupported,
+            detail: Some(format!("Found chef-client {}, but this job requires {} or later",
+                                 version_string(&found), version_string(&want)))
+        })
+    }
+}
+
+/// The `min_chef_client_version` from the project config, if any.
+pub fn required_version(config: &Json) -> Option<String> {
+    config.find("min_chef_client_version").and_then(|v| v.as_string()).map(|v| v.to_string())
+}
+
+/// Run `chef-client --version` and check it against the project's
+/// `min_chef_client_version`. Does nothing when no minimum is set.
+pub fn preflight(config: &Json) -> Result<(), DeliveryError> {
+    let required = match required_version(config) {
+        Some(r) => r,
+        None => return Ok(())
+    };
+    let output = match Command::new("chef-client").arg("--version").output() {
+        Ok(o) => o,
+        Err(e) => return Err(DeliveryError{ kind: Kind::FailedToExecute, detail: Some(format!("failed to execute chef-client: {}", error::Error::description(&e)))})
+    };
+    check_version(&String::from_utf8_lossy(&output.stdout), &required)
+}
+
+fn version_string(version: &[u64]) -> String {
+    let parts: Vec<String> = version.iter().map(|v| v.to_string()).collect();
+    parts.connect(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Kind;
+    use rustc_serialize::json::Json;
+
+    #[test]
+    fn parse_version_test() {
+        assert_eq!(Some(vec![12, 4, 1]), parse_version("Chef: 12.4.1\n"));
+        assert_eq!(Some(vec![12, 0, 3]), parse_version("Chef: 12.0.3"));
+        assert_eq!(Some(vec![12, 4]), parse_version("12.4"));
+        assert_eq!(None, parse_version("Chef: unknown"));
+    }
+
+    #[test]
+    fn at_least_test() {
+        assert!(at_least(&[12, 4, 1], &[12, 4, 1]));
+        assert!(at_least(&[12, 10, 0], &[12, 4]));
+        assert!(at_least(&[12, 4], &[12, 4, 0]));
+        assert!(!at_least(&[11, 18, 12], &[12, 0]));
+        assert!(!at_least(&[12, 4], &[12, 4, 1]));
+    }
+
+    #[test]
+    fn check_version_new_enough() {
+        assert!(check_version("Chef: 12.4.1\n", "12.0.0").is_ok());
+    }
+
+    #[test]
+    fn check_version_too_old() {
+        match check_version("Chef: 11.18.12\n", "12.0") {
+            Err(e) => match e.kind {
+                Kind::ChefClientVersionUnsupported => {
+                    assert_eq!("Found chef-client 11.18.12, but this job requires 12.0 or later", e.detail.unwrap());
+                },
+                _ => panic!("Expected ChefClientVersionUnsupported, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected chef-client 11 to be too old")
+        }
+    }
+
+    #[test]
+    fn required_version_test() {
+        let json = Json::from_str(r#"{"version":"1","min_chef_client_version":"12.2.0"}"#).unwrap();
+        assert_eq!(Some("12.2.0".to_string()), required_version(&json));
+        let json = Json::from_str(r#"{"version":"1"}"#).unwrap();
+        assert_eq!(None, required_version(&json));
+    }
+}
@@ -15,16 +15,720 @@
 // limitations under the License.
 //
 
-use rustc_serialize::json::{Json};
-use errors::{DeliveryError};
+use rustc_serialize::json::{Json, JsonEvent, Parser, StackElement};
+use config::Config;
+use errors::{DeliveryError, Kind};
+use std::cmp::min;
+use std::collections::{BTreeMap, HashSet};
 use std::io::prelude::*;
 use std::fs::File;
 use std::path::PathBuf;
+use utils::say::sayln;
+use job::remote_config;
 
 pub fn load_config(file: &PathBuf) -> Result<Json, DeliveryError> {
     let mut config_file = try!(File::open(file));
     let mut config_json = String::new();
     try!(config_file.read_to_string(&mut config_json));
+    try!(check_config_format(&config_json));
+    try!(check_duplicate_keys(&config_json));
     let data = try!(Json::from_str(&config_json));
     Ok(data)
 }
+
+/// What format `body` looks to be in: `"JSON"` for an object, or our
+/// best guess at what else it might be (`"YAML"`, `"TOML"`), or None
+/// if we can't tell.
+pub fn config_format(body: &str) -> Option<&'static str> {
+    let body = body.trim_left();
+    if body.starts_with("{") {
+        return Some("JSON");
+    }
+    let first = body.lines_any().map(|l| l.trim())
+        .find(|l| !l.is_empty() && !l.starts_with("#"))
+        .unwrap_or("");
+    if first == "---" || first.starts_with("- ") || regex!(r"^[\w.-]+:(\s|$)").is_match(first) {
+        Some("YAML")
+    } else if regex!(r"^\[[\w.-]+\]$").is_match(first) || regex!(r"^[\w.-]+\s*=").is_match(first) {
+        Some("TOML")
+    } else {
+        None
+    }
+}
+
+/// Return `Kind::UnsupportedConfigFormat` unless `body` is a JSON
+/// object, saying what it looks like instead. Whether the JSON then
+/// parses is left to the parser.
+pub fn check_config_format(body: &str) -> Result<(), DeliveryError> {
+    let looks_like = match config_format(body) {
+        Some("JSON") => return Ok(()),
+        Some(f) => format!("This looks like {}; ", f),
+        None => String::new()
+    };
+    Err(DeliveryError{
+        kind: Kind::UnsupportedConfigFormat,
+        detail: Some(format!("{}.delivery/config.json must be a JSON object, like {{\"version\": \"2\", \"build_cookbook\": {{..}}}}",
+                             looks_like))
+    })
+}
+
+/// `Json::from_str` quietly keeps the last of any repeated keys, which
+/// hides mistakes in the config. Walk the document first and return
+/// `Kind::DuplicateConfigKey` if any object repeats a key. Syntax
+/// errors are left for the real parse to report.
+pub fn check_duplicate_keys(json: &str) -> Result<(), DeliveryError> {
+    let mut parser = Parser::new(json.chars());
+    let mut seen: Vec<HashSet<String>> = Vec::new();
+    loop {
+        let event = match parser.next() {
+            Some(e) => e,
+            None => break
+        };
+        match event {
+            JsonEvent::Error(_) => break,
+            JsonEvent::ObjectEnd => { seen.pop(); continue; },
+            JsonEvent::ArrayEnd => continue,
+            _ => {}
+        }
+        let key = match parser.stack().top() {
+            Some(StackElement::Key(k)) => Some(k.to_string()),
+            _ => None
+        };
+        match (key, seen.last_mut()) {
+            (Some(k), Some(keys)) => {
+                if !keys.insert(k.clone()) {
+                    return Err(DeliveryError{
+                        kind: Kind::DuplicateConfigKey,
+                        detail: Some(format!("Duplicate key '{}'", k))
+                    });
+                }
+            },
+            _ => {}
+        }
+        match event {
+            JsonEvent::ObjectStart => seen.push(HashSet::new()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Load the config, then apply `patch` to it as a JSON merge patch
+/// (RFC 7386). An empty `patch` leaves the config untouched. Any
+/// `config_url` is fetched with the client settings in `cli_config`.
+pub fn load_config_with_patch(file: &PathBuf, patch: &str,
+                              cli_config: &Config) -> Result<Json, DeliveryError> {
+    let config = try!(load_config(file));
+    let config = try!(remote_config::resolve(&config, remote_config::cache().as_ref(), cli_config));
+    let patched = try!(apply_patch(&config, patch));
+    try!(validate_build_cookbook(&patched));
+    try!(check_build_cookbook_version(&patched));
+    try!(config_version(&patched));
+    let strict = patched.find("strict").and_then(|s| s.as_boolean()).unwrap_or(false);
+    for warning in try!(check_unknown_fields(&patched, strict)).iter() {
+        sayln("yellow", warning);
+    }
+    Ok(patched)
+}
+
+/// The top-level keys we expect in `.delivery/config.json`.
+pub const KNOWN_KEYS: [&'static str; 11] = ["version", "build_cookbook", "skip_phases",
+                                            "build_nodes", "build_attributes", "dependencies",
+                                            "delivery-truck", "min_chef_client_version",
+                                            "strict", "config_url", "config_url_timeout"];
+
+/// Look for top-level keys we don't know, which are most likely typos.
+/// With `strict` (`"strict": true` in the config) the first is
+/// `Kind::ConfigSchemaUnknownField`; otherwise each comes back as a
+/// warning. Either way we suggest the closest known key.
+pub fn check_unknown_fields(config: &Json, strict: bool) -> Result<Vec<String>, DeliveryError> {
+    let obj = match config.as_object() {
+        Some(o) => o,
+        None => return Ok(Vec::new())
+    };
+    let mut warnings = Vec::new();
+    for key in obj.keys() {
+        if KNOWN_KEYS.iter().any(|k| *k == &key[..]) {
+            continue;
+        }
+        let msg = match nearest_key(key) {
+            Some(k) => format!("Unknown config key '{}'; did you mean '{}'?", key, k),
+            None => format!("Unknown config key '{}'", key)
+        };
+        if strict {
+            return Err(DeliveryError{ kind: Kind::ConfigSchemaUnknownField, detail: Some(msg) });
+        }
+        warnings.push(msg);
+    }
+    Ok(warnings)
+}
+
+/// The known key closest to `key`, if any is close enough to be a
+/// plausible typo.
+pub fn nearest_key(key: &str) -> Option<&'static str> {
+    let mut best: Option<(usize, &'static str)> = None;
+    for k in KNOWN_KEYS.iter() {
+        let d = edit_distance(key, k);
+        match best {
+            Some((bd, _)) if bd <= d => {},
+            _ => best = Some((d, *k))
+        }
+    }
+    match best {
+        Some((d, k)) if d <= 3 => Some(k),
+        _ => None
+    }
+}
+
+/// Levenshtein distance between `a` and `b`.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            let substitute = prev[j] + cost;
+            let delete = prev[j + 1] + 1;
+            let insert = cur[j] + 1;
+            cur.push(min(substitute, min(delete, insert)));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// The config `version`, which may be written as a number (`2`) or a
+/// string (`"2"`). Anything that isn't a whole, non-negative number is
+/// `Kind::InvalidJsonField`. A config without a version gives `None`.
+pub fn config_version(config: &Json) -> Result<Option<u64>, DeliveryError> {
+    let value = match config.find("version") {
+        Some(v) => v,
+        None => return Ok(None)
+    };
+    let version = match *value {
+        Json::U64(n) => Some(n),
+        Json::I64(n) if n >= 0 => Some(n as u64),
+        Json::String(ref s) => s.trim().parse::<u64>().ok(),
+        _ => None
+    };
+    match version {
+        Some(v) => Ok(Some(v)),
+        None => Err(DeliveryError{
+            kind: Kind::InvalidJsonField,
+            detail: Some(format!("'version' must be a whole number, not {}", value))
+        })
+    }
+}
+
+/// The keys that say where a `build_cookbook` comes from. Only one
+/// may be given.
+pub const BUILD_COOKBOOK_SOURCES: [&'static str; 5] = ["path", "git", "supermarket", "enterprise", "server"];
+
+/// Return `Kind::BuildCookbookSourceConflict`, naming the keys, if the
+/// `build_cookbook` object gives more than one source.
+pub fn check_build_cookbook_source(config: &Json) -> Result<(), DeliveryError> {
+    let build_cookbook = match config.find("build_cookbook") {
+        Some(b) if b.is_object() => b,
+        _ => return Ok(())
+    };
+    let found: Vec<&str> = BUILD_COOKBOOK_SOURCES.iter()
+        .filter(|k| build_cookbook.find(k).is_some())
+        .map(|k| *k)
+        .collect();
+    if found.len() > 1 {
+        return Err(DeliveryError{
+            kind: Kind::BuildCookbookSourceConflict,
+            detail: Some(format!("Pick one of: {}", found.connect(", ")))
+        });
+    }
+    Ok(())
+}
+
+/// Where a build cookbook comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BuildCookbookSource {
+    /// A directory in the project, relative to its root.
+    Local(String),
+    /// A git repository, checked out at `branch`.
+    Git { url: String, branch: String },
+    /// The Supermarket.
+    Supermarket,
+    /// The Chef Server.
+    ChefServer,
+    /// A project on the Delivery server.
+    Delivery { enterprise: String, organization: String }
+}
+
+/// The `build_cookbook` from `.delivery/config.json`, once it has
+/// been checked by `validate_build_cookbook`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BuildCookbook {
+    pub name: String,
+    pub source: BuildCookbookSource
+}
+
+/// Check the `build_cookbook` in `config` before any work is done
+/// with it. It is either a string, naming a cookbook on the Chef
+/// Server or (with a `/`) giving a path in the project, or an object
+/// with a `name` and exactly one of `BUILD_COOKBOOK_SOURCES`. The
+/// field that is missing, of the wrong type or in conflict is named
+/// in the error's detail.
+pub fn validate_build_cookbook(config: &Json) -> Result<BuildCookbook, DeliveryError> {
+    let build_cookbook = try!(config.find("build_cookbook").ok_or(DeliveryError{
+        kind: Kind::NoBuildCookbook,
+        detail: Some("Add a 'build_cookbook' to .delivery/config.json".to_string())
+    }));
+    match *build_cookbook {
+        Json::String(ref s) => return build_cookbook_from_string(s),
+        Json::Object(_) => {},
+        _ => return Err(DeliveryError{
+            kind: Kind::NoValidBuildCookbook,
+            detail: Some(format!("'build_cookbook' must be a string or an object, not {}", build_cookbook))
+        })
+    }
+    let name = match build_cookbook.find("name") {
+        Some(&Json::String(ref n)) if !n.is_empty() => n.to_string(),
+        Some(&Json::String(_)) | None => return Err(DeliveryError{
+            kind: Kind::MissingBuildCookbookName,
+            detail: Some("'build_cookbook' has no 'name'".to_string())
+        }),
+        Some(_) => return Err(DeliveryError{
+            kind: Kind::ExpectedJsonString,
+            detail: Some("'build_cookbook.name' must be a string".to_string())
+        })
+    };
+    try!(check_build_cookbook_source(config));
+    let source = match BUILD_COOKBOOK_SOURCES.iter().find(|k| build_cookbook.find(k).is_some()) {
+        Some(&"path") => BuildCookbookSource::Local(try!(string_field(build_cookbook, "path"))),
+        Some(&"git") => BuildCookbookSource::Git{
+            url: try!(string_field(build_cookbook, "git")),
+            branch: match build_cookbook.find("branch") {
+                Some(_) => try!(string_field(build_cookbook, "branch")),
+                None => "master".to_string()
+            }
+        },
+        Some(&"supermarket") => {
+            try!(string_field(build_cookbook, "supermarket"));
+            BuildCookbookSource::Supermarket
+        },
+        Some(&"enterprise") => BuildCookbookSource::Delivery{
+            enterprise: try!(string_field(build_cookbook, "enterprise")),
+            organization: try!(string_field(build_cookbook, "organization"))
+        },
+        Some(&"server") => {
+            try!(string_field(build_cookbook, "server"));
+            BuildCookbookSource::ChefServer
+        },
+        _ => return Err(DeliveryError{
+            kind: Kind::NoValidBuildCookbook,
+            detail: Some(format!("'build_cookbook' needs one of: {}", BUILD_COOKBOOK_SOURCES.connect(", ")))
+        })
+    };
+    Ok(BuildCookbook{ name: name, source: source })
+}
+
+fn build_cookbook_from_string(value: &str) -> Result<BuildCookbook, DeliveryError> {
+    let trimmed = value.trim_right_matches('/');
+    let name = trimmed.split('/').last().unwrap_or("");
+    if name.is_empty() {
+        return Err(DeliveryError{
+            kind: Kind::MissingBuildCookbookName,
+            detail: Some(format!("'build_cookbook' \"{}\" doesn't name a cookbook", value))
+        });
+    }
+    let source = if trimmed.contains("/") {
+        BuildCookbookSource::Local(trimmed.to_string())
+    } else {
+        BuildCookbookSource::ChefServer
+    };
+    Ok(BuildCookbook{ name: name.to_string(), source: source })
+}
+
+/// The string at `build_cookbook.<key>`, which must be there.
+fn string_field(build_cookbook: &Json, key: &str) -> Result<String, DeliveryError> {
+    match build_cookbook.find(key) {
+        Some(&Json::String(ref v)) => Ok(v.to_string()),
+        Some(_) => Err(DeliveryError{
+            kind: Kind::ExpectedJsonString,
+            detail: Some(format!("'build_cookbook.{}' must be a string", key))
+        }),
+        None => Err(DeliveryError{
+            kind: Kind::MissingBuildCookbookField,
+            detail: Some(format!("'build_cookbook.{}' is missing", key))
+        })
+    }
+}
+
+/// The operators a `build_cookbook` version constraint may start with.
+pub const VERSION_OPERATORS: [&'static str; 7] = [">=", "<=", "~>", "!=", "=", ">", "<"];
+
+/// Return `Kind::InvalidBuildCookbookVersion`, with the bad value, if
+/// the `build_cookbook` has a `version` that isn't a cookbook version
+/// (`1`, `1.2` or `1.2.3`), optionally after a constraint operator
+/// (`~> 1.2`). A supermarket build cookbook is downloaded and cached
+/// by its version, so it can't have an operator.
+pub fn check_build_cookbook_version(config: &Json) -> Result<(), DeliveryError> {
+    let value = match config.find_path(&["build_cookbook", "version"]) {
+        Some(v) => v,
+        None => return Ok(())
+    };
+    let supermarket = config.find_path(&["build_cookbook", "supermarket"]).is_some();
+    let detail = match value.as_string() {
+        Some(v) if supermarket && !is_exact_version(v) && is_version_constraint(v) =>
+            format!("'{}' is a constraint; a supermarket build_cookbook needs an exact version like 1.2.3", v),
+        Some(v) if is_version_constraint(v) => return Ok(()),
+        Some(v) => format!("'{}' is not a version like 1.2.3 or a constraint like ~> 1.2", v),
+        None => format!("'{}' is not a version like 1.2.3 or a constraint like ~> 1.2", value)
+    };
+    Err(DeliveryError{
+        kind: Kind::InvalidBuildCookbookVersion,
+        detail: Some(detail)
+    })
+}
+
+/// Whether `version` is a cookbook version with no operator.
+pub fn is_exact_version(version: &str) -> bool {
+    let version = version.trim();
+    is_version_constraint(version) && !VERSION_OPERATORS.iter().any(|op| version.starts_with(*op))
+}
+
+/// Whether `constraint` is an optional operator and a version of one
+/// to three dot-separated numbers.
+pub fn is_version_constraint(constraint: &str) -> bool {
+    let constraint = constraint.trim();
+    let version = match VERSION_OPERATORS.iter().find(|op| constraint.starts_with(**op)) {
+        Some(op) => constraint[op.len()..].trim_left(),
+        None => constraint
+    };
+    let parts: Vec<&str> = version.split('.').collect();
+    parts.len() <= 3 &&
+        parts.iter().all(|p| !p.is_empty() && p.chars().all(|c| c.is_digit(10)))
+}
+
+pub fn apply_patch(config: &Json, patch: &str) -> Result<Json, DeliveryError> {
+    if patch.is_empty() {
+        return Ok(config.clone());
+    }
+    let patch_json = match Json::from_str(patch) {
+        Ok(j) => j,
+        Err(e) => return Err(DeliveryError{
+            kind: Kind::JsonParseError,
+            detail: Some(format!("Invalid --config-patch: {:?}", e))
+        })
+    };
+    Ok(merge_patch(config, &patch_json))
+}
+
+/// Merge `patch` into `target`. Objects are merged key by key, a
+/// `null` removes the key, and anything else replaces the target
+/// value outright.
+pub fn merge_patch(target: &Json, patch: &Json) -> Json {
+    match *patch {
+        Json::Object(ref patch_obj) => {
+            let mut result = match *target {
+                Json::Object(ref target_obj) => target_obj.clone(),
+                _ => BTreeMap::new()
+            };
+            for (key, value) in patch_obj.iter() {
+                if value.is_null() {
+                    result.remove(key);
+                } else {
+                    let merged = match result.get(key) {
+                        Some(existing) => merge_patch(existing, value),
+                        None => merge_patch(&Json::Null, value)
+                    };
+                    result.insert(key.clone(), merged);
+                }
+            }
+            Json::Object(result)
+        },
+        _ => patch.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Kind;
+    use rustc_serialize::json::Json;
+
+    fn config() -> Json {
+        Json::from_str(r#"{"version":"2","build_cookbook":{"name":"delivery_test","path":"cookbooks/delivery_test"},"skip_phases":["smoke"]}"#).unwrap()
+    }
+
+    #[test]
+    fn apply_patch_updates_field() {
+        let patched = apply_patch(&config(), r#"{"build_cookbook":{"name":"other"}}"#).unwrap();
+        assert_eq!("other", patched.find_path(&["build_cookbook", "name"]).unwrap().as_string().unwrap());
+        assert_eq!("cookbooks/delivery_test", patched.find_path(&["build_cookbook", "path"]).unwrap().as_string().unwrap());
+    }
+
+    #[test]
+    fn apply_patch_adds_field() {
+        let patched = apply_patch(&config(), r#"{"build_cookbook":{"branch":"master"}}"#).unwrap();
+        assert_eq!("master", patched.find_path(&["build_cookbook", "branch"]).unwrap().as_string().unwrap());
+    }
+
+    #[test]
+    fn apply_patch_removes_field_with_null() {
+        let patched = apply_patch(&config(), r#"{"build_cookbook":{"path":null},"skip_phases":null}"#).unwrap();
+        assert!(patched.find_path(&["build_cookbook", "path"]).is_none());
+        assert!(patched.find("skip_phases").is_none());
+        assert!(patched.find_path(&["build_cookbook", "name"]).is_some());
+    }
+
+    #[test]
+    fn apply_patch_rejects_invalid_json() {
+        match apply_patch(&config(), "{not json") {
+            Err(e) => match e.kind {
+                Kind::JsonParseError => {},
+                _ => panic!("Expected JsonParseError, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected an invalid patch to fail")
+        }
+    }
+
+    #[test]
+    fn check_duplicate_keys_test() {
+        let json = r#"{
+            "version": "2",
+            "build_cookbook": {"name": "one", "path": "cookbooks/one"},
+            "build_cookbook": {"name": "two", "path": "cookbooks/two"}
+        }"#;
+        match check_duplicate_keys(json) {
+            Err(e) => match e.kind {
+                Kind::DuplicateConfigKey => assert_eq!("Duplicate key 'build_cookbook'", e.detail.unwrap()),
+                _ => panic!("Expected DuplicateConfigKey, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected the duplicate key to be found")
+        }
+    }
+
+    #[test]
+    fn check_duplicate_keys_allows_same_key_in_different_objects() {
+        let json = r#"{"a": {"name": "one"}, "b": {"name": "two"}, "c": [{"name": 1}, {"name": 2}]}"#;
+        assert!(check_duplicate_keys(json).is_ok());
+    }
+
+    #[test]
+    fn check_build_cookbook_source_single() {
+        assert!(check_build_cookbook_source(&config()).is_ok());
+    }
+
+    #[test]
+    fn check_build_cookbook_source_conflict() {
+        let json = Json::from_str(r#"{"build_cookbook":{"name":"delivery_test","supermarket":"true","git":"https://github.com/chef/delivery_test.git"}}"#).unwrap();
+        match check_build_cookbook_source(&json) {
+            Err(e) => match e.kind {
+                Kind::BuildCookbookSourceConflict => assert_eq!("Pick one of: git, supermarket", e.detail.unwrap()),
+                _ => panic!("Expected BuildCookbookSourceConflict, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected two sources to conflict")
+        }
+    }
+
+    #[test]
+    fn edit_distance_test() {
+        assert_eq!(0, edit_distance("version", "version"));
+        assert_eq!(1, edit_distance("build_cookbok", "build_cookbook"));
+        assert_eq!(3, edit_distance("kitten", "sitting"));
+    }
+
+    #[test]
+    fn check_unknown_fields_lenient() {
+        let json = Json::from_str(r#"{"version":"2","build_cookbok":{"name":"delivery_test"}}"#).unwrap();
+        assert_eq!(vec!["Unknown config key 'build_cookbok'; did you mean 'build_cookbook'?".to_string()],
+                   check_unknown_fields(&json, false).unwrap());
+        assert!(check_unknown_fields(&config(), false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn check_unknown_fields_strict() {
+        let json = Json::from_str(r#"{"version":"2","strict":true,"build_cookbok":{"name":"delivery_test"}}"#).unwrap();
+        match check_unknown_fields(&json, true) {
+            Err(e) => match e.kind {
+                Kind::ConfigSchemaUnknownField => {
+                    assert_eq!("Unknown config key 'build_cookbok'; did you mean 'build_cookbook'?", e.detail.unwrap());
+                },
+                _ => panic!("Expected ConfigSchemaUnknownField, got {:?}", e)
+            },
+            Ok(w) => panic!("Expected strict mode to fail, got {:?}", w)
+        }
+    }
+
+    #[test]
+    fn nearest_key_too_far() {
+        assert_eq!(None, nearest_key("my_cookbook_settings"));
+    }
+
+    #[test]
+    fn config_version_integer() {
+        let json = Json::from_str(r#"{"version":2}"#).unwrap();
+        assert_eq!(Some(2), config_version(&json).unwrap());
+    }
+
+    #[test]
+    fn config_version_string_number() {
+        assert_eq!(Some(2), config_version(&config()).unwrap());
+        let json = Json::from_str(r#"{"build_cookbook":"delivery_test"}"#).unwrap();
+        assert_eq!(None, config_version(&json).unwrap());
+    }
+
+    #[test]
+    fn config_version_invalid() {
+        for bad in &[r#"{"version":"two"}"#, r#"{"version":2.5}"#,
+                     r#"{"version":-1}"#, r#"{"version":[2]}"#] {
+            match config_version(&Json::from_str(bad).unwrap()) {
+                Err(e) => match e.kind {
+                    Kind::InvalidJsonField => {},
+                    _ => panic!("Expected InvalidJsonField, got {:?}", e)
+                },
+                Ok(v) => panic!("Expected {} to be rejected, got {:?}", bad, v)
+            }
+        }
+    }
+
+    #[test]
+    fn check_build_cookbook_version_valid() {
+        for good in &["1", "1.2", "1.2.3", "= 1.2.3", "~> 1.2", ">=0.3.0", "< 2", "!= 1.0.1"] {
+            assert!(is_version_constraint(good), "Expected '{}' to be valid", good);
+        }
+        let json = Json::from_str(r#"{"build_cookbook":{"name":"delivery-truck","git":"https://github.com/chef-cookbooks/delivery-truck.git","version":"~> 1.2"}}"#).unwrap();
+        assert!(check_build_cookbook_version(&json).is_ok());
+        let json = Json::from_str(r#"{"build_cookbook":{"name":"delivery-truck","supermarket":"true","version":"1.2.3"}}"#).unwrap();
+        assert!(check_build_cookbook_version(&json).is_ok());
+        assert!(check_build_cookbook_version(&config()).is_ok());
+    }
+
+    #[test]
+    fn check_build_cookbook_version_supermarket_constraint() {
+        let json = Json::from_str(r#"{"build_cookbook":{"name":"delivery-truck","supermarket":"true","version":"~> 1.2"}}"#).unwrap();
+        match check_build_cookbook_version(&json) {
+            Err(e) => match e.kind {
+                Kind::InvalidBuildCookbookVersion => assert_eq!(
+                    "'~> 1.2' is a constraint; a supermarket build_cookbook needs an exact version like 1.2.3",
+                    e.detail.unwrap()),
+                _ => panic!("Expected InvalidBuildCookbookVersion, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected the constraint to be rejected")
+        }
+        assert!(is_exact_version("1.2.3"));
+        assert!(!is_exact_version("= 1.2.3"));
+    }
+
+    #[test]
+    fn check_build_cookbook_version_invalid() {
+        for bad in &["", "latest", "1.2.3.4", "1..2", "v1.2", "1.2.x", "~>", ">> 1.2", "1.2-beta"] {
+            assert!(!is_version_constraint(bad), "Expected '{}' to be invalid", bad);
+        }
+        for bad in &[r#"{"build_cookbook":{"name":"delivery-truck","version":"1.x"}}"#,
+                     r#"{"build_cookbook":{"name":"delivery-truck","version":1.2}}"#] {
+            match check_build_cookbook_version(&Json::from_str(bad).unwrap()) {
+                Err(e) => match e.kind {
+                    Kind::InvalidBuildCookbookVersion => {},
+                    _ => panic!("Expected InvalidBuildCookbookVersion, got {:?}", e)
+                },
+                Ok(_) => panic!("Expected {} to be rejected", bad)
+            }
+        }
+        let json = Json::from_str(r#"{"build_cookbook":{"version":"1.x"}}"#).unwrap();
+        assert_eq!("'1.x' is not a version like 1.2.3 or a constraint like ~> 1.2",
+                   check_build_cookbook_version(&json).unwrap_err().detail.unwrap());
+    }
+
+    fn build_cookbook_error(json: &str) -> (String, String) {
+        match validate_build_cookbook(&Json::from_str(json).unwrap()) {
+            Err(e) => (e.kind.name(), e.detail.unwrap()),
+            Ok(b) => panic!("Expected {} to be invalid, got {:?}", json, b)
+        }
+    }
+
+    #[test]
+    fn validate_build_cookbook_valid() {
+        let bc = |json: &str| validate_build_cookbook(&Json::from_str(json).unwrap()).unwrap();
+        assert_eq!(BuildCookbook{ name: "delivery_test".to_string(),
+                                  source: BuildCookbookSource::Local("cookbooks/delivery_test".to_string()) },
+                   validate_build_cookbook(&config()).unwrap());
+        assert_eq!(BuildCookbookSource::Git{ url: "https://github.com/chef/truck.git".to_string(), branch: "master".to_string() },
+                   bc(r#"{"build_cookbook":{"name":"truck","git":"https://github.com/chef/truck.git"}}"#).source);
+        assert_eq!(BuildCookbookSource::Git{ url: "../truck".to_string(), branch: "stable".to_string() },
+                   bc(r#"{"build_cookbook":{"name":"truck","git":"../truck","branch":"stable"}}"#).source);
+        assert_eq!(BuildCookbookSource::Supermarket,
+                   bc(r#"{"build_cookbook":{"name":"httpd","supermarket":"true","version":"1.0.0"}}"#).source);
+        assert_eq!(BuildCookbookSource::ChefServer,
+                   bc(r#"{"build_cookbook":{"name":"httpd","server":"true"}}"#).source);
+        assert_eq!(BuildCookbookSource::Delivery{ enterprise: "marvel".to_string(), organization: "x-men".to_string() },
+                   bc(r#"{"build_cookbook":{"name":"cerebro","enterprise":"marvel","organization":"x-men"}}"#).source);
+        assert_eq!(BuildCookbook{ name: "httpd".to_string(), source: BuildCookbookSource::ChefServer },
+                   bc(r#"{"build_cookbook":"httpd"}"#));
+        assert_eq!(BuildCookbook{ name: "delivery_test".to_string(),
+                                  source: BuildCookbookSource::Local("cookbooks/delivery_test".to_string()) },
+                   bc(r#"{"build_cookbook":"cookbooks/delivery_test/"}"#));
+    }
+
+    #[test]
+    fn validate_build_cookbook_missing() {
+        assert_eq!(("NoBuildCookbook".to_string(), "Add a 'build_cookbook' to .delivery/config.json".to_string()),
+                   build_cookbook_error(r#"{"version":"2"}"#));
+        assert_eq!(("MissingBuildCookbookName".to_string(), "'build_cookbook' has no 'name'".to_string()),
+                   build_cookbook_error(r#"{"build_cookbook":{"path":"cookbooks/delivery_test"}}"#));
+        assert_eq!(("MissingBuildCookbookName".to_string(), "'build_cookbook' \"/\" doesn't name a cookbook".to_string()),
+                   build_cookbook_error(r#"{"build_cookbook":"/"}"#));
+        assert_eq!(("NoValidBuildCookbook".to_string(), "'build_cookbook' needs one of: path, git, supermarket, enterprise, server".to_string()),
+                   build_cookbook_error(r#"{"build_cookbook":{"name":"delivery_test"}}"#));
+        assert_eq!(("MissingBuildCookbookField".to_string(), "'build_cookbook.organization' is missing".to_string()),
+                   build_cookbook_error(r#"{"build_cookbook":{"name":"cerebro","enterprise":"marvel"}}"#));
+    }
+
+    #[test]
+    fn validate_build_cookbook_wrong_types() {
+        assert_eq!(("NoValidBuildCookbook".to_string(), "'build_cookbook' must be a string or an object, not [\"truck\"]".to_string()),
+                   build_cookbook_error(r#"{"build_cookbook":["truck"]}"#));
+        assert_eq!(("ExpectedJsonString".to_string(), "'build_cookbook.name' must be a string".to_string()),
+                   build_cookbook_error(r#"{"build_cookbook":{"name":7,"path":"cookbooks/seven"}}"#));
+        assert_eq!(("ExpectedJsonString".to_string(), "'build_cookbook.branch' must be a string".to_string()),
+                   build_cookbook_error(r#"{"build_cookbook":{"name":"truck","git":"../truck","branch":true}}"#));
+        assert_eq!(("ExpectedJsonString".to_string(), "'build_cookbook.supermarket' must be a string".to_string()),
+                   build_cookbook_error(r#"{"build_cookbook":{"name":"httpd","supermarket":true}}"#));
+    }
+
+    #[test]
+    fn validate_build_cookbook_conflicting_sources() {
+        assert_eq!(("BuildCookbookSourceConflict".to_string(), "Pick one of: git, supermarket".to_string()),
+                   build_cookbook_error(r#"{"build_cookbook":{"name":"truck","git":"../truck","supermarket":"true"}}"#));
+    }
+
+    #[test]
+    fn check_config_format_json() {
+        assert_eq!(Some("JSON"), config_format("\n  {\"version\": \"2\"}"));
+        assert!(check_config_format(r#"{"version":"2","build_cookbook":"delivery_test"}"#).is_ok());
+        // Broken JSON is still JSON; the parser says what is wrong
+        assert!(check_config_format("{\"version\": ").is_ok());
+    }
+
+    #[test]
+    fn check_config_format_yaml_in_json_file() {
+        let yaml = "# saved from the wiki\n---\nversion: 2\nbuild_cookbook:\n  name: delivery_test\n";
+        assert_eq!(Some("YAML"), config_format(yaml));
+        assert_eq!(Some("YAML"), config_format("version: 2\n"));
+        match check_config_format(yaml) {
+            Err(e) => match e.kind {
+                Kind::UnsupportedConfigFormat => {
+                    assert_eq!("This looks like YAML; .delivery/config.json must be a JSON object, like {\"version\": \"2\", \"build_cookbook\": {..}}",
+                               e.detail.unwrap());
+                },
+                _ => panic!("Expected UnsupportedConfigFormat, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected YAML to be refused")
+        }
+    }
+
+    #[test]
+    fn check_config_format_other() {
+        assert_eq!(Some("TOML"), config_format("[build_cookbook]\nname = \"delivery_test\"\n"));
+        assert_eq!(None, config_format("\u{7f}ELF"));
+        assert!(check_config_format("[\"version\"]").is_err());
+        assert!(check_config_format("").is_err());
+    }
+}
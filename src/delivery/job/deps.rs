@@ -0,0 +1,144 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Read cookbook dependencies from `metadata.rb` and make sure they
+//! don't loop back on themselves.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use errors::{DeliveryError, Kind};
+
+pub type DependencyGraph = BTreeMap<String, Vec<String>>;
+
+/// The cookbooks named by `depends` lines in a `metadata.rb`.
+pub fn parse_depends(metadata: &str) -> Vec<String> {
+    let r = regex!(r#"^\s*depends\s+['"]([^'"]+)['"]"#);
+    metadata.lines_any()
+        .filter_map(|line| r.captures(line).and_then(|caps| caps.at(1)))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// Build the graph for every cookbook under `cookbooks_dir` that has
+/// a `metadata.rb`, keyed on the directory name.
+pub fn dependency_graph(cookbooks_dir: &PathBuf) -> Result<DependencyGraph, DeliveryError> {
+    let mut graph = BTreeMap::new();
+    for entry in try!(fs::read_dir(cookbooks_dir)) {
+        let path = try!(entry).path();
+        let metadata_path = path.join("metadata.rb");
+        if !metadata_path.is_file() {
+            continue;
+        }
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(n) => n.to_string(),
+            None => continue
+        };
+        let mut metadata = String::new();
+        let mut f = try!(File::open(&metadata_path));
+        try!(f.read_to_string(&mut metadata));
+        graph.insert(name, parse_depends(&metadata));
+    }
+    Ok(graph)
+}
+
+/// Walk the dependencies of `root`, returning
+/// `Kind::BuildCookbookCircularDependency` with the path of the first
+/// cycle found, e.g. `a -> b -> a`.
+pub fn check_cycles(graph: &DependencyGraph, root: &str) -> Result<(), DeliveryError> {
+    let mut path: Vec<String> = Vec::new();
+    let mut done: Vec<String> = Vec::new();
+    visit(graph, root, &mut path, &mut done)
+}
+
+fn visit(graph: &DependencyGraph, name: &str,
+         path: &mut Vec<String>, done: &mut Vec<String>) -> Result<(), DeliveryError> {
+    if path.iter().any(|p| p == name) {
+        let start = path.iter().position(|p| p == name).unwrap();
+        let mut cycle: Vec<String> = path[start..].to_vec();
+        cycle.push(name.to_string());
+        return Err(DeliveryError{ kind: Kind::BuildCookbookCircularDependency,
+                                  detail: Some(cycle.connect(" -> ")) });
+    }
+    if done.iter().any(|d| d == name) {
+        return Ok(());
+    }
+    path.push(name.to_string());
+    match graph.get(name) {
+        Some(deps) => {
+            for dep in deps.iter() {
+                try!(visit(graph, dep, path, done));
+            }
+        },
+        None => {}
+    }
+    path.pop();
+    done.push(name.to_string());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Kind;
+    use std::collections::BTreeMap;
+
+    fn graph(edges: &[(&str, &[&str])]) -> DependencyGraph {
+        let mut g = BTreeMap::new();
+        for &(name, deps) in edges.iter() {
+            g.insert(name.to_string(), deps.iter().map(|d| d.to_string()).collect());
+        }
+        g
+    }
+
+    #[test]
+    fn parse_depends_test() {
+        let metadata = "name 'delivery_test'
+version '0.1.0'
+depends 'build-essential'
+depends \"delivery-truck\", '~> 1.0'
+# depends 'commented'";
+        assert_eq!(vec!["build-essential".to_string(), "delivery-truck".to_string()],
+                   parse_depends(metadata));
+    }
+
+    #[test]
+    fn check_cycles_without_cycle() {
+        let g = graph(&[("build", &["truck", "sugar"]),
+                        ("truck", &["sugar"]),
+                        ("sugar", &[])]);
+        assert!(check_cycles(&g, "build").is_ok());
+    }
+
+    #[test]
+    fn check_cycles_with_cycle() {
+        let g = graph(&[("build", &["truck"]),
+                        ("truck", &["sugar"]),
+                        ("sugar", &["truck"])]);
+        match check_cycles(&g, "build") {
+            Err(e) => match e.kind {
+                Kind::BuildCookbookCircularDependency => {
+                    assert_eq!("truck -> sugar -> truck", e.detail.unwrap());
+                },
+                _ => panic!("Expected BuildCookbookCircularDependency, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected a cycle")
+        }
+    }
+}
@@ -19,3 +19,8 @@ pub mod workspace;
 pub mod change;
 pub mod dna;
 pub mod config;
+pub mod deps;
+pub mod chef;
+pub mod remote_config;
+pub mod supermarket;
+pub mod phase_env;
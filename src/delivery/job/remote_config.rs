@@ -0,0 +1,304 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! A `build_cookbook` definition hosted at a URL.
+//!
+//! When `.delivery/config.json` has a `config_url`, we fetch it and
+//! use the JSON there as the `build_cookbook`, with anything the local
+//! `build_cookbook` says merged over the top. Fetched configs are
+//! cached for an hour, and a fetch that takes longer than
+//! `config_url_timeout` seconds (30 by default) is abandoned.
+//!
+//! A fetched config can have a `config_url` of its own, which is
+//! fetched in turn and sits beneath it, in the same way. A chain of
+//! these that comes back on itself is `Kind::ConfigCircularInclude`.
+
+use config::Config;
+use errors::{DeliveryError, Kind};
+use http::APIClient;
+use job::config::merge_patch;
+use rustc_serialize::json::Json;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::time::duration::Duration;
+use tempdir::TempDir;
+use utils::artifact_cache::ArtifactCache;
+use utils::path_join_many::PathJoinMany;
+use utils;
+
+pub const DEFAULT_FETCH_TIMEOUT_SECS: u64 = 30;
+
+/// Fetched configs are reused for an hour.
+pub const CACHE_TTL_SECS: i64 = 60 * 60;
+
+/// The cache for fetched configs, `~/.delivery/cache/config`.
+pub fn cache() -> Option<ArtifactCache> {
+    utils::home_dir().map(|h| {
+        let mut cache = ArtifactCache::new(&h.join_many(&[".delivery", "cache", "config"]));
+        cache.ttl_secs = CACHE_TTL_SECS;
+        cache
+    })
+}
+
+/// Download `url` with `client` into `dest`. Anything but a
+/// successful response is `Kind::DownloadFailed`, apart from a host
+/// outside `allowed_servers` or a lack of disk space.
+pub fn fetch(client: &APIClient, url: &str, dest: &PathBuf) -> Result<(), DeliveryError> {
+    match client.download(url, dest) {
+        Ok(_) => Ok(()),
+        Err(e) => match e.kind {
+            Kind::ServerNotAllowed | Kind::InsufficientDiskSpace => Err(e),
+            _ => Err(DeliveryError{
+                kind: Kind::DownloadFailed,
+                detail: Some(format!("{}: {}", url, e))
+            })
+        }
+    }
+}
+
+/// The build cookbook definition in a fetched config: its
+/// `build_cookbook` if it has one, or else the whole document.
+pub fn remote_build_cookbook(body: &str, url: &str) -> Result<Json, DeliveryError> {
+    let json = match Json::from_str(body) {
+        Ok(j) => j,
+        Err(e) => return Err(DeliveryError{
+            kind: Kind::DownloadFailed,
+            detail: Some(format!("{}: not a JSON config: {}", url, e))
+        })
+    };
+    let build_cookbook = match json.find("build_cookbook") {
+        Some(b) => b.clone(),
+        None => json
+    };
+    if build_cookbook.is_object() {
+        Ok(build_cookbook)
+    } else {
+        Err(DeliveryError{
+            kind: Kind::DownloadFailed,
+            detail: Some(format!("{}: expected a build_cookbook object", url))
+        })
+    }
+}
+
+/// `config` with its `build_cookbook` filled in from `config_url`, if
+/// it has one; otherwise `config` as it is. The fetch goes through an
+/// `APIClient` made from the cli.toml `cli_config`, so it has the same
+/// proxy, TLS and `allowed_servers` settings as our API requests.
+pub fn resolve(config: &Json, cache: Option<&ArtifactCache>,
+               cli_config: &Config) -> Result<Json, DeliveryError> {
+    let url = match config.find("config_url").and_then(|u| u.as_string()) {
+        Some(u) => u.to_string(),
+        None => return Ok(config.clone())
+    };
+    let timeout = config.find("config_url_timeout")
+        .and_then(|t| t.as_u64())
+        .unwrap_or(DEFAULT_FETCH_TIMEOUT_SECS);
+    let client = try!(APIClient::from_config(cli_config))
+        .with_timeout(Duration::seconds(timeout as i64));
+    let remote = try!(resolve_includes(&url, &mut Vec::new(),
+                                       &mut |u: &str| fetch_body(&client, u, cache)));
+    let build_cookbook = match config.find("build_cookbook") {
+        Some(local) => merge_patch(&remote, local),
+        None => remote
+    };
+    let mut obj = config.as_object().map(|o| o.clone()).unwrap_or(BTreeMap::new());
+    obj.insert("build_cookbook".to_string(), build_cookbook);
+    Ok(Json::Object(obj))
+}
+
+/// The body at `url`, from `cache` if it is fresh there.
+fn fetch_body(client: &APIClient, url: &str,
+              cache: Option<&ArtifactCache>) -> Result<String, DeliveryError> {
+    let tempdir = try!(TempDir::new("delivery-config"));
+    let dest = tempdir.path().join("config.json");
+    match cache {
+        Some(c) => {
+            let key = ArtifactCache::key("config_url", url, "", "");
+            try!(c.fetch(&key, &dest, |p| fetch(client, url, p)));
+        },
+        None => try!(fetch(client, url, &dest))
+    }
+    let mut body = String::new();
+    try!(try!(File::open(&dest)).read_to_string(&mut body));
+    Ok(body)
+}
+
+/// The build cookbook at `url`, merged over whatever its own
+/// `config_url` includes. `seen` holds the URLs that led here, so we
+/// can tell when an include comes back around.
+fn resolve_includes<F>(url: &str, seen: &mut Vec<String>,
+                       fetch_body: &mut F) -> Result<Json, DeliveryError>
+    where F: FnMut(&str) -> Result<String, DeliveryError> {
+    match seen.iter().position(|u| &u[..] == url) {
+        Some(start) => {
+            let mut cycle: Vec<String> = seen[start..].to_vec();
+            cycle.push(url.to_string());
+            return Err(DeliveryError{
+                kind: Kind::ConfigCircularInclude,
+                detail: Some(format!("config_url includes loop: {}", cycle.connect(" -> ")))
+            });
+        },
+        None => seen.push(url.to_string())
+    }
+    let body = try!(fetch_body(url));
+    let mut build_cookbook = try!(remote_build_cookbook(&body, url));
+    let include = Json::from_str(&body).ok()
+        .and_then(|j| j.find("config_url").and_then(|u| u.as_string()).map(|u| u.to_string()));
+    // When the whole document is the build cookbook, the include
+    // settings aren't part of it
+    if let Json::Object(ref mut obj) = build_cookbook {
+        obj.remove("config_url");
+        obj.remove("config_url_timeout");
+    }
+    match include {
+        Some(next) => {
+            let base = try!(resolve_includes(&next, seen, fetch_body));
+            Ok(merge_patch(&base, &build_cookbook))
+        },
+        None => Ok(build_cookbook)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::resolve_includes;
+    use config::Config;
+    use errors::{DeliveryError, Kind};
+    use http::mock::{self, MockServer};
+    use rustc_serialize::json::Json;
+    use std::collections::BTreeMap;
+    use tempdir::TempDir;
+    use utils::artifact_cache::ArtifactCache;
+
+    fn cli_config() -> Config {
+        Config::parse_config("server = \"delivery.example.com\"\nenterprise = \"acme\"").unwrap()
+    }
+
+    fn config_for(server: &MockServer) -> Json {
+        Json::from_str(&format!(r#"{{"version":"2","config_url":"http://{}/build_cookbook.json","build_cookbook":{{"branch":"stable"}}}}"#,
+                                server.addr())).unwrap()
+    }
+
+    #[test]
+    fn resolve_fetches_and_merges() {
+        let body = r#"{"build_cookbook":{"name":"delivery-truck","git":"https://github.com/chef-cookbooks/delivery-truck.git","branch":"master"}}"#;
+        let server = MockServer::start(vec![mock::response("200 OK", body)]);
+        let resolved = resolve(&config_for(&server), None, &cli_config()).unwrap();
+        let bc = resolved.find("build_cookbook").unwrap();
+        assert_eq!("delivery-truck", bc.find("name").unwrap().as_string().unwrap());
+        // The local build_cookbook wins
+        assert_eq!("stable", bc.find("branch").unwrap().as_string().unwrap());
+        assert!(server.requests()[0].starts_with("GET /build_cookbook.json"));
+    }
+
+    #[test]
+    fn resolve_uses_cache() {
+        let tempdir = TempDir::new("config-cache").ok().expect("TempDir failed");
+        let cache = ArtifactCache::new(&tempdir.path().to_path_buf());
+        let server = MockServer::start(vec![mock::response("200 OK", r#"{"name":"delivery-truck"}"#)]);
+        let config = config_for(&server);
+        resolve(&config, Some(&cache), &cli_config()).unwrap();
+        // The server only answers once, so this must come from the cache
+        let resolved = resolve(&config, Some(&cache), &cli_config()).unwrap();
+        assert_eq!("delivery-truck", resolved.find_path(&["build_cookbook", "name"]).unwrap().as_string().unwrap());
+        assert_eq!(1, server.requests().len());
+    }
+
+    #[test]
+    fn resolve_failed_fetch() {
+        let server = MockServer::start(vec![mock::response("404 Not Found", "{}")]);
+        match resolve(&config_for(&server), None, &cli_config()) {
+            Err(e) => match e.kind {
+                Kind::DownloadFailed => assert!(e.detail.unwrap().contains("404")),
+                _ => panic!("Expected DownloadFailed, got {:?}", e)
+            },
+            Ok(j) => panic!("Expected the fetch to fail, got {}", j)
+        }
+    }
+
+    #[test]
+    fn resolve_refuses_servers_not_allowed() {
+        let server = MockServer::start(vec![mock::response("200 OK", "{}")]);
+        let cli = Config::parse_config("server = \"delivery.example.com\"\nenterprise = \"acme\"\nallowed_servers = [\"delivery.example.com\"]").unwrap();
+        match resolve(&config_for(&server), None, &cli) {
+            Err(e) => match e.kind {
+                Kind::ServerNotAllowed => {},
+                _ => panic!("Expected ServerNotAllowed, got {:?}", e)
+            },
+            Ok(j) => panic!("Expected the fetch to be refused, got {}", j)
+        }
+        assert_eq!(0, server.requests().len());
+    }
+
+    #[test]
+    fn resolve_without_config_url() {
+        let config = Json::from_str(r#"{"version":"2","build_cookbook":{"name":"local"}}"#).unwrap();
+        assert_eq!(config, resolve(&config, None, &cli_config()).unwrap());
+    }
+
+    #[test]
+    fn remote_build_cookbook_must_be_object() {
+        assert!(remote_build_cookbook(r#"{"build_cookbook":"delivery-truck"}"#, "http://x").is_err());
+        assert!(remote_build_cookbook("not json", "http://x").is_err());
+    }
+
+    fn fetch_from(docs: &BTreeMap<String, String>, url: &str) -> Result<String, DeliveryError> {
+        match docs.get(url) {
+            Some(d) => Ok(d.clone()),
+            None => Err(DeliveryError{ kind: Kind::DownloadFailed, detail: Some(url.to_string()) })
+        }
+    }
+
+    #[test]
+    fn resolve_includes_linear_chain() {
+        let mut docs = BTreeMap::new();
+        docs.insert("http://a/config.json".to_string(),
+                    r#"{"config_url":"http://b/config.json","build_cookbook":{"branch":"stable"}}"#.to_string());
+        docs.insert("http://b/config.json".to_string(),
+                    r#"{"config_url":"http://c/config.json","name":"delivery-truck"}"#.to_string());
+        docs.insert("http://c/config.json".to_string(),
+                    r#"{"name":"base","git":"https://github.com/chef-cookbooks/delivery-truck.git","branch":"master"}"#.to_string());
+        let bc = resolve_includes("http://a/config.json", &mut Vec::new(),
+                                  &mut |u: &str| fetch_from(&docs, u)).unwrap();
+        assert_eq!("delivery-truck", bc.find("name").unwrap().as_string().unwrap());
+        assert_eq!("stable", bc.find("branch").unwrap().as_string().unwrap());
+        assert!(bc.find("git").is_some());
+        assert!(bc.find("config_url").is_none());
+    }
+
+    #[test]
+    fn resolve_includes_cycle() {
+        let mut docs = BTreeMap::new();
+        docs.insert("http://a/config.json".to_string(),
+                    r#"{"config_url":"http://b/config.json","name":"a"}"#.to_string());
+        docs.insert("http://b/config.json".to_string(),
+                    r#"{"config_url":"http://a/config.json","name":"b"}"#.to_string());
+        match resolve_includes("http://a/config.json", &mut Vec::new(), &mut |u: &str| fetch_from(&docs, u)) {
+            Err(e) => match e.kind {
+                Kind::ConfigCircularInclude => assert_eq!(
+                    "config_url includes loop: http://a/config.json -> http://b/config.json -> http://a/config.json",
+                    e.detail.unwrap()),
+                _ => panic!("Expected ConfigCircularInclude, got {:?}", e)
+            },
+            Ok(j) => panic!("Expected the cycle to be reported, got {}", j)
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Download cookbooks from a Supermarket with `knife`, a few at a time.
+
+use errors::{DeliveryError, Kind};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+use utils;
+
+/// How many downloads `download_all` runs at once by default.
+pub const DOWNLOAD_WORKERS: usize = 4;
+
+/// A cookbook to download: its name, and the version to pin, if any.
+#[derive(Clone, Debug)]
+pub struct CookbookRef {
+    pub name: String,
+    pub version: Option<String>
+}
+
+impl CookbookRef {
+    pub fn new(name: &str, version: Option<&str>) -> CookbookRef {
+        CookbookRef{ name: name.to_string(), version: version.map(|v| v.to_string()) }
+    }
+}
+
+/// Run `knife cookbook site download` from `root`, writing the
+/// cookbook to `dest`. The download is cut off once it passes
+/// `max_size` bytes, which is `Kind::CookbookTooLarge`.
+pub fn knife_download(root: &PathBuf, name: &str, version: Option<&str>, dest: &PathBuf,
+                      max_size: u64) -> Result<(), DeliveryError> {
+    let mut command = size_limited("knife", max_size);
+    command.arg("cookbook").arg("site").arg("download").arg(name);
+    match version {
+        Some(v) => { command.arg(v); },
+        None => {}
+    }
+    let result = try!(command.arg("-f")
+                      .arg(dest.to_str().unwrap())
+                      .current_dir(root)
+                      .output());
+    if ! result.status.success() {
+        try!(check_cut_off(name, dest, max_size));
+        let output = String::from_utf8_lossy(&result.stdout);
+        let error = String::from_utf8_lossy(&result.stderr);
+        return Err(DeliveryError{kind: Kind::SupermarketFailed, detail: Some(format!("Failed 'knife cookbook site download'\nOUT: {}\nERR: {}", &output, &error).to_string())});
+    }
+    Ok(())
+}
+
+/// A command running `program` under `ulimit -f`, so no file it
+/// writes can grow past `max_size` bytes. `ulimit -f` counts in
+/// 512-byte blocks.
+pub fn size_limited(program: &str, max_size: u64) -> Command {
+    let blocks = (max_size + 511) / 512;
+    let mut command = Command::new("sh");
+    command.arg("-c").arg("ulimit -f \"$0\" && exec \"$@\"")
+        .arg(&blocks.to_string()).arg(program);
+    command
+}
+
+/// After a failed download, `Kind::CookbookTooLarge` if it was the
+/// size limit that stopped it.
+fn check_cut_off(name: &str, dest: &PathBuf, max_size: u64) -> Result<(), DeliveryError> {
+    match fs::metadata(dest) {
+        Ok(ref m) if m.len() >= max_size => {
+            Err(DeliveryError{
+                kind: Kind::CookbookTooLarge,
+                detail: Some(format!("The download of {} was stopped at {} bytes; the limit is {} bytes",
+                                     name, m.len(), max_size))
+            })
+        },
+        _ => Ok(())
+    }
+}
+
+/// Download each of `cookbooks` with `fetch`, `workers` at a time.
+/// Every download gets a directory of its own under `dir`
+/// (`<position>-<name>`), and `fetch` is asked to write
+/// `<name>.tgz` there. Returns the archives in the order given.
+///
+/// Once a download fails no new ones are started, but those already
+/// running are waited for. The error returned is the first failure in
+/// the order given, with the cookbook named in its detail.
+pub fn download_all<F>(cookbooks: &[CookbookRef], dir: &PathBuf, workers: usize,
+                       fetch: F) -> Result<Vec<PathBuf>, DeliveryError>
+    where F: Fn(&str, Option<&str>, &PathBuf) -> Result<(), DeliveryError> + Send + Sync + 'static {
+    let mut queue = Vec::new();
+    for (i, cookbook) in cookbooks.iter().enumerate() {
+        let cookbook_dir = dir.join(&format!("{}-{}", i, cookbook.name));
+        try!(utils::mkdir_recursive(&cookbook_dir));
+        queue.push((i, cookbook.clone(), cookbook_dir.join(&format!("{}.tgz", cookbook.name))));
+    }
+    queue.reverse();
+    let queue = Arc::new(Mutex::new(queue));
+    let failed = Arc::new(AtomicBool::new(false));
+    let fetch = Arc::new(fetch);
+    let (tx, rx) = channel();
+    let mut handles = Vec::new();
+    for _ in 0..workers {
+        let (queue, failed, fetch, tx) = (queue.clone(), failed.clone(), fetch.clone(), tx.clone());
+        handles.push(thread::spawn(move || {
+            loop {
+                if failed.load(Ordering::SeqCst) {
+                    break;
+                }
+                let next = queue.lock().unwrap().pop();
+                let (i, cookbook, dest) = match next {
+                    Some(n) => n,
+                    None => break
+                };
+                let result = (*fetch)(&cookbook.name, cookbook.version.as_ref().map(|v| &v[..]), &dest);
+                if result.is_err() {
+                    failed.store(true, Ordering::SeqCst);
+                }
+                tx.send((i, result.map(|_| dest).map_err(|e| named_error(&cookbook.name, e)))).unwrap();
+            }
+        }));
+    }
+    drop(tx);
+    let mut results: Vec<Option<Result<PathBuf, DeliveryError>>> = cookbooks.iter().map(|_| None).collect();
+    for (i, result) in rx.iter() {
+        results[i] = Some(result);
+    }
+    for handle in handles.into_iter() {
+        if handle.join().is_err() {
+            return Err(DeliveryError{
+                kind: Kind::SupermarketFailed,
+                detail: Some("A cookbook download panicked".to_string())
+            });
+        }
+    }
+    let mut archives = Vec::new();
+    for result in results.into_iter() {
+        match result {
+            Some(Ok(archive)) => archives.push(archive),
+            Some(Err(e)) => return Err(e),
+            None => {}
+        }
+    }
+    Ok(archives)
+}
+
+fn named_error(name: &str, e: DeliveryError) -> DeliveryError {
+    let detail = match e.detail {
+        Some(ref d) => format!("Failed to download cookbook '{}': {}", name, d),
+        None => format!("Failed to download cookbook '{}': {}", name, e.description())
+    };
+    DeliveryError{ kind: e.kind, detail: Some(detail) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::{DeliveryError, Kind};
+    use std::fs::{self, File};
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Barrier};
+    use tempdir::TempDir;
+
+    fn write_archive(name: &str, dest: &PathBuf) -> Result<(), DeliveryError> {
+        let mut f = try!(File::create(dest));
+        try!(f.write_all(name.as_bytes()));
+        Ok(())
+    }
+
+    fn cookbooks(names: &[&str]) -> Vec<CookbookRef> {
+        names.iter().map(|n| CookbookRef::new(n, Some("1.0.0"))).collect()
+    }
+
+    #[test]
+    fn download_all_in_order() {
+        let tempdir = TempDir::new("supermarket").unwrap();
+        let dir = tempdir.path().to_path_buf();
+        let wanted = vec![CookbookRef::new("truck", Some("1.0.0")),
+                          CookbookRef::new("sugar", None),
+                          CookbookRef::new("truck", Some("2.0.0"))];
+        let archives = download_all(&wanted, &dir, DOWNLOAD_WORKERS, |name, _, dest| write_archive(name, dest)).unwrap();
+        assert_eq!(vec![dir.join("0-truck").join("truck.tgz"),
+                        dir.join("1-sugar").join("sugar.tgz"),
+                        dir.join("2-truck").join("truck.tgz")],
+                   archives);
+        assert!(archives.iter().all(|a| a.is_file()));
+    }
+
+    #[test]
+    fn download_all_reports_the_failed_cookbook() {
+        let tempdir = TempDir::new("supermarket").unwrap();
+        let dir = tempdir.path().to_path_buf();
+        let wanted = cookbooks(&["truck", "broken", "sugar", "yum"]);
+        // Every download has started before any of them finishes
+        let started = Arc::new(Barrier::new(4));
+        let result = download_all(&wanted, &dir, 4, move |name, _, dest| {
+            started.wait();
+            if name == "broken" {
+                return Err(DeliveryError{ kind: Kind::SupermarketFailed, detail: Some("404 Not Found".to_string()) });
+            }
+            write_archive(name, dest)
+        });
+        match result {
+            Err(e) => match e.kind {
+                Kind::SupermarketFailed => {
+                    assert_eq!("Failed to download cookbook 'broken': 404 Not Found", e.detail.unwrap());
+                },
+                _ => panic!("Expected SupermarketFailed, got {:?}", e)
+            },
+            Ok(a) => panic!("Expected the broken download to fail, got {:?}", a)
+        }
+        // The downloads already running when it failed still finished
+        for (i, name) in ["truck", "sugar", "yum"].iter().enumerate() {
+            let position = if i == 0 { 0 } else { i + 1 };
+            assert!(dir.join(&format!("{}-{}", position, name)).join(&format!("{}.tgz", name)).is_file());
+        }
+    }
+
+    #[test]
+    fn download_all_starts_nothing_after_a_failure() {
+        let tempdir = TempDir::new("supermarket").unwrap();
+        let dir = tempdir.path().to_path_buf();
+        let wanted = cookbooks(&["broken", "truck"]);
+        let result = download_all(&wanted, &dir, 1, |name, _, dest| {
+            if name == "broken" {
+                return Err(DeliveryError{ kind: Kind::SupermarketFailed, detail: None });
+            }
+            write_archive(name, dest)
+        });
+        assert!(result.is_err());
+        assert!(!dir.join("1-truck").join("truck.tgz").is_file());
+    }
+
+    #[test]
+    fn size_limited_cuts_off_large_writes() {
+        let tempdir = TempDir::new("supermarket").unwrap();
+        let out = tempdir.path().join("big.tgz");
+        let result = size_limited("dd", 1024)
+            .arg("if=/dev/zero")
+            .arg(&format!("of={}", out.to_str().unwrap()))
+            .arg("bs=1024").arg("count=8")
+            .output().unwrap();
+        assert!(!result.status.success());
+        assert_eq!(1024, fs::metadata(&out).unwrap().len());
+    }
+}
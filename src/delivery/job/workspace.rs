@@ -15,20 +15,30 @@
 // limitations under the License.
 //
 
+use config::Config;
 use errors::{DeliveryError, Kind};
 use git;
 use rustc_serialize::{Encodable, Encoder};
 use rustc_serialize::json::{self, Json};
 use job::dna::{Top, DNA, WorkspaceCompat};
 use job::change::{Change, BuilderCompat};
+use job::config::BuildCookbookSource;
+use job::phase_env::{self, PhaseEnv};
+use job::supermarket::{self, CookbookRef};
 use job;
+use std::collections::BTreeMap;
 use std::process::{Command, Stdio};
-use std::path::PathBuf;
-use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::io;
 use std::io::prelude::*;
 use utils;
 use utils::path_join_many::PathJoinMany;
+use utils::say::sayln;
+use utils::artifact_cache::ArtifactCache;
 use std::error;
+use openssl::crypto::hash::{Hasher, Type};
+use rustc_serialize::hex::ToHex;
 
 #[derive(RustcDecodable, Debug)]
 pub struct Workspace {
@@ -36,7 +46,15 @@ pub struct Workspace {
     pub chef: PathBuf,
     pub cache: PathBuf,
     pub repo: PathBuf,
-    pub ssh_wrapper: PathBuf
+    pub ssh_wrapper: PathBuf,
+    pub skip_chown: bool,
+    /// Where to cache downloaded build cookbooks; None turns it off.
+    pub artifact_cache: Option<PathBuf>,
+    /// Refuse a build cookbook we can't check against a checksum,
+    /// rather than warning.
+    pub require_checksum: bool,
+    /// Extra environment for each phase's processes.
+    pub phase_env: PhaseEnv
 }
 
 #[derive(Debug)]
@@ -67,7 +85,11 @@ impl Workspace {
             chef: root.join("chef"),
             cache: root.join("cache"),
             repo: root.join("repo"),
-            ssh_wrapper: root.join("bin").join("git_ssh")
+            ssh_wrapper: root.join("bin").join("git_ssh"),
+            skip_chown: false,
+            artifact_cache: None,
+            require_checksum: false,
+            phase_env: BTreeMap::new()
         }
     }
 
@@ -85,75 +107,72 @@ impl Workspace {
         Ok(())
     }
 
-    fn setup_build_cookbook_from_path(&self, path: &PathBuf) -> Result<(), DeliveryError> {
-        utils::copy_recursive(path, &self.chef.join("build_cookbook"))
+    fn setup_build_cookbook_from_path(&self, path: &str) -> Result<(), DeliveryError> {
+        let cookbook = try!(check_local_cookbook(&self.repo, path));
+        utils::copy_recursive(&cookbook, &self.chef.join("build_cookbook"))
     }
 
-    fn setup_build_cookbook_from_git(&self, build_cookbook: &Json, git_url: &str) -> Result<(), DeliveryError> {
-        let branch = match build_cookbook.find("branch") {
-            Some(b) => try!(b.as_string().ok_or(DeliveryError{
-                kind: Kind::ExpectedJsonString,
-                detail: Some("Expected 'branch' value to be a string".to_string())
-            })),
-            None => "master"
-        };
+    fn setup_build_cookbook_from_git(&self, git_url: &str, branch: &str) -> Result<(), DeliveryError> {
         try!(git::git_command(&["clone", git_url, self.chef.join("build_cookbook").to_str().unwrap()], &self.chef));
-        try!(git::git_command(&["checkout", &branch], &self.chef.join("build_cookbook")));
+        try!(git::git_command(&["checkout", branch], &self.chef.join("build_cookbook")));
         Ok(())
     }
 
     // This will need a windows implementation, and probably won't work on non-gnu tar systems
     // either.
-    fn setup_build_cookbook_from_supermarket(&self, build_cookbook: &Json) -> Result<(), DeliveryError> {
-        let is_name = build_cookbook.find("name");
-        if is_name.is_some() {
-            let name = match is_name.unwrap().as_string() {
-                Some(n) => n,
-                None => return Err(DeliveryError{
-                    kind: Kind::ExpectedJsonString,
-                    detail: Some("Build cookbook 'path' value must be a string".to_string())
-                })
-            };
-            let result = try!(Command::new("knife")
-                 .arg("cookbook")
-                 .arg("site")
-                 .arg("download")
-                 .arg(&name)
-                 .arg("-f")
-                 .arg(self.chef.join("build_cookbook.tgz").to_str().unwrap())
-                 .current_dir(&self.root)
-                 .output());
-            if ! result.status.success() {
-                let output = String::from_utf8_lossy(&result.stdout);
-                let error = String::from_utf8_lossy(&result.stderr);
-                return Err(DeliveryError{kind: Kind::SupermarketFailed, detail: Some(format!("Failed 'knife cookbook site download'\nOUT: {}\nERR: {}", &output, &error).to_string())});
-            }
-            let tar_result = try!(Command::new("tar")
-                 .arg("zxf")
-                 .arg(self.chef.join("build_cookbook.tgz").to_str().unwrap())
-                 .current_dir(&self.chef)
-                 .output());
-            if ! tar_result.status.success() {
-                let output = String::from_utf8_lossy(&tar_result.stdout);
-                let error = String::from_utf8_lossy(&tar_result.stderr);
-                return Err(DeliveryError{kind: Kind::TarFailed, detail: Some(format!("Failed 'tar zxf'\nOUT: {}\nERR: {}", &output, &error).to_string())});
-            }
-            let mv_result = try!(Command::new("mv")
-                 .arg(self.chef.join(name).to_str().unwrap())
-                 .arg(self.chef.join("build_cookbook").to_str().unwrap())
-                 .current_dir(&self.chef)
-                 .output());
-            if ! mv_result.status.success() {
-                let output = String::from_utf8_lossy(&mv_result.stdout);
-                let error = String::from_utf8_lossy(&mv_result.stderr);
-                return Err(DeliveryError{kind: Kind::MoveFailed, detail: Some(format!("Failed 'mv'\nOUT: {}\nERR: {}", &output, &error).to_string())});
-            }
-        } else {
-            return Err(DeliveryError{ kind: Kind::MissingBuildCookbookName, detail: None })
+    fn setup_build_cookbook_from_supermarket(&self, name: &str, build_cookbook: &Json) -> Result<(), DeliveryError> {
+        let tgz = self.chef.join("build_cookbook.tgz");
+        // Only a pinned version is the same artifact every time
+        let version = build_cookbook.find("version").and_then(|v| v.as_string());
+        let checksum = build_cookbook.find("checksum").and_then(|c| c.as_string());
+        // `max_size` (in bytes) overrides the default download limit
+        let max_size = build_cookbook.find("max_size")
+            .and_then(|m| m.as_u64())
+            .unwrap_or(utils::tar::DEFAULT_MAX_SIZE);
+        match (&self.artifact_cache, version) {
+            (&Some(ref dir), Some(v)) => {
+                let key = ArtifactCache::key("supermarket", name, v, checksum.unwrap_or(""));
+                let hit = try!(ArtifactCache::new(dir).fetch(&key, &tgz, |dest| {
+                    self.download_from_supermarket(name, version, dest, max_size)
+                }));
+                if hit {
+                    sayln("white", &format!("Using cached {} {}", name, v));
+                }
+            },
+            _ => try!(self.download_from_supermarket(name, version, &tgz, max_size))
+        }
+        try!(verify_checksum(&tgz, checksum, self.require_checksum));
+        try!(utils::tar::extract_all(vec![(tgz.clone(), self.chef.clone())], max_size,
+                                     utils::tar::DEFAULT_WORKERS));
+        let mv_result = try!(Command::new("mv")
+             .arg(self.chef.join(name).to_str().unwrap())
+             .arg(self.chef.join("build_cookbook").to_str().unwrap())
+             .current_dir(&self.chef)
+             .output());
+        if ! mv_result.status.success() {
+            let output = String::from_utf8_lossy(&mv_result.stdout);
+            let error = String::from_utf8_lossy(&mv_result.stderr);
+            return Err(DeliveryError{kind: Kind::MoveFailed, detail: Some(format!("Failed 'mv'\nOUT: {}\nERR: {}", &output, &error).to_string())});
         }
         Ok(())
     }
 
+    /// Download `name` with `knife` into `dest`, through
+    /// `supermarket::download_all` so a failure names the cookbook,
+    /// cutting it off past `max_size` bytes.
+    fn download_from_supermarket(&self, name: &str, version: Option<&str>, dest: &PathBuf,
+                                 max_size: u64) -> Result<(), DeliveryError> {
+        let root = self.root.clone();
+        let dir = self.chef.join("supermarket");
+        let wanted = [CookbookRef::new(name, version)];
+        let archives = try!(supermarket::download_all(&wanted, &dir, supermarket::DOWNLOAD_WORKERS,
+                                                      move |n, v, d| supermarket::knife_download(&root, n, v, d, max_size)));
+        for archive in archives.iter() {
+            try!(fs::rename(archive, dest));
+        }
+        utils::remove_recursive(&dir)
+    }
+
     fn setup_build_cookbook_from_chef_server(&self, name: &str) -> Result<(), DeliveryError> {
         try!(utils::mkdir_recursive(&self.chef.join("tmp_cookbook")));
         let result = try!(Command::new("knife")
@@ -181,84 +200,45 @@ impl Workspace {
         Ok(())
     }
 
-    fn setup_build_cookbook_from_delivery(&self, build_cookbook: &Json, user: &str, server: &str) -> Result<(), DeliveryError> {
-        let is_name = try!(build_cookbook.find("name").ok_or(DeliveryError{ kind: Kind::MissingBuildCookbookField, detail: Some("Missing name".to_string())}));
-        let name = try!(is_name.as_string().ok_or(DeliveryError{
-            kind: Kind::ExpectedJsonString,
-            detail: Some("Build cookbook 'name' value must be a string".to_string())
-        }));
-        let is_ent = try!(build_cookbook.find("enterprise").ok_or(DeliveryError{ kind: Kind::MissingBuildCookbookField, detail: Some("Missing enterprise".to_string())}));
-        let ent = try!(is_ent.as_string().ok_or(DeliveryError{
-            kind: Kind::ExpectedJsonString,
-            detail: Some("Build cookbook 'enterprise' value must be a string".to_string())
-        }));
-        let is_org = try!(build_cookbook.find("organization").ok_or(DeliveryError{ kind: Kind::MissingBuildCookbookField, detail: Some("Missing organization".to_string())}));
-        let org = try!(is_org.as_string().ok_or(DeliveryError{
-            kind: Kind::ExpectedJsonString,
-            detail: Some("Build cookbook 'organization' value must be a string".to_string())
-        }));
-        let url = git::delivery_ssh_url(user, server, &ent, &org, &name);
+    fn setup_build_cookbook_from_delivery(&self, name: &str, ent: &str, org: &str, user: &str, server: &str) -> Result<(), DeliveryError> {
+        let url = git::delivery_ssh_url(user, server, ent, org, name);
         try!(git::git_command(&["clone", &url, self.chef.join("build_cookbook").to_str().unwrap()], &self.chef));
         Ok(())
     }
 
     fn setup_build_cookbook(&self, config: &Json, user: &str, server: &str) -> Result<(), DeliveryError> {
-        let build_cookbook = try!(config.find("build_cookbook").ok_or(DeliveryError{
-            kind: Kind::NoBuildCookbook,
-            detail: None
-        }));
-        if build_cookbook.is_string() {
-            let path = build_cookbook.as_string().unwrap();
-            if path.contains("/") {
-                return self.setup_build_cookbook_from_path(&self.repo.join(&path));
-            } else {
-                return self.setup_build_cookbook_from_chef_server(&path);
-            }
+        let build_cookbook = try!(job::config::validate_build_cookbook(config));
+        // Only a supermarket download comes with a checksum to check
+        if self.require_checksum && build_cookbook.source != BuildCookbookSource::Supermarket {
+            return Err(DeliveryError{
+                kind: Kind::BuildCookbookChecksumMissing,
+                detail: Some("Only a supermarket build_cookbook can be checked against a checksum; run without --require-checksum to use this one".to_string())
+            });
         }
-        let valid_paths = vec!["path", "git", "supermarket", "enterprise"];
-        for path in valid_paths {
-            let is_path = build_cookbook.find(path);
-            if is_path.is_some() {
-                match is_path.unwrap().as_string() {
-                    Some(p) => {
-                        match path {
-                            "path" => return self.setup_build_cookbook_from_path(&self.repo.join(p)),
-                            "git"  => return self.setup_build_cookbook_from_git(&build_cookbook, &p),
-                            "supermarket" => return self.setup_build_cookbook_from_supermarket(&build_cookbook),
-                            "enterprise" => return self.setup_build_cookbook_from_delivery(&build_cookbook, user, server),
-                            "server" => {
-                                let is_name = try!(build_cookbook.find("name")
-                                                   .ok_or(DeliveryError{
-                                                       kind: Kind::MissingBuildCookbookName,
-                                                       detail: None
-                                                   }));
-                                let name = try!(is_name.as_string()
-                                                .ok_or(DeliveryError{
-                                                    kind: Kind::ExpectedJsonString,
-                                                    detail: Some("Expected 'name' to be a string".to_string())
-                                                }));
-                                return self.setup_build_cookbook_from_chef_server(&name);
-                            },
-                            _ => unreachable!()
-                        }
-                    },
-                    None => return Err(DeliveryError{
-                        kind: Kind::ExpectedJsonString,
-                        detail: Some(format!("Build cookbook '{}' value must be a string", path).to_string())
-                    })
-                }
+        let name = &build_cookbook.name;
+        match build_cookbook.source {
+            BuildCookbookSource::Local(ref path) => self.setup_build_cookbook_from_path(path),
+            BuildCookbookSource::Git{ ref url, ref branch } => self.setup_build_cookbook_from_git(url, branch),
+            BuildCookbookSource::Supermarket => {
+                self.setup_build_cookbook_from_supermarket(name, config.find("build_cookbook").unwrap())
+            },
+            BuildCookbookSource::ChefServer => self.setup_build_cookbook_from_chef_server(name),
+            BuildCookbookSource::Delivery{ ref enterprise, ref organization } => {
+                self.setup_build_cookbook_from_delivery(name, enterprise, organization, user, server)
             }
         }
-        Err(DeliveryError{ kind: Kind::NoValidBuildCookbook, detail: None })
     }
 
     fn berks_vendor(&self, config: &Json) -> Result<(), DeliveryError> {
         try!(utils::remove_recursive(&self.chef.join("cookbooks")));
-        if self.chef.join_many(&["build_cookbook", "Berksfile"]).is_file() {
+        let build_cookbook = self.chef.join("build_cookbook");
+        if uses_berkshelf(&build_cookbook) {
+            try!(check_berksfile(&build_cookbook));
             let mut command = Command::new("berks");
             command.arg("vendor");
             command.arg(&self.chef.join("cookbooks"));
             command.current_dir(&self.chef.join("build_cookbook"));
+            phase_env::apply(&mut command, &self.phase_env, "default");
             let output = match command.output() {
                 Ok(o) => o,
                 Err(e) => { return Err(DeliveryError{ kind: Kind::FailedToExecute, detail: Some(format!("failed to execute berks vendor: {}", error::Error::description(&e)))}) },
@@ -288,61 +268,31 @@ impl Workspace {
         Ok(())
     }
 
-    /// This sets permissions in the workspace repo and cache directories. Going to
-    /// want a windows implementation here.
-    pub fn set_drop_permissions(&self) -> Result<(), DeliveryError> {
-        let result = Command::new("chown")
-            .arg("-R")
-            .arg("dbuild:dbuild")
-            .arg(self.repo.to_str().unwrap())
-            .arg(self.chef.join("cookbooks").to_str().unwrap())
-            .arg(self.chef.join("nodes").to_str().unwrap())
-            .arg(self.cache.to_str().unwrap())
-            .output();
-       let output = match result {
-            Ok(o) => o,
-            Err(e) => { return Err(DeliveryError{ kind: Kind::FailedToExecute, detail: Some(format!("failed to execute chown: {}", error::Error::description(&e)))}) },
-        };
-        if !output.status.success() {
-            return Err(DeliveryError{ kind: Kind::ChownFailed, detail: Some(format!("STDOUT: {}\nSTDERR: {}\n", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr)))});
+    /// This sets permissions in the workspace repo and cache directories. On
+    /// Windows there is nothing to set, and this returns `Ok(false)`.
+    ///
+    /// When `skip_chown` is set we only warn, for local runs on machines
+    /// without a dbuild user. Returns whether chown actually ran.
+    pub fn set_drop_permissions(&self) -> Result<bool, DeliveryError> {
+        if self.skip_chown {
+            sayln("yellow", "Skipping chown of the workspace to dbuild:dbuild; the job may not be able to write to it");
+            return Ok(false);
         }
-        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-        debug!("chmod stdout: {}", stdout);
-        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-        debug!("chmod stderr: {}", stderr);
-        Ok(())
+        let cookbooks = self.chef.join("cookbooks");
+        let nodes = self.chef.join("nodes");
+        utils::chown_recursive(&[self.repo.as_path(), cookbooks.as_path(), nodes.as_path(), self.cache.as_path()],
+                               "dbuild:dbuild")
     }
 
     pub fn build_cookbook_name(&self, config: &Json) -> Result<String, DeliveryError> {
-        let bc_name = match config.find("build_cookbook") {
-            Some(bc) => {
-                if bc.is_string() {
-                    let bc_string = bc.as_string().unwrap();
-                    if bc_string.contains("/") {
-                        let r = regex!(r"(.+)/(.+)");
-                        let caps_result = r.captures(bc_string);
-                        let caps = caps_result.unwrap();
-                        caps.at(2).unwrap()
-                    } else {
-                        bc_string
-                    }
-                } else {
-                    let is_bc_name = try!(bc.find("name").ok_or(DeliveryError{
-                        kind: Kind::MissingBuildCookbookName,
-                        detail: None}));
-                    try!(is_bc_name.as_string().ok_or(DeliveryError{
-                        kind: Kind::ExpectedJsonString,
-                        detail: None}))
-                }
-            },
-            None => return Err(DeliveryError{kind: Kind::NoValidBuildCookbook, detail: None})
-        };
-        Ok(bc_name.to_string())
+        job::config::validate_build_cookbook(config).map(|b| b.name)
     }
 
-    pub fn run_job(&self, phase: &str, drop_privilege: Privilege) -> Result<(), DeliveryError> {
-        let config = try!(job::config::load_config(&self.repo.join_many(&[".delivery", "config.json"])));
+    pub fn run_job(&self, phase: &str, drop_privilege: Privilege, config_patch: &str,
+                   cli_config: &Config) -> Result<(), DeliveryError> {
+        let config = try!(job::config::load_config_with_patch(&self.repo.join_many(&[".delivery", "config.json"]), config_patch, cli_config));
         let bc_name = try!(self.build_cookbook_name(&config));
+        try!(job::chef::preflight(&config));
         let mut command = Command::new("chef-client");
         command.arg("-z");
         command.arg("--force-formatter");
@@ -365,6 +315,7 @@ impl Workspace {
         command.stdout(Stdio::inherit());
         command.stderr(Stdio::inherit());
         command.env("HOME", &self.cache.to_str().unwrap());
+        phase_env::apply(&mut command, &self.phase_env, phase);
         match phase {
             "default" => command.env("DELIVERY_BUILD_SETUP", "TRUE"),
             _ => command.env("DELIVERY_BUILD_SETUP", "FALSE")
@@ -380,7 +331,8 @@ impl Workspace {
         Ok(())
     }
 
-    pub fn setup_chef_for_job(&self, user: &str, server: &str, change: Change) -> Result<(), DeliveryError> {
+    pub fn setup_chef_for_job(&self, user: &str, server: &str, change: Change, config_patch: &str,
+                              cli_config: &Config) -> Result<(), DeliveryError> {
         let mut config_rb = try!(File::create(&self.chef.join("config.rb")));
         try!(config_rb.write_all(b"file_cache_path File.expand_path(File.join(File.dirname(__FILE__), '..', 'cache'))
 cache_type 'BasicFile'
@@ -399,9 +351,12 @@ else
 end
 "));
         try!(utils::chmod(&self.chef.join("config.rb"), "0644"));
-        let config = try!(job::config::load_config(&self.repo.join_many(&[".delivery", "config.json"])));
+        let config = try!(job::config::load_config_with_patch(&self.repo.join_many(&[".delivery", "config.json"]), config_patch, cli_config));
         try!(self.setup_build_cookbook(&config, user, server));
         try!(self.berks_vendor(&config));
+        let bc_name = try!(self.build_cookbook_name(&config));
+        let graph = try!(job::deps::dependency_graph(&self.chef.join("cookbooks")));
+        try!(job::deps::check_cycles(&graph, &bc_name));
         let workspace_data = WorkspaceCompat{
             root: self.root.to_str().unwrap().to_string(),
             chef: self.chef.to_str().unwrap().to_string(),
@@ -450,10 +405,109 @@ end
     }
 }
 
+/// A build cookbook uses Berkshelf if it has a `Berksfile` or a
+/// `Berksfile.lock`.
+pub fn uses_berkshelf(cookbook: &PathBuf) -> bool {
+    cookbook.join("Berksfile").is_file() || cookbook.join("Berksfile.lock").is_file()
+}
+
+/// The path to the cookbook's `Berksfile`, or `Kind::BerksfileNotFound`
+/// naming where we looked.
+pub fn check_berksfile(cookbook: &PathBuf) -> Result<PathBuf, DeliveryError> {
+    let berksfile = cookbook.join("Berksfile");
+    if berksfile.is_file() {
+        Ok(berksfile)
+    } else {
+        Err(DeliveryError{
+            kind: Kind::BerksfileNotFound,
+            detail: Some(format!("Expected a Berksfile at {}", berksfile.display()))
+        })
+    }
+}
+
+/// The directory of a `path` build cookbook, relative to `repo`. It
+/// must be there, inside `repo` once symlinks and `..` are followed,
+/// and have a `metadata.rb`, or it is `Kind::NoValidBuildCookbook`
+/// naming the path. An absolute path is refused outright.
+pub fn check_local_cookbook(repo: &PathBuf, path: &str) -> Result<PathBuf, DeliveryError> {
+    let cookbook = repo.join(path);
+    let problem = if Path::new(path).is_absolute() {
+        "is absolute; give it relative to the project"
+    } else if !cookbook.is_dir() {
+        "is not a directory in the project"
+    } else if !inside(repo, &cookbook) {
+        "is outside the project"
+    } else if !cookbook.join("metadata.rb").is_file() {
+        "has no metadata.rb"
+    } else {
+        return Ok(cookbook);
+    };
+    Err(DeliveryError{
+        kind: Kind::NoValidBuildCookbook,
+        detail: Some(format!("The build_cookbook path '{}' {}", path, problem))
+    })
+}
+
+/// Whether `path` is `dir` or under it, once both are canonical.
+fn inside(dir: &PathBuf, path: &PathBuf) -> bool {
+    match (fs::canonicalize(dir), fs::canonicalize(path)) {
+        (Ok(d), Ok(p)) => p.starts_with(&d),
+        _ => false
+    }
+}
+
+/// Check the build cookbook archive at `archive` against the sha256
+/// `checksum` from its config, returning whether it was checked.
+/// Without a checksum, `require` makes that
+/// `Kind::BuildCookbookChecksumMissing`; otherwise we warn and go on.
+pub fn verify_checksum(archive: &PathBuf, checksum: Option<&str>,
+                       require: bool) -> Result<bool, DeliveryError> {
+    let expected = match checksum {
+        Some(c) => c.trim().to_lowercase(),
+        None if require => return Err(DeliveryError{
+            kind: Kind::BuildCookbookChecksumMissing,
+            detail: Some(format!("No checksum to verify {} with; add a sha256 'checksum' to the build_cookbook",
+                                 archive.display()))
+        }),
+        None => {
+            sayln("yellow", &format!("No checksum for {}; using it unverified", archive.display()));
+            return Ok(false);
+        }
+    };
+    // Hash it as we read it, so a large archive isn't held in memory
+    let mut hasher = Hasher::new(Type::SHA256);
+    try!(io::copy(&mut try!(File::open(archive)), &mut hasher));
+    let actual = hasher.finish().to_hex();
+    if actual == expected {
+        Ok(true)
+    } else {
+        Err(DeliveryError{
+            kind: Kind::BuildCookbookChecksumMismatch,
+            detail: Some(format!("{} has sha256 {}, but the build_cookbook checksum is {}",
+                                 archive.display(), actual, expected))
+        })
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use errors::Kind;
+    use std::fs::File;
+    use std::io::prelude::*;
     use std::path::PathBuf;
+    use std::process::Command;
+    use tempdir::TempDir;
+    use utils;
+    use utils::path_join_many::PathJoinMany;
+
+    const HELLO_SHA256: &'static str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn hello_archive(dir: &TempDir) -> PathBuf {
+        let archive = dir.path().join("build_cookbook.tgz");
+        File::create(&archive).unwrap().write_all(b"hello").unwrap();
+        archive
+    }
 
     #[test]
     fn new() {
@@ -463,5 +517,130 @@ mod test {
         assert_eq!(w.chef, root.join("chef"));
         assert_eq!(w.cache, root.join("cache"));
         assert_eq!(w.repo, root.join("repo"));
+        assert_eq!(w.skip_chown, false);
+        assert_eq!(w.artifact_cache, None);
+        assert_eq!(w.require_checksum, false);
+        assert!(w.phase_env.is_empty());
+    }
+
+    #[test]
+    fn set_drop_permissions_with_skip_chown() {
+        // chown would fail on these paths, so Ok means it never ran
+        let mut w = Workspace::new(&PathBuf::from("/bogonista/workspace"));
+        w.skip_chown = true;
+        assert_eq!(false, w.set_drop_permissions().unwrap());
+    }
+
+    #[test]
+    fn check_berksfile_present() {
+        let tempdir = TempDir::new("berksfile").ok().expect("TempDir failed");
+        let cookbook = tempdir.path().to_path_buf();
+        File::create(&cookbook.join("Berksfile")).unwrap();
+        assert!(uses_berkshelf(&cookbook));
+        assert_eq!(cookbook.join("Berksfile"), check_berksfile(&cookbook).unwrap());
+    }
+
+    #[test]
+    fn check_berksfile_absent() {
+        let tempdir = TempDir::new("berksfile").ok().expect("TempDir failed");
+        let cookbook = tempdir.path().to_path_buf();
+        assert!(!uses_berkshelf(&cookbook));
+        File::create(&cookbook.join("Berksfile.lock")).unwrap();
+        assert!(uses_berkshelf(&cookbook));
+        match check_berksfile(&cookbook) {
+            Err(e) => match e.kind {
+                Kind::BerksfileNotFound => {
+                    let expected = format!("Expected a Berksfile at {}", cookbook.join("Berksfile").display());
+                    assert_eq!(expected, e.detail.unwrap());
+                },
+                _ => panic!("Expected BerksfileNotFound, got {:?}", e)
+            },
+            Ok(p) => panic!("Expected no Berksfile, got {:?}", p)
+        }
+    }
+
+    fn assert_no_valid_cookbook(repo: &PathBuf, path: &str, expected: &str) {
+        match check_local_cookbook(repo, path) {
+            Err(e) => match e.kind {
+                Kind::NoValidBuildCookbook => assert_eq!(Some(expected.to_string()), e.detail),
+                _ => panic!("Expected NoValidBuildCookbook, got {:?}", e)
+            },
+            Ok(p) => panic!("Expected {} to be refused, got {:?}", path, p)
+        }
+    }
+
+    #[test]
+    fn check_local_cookbook_present() {
+        let tempdir = TempDir::new("repo").ok().expect("TempDir failed");
+        let repo = tempdir.path().to_path_buf();
+        let cookbook = repo.join_many(&["cookbooks", "build"]);
+        utils::mkdir_recursive(&cookbook).unwrap();
+        File::create(&cookbook.join("metadata.rb")).unwrap();
+        assert_eq!(cookbook, check_local_cookbook(&repo, "cookbooks/build").unwrap());
+    }
+
+    #[test]
+    fn check_local_cookbook_missing() {
+        let tempdir = TempDir::new("repo").ok().expect("TempDir failed");
+        assert_no_valid_cookbook(&tempdir.path().to_path_buf(), "cookbooks/build",
+                                 "The build_cookbook path 'cookbooks/build' is not a directory in the project");
+    }
+
+    #[test]
+    fn check_local_cookbook_outside_the_project() {
+        let tempdir = TempDir::new("repo").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        let repo = root.join("repo");
+        let elsewhere = root.join("elsewhere");
+        utils::mkdir_recursive(&repo).unwrap();
+        utils::mkdir_recursive(&elsewhere).unwrap();
+        File::create(&elsewhere.join("metadata.rb")).unwrap();
+        assert_no_valid_cookbook(&repo, "../elsewhere",
+                                 "The build_cookbook path '../elsewhere' is outside the project");
+        assert_no_valid_cookbook(&repo, elsewhere.to_str().unwrap(),
+                                 &format!("The build_cookbook path '{}' is absolute; give it relative to the project",
+                                          elsewhere.display()));
+        // A symlink out of the project is no better
+        Command::new("ln").arg("-s").arg(&elsewhere).arg(&repo.join("build")).status().unwrap();
+        assert_no_valid_cookbook(&repo, "build",
+                                 "The build_cookbook path 'build' is outside the project");
+    }
+
+    #[test]
+    fn check_local_cookbook_without_metadata() {
+        let tempdir = TempDir::new("repo").ok().expect("TempDir failed");
+        let repo = tempdir.path().to_path_buf();
+        utils::mkdir_recursive(&repo.join_many(&["cookbooks", "build"])).unwrap();
+        assert_no_valid_cookbook(&repo, "cookbooks/build",
+                                 "The build_cookbook path 'cookbooks/build' has no metadata.rb");
+    }
+
+    #[test]
+    fn verify_checksum_with_checksum() {
+        let tempdir = TempDir::new("checksum").ok().expect("TempDir failed");
+        let archive = hello_archive(&tempdir);
+        assert_eq!(true, verify_checksum(&archive, Some(HELLO_SHA256), false).unwrap());
+        assert_eq!(true, verify_checksum(&archive, Some(HELLO_SHA256), true).unwrap());
+        match verify_checksum(&archive, Some("deadbeef"), true) {
+            Err(e) => match e.kind {
+                Kind::BuildCookbookChecksumMismatch => assert!(e.detail.unwrap().contains(HELLO_SHA256)),
+                _ => panic!("Expected BuildCookbookChecksumMismatch, got {:?}", e)
+            },
+            Ok(v) => panic!("Expected a mismatch, got {}", v)
+        }
+    }
+
+    #[test]
+    fn verify_checksum_without_checksum() {
+        let tempdir = TempDir::new("checksum").ok().expect("TempDir failed");
+        let archive = hello_archive(&tempdir);
+        assert_eq!(false, verify_checksum(&archive, None, false).unwrap());
+        match verify_checksum(&archive, None, true) {
+            Err(e) => match e.kind {
+                Kind::BuildCookbookChecksumMissing => {},
+                _ => panic!("Expected BuildCookbookChecksumMissing, got {:?}", e)
+            },
+            Ok(v) => panic!("Expected the missing checksum to be refused, got {}", v)
+        }
     }
 }
@@ -15,7 +15,7 @@
 // limitations under the License.
 //
 
-#![feature(plugin, collections, std_misc, path_ext, convert, thread_sleep)]
+#![feature(plugin, collections, std_misc, path_ext, convert, thread_sleep, fs_canonicalize)]
 #![plugin(regex_macros, docopt_macros)]
 extern crate regex;
 #[no_link] extern crate regex_macros;
@@ -30,6 +30,7 @@ extern crate libc;
 extern crate tempdir;
 extern crate uuid;
 extern crate hyper;
+extern crate openssl;
 extern crate mime;
 
 pub mod errors;
@@ -42,3 +43,5 @@ pub mod getpass;
 pub mod token;
 pub mod http;
 pub mod project;
+pub mod support;
+pub mod cache;
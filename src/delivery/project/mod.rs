@@ -19,11 +19,37 @@ use hyper::status::StatusCode;
 use utils::say::{say, sayln};
 use errors::{DeliveryError, Kind};
 use std::path::{PathBuf};
+use utils::path_join_many::PathJoinMany;
 use http::APIClient;
 use git;
+use config::{check_server_allowed, Config};
 
-pub fn import(user: &str, server: &str, ent: &str, org: &str, proj: &str, path: &PathBuf)
-    -> Result<(), DeliveryError> {
+/// The root of the project `start` is in: the nearest directory, from
+/// `start` up, with a `.delivery/config.json` in it. A `.delivery`
+/// directory on its own isn't enough, since `~/.delivery` holds the
+/// token store. Outside any project that is `Kind::NotInProject`.
+pub fn find_root(start: &PathBuf) -> Result<PathBuf, DeliveryError> {
+    let mut path = start.clone();
+    loop {
+        if path.join_many(&[".delivery", "config.json"]).is_file() {
+            return Ok(path);
+        }
+        if !path.pop() {
+            return Err(DeliveryError{
+                kind: Kind::NotInProject,
+                detail: Some(format!("No .delivery/config.json at or above {}; run delivery init to set this project up",
+                                     start.display()))
+            });
+        }
+    }
+}
+
+/// Import the project at `path`: set up its git remote, then create
+/// the project on the server with `client`, which is already
+/// authenticated, and a pipeline for the remote's default branch.
+/// Returns the name of that pipeline.
+pub fn import(client: &APIClient, user: &str, server: &str, ent: &str, org: &str, proj: &str,
+              path: &PathBuf) -> Result<String, DeliveryError> {
 
     // Init && config local repo if necessary
     try!(git::init_repo(path));
@@ -31,10 +57,6 @@ pub fn import(user: &str, server: &str, ent: &str, org: &str, proj: &str, path:
         sayln("white", "Remote 'delivery' added to git config!");
     }
 
-    let mut client = APIClient::new_https(server, ent);
-    let auth = try!(client.get_auth_from_home(server, ent, user));
-    client.set_auth(auth);
-
     if ! client.project_exists(org, proj) {
         say("white", "Creating project: ");
         sayln("magenta", &format!("{} ", proj));
@@ -54,10 +76,11 @@ pub fn import(user: &str, server: &str, ent: &str, org: &str, proj: &str, path:
         let _ = git::git_push_master();
     }
 
-    say("white", "Creating master pipeline for project: ");
+    let pipeline = try!(git::get_remote_head("delivery", path));
+    say("white", &format!("Creating {} pipeline for project: ", pipeline));
     say("magenta", &format!("{} ", proj));
     say("white", "... ");
-    match client.create_pipeline(org, proj, &"master") {
+    match client.create_pipeline(org, proj, &pipeline) {
          Ok(_) => {
             sayln("white", "done");
         },
@@ -75,6 +98,295 @@ pub fn import(user: &str, server: &str, ent: &str, org: &str, proj: &str, path:
             }
         }
     }
-    return Ok(())
+    return Ok(pipeline)
+}
+
+
+/// Where one part of `delivery setup` stood once setup was done
+/// with it.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SetupState {
+    /// It was missing, and setup made it.
+    Created,
+    /// It was there, but different; setup brought it up to date.
+    Updated,
+    /// It was already there, just as asked for.
+    AlreadyPresent,
+    /// Setup had nothing to make it from, so left it alone.
+    Skipped(String)
+}
+
+impl SetupState {
+    fn describe(&self) -> String {
+        match *self {
+            SetupState::Created => "created".to_string(),
+            SetupState::Updated => "updated".to_string(),
+            SetupState::AlreadyPresent => "already present".to_string(),
+            SetupState::Skipped(ref why) => format!("skipped ({})", why)
+        }
+    }
+}
+
+/// What `setup` found and did, for the `delivery` git remote and for
+/// `.delivery/cli.toml`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SetupReport {
+    pub remote: SetupState,
+    pub config: SetupState
+}
+
+impl SetupReport {
+    /// True when setup changed nothing.
+    pub fn nothing_to_do(&self) -> bool {
+        [&self.remote, &self.config].iter().all(|s| match **s {
+            SetupState::Created | SetupState::Updated => false,
+            _ => true
+        })
+    }
+
+    /// Print one line for each part of setup.
+    pub fn report(&self) {
+        say("white", "git remote delivery: ");
+        sayln("magenta", &self.remote.describe());
+        say("white", ".delivery/cli.toml: ");
+        sayln("magenta", &self.config.describe());
+        if self.nothing_to_do() {
+            sayln("green", "Nothing to do; delivery is already set up here.");
+        }
+    }
+}
+
+/// Set up the project at `path` with `config`: add the `delivery` git
+/// remote, when `path` is a git repository and the config names the
+/// whole project, and write `.delivery/cli.toml`. Each part is checked
+/// on its own, so a re-run only makes what is missing or different.
+/// A `delivery` remote that points somewhere else is left alone and is
+/// a `Kind::GitSetupFailed`, and a server that isn't one of the
+/// `allowed_servers` a `Kind::ServerNotAllowed`, before anything is
+/// written.
+pub fn setup(config: &Config, path: &PathBuf) -> Result<SetupReport, DeliveryError> {
+    // The remote is on the server, so neither is set up for one that
+    // isn't allowed
+    match config.server {
+        Some(ref s) => try!(check_server_allowed(s, &config.allowed_servers)),
+        None => {}
+    }
+    let remote = match remote_url_for(config, path) {
+        Ok(url) => match git::delivery_remote_url(path) {
+            Some(ref existing) if *existing == url => SetupState::AlreadyPresent,
+            Some(existing) => return Err(DeliveryError{
+                kind: Kind::GitSetupFailed,
+                detail: Some(format!("The git remote delivery is {}, not {}; remove it with 'git remote remove delivery' to set it up again",
+                                     existing, url))
+            }),
+            None => {
+                try!(git::git_command(&["remote", "add", "delivery", &url], path));
+                SetupState::Created
+            }
+        },
+        Err(why) => SetupState::Skipped(why)
+    };
+
+    let config_state = if !path.join(".delivery").join("cli.toml").is_file() {
+        SetupState::Created
+    } else if config.same_as_file(path) {
+        SetupState::AlreadyPresent
+    } else {
+        SetupState::Updated
+    };
+    if config_state != SetupState::AlreadyPresent {
+        try!(config.write_file(path));
+    }
+    Ok(SetupReport{ remote: remote, config: config_state })
+}
+
+// The url the `delivery` remote should have, or why there is none to
+// add.
+fn remote_url_for(config: &Config, path: &PathBuf) -> Result<String, String> {
+    if !path.join(".git").exists() {
+        return Err("not a git repository".to_string());
+    }
+    let project = match config.project.clone() {
+        Some(p) => Some(p),
+        None => git::project_from_remote(path)
+    };
+    match (config.user.as_ref(), config.server.as_ref(), config.enterprise.as_ref(),
+           config.organization.as_ref(), project.as_ref()) {
+        (Some(u), Some(s), Some(e), Some(o), Some(p)) =>
+            Ok(git::delivery_ssh_url(u, s, e, o, p)),
+        _ => Err("user, server, enterprise, organization and project are not all set".to_string())
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Kind;
+    use std::path::PathBuf;
+    use tempdir::TempDir;
+    use utils;
+    use utils::path_join_many::PathJoinMany;
+    use config::Config;
+    use git;
+    use std::fs::File;
+
+    fn make_project(root: &PathBuf) {
+        utils::mkdir_recursive(&root.join(".delivery")).unwrap();
+        File::create(&root.join_many(&[".delivery", "config.json"])).unwrap();
+    }
+
+    #[test]
+    fn find_root_in_root() {
+        let tempdir = TempDir::new("project").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        make_project(&root);
+        assert_eq!(root, find_root(&root).unwrap());
+    }
+
+    #[test]
+    fn find_root_in_subdirectory() {
+        let tempdir = TempDir::new("project").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        make_project(&root);
+        let nested = root.join_many(&["cookbooks", "build", "recipes"]);
+        utils::mkdir_recursive(&nested).unwrap();
+        assert_eq!(root, find_root(&nested).unwrap());
+    }
+
+    #[test]
+    fn find_root_skips_a_delivery_directory_without_config() {
+        // Like ~/.delivery, which only holds tokens and caches
+        let tempdir = TempDir::new("project").ok().expect("TempDir failed");
+        let home = tempdir.path().to_path_buf();
+        utils::mkdir_recursive(&home.join_many(&[".delivery", "api-tokens"])).unwrap();
+        let nested = home.join_many(&["src", "widgets"]);
+        utils::mkdir_recursive(&nested).unwrap();
+        match find_root(&nested) {
+            Err(e) => match e.kind {
+                Kind::NotInProject => {},
+                _ => panic!("Expected NotInProject, got {:?}", e)
+            },
+            Ok(p) => panic!("Expected no project, got {:?}", p)
+        }
+        make_project(&nested);
+        assert_eq!(nested, find_root(&nested).unwrap());
+    }
+
+    #[test]
+    fn find_root_outside_project() {
+        let tempdir = TempDir::new("project").ok().expect("TempDir failed");
+        let nowhere = tempdir.path().join("nowhere");
+        utils::mkdir_recursive(&nowhere).unwrap();
+        match find_root(&nowhere) {
+            Err(e) => match e.kind {
+                Kind::NotInProject => assert!(e.detail.unwrap().ends_with("run delivery init to set this project up")),
+                _ => panic!("Expected NotInProject, got {:?}", e)
+            },
+            Ok(p) => panic!("Expected no project, got {:?}", p)
+        }
+    }
+
+    fn setup_config() -> Config {
+        Config::default()
+            .set_user("adam")
+            .set_server("delivery.example.com")
+            .set_enterprise("ent")
+            .set_organization("org")
+            .set_project("widgets")
+    }
+
+    fn git_repo(tempdir: &TempDir) -> PathBuf {
+        let root = tempdir.path().to_path_buf();
+        git::git_command(&["init"], &root).unwrap();
+        root
+    }
+
+    #[test]
+    fn setup_fresh_creates_everything() {
+        let tempdir = TempDir::new("project").ok().expect("TempDir failed");
+        let root = git_repo(&tempdir);
+        let config = setup_config();
+        let report = setup(&config, &root).unwrap();
+        assert_eq!(SetupState::Created, report.remote);
+        assert_eq!(SetupState::Created, report.config);
+        assert!(!report.nothing_to_do());
+        assert_eq!(Some(git::delivery_ssh_url("adam", "delivery.example.com", "ent", "org", "widgets")),
+                   git::delivery_remote_url(&root));
+        assert!(config.same_as_file(&root));
+    }
+
+    #[test]
+    fn setup_refuses_a_server_not_allowed() {
+        let tempdir = TempDir::new("project").ok().expect("TempDir failed");
+        let root = git_repo(&tempdir);
+        let mut config = setup_config();
+        config.allowed_servers = Some(vec!["staging.example.com".to_string()]);
+        match setup(&config, &root) {
+            Err(e) => match e.kind {
+                Kind::ServerNotAllowed => {},
+                _ => panic!("Expected ServerNotAllowed, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected setup to refuse the server")
+        }
+        assert_eq!(None, git::delivery_remote_url(&root));
+        assert!(!root.join_many(&[".delivery", "cli.toml"]).exists());
+    }
+
+    #[test]
+    fn setup_with_remote_but_no_config() {
+        let tempdir = TempDir::new("project").ok().expect("TempDir failed");
+        let root = git_repo(&tempdir);
+        let url = git::delivery_ssh_url("adam", "delivery.example.com", "ent", "org", "widgets");
+        git::git_command(&["remote", "add", "delivery", &url], &root).unwrap();
+        let report = setup(&setup_config(), &root).unwrap();
+        assert_eq!(SetupState::AlreadyPresent, report.remote);
+        assert_eq!(SetupState::Created, report.config);
+        assert!(!report.nothing_to_do());
+    }
+
+    #[test]
+    fn setup_again_has_nothing_to_do() {
+        let tempdir = TempDir::new("project").ok().expect("TempDir failed");
+        let root = git_repo(&tempdir);
+        setup(&setup_config(), &root).unwrap();
+        let report = setup(&setup_config(), &root).unwrap();
+        assert_eq!(SetupState::AlreadyPresent, report.remote);
+        assert_eq!(SetupState::AlreadyPresent, report.config);
+        assert!(report.nothing_to_do());
+    }
+
+    #[test]
+    fn setup_updates_a_changed_config() {
+        let tempdir = TempDir::new("project").ok().expect("TempDir failed");
+        let root = git_repo(&tempdir);
+        setup(&setup_config(), &root).unwrap();
+        let report = setup(&setup_config().set_pipeline("release"), &root).unwrap();
+        assert_eq!(SetupState::AlreadyPresent, report.remote);
+        assert_eq!(SetupState::Updated, report.config);
+    }
+
+    #[test]
+    fn setup_leaves_another_remote_alone() {
+        let tempdir = TempDir::new("project").ok().expect("TempDir failed");
+        let root = git_repo(&tempdir);
+        git::git_command(&["remote", "add", "delivery", "ssh://elsewhere/repo"], &root).unwrap();
+        match setup(&setup_config(), &root) {
+            Err(e) => match e.kind {
+                Kind::GitSetupFailed => assert!(e.detail.unwrap().contains("ssh://elsewhere/repo")),
+                _ => panic!("Expected GitSetupFailed, got {:?}", e)
+            },
+            Ok(r) => panic!("Expected an error, got {:?}", r)
+        }
+        assert!(!root.join(".delivery").join("cli.toml").is_file());
+        assert_eq!(Some("ssh://elsewhere/repo".to_string()), git::delivery_remote_url(&root));
+    }
+
+    #[test]
+    fn setup_outside_git_skips_the_remote() {
+        let tempdir = TempDir::new("project").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        let report = setup(&setup_config(), &root).unwrap();
+        assert_eq!(SetupState::Skipped("not a git repository".to_string()), report.remote);
+        assert_eq!(SetupState::Created, report.config);
+    }
+}
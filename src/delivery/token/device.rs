@@ -0,0 +1,198 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Pending device codes for the OAuth device flow.
+//!
+//! Between asking for a device code and the user authorizing it, we
+//! only poll. If the user gives up on the terminal in the meantime,
+//! the next `delivery token` should pick up the same code rather than
+//! hand out a new one, so pending codes are kept in
+//! `~/.delivery/device-codes.json` until they expire or a token
+//! comes back for them.
+
+use errors::{DeliveryError, Kind};
+use rustc_serialize::json;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use utils;
+use utils::path_join_many::PathJoinMany;
+
+/// A device code the user hasn't authorized yet. `expires_at` is in
+/// seconds since the epoch.
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug, PartialEq)]
+pub struct PendingDeviceCode {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub interval: u64,
+    pub expires_at: i64
+}
+
+impl PendingDeviceCode {
+    pub fn is_expired(&self, now: i64) -> bool {
+        now >= self.expires_at
+    }
+}
+
+/// The pending codes, by `server,ent,user`, backed by a JSON file that
+/// is rewritten on every change.
+#[derive(Debug)]
+pub struct DeviceCodeStore {
+    codes: BTreeMap<String, PendingDeviceCode>,
+    path: PathBuf
+}
+
+impl DeviceCodeStore {
+    pub fn from_home() -> Result<DeviceCodeStore, DeliveryError> {
+        let home_dot_delivery = match utils::home_dir() {
+            Some(home) => home.join_many(&[".delivery"]),
+            None => return Err(DeliveryError{ kind: Kind::NoHomedir,
+                                              detail: Some("unable to find home dir".to_string()) })
+        };
+        try!(utils::mkdir_recursive(&home_dot_delivery));
+        DeviceCodeStore::from_file(&home_dot_delivery.join("device-codes.json"))
+    }
+
+    /// The store in `path`; a missing file has no codes in it.
+    pub fn from_file(path: &PathBuf) -> Result<DeviceCodeStore, DeliveryError> {
+        let mut codes = BTreeMap::new();
+        if path.is_file() {
+            let mut body = String::new();
+            try!(try!(File::open(path)).read_to_string(&mut body));
+            if !body.trim().is_empty() {
+                codes = try!(json::decode(&body));
+            }
+        }
+        Ok(DeviceCodeStore{ codes: codes, path: path.clone() })
+    }
+
+    pub fn lookup(&self, server: &str, ent: &str, user: &str) -> Option<&PendingDeviceCode> {
+        self.codes.get(&DeviceCodeStore::key(server, ent, user))
+    }
+
+    pub fn save(&mut self, server: &str, ent: &str, user: &str,
+                code: &PendingDeviceCode) -> Result<(), DeliveryError> {
+        self.codes.insert(DeviceCodeStore::key(server, ent, user), code.clone());
+        self.write()
+    }
+
+    /// Forget the code for `user`, once it has expired or a token has
+    /// come back for it. Returns the code that was removed.
+    pub fn discard(&mut self, server: &str, ent: &str,
+                   user: &str) -> Result<Option<PendingDeviceCode>, DeliveryError> {
+        let removed = self.codes.remove(&DeviceCodeStore::key(server, ent, user));
+        if removed.is_some() {
+            try!(self.write());
+        }
+        Ok(removed)
+    }
+
+    fn key(server: &str, ent: &str, user: &str) -> String {
+        format!("{},{},{}", server, ent, user)
+    }
+
+    fn write(&self) -> Result<(), DeliveryError> {
+        // A device code is as good as a token until it expires
+        let mut file = try!(File::create(&self.path));
+        try!(utils::chmod(&self.path, "0600"));
+        try!(file.write_all(try!(json::encode(&self.codes)).as_bytes()));
+        Ok(())
+    }
+}
+
+/// The device code to poll for `user`: the pending one, if it hasn't
+/// expired by `now`, or else a new one from `start`, which is saved
+/// for the next run. An expired code is discarded. The flag says
+/// whether the code was resumed.
+pub fn resume_or_start<F>(store: &mut DeviceCodeStore, server: &str, ent: &str, user: &str,
+                          now: i64, start: F) -> Result<(PendingDeviceCode, bool), DeliveryError>
+    where F: FnOnce() -> Result<PendingDeviceCode, DeliveryError> {
+    let pending = store.lookup(server, ent, user).map(|c| c.clone());
+    match pending {
+        Some(ref code) if !code.is_expired(now) => return Ok((code.clone(), true)),
+        Some(_) => {
+            debug!("Discarding the expired device code for {}", user);
+            try!(store.discard(server, ent, user));
+        },
+        None => {}
+    }
+    let code = try!(start());
+    try!(store.save(server, ent, user, &code));
+    Ok((code, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::{DeliveryError, Kind};
+    use tempdir::TempDir;
+
+    fn code(device_code: &str, expires_at: i64) -> PendingDeviceCode {
+        PendingDeviceCode{
+            device_code: device_code.to_string(),
+            user_code: "WDJB-MJHT".to_string(),
+            verification_uri: "https://delivery.example.com/device".to_string(),
+            interval: 5,
+            expires_at: expires_at
+        }
+    }
+
+    #[test]
+    fn resume_pending_code() {
+        let tempdir = TempDir::new("device-codes").ok().expect("TempDir failed");
+        let path = tempdir.path().join("device-codes.json");
+        let mut store = DeviceCodeStore::from_file(&path).unwrap();
+        let (first, resumed) = resume_or_start(&mut store, "delivery", "acme", "alice", 1000,
+                                               || Ok(code("first", 1900))).unwrap();
+        assert!(!resumed);
+        // The next run reads the code back from the file
+        let mut store = DeviceCodeStore::from_file(&path).unwrap();
+        let (again, resumed) = resume_or_start(&mut store, "delivery", "acme", "alice", 1500, || {
+            Err(DeliveryError{ kind: Kind::AuthenticationFailed, detail: Some("asked for a new code".to_string()) })
+        }).unwrap();
+        assert!(resumed);
+        assert_eq!(first, again);
+        assert!(store.lookup("delivery", "acme", "bob").is_none());
+    }
+
+    #[test]
+    fn discard_expired_code() {
+        let tempdir = TempDir::new("device-codes").ok().expect("TempDir failed");
+        let path = tempdir.path().join("device-codes.json");
+        let mut store = DeviceCodeStore::from_file(&path).unwrap();
+        store.save("delivery", "acme", "alice", &code("stale", 1000)).unwrap();
+        let (fresh, resumed) = resume_or_start(&mut store, "delivery", "acme", "alice", 1000,
+                                               || Ok(code("fresh", 1900))).unwrap();
+        assert!(!resumed);
+        assert_eq!("fresh", fresh.device_code);
+        let store = DeviceCodeStore::from_file(&path).unwrap();
+        assert_eq!(Some(&code("fresh", 1900)), store.lookup("delivery", "acme", "alice"));
+    }
+
+    #[test]
+    fn discard_after_token() {
+        let tempdir = TempDir::new("device-codes").ok().expect("TempDir failed");
+        let path = tempdir.path().join("device-codes.json");
+        let mut store = DeviceCodeStore::from_file(&path).unwrap();
+        store.save("delivery", "acme", "alice", &code("done", 1900)).unwrap();
+        assert_eq!(Some(code("done", 1900)), store.discard("delivery", "acme", "alice").unwrap());
+        assert!(DeviceCodeStore::from_file(&path).unwrap().lookup("delivery", "acme", "alice").is_none());
+    }
+}
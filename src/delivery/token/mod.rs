@@ -22,7 +22,18 @@
 //! The `TokenStore` manages a map of keys to tokens and a path to the
 //! backing file. Adding or updating a token is done via `write_token`
 //! and will immediately rewrite the backing file. Find an existing
-//! token using `lookup`.
+//! token using `lookup`, or `read_token` when a missing one is an
+//! error. The module-level `write_token` and `read_token` do the same
+//! against the store in `~/.delivery/api-tokens`. `ensure_valid` is
+//! where API callers get a token: `DELIVERY_TOKEN`, or else the cached
+//! one, replaced when the server no longer accepts it. Only one
+//! process refreshes the tokens for a server at a time; see
+//! `refresh_token`.
+//!
+//! The file starts with a `#version N` line giving its format. Files
+//! from before there was a version are format 1, and are rewritten in
+//! the current format when read; a file from a newer format is
+//! `Kind::TokenCacheVersionMismatch`.
 //!
 use std::io::prelude::*;
 use std::io::BufReader;
@@ -30,9 +41,162 @@ use std::path::PathBuf;
 use std::fs::{File, OpenOptions};
 use std::collections::BTreeMap;
 use std::env;
+use config::Config;
 use errors::{DeliveryError, Kind};
+use http::{self, APIClient, APIAuth};
+use hyper::status::StatusCode;
 use utils;
+use utils::lock;
 use utils::path_join_many::PathJoinMany;
+use self::device::DeviceCodeStore;
+
+pub mod device;
+
+/// The format of the token cache file we write.
+pub const TOKEN_CACHE_VERSION: u32 = 2;
+
+/// How long to wait for another process refreshing a token for the
+/// same server before giving up.
+pub const REFRESH_WAIT_MS: u64 = 30000;
+
+/// Save `token` for `user` in the store in `~/.delivery/api-tokens`,
+/// replacing any token we had for them. Returns the path written.
+pub fn write_token(server: &str, ent: &str, user: &str,
+                   token: &str) -> Result<PathBuf, DeliveryError> {
+    let mut tstore = try!(TokenStore::from_home());
+    try!(tstore.write_token(server, ent, user, token));
+    Ok(tstore.path())
+}
+
+/// The token saved for `user` in `~/.delivery/api-tokens`, or
+/// `Kind::NoToken` if there isn't one.
+pub fn read_token(server: &str, ent: &str, user: &str) -> Result<String, DeliveryError> {
+    let tstore = try!(TokenStore::from_home());
+    tstore.read_token(server, ent, user)
+}
+
+/// The environment variable a token can be given in, for CI jobs
+/// that shouldn't keep one on disk.
+pub const TOKEN_ENV: &'static str = "DELIVERY_TOKEN";
+
+/// The token in `env_token`, the value of `DELIVERY_TOKEN`, unless it
+/// is empty.
+fn given_token(env_token: Option<String>) -> Option<String> {
+    match env_token {
+        Some(ref t) if !t.trim().is_empty() => Some(t.trim().to_string()),
+        _ => None
+    }
+}
+
+/// Authenticate `client`, made for the server in `config`, as `user`,
+/// with the token in `~/.delivery/api-tokens` as long as the server
+/// still accepts it. A missing token, or one the server turns away
+/// with a 401, is replaced by requesting a new one with a password we
+/// prompt for. `DELIVERY_TOKEN` wins over the store; it is used as it
+/// is, as it isn't ours to replace.
+///
+/// The store only keeps the token itself, not when it was issued, so
+/// the only way to tell it has expired is to try it.
+pub fn ensure_valid(client: &mut APIClient, config: &Config,
+                    server: &str, ent: &str, user: &str) -> Result<String, DeliveryError> {
+    match given_token(env::var(TOKEN_ENV).ok()) {
+        Some(t) => {
+            client.set_auth(APIAuth::new(user, &t));
+            return Ok(t);
+        },
+        None => {}
+    }
+    let mut tstore = try!(TokenStore::from_home());
+    let opts = http::token::TokenOptions::from_config(config);
+    ensure_valid_with(client, &mut tstore, server, ent, user, &opts,
+                      || http::token::prompt_password())
+}
+
+/// Like `ensure_valid`, against `tstore`, asking `pass` for the
+/// password only if a new token is needed. On success `client` is
+/// authenticated with the token returned.
+pub fn ensure_valid_with<F>(client: &mut APIClient, tstore: &mut TokenStore,
+                            server: &str, ent: &str, user: &str,
+                            opts: &http::token::TokenOptions,
+                            pass: F) -> Result<String, DeliveryError>
+    where F: FnOnce() -> Result<String, DeliveryError> {
+    let cached = tstore.lookup(server, ent, user).map(|t| t.clone());
+    if let Some(ref token) = cached {
+        client.set_auth(APIAuth::new(user, token));
+        // Listing orgs is about the cheapest thing that needs a token
+        let probe = try!(client.get("orgs"));
+        if probe.status != StatusCode::Unauthorized {
+            return Ok(token.clone());
+        }
+        debug!("Token for {} on {} was refused; requesting a new one", user, server);
+    }
+    // Only once: a token the server just minted isn't probed again
+    let stale = cached.as_ref().map(|t| &t[..]);
+    refresh_token(client, tstore, server, ent, user, opts, pass, stale)
+}
+
+/// Replace `stale`, the token for `user` the server refused (if we
+/// had one), with a new one requested with the password from `pass`;
+/// or, when `opts` has a device endpoint, with the device flow, picking
+/// up a code still pending in `device-codes.json` next to the store.
+///
+/// Several commands run at once can all find the token expired, so a
+/// refresh holds a lock per server next to the store. Whoever gets it
+/// second waits, then finds the token the first one saved and uses
+/// that instead of asking the server again.
+pub fn refresh_token<F>(client: &mut APIClient, tstore: &mut TokenStore,
+                        server: &str, ent: &str, user: &str,
+                        opts: &http::token::TokenOptions, pass: F,
+                        stale: Option<&str>) -> Result<String, DeliveryError>
+    where F: FnOnce() -> Result<String, DeliveryError> {
+    let dir = tstore.path().parent().map(|p| p.to_path_buf()).unwrap_or(PathBuf::from("."));
+    let _lock = try!(lock::wait_for(&dir, &refresh_lock_name(server), REFRESH_WAIT_MS));
+    let path = tstore.path();
+    *tstore = try!(TokenStore::from_file(&path));
+    let current = tstore.lookup(server, ent, user).map(|t| t.clone());
+    let token = match current {
+        Some(ref t) if Some(&t[..]) != stale => {
+            debug!("Using the token for {} on {} another process just saved", user, server);
+            t.clone()
+        },
+        _ => {
+            let t = match opts.device_endpoint {
+                Some(ref endpoint) => {
+                    let mut codes = try!(DeviceCodeStore::from_file(&dir.join("device-codes.json")));
+                    try!(http::token::request_with_device_code(client, &mut codes, server, ent, user,
+                                                               endpoint, opts))
+                },
+                None => {
+                    let password = try!(pass());
+                    try!(http::token::request_with_client(client, user, &password, opts))
+                }
+            };
+            try!(tstore.write_token(server, ent, user, &t));
+            t
+        }
+    };
+    client.set_auth(APIAuth::new(user, &token));
+    Ok(token)
+}
+
+/// The lock for refreshing tokens for `server`; anything but letters,
+/// numbers, '.' and '-' becomes '_' so it makes a file name.
+fn refresh_lock_name(server: &str) -> String {
+    let safe: String = server.chars()
+        .map(|c| if c.is_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect();
+    format!(".refresh-{}.lock", safe)
+}
+
+/// What `TokenStore::import` did: how many tokens were new, how many
+/// replaced one we had, and the keys left alone because they
+/// conflicted.
+#[derive(Debug)]
+pub struct ImportSummary {
+    pub added: usize,
+    pub replaced: usize,
+    pub skipped: Vec<String>
+}
 
 #[derive(Debug)]
 pub struct TokenStore {
@@ -42,22 +206,31 @@ pub struct TokenStore {
 
 impl TokenStore {
     pub fn from_home() -> Result<TokenStore, DeliveryError> {
-        let home_dot_delivery = match env::home_dir() {
-            Some(home) => home.join_many(&[".delivery"]),
+        match utils::home_dir() {
+            Some(home) => TokenStore::from_home_dir(&home),
             None => {
                 let msg = "unable to find home dir".to_string();
-                return Err(DeliveryError{ kind: Kind::NoHomedir,
-                                          detail: Some(msg) })
+                Err(DeliveryError{ kind: Kind::NoHomedir,
+                                   detail: Some(msg) })
             }
-        };
+        }
+    }
+
+    /// The store in `.delivery/api-tokens` under `home`.
+    pub fn from_home_dir(home: &PathBuf) -> Result<TokenStore, DeliveryError> {
+        let home_dot_delivery = home.join_many(&[".delivery"]);
         try!(utils::mkdir_recursive(&home_dot_delivery));
         let token_path = home_dot_delivery.join_many(&["api-tokens"]);
         TokenStore::from_file(&token_path)
     }
 
     pub fn from_file(path: &PathBuf) -> Result<TokenStore, DeliveryError> {
-        let tokens = try!(TokenStore::read_config(&path));
+        let (version, tokens) = try!(TokenStore::read_config(&path));
         let tstore = TokenStore {path: path.clone(), tokens: tokens};
+        if version < TOKEN_CACHE_VERSION {
+            debug!("Migrating {} from token cache format {}", path.display(), version);
+            try!(tstore.write_config());
+        }
         Ok(tstore)
     }
 
@@ -71,6 +244,18 @@ impl TokenStore {
         self.tokens.get(&key)
     }
 
+    /// Like `lookup`, but a missing token is `Kind::NoToken`.
+    pub fn read_token(&self,
+                      server: &str, ent: &str, user: &str) -> Result<String, DeliveryError> {
+        match self.lookup(server, ent, user) {
+            Some(token) => Ok(token.clone()),
+            None => Err(DeliveryError{
+                kind: Kind::NoToken,
+                detail: Some(format!("server: {}, ent: {}, user: {}", server, ent, user))
+            })
+        }
+    }
+
     pub fn write_token(&mut self,
                        server: &str,
                        ent: &str,
@@ -84,6 +269,74 @@ impl TokenStore {
         }
     }
 
+    /// Forget the token for `user`, rewriting the backing file.
+    /// Returns the token that was removed, if there was one.
+    pub fn remove_token(&mut self,
+                        server: &str,
+                        ent: &str,
+                        user: &str) -> Result<Option<String>, DeliveryError> {
+        let key = TokenStore::key(server, ent, user);
+        let result = self.tokens.remove(&key);
+        try!(self.write_config());
+        Ok(result)
+    }
+
+    /// Write every token to `dest`, in the same format as the store,
+    /// readable only by the owner. Returns how many were written.
+    pub fn export(&self, dest: &PathBuf) -> Result<usize, DeliveryError> {
+        try!(TokenStore::write_tokens(&self.tokens, dest));
+        Ok(self.tokens.len())
+    }
+
+    /// Load the tokens exported to `src`. Every entry is checked
+    /// first, and a malformed one is `Kind::InvalidTokenEntry` with
+    /// nothing imported. A token for a server, enterprise and user we
+    /// already have is replaced with `overwrite`, and skipped without.
+    pub fn import(&mut self, src: &PathBuf,
+                  overwrite: bool) -> Result<ImportSummary, DeliveryError> {
+        let mut content = String::new();
+        try!(try!(File::open(src)).read_to_string(&mut content));
+        let mut entries = Vec::new();
+        for (n, line) in content.lines_any().enumerate() {
+            if line.trim().is_empty() || line.starts_with("#version") {
+                continue;
+            }
+            match TokenStore::parse_entry(line) {
+                Some(entry) => entries.push(entry),
+                None => return Err(DeliveryError{
+                    kind: Kind::InvalidTokenEntry,
+                    detail: Some(format!("{}: line {} is not server,enterprise,user|token", src.display(), n + 1))
+                })
+            }
+        }
+        let mut summary = ImportSummary { added: 0, replaced: 0, skipped: Vec::new() };
+        for (key, token) in entries.into_iter() {
+            let existing = self.tokens.get(&key).map(|t| t.clone());
+            match existing {
+                None => summary.added += 1,
+                Some(ref t) if *t == token => continue,
+                Some(_) if overwrite => summary.replaced += 1,
+                Some(_) => { summary.skipped.push(key); continue; }
+            }
+            self.tokens.insert(key, token);
+        }
+        try!(self.write_config());
+        Ok(summary)
+    }
+
+    /// A `server,ent,user|token` line, if every part is there.
+    fn parse_entry(line: &str) -> Option<(String, String)> {
+        let items: Vec<&str> = line.trim().split("|").collect();
+        if items.len() != 2 || items[1].is_empty() {
+            return None;
+        }
+        let key_parts: Vec<&str> = items[0].split(",").collect();
+        if key_parts.len() != 3 || key_parts.iter().any(|p| p.is_empty()) {
+            return None;
+        }
+        Some((items[0].to_string(), items[1].to_string()))
+    }
+
     fn key(server: &str, ent: &str, user: &str) -> String {
         format!("{},{},{}", server, ent, user)
     }
@@ -96,15 +349,26 @@ impl TokenStore {
     }
 
     fn write_config(&self) -> Result<(), DeliveryError> {
-        let mut file = try!(File::create(&self.path));
-        for (k, v) in self.tokens.iter() {
+        TokenStore::write_tokens(&self.tokens, &self.path)
+    }
+
+    fn write_tokens(tokens: &BTreeMap<String, String>,
+                    path: &PathBuf) -> Result<(), DeliveryError> {
+        // Create it empty and lock it down before any token goes in
+        let mut file = try!(File::create(path));
+        try!(utils::chmod(path, "0600"));
+        try!(file.write_all(format!("#version {}\n", TOKEN_CACHE_VERSION).as_bytes()));
+        for (k, v) in tokens.iter() {
             let line = format!("{}|{}\n", k, v);
             try!(file.write_all(line.as_bytes()));
         }
         Ok(())
     }
 
-    fn read_config(path: &PathBuf) -> Result<BTreeMap<String, String>, DeliveryError> {
+    /// The format version and tokens in the file at `path`, which is
+    /// created if it doesn't exist. An empty file is the current
+    /// format.
+    fn read_config(path: &PathBuf) -> Result<(u32, BTreeMap<String, String>), DeliveryError> {
         let mut opener = OpenOptions::new();
         opener.create(true);
         opener.truncate(false);
@@ -113,9 +377,17 @@ impl TokenStore {
         let file = try!(opener.open(&path));
         let reader = BufReader::new(file);
         let mut map: BTreeMap<String, String> = BTreeMap::new();
+        let mut version = None;
 
         for line in reader.lines() {
             let real_line = try!(line);
+            if version.is_none() {
+                let found = try!(TokenStore::parse_version(&real_line, path));
+                version = Some(found.unwrap_or(1));
+                if found.is_some() {
+                    continue;
+                }
+            }
             let split = real_line.trim().split("|");
             let items = split.collect::<Vec<&str>>();
             if items.len() == 2 {
@@ -126,7 +398,24 @@ impl TokenStore {
                 println!("skipping malformed line: {}", real_line);
             }
         }
-        Ok(map)
+        Ok((version.unwrap_or(TOKEN_CACHE_VERSION), map))
+    }
+
+    /// The version in a `#version N` header line, or None if `line`
+    /// isn't one. A version newer than we know, or one we can't read,
+    /// is `Kind::TokenCacheVersionMismatch`.
+    fn parse_version(line: &str, path: &PathBuf) -> Result<Option<u32>, DeliveryError> {
+        if !line.starts_with("#version") {
+            return Ok(None);
+        }
+        match line["#version".len()..].trim().parse::<u32>() {
+            Ok(v) if v <= TOKEN_CACHE_VERSION => Ok(Some(v)),
+            _ => Err(DeliveryError{
+                kind: Kind::TokenCacheVersionMismatch,
+                detail: Some(format!("{} is '{}', but this delivery only reads up to #version {}; upgrade delivery or remove the file",
+                                     path.display(), line.trim(), TOKEN_CACHE_VERSION))
+            })
+        }
     }
 
 }
@@ -134,9 +423,19 @@ impl TokenStore {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::given_token;
+    use errors::Kind;
+    use http::APIClient;
+    use http::token::TokenOptions;
+    use http::mock::{self, MockServer};
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use utils::lock;
     use std::io::prelude::*;
-    use std::fs::File;
+    use std::fs::{self, File};
     use tempdir::TempDir;
+    use time;
+    use token::device::{DeviceCodeStore, PendingDeviceCode};
     use utils::path_join_many::PathJoinMany;
 
      #[test]
@@ -158,6 +457,288 @@ mod tests {
         let mut f = File::open(&tfile).ok().expect("tfile open error");
         let mut content = String::new();
         assert_eq!(true, f.read_to_string(&mut content).is_ok());
-        assert_eq!("127.0.0.1,acme,bob|beefbeef\n", content);
+        assert_eq!("#version 2\n127.0.0.1,acme,bob|beefbeef\n", content);
+    }
+
+    #[test]
+    fn remove_token_test() {
+        let tempdir = TempDir::new("t1").ok().expect("TempDir failed");
+        let tfile = tempdir.path().join_many(&["api-tokens"]);
+        let mut tstore = TokenStore::from_file(&tfile).ok().expect("no create");
+        tstore.write_token("127.0.0.1", "acme", "bob", "beefbeef").unwrap();
+        tstore.write_token("127.0.0.1", "acme", "alice", "cafecafe").unwrap();
+        assert_eq!(Some("beefbeef".to_string()),
+                   tstore.remove_token("127.0.0.1", "acme", "bob").unwrap());
+        assert_eq!(None, tstore.lookup("127.0.0.1", "acme", "bob"));
+        let mut content = String::new();
+        File::open(&tfile).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!("#version 2\n127.0.0.1,acme,alice|cafecafe\n", content);
+        assert_eq!(None, tstore.remove_token("127.0.0.1", "acme", "bob").unwrap());
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let tempdir = TempDir::new("t1").ok().expect("TempDir failed");
+        let mut old = TokenStore::from_file(&tempdir.path().join("old-tokens")).unwrap();
+        old.write_token("127.0.0.1", "acme", "bob", "beefbeef").unwrap();
+        old.write_token("delivery.example.com", "acme", "alice", "cafecafe").unwrap();
+        let export = tempdir.path().join("export");
+        assert_eq!(2, old.export(&export).unwrap());
+
+        let mut new = TokenStore::from_file(&tempdir.path().join("new-tokens")).unwrap();
+        let summary = new.import(&export, false).unwrap();
+        assert_eq!(2, summary.added);
+        assert_eq!(&"beefbeef", new.lookup("127.0.0.1", "acme", "bob").unwrap());
+        assert_eq!(&"cafecafe", new.lookup("delivery.example.com", "acme", "alice").unwrap());
+        let reloaded = TokenStore::from_file(&new.path()).unwrap();
+        assert_eq!(&"cafecafe", reloaded.lookup("delivery.example.com", "acme", "alice").unwrap());
+    }
+
+    #[test]
+    fn import_conflicts() {
+        let tempdir = TempDir::new("t1").ok().expect("TempDir failed");
+        let export = tempdir.path().join("export");
+        File::create(&export).unwrap().write_all(b"127.0.0.1,acme,bob|newtoken\n").unwrap();
+        let mut tstore = TokenStore::from_file(&tempdir.path().join("api-tokens")).unwrap();
+        tstore.write_token("127.0.0.1", "acme", "bob", "beefbeef").unwrap();
+
+        let summary = tstore.import(&export, false).unwrap();
+        assert_eq!(vec!["127.0.0.1,acme,bob".to_string()], summary.skipped);
+        assert_eq!(&"beefbeef", tstore.lookup("127.0.0.1", "acme", "bob").unwrap());
+
+        let summary = tstore.import(&export, true).unwrap();
+        assert_eq!(1, summary.replaced);
+        assert_eq!(&"newtoken", tstore.lookup("127.0.0.1", "acme", "bob").unwrap());
+    }
+
+    #[test]
+    fn import_rejects_malformed_entries() {
+        let tempdir = TempDir::new("t1").ok().expect("TempDir failed");
+        let export = tempdir.path().join("export");
+        File::create(&export).unwrap().write_all(b"127.0.0.1,acme,bob|beefbeef\nnot a token\n").unwrap();
+        let mut tstore = TokenStore::from_file(&tempdir.path().join("api-tokens")).unwrap();
+        match tstore.import(&export, false) {
+            Err(e) => match e.kind {
+                Kind::InvalidTokenEntry => assert!(e.detail.unwrap().ends_with("line 2 is not server,enterprise,user|token")),
+                _ => panic!("Expected InvalidTokenEntry, got {:?}", e)
+            },
+            Ok(s) => panic!("Expected the import to fail, got {:?}", s)
+        }
+        assert_eq!(None, tstore.lookup("127.0.0.1", "acme", "bob"));
+    }
+
+    #[test]
+    fn read_current_version() {
+        let tempdir = TempDir::new("t1").ok().expect("TempDir failed");
+        let tfile = tempdir.path().join("api-tokens");
+        File::create(&tfile).unwrap().write_all(b"#version 2\n127.0.0.1,acme,bob|beefbeef\n").unwrap();
+        let tstore = TokenStore::from_file(&tfile).unwrap();
+        assert_eq!(&"beefbeef", tstore.lookup("127.0.0.1", "acme", "bob").unwrap());
+    }
+
+    #[test]
+    fn read_older_version_migrates() {
+        let tempdir = TempDir::new("t1").ok().expect("TempDir failed");
+        let tfile = tempdir.path().join("api-tokens");
+        File::create(&tfile).unwrap().write_all(b"127.0.0.1,acme,bob|beefbeef\n").unwrap();
+        let tstore = TokenStore::from_file(&tfile).unwrap();
+        assert_eq!(&"beefbeef", tstore.lookup("127.0.0.1", "acme", "bob").unwrap());
+        let mut content = String::new();
+        File::open(&tfile).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!("#version 2\n127.0.0.1,acme,bob|beefbeef\n", content);
+    }
+
+    #[test]
+    fn read_newer_version_rejected() {
+        let tempdir = TempDir::new("t1").ok().expect("TempDir failed");
+        let tfile = tempdir.path().join("api-tokens");
+        File::create(&tfile).unwrap().write_all(b"#version 3\n{\"tokens\":[]}\n").unwrap();
+        match TokenStore::from_file(&tfile) {
+            Err(e) => match e.kind {
+                Kind::TokenCacheVersionMismatch => assert!(e.detail.unwrap().contains("'#version 3'")),
+                _ => panic!("Expected TokenCacheVersionMismatch, got {:?}", e)
+            },
+            Ok(t) => panic!("Expected a newer cache to be rejected, got {:?}", t)
+        }
+        // and left alone
+        let mut content = String::new();
+        File::open(&tfile).unwrap().read_to_string(&mut content).unwrap();
+        assert!(content.starts_with("#version 3"));
+    }
+
+    #[test]
+    fn read_token_missing_is_no_token() {
+        let tempdir = TempDir::new("t1").ok().expect("TempDir failed");
+        let tstore = TokenStore::from_file(&tempdir.path().join("api-tokens")).unwrap();
+        match tstore.read_token("127.0.0.1", "acme", "bob") {
+            Err(e) => match e.kind {
+                Kind::NoToken => assert!(e.detail.unwrap().contains("user: bob")),
+                _ => panic!("Expected NoToken, got {:?}", e)
+            },
+            Ok(t) => panic!("Expected no token, got {}", t)
+        }
+    }
+
+    #[test]
+    fn write_token_twice_overwrites() {
+        let tempdir = TempDir::new("t1").ok().expect("TempDir failed");
+        let tfile = tempdir.path().join("api-tokens");
+        let mut tstore = TokenStore::from_file(&tfile).unwrap();
+        tstore.write_token("127.0.0.1", "acme", "bob", "beefbeef").unwrap();
+        tstore.write_token("127.0.0.1", "acme", "bob", "cafecafe").unwrap();
+        let reloaded = TokenStore::from_file(&tfile).unwrap();
+        assert_eq!("cafecafe", reloaded.read_token("127.0.0.1", "acme", "bob").unwrap());
+        let mut content = String::new();
+        File::open(&tfile).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!("#version 2\n127.0.0.1,acme,bob|cafecafe\n", content);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn write_token_is_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let tempdir = TempDir::new("t1").ok().expect("TempDir failed");
+        let tfile = tempdir.path().join("api-tokens");
+        let mut tstore = TokenStore::from_file(&tfile).unwrap();
+        tstore.write_token("127.0.0.1", "acme", "bob", "beefbeef").unwrap();
+        let mode = fs::metadata(&tfile).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+    }
+
+    #[test]
+    fn ensure_valid_with_cached_token_still_valid_test() {
+        let tempdir = TempDir::new("t1").ok().expect("TempDir failed");
+        let server = MockServer::start(vec![mock::response("200 OK", "[]")]);
+        let mut tstore = TokenStore::from_file(&tempdir.path().join("api-tokens")).unwrap();
+        tstore.write_token(&server.addr(), "acme", "alice", "beefbeef").unwrap();
+        let mut client = APIClient::new_http(&server.addr(), "acme");
+        let token = ensure_valid_with(&mut client, &mut tstore, &server.addr(),
+                                      "acme", "alice", &TokenOptions::default(),
+                                      || panic!("A valid token needs no password")).unwrap();
+        assert_eq!("beefbeef", token);
+        let requests = server.requests();
+        assert_eq!(1, requests.len());
+        assert!(requests[0].starts_with("GET /api/v0/e/acme/orgs HTTP/1.1"));
+        assert!(requests[0].contains("chef-delivery-token: beefbeef"));
+    }
+
+    #[test]
+    fn ensure_valid_with_expired_token_refreshes_test() {
+        let tempdir = TempDir::new("t1").ok().expect("TempDir failed");
+        let server = MockServer::start(vec![mock::response("401 Unauthorized", ""),
+                                            mock::response("200 OK", "{\"token\":\"cafecafe\"}")]);
+        let tfile = tempdir.path().join("api-tokens");
+        let mut tstore = TokenStore::from_file(&tfile).unwrap();
+        tstore.write_token(&server.addr(), "acme", "alice", "beefbeef").unwrap();
+        let mut client = APIClient::new_http(&server.addr(), "acme");
+        let token = ensure_valid_with(&mut client, &mut tstore, &server.addr(),
+                                      "acme", "alice", &TokenOptions::default(),
+                                      || Ok("sesame123".to_string())).unwrap();
+        assert_eq!("cafecafe", token);
+        let requests = server.requests();
+        assert_eq!(2, requests.len());
+        assert!(requests[1].starts_with("POST /api/v0/e/acme/users/alice/get-token"));
+        let reread = TokenStore::from_file(&tfile).unwrap();
+        assert_eq!("cafecafe", reread.read_token(&server.addr(), "acme", "alice").unwrap());
+    }
+
+    #[test]
+    fn ensure_valid_with_device_flow_resumes_pending_code_test() {
+        let tempdir = TempDir::new("t1").ok().expect("TempDir failed");
+        let server = MockServer::start(vec![mock::response("401 Unauthorized", ""),
+                                            mock::response("200 OK", "{\"token\":\"cafecafe\"}")]);
+        let tfile = tempdir.path().join("api-tokens");
+        let mut tstore = TokenStore::from_file(&tfile).unwrap();
+        tstore.write_token(&server.addr(), "acme", "alice", "beefbeef").unwrap();
+        let codes_file = tempdir.path().join("device-codes.json");
+        let pending = PendingDeviceCode{
+            device_code: "pending".to_string(),
+            user_code: "WDJB-MJHT".to_string(),
+            verification_uri: "https://delivery.example.com/device".to_string(),
+            interval: 0,
+            expires_at: time::get_time().sec + 600
+        };
+        DeviceCodeStore::from_file(&codes_file).unwrap()
+            .save(&server.addr(), "acme", "alice", &pending).unwrap();
+        let opts = TokenOptions{ device_endpoint: Some("/device".to_string()),
+                                 ..TokenOptions::default() };
+        let mut client = APIClient::new_http(&server.addr(), "acme");
+        let token = ensure_valid_with(&mut client, &mut tstore, &server.addr(),
+                                      "acme", "alice", &opts,
+                                      || panic!("The device flow needs no password")).unwrap();
+        assert_eq!("cafecafe", token);
+        let requests = server.requests();
+        assert_eq!(2, requests.len());
+        assert!(requests[1].starts_with("POST /api/v0/e/acme/users/alice/get-token"));
+        assert!(requests[1].contains("\"device_code\":\"pending\""));
+        assert!(DeviceCodeStore::from_file(&codes_file).unwrap()
+                .lookup(&server.addr(), "acme", "alice").is_none());
+        let reread = TokenStore::from_file(&tfile).unwrap();
+        assert_eq!("cafecafe", reread.read_token(&server.addr(), "acme", "alice").unwrap());
+    }
+
+    #[test]
+    fn refresh_token_waits_for_another_refresh_test() {
+        let tempdir = TempDir::new("t1").ok().expect("TempDir failed");
+        let server = MockServer::start(vec![]);
+        let tfile = tempdir.path().join("api-tokens");
+        let mut tstore = TokenStore::from_file(&tfile).unwrap();
+        tstore.write_token(&server.addr(), "acme", "alice", "beefbeef").unwrap();
+        // Another process is part way through refreshing
+        let held = lock::acquire_named(&tempdir.path().to_path_buf(),
+                                       &super::refresh_lock_name(&server.addr()), false).unwrap();
+        let addr = server.addr();
+        let waiting_file = tfile.clone();
+        let waiter = thread::spawn(move || {
+            let mut tstore = TokenStore::from_file(&waiting_file).unwrap();
+            let mut client = APIClient::new_http(&addr, "acme");
+            refresh_token(&mut client, &mut tstore, &addr, "acme", "alice",
+                          &TokenOptions::default(), || Ok("sesame123".to_string()), Some("beefbeef"))
+        });
+        thread::sleep(::std::time::duration::Duration::milliseconds(100));
+        tstore.write_token(&server.addr(), "acme", "alice", "cafecafe").unwrap();
+        drop(held);
+        assert_eq!("cafecafe", waiter.join().unwrap().unwrap());
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn concurrent_refreshes_request_one_token_test() {
+        let tempdir = TempDir::new("t1").ok().expect("TempDir failed");
+        let server = MockServer::start(vec![mock::response("200 OK", "{\"token\":\"cafecafe\"}")]);
+        let tfile = tempdir.path().join("api-tokens");
+        TokenStore::from_file(&tfile).unwrap()
+            .write_token(&server.addr(), "acme", "alice", "beefbeef").unwrap();
+        let start = Arc::new(Barrier::new(4));
+        let workers: Vec<_> = (0..4).map(|_| {
+            let addr = server.addr();
+            let file = tfile.clone();
+            let start = start.clone();
+            thread::spawn(move || {
+                let mut tstore = TokenStore::from_file(&file).unwrap();
+                let mut client = APIClient::new_http(&addr, "acme");
+                start.wait();
+                refresh_token(&mut client, &mut tstore, &addr, "acme", "alice",
+                              &TokenOptions::default(), || Ok("sesame123".to_string()), Some("beefbeef"))
+            })
+        }).collect();
+        for w in workers.into_iter() {
+            assert_eq!("cafecafe", w.join().unwrap().unwrap());
+        }
+        let requests = server.requests();
+        assert_eq!(1, requests.len());
+        assert!(requests[0].starts_with("POST /api/v0/e/acme/users/alice/get-token"));
+    }
+
+    #[test]
+    fn given_token_prefers_env_token() {
+        assert_eq!(Some("envtoken".to_string()), given_token(Some(" envtoken\n".to_string())));
+    }
+
+    #[test]
+    fn given_token_ignores_empty_env() {
+        for empty in [None, Some("".to_string()), Some(" \n".to_string())].iter() {
+            assert_eq!(None, given_token(empty.clone()));
+        }
     }
 }
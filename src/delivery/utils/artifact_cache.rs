@@ -0,0 +1,293 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! A local cache of downloaded cookbook artifacts, shared by every job
+//! on the machine.
+//!
+//! Entries are keyed on where the artifact came from, its version and
+//! its checksum, so the same key always means the same bytes. Each
+//! entry is a directory holding the `artifact` and a `stamp` with the
+//! last time it was used; entries unused for longer than the TTL are
+//! evicted, and then the least recently used ones until the cache fits
+//! in its size limit. Fetches take a lock on the cache, so two jobs
+//! can't fill or evict the same entry at once.
+
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use time;
+use errors::{DeliveryError, Kind};
+use utils;
+use utils::lock;
+use utils::path_join_many::PathJoinMany;
+
+/// Entries unused for a week are evicted.
+pub const DEFAULT_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Keep the whole cache under 1GB.
+pub const DEFAULT_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// The lock a fetch holds, in the cache directory; `evict` leaves it
+/// alone.
+pub const LOCK_NAME: &'static str = ".fetch.lock";
+
+/// How long to wait for another job's fetch, which may be downloading
+/// a large cookbook.
+pub const LOCK_WAIT_MS: u64 = 10 * 60 * 1000;
+
+#[derive(Debug)]
+pub struct ArtifactCache {
+    pub dir: PathBuf,
+    pub ttl_secs: i64,
+    pub max_bytes: u64
+}
+
+impl ArtifactCache {
+    pub fn new(dir: &PathBuf) -> ArtifactCache {
+        ArtifactCache {
+            dir: dir.clone(),
+            ttl_secs: DEFAULT_TTL_SECS,
+            max_bytes: DEFAULT_MAX_BYTES
+        }
+    }
+
+    /// `~/.delivery/cache/artifacts`, if we have a home directory.
+    pub fn default_dir() -> Option<PathBuf> {
+        utils::home_dir().map(|h| h.join_many(&[".delivery", "cache", "artifacts"]))
+    }
+
+    /// The cache key for an artifact. Each part is reduced to
+    /// characters that are safe in a file name.
+    pub fn key(source: &str, name: &str, version: &str, checksum: &str) -> String {
+        let parts: Vec<String> = [source, name, version, checksum].iter()
+            .map(|p| sanitize(p))
+            .collect();
+        parts.connect("_")
+    }
+
+    /// Where the artifact for `key` lives, if it is cached and fresh.
+    pub fn lookup(&self, key: &str, now: i64) -> Option<PathBuf> {
+        let entry = self.dir.join(key);
+        let artifact = entry.join("artifact");
+        if fs::metadata(&artifact).is_err() {
+            return None;
+        }
+        match read_stamp(&entry) {
+            Some(stamp) if now - stamp <= self.ttl_secs => Some(artifact),
+            _ => None
+        }
+    }
+
+    /// Copy the artifact for `key` to `dest`, calling `download` to
+    /// fill the cache first if it is missing. Returns true if the
+    /// cache already had it. The cache is locked throughout.
+    pub fn fetch<F>(&self, key: &str, dest: &PathBuf, download: F) -> Result<bool, DeliveryError>
+        where F: FnOnce(&PathBuf) -> Result<(), DeliveryError>
+    {
+        try!(utils::mkdir_recursive(&self.dir));
+        let _lock = try!(lock::wait_for(&self.dir, LOCK_NAME, LOCK_WAIT_MS));
+        let now = time::get_time().sec;
+        let entry = self.dir.join(key);
+        let hit = match self.lookup(key, now) {
+            Some(_) => true,
+            None => {
+                try!(utils::mkdir_recursive(&entry));
+                try!(download(&entry.join("artifact")));
+                false
+            }
+        };
+        try!(cache_io(fs::copy(&entry.join("artifact"), dest), dest));
+        try!(write_stamp(&entry, now));
+        try!(self.evict(now));
+        Ok(hit)
+    }
+
+    /// Remove entries older than the TTL, then the least recently used
+    /// ones until the cache is no bigger than `max_bytes`.
+    pub fn evict(&self, now: i64) -> Result<(), DeliveryError> {
+        let listing = match fs::read_dir(&self.dir) {
+            Ok(l) => l,
+            Err(_) => return Ok(())
+        };
+        let mut entries: Vec<(i64, u64, PathBuf)> = Vec::new();
+        for item in listing {
+            let path = try!(cache_io(item, &self.dir)).path();
+            if path.file_name().and_then(|n| n.to_str()) == Some(LOCK_NAME) {
+                continue;
+            }
+            let stamp = read_stamp(&path).unwrap_or(0);
+            if now - stamp > self.ttl_secs {
+                try!(utils::remove_recursive(&path));
+                continue;
+            }
+            let size = fs::metadata(&path.join("artifact")).map(|m| m.len()).unwrap_or(0);
+            entries.push((stamp, size, path));
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut total = entries.iter().fold(0, |sum, e| sum + e.1);
+        for &(_, size, ref path) in entries.iter() {
+            if total <= self.max_bytes {
+                break;
+            }
+            try!(utils::remove_recursive(path));
+            total -= size;
+        }
+        Ok(())
+    }
+}
+
+fn sanitize(part: &str) -> String {
+    part.chars()
+        .map(|c| if c.is_alphanumeric() || c == '.' || c == '-' { c } else { '-' })
+        .collect()
+}
+
+fn read_stamp(entry: &PathBuf) -> Option<i64> {
+    let mut stamp = String::new();
+    match File::open(&entry.join("stamp")).and_then(|mut f| f.read_to_string(&mut stamp)) {
+        Ok(_) => stamp.trim().parse::<i64>().ok(),
+        Err(_) => None
+    }
+}
+
+fn write_stamp(entry: &PathBuf, now: i64) -> Result<(), DeliveryError> {
+    let path = entry.join("stamp");
+    let mut f = try!(cache_io(File::create(&path), &path));
+    try!(cache_io(f.write_all(format!("{}\n", now).as_bytes()), &path));
+    Ok(())
+}
+
+fn cache_io<T, E: ::std::error::Error>(result: Result<T, E>, path: &PathBuf) -> Result<T, DeliveryError> {
+    result.map_err(|e| DeliveryError{
+        kind: Kind::ArtifactCacheFailed,
+        detail: Some(format!("{}: {}", path.display(), e.description()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::write_stamp;
+    use errors::DeliveryError;
+    use std::cell::Cell;
+    use std::fs::{self, File};
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+    use std::thread;
+    use tempdir::TempDir;
+    use time;
+    use utils::lock;
+
+    fn write_artifact(path: &PathBuf, count: &Cell<u32>) -> Result<(), DeliveryError> {
+        count.set(count.get() + 1);
+        try!(File::create(path)).write_all(b"cookbook").unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn key_test() {
+        assert_eq!("supermarket_delivery-truck_1.2.0_abc123",
+                   ArtifactCache::key("supermarket", "delivery_truck", "1.2.0", "abc123"));
+        assert_eq!("supermarket_a-b_1.0_", ArtifactCache::key("supermarket", "a/b", "1.0", ""));
+    }
+
+    #[test]
+    fn second_fetch_hits_cache() {
+        let tempdir = TempDir::new("artifact_cache").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        let cache = ArtifactCache::new(&root.join("cache"));
+        let key = ArtifactCache::key("supermarket", "delivery-truck", "1.2.0", "");
+        let count = Cell::new(0);
+
+        assert_eq!(false, cache.fetch(&key, &root.join("one.tgz"), |p| write_artifact(p, &count)).unwrap());
+        assert_eq!(true, cache.fetch(&key, &root.join("two.tgz"), |p| write_artifact(p, &count)).unwrap());
+        assert_eq!(1, count.get());
+        let mut body = String::new();
+        File::open(&root.join("two.tgz")).unwrap().read_to_string(&mut body).unwrap();
+        assert_eq!("cookbook", body);
+    }
+
+    #[test]
+    fn different_version_misses_cache() {
+        let tempdir = TempDir::new("artifact_cache").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        let cache = ArtifactCache::new(&root.join("cache"));
+        let count = Cell::new(0);
+        let one = ArtifactCache::key("supermarket", "delivery-truck", "1.2.0", "");
+        let two = ArtifactCache::key("supermarket", "delivery-truck", "1.3.0", "");
+        assert_eq!(false, cache.fetch(&one, &root.join("one.tgz"), |p| write_artifact(p, &count)).unwrap());
+        assert_eq!(false, cache.fetch(&two, &root.join("two.tgz"), |p| write_artifact(p, &count)).unwrap());
+        assert_eq!(2, count.get());
+    }
+
+    #[test]
+    fn evict_expired_entries() {
+        let tempdir = TempDir::new("artifact_cache").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        let cache = ArtifactCache::new(&root);
+        let entry = root.join("old");
+        fs::create_dir(&entry).unwrap();
+        File::create(&entry.join("artifact")).unwrap().write_all(b"old").unwrap();
+        write_stamp(&entry, 1000).unwrap();
+        assert!(cache.lookup("old", 1000).is_some());
+        assert!(cache.lookup("old", 1000 + DEFAULT_TTL_SECS + 1).is_none());
+        cache.evict(1000 + DEFAULT_TTL_SECS + 1).unwrap();
+        assert!(fs::metadata(&entry).is_err());
+    }
+
+    #[test]
+    fn fetch_waits_for_the_lock() {
+        let tempdir = TempDir::new("artifact_cache").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        let cache = ArtifactCache::new(&root.join("cache"));
+        let key = ArtifactCache::key("supermarket", "delivery-truck", "1.2.0", "");
+        fs::create_dir(&root.join("cache")).unwrap();
+        let held = lock::acquire_named(&root.join("cache"), LOCK_NAME, false).unwrap();
+        let releaser = thread::spawn(move || {
+            thread::sleep(::std::time::duration::Duration::milliseconds(200));
+            drop(held);
+        });
+        let count = Cell::new(0);
+        let started = time::precise_time_ns();
+        assert_eq!(false, cache.fetch(&key, &root.join("one.tgz"), |p| write_artifact(p, &count)).unwrap());
+        assert!(time::precise_time_ns() - started >= 200 * 1_000_000);
+        releaser.join().unwrap();
+        // The lock is released afterwards, and survives eviction
+        assert!(fs::metadata(&root.join("cache").join(LOCK_NAME)).is_err());
+        let again = lock::acquire_named(&root.join("cache"), LOCK_NAME, false).unwrap();
+        cache.evict(time::get_time().sec + DEFAULT_TTL_SECS + 1).unwrap();
+        assert!(fs::metadata(&again.path).is_ok());
+    }
+
+    #[test]
+    fn evict_least_recently_used_over_size() {
+        let tempdir = TempDir::new("artifact_cache").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        let mut cache = ArtifactCache::new(&root);
+        cache.max_bytes = 5;
+        for &(name, stamp) in [("older", 100), ("newer", 200)].iter() {
+            let entry = root.join(name);
+            fs::create_dir(&entry).unwrap();
+            File::create(&entry.join("artifact")).unwrap().write_all(b"four").unwrap();
+            write_stamp(&entry, stamp).unwrap();
+        }
+        cache.evict(300).unwrap();
+        assert!(fs::metadata(&root.join("older")).is_err());
+        assert!(fs::metadata(&root.join("newer")).is_ok());
+    }
+}
@@ -0,0 +1,126 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Ask before doing something that is hard to take back, like pushing
+//! a review. Like the setup wizard, this reads and writes through
+//! `BufRead` and `Write` so the tests can answer for themselves.
+
+use std::io::prelude::*;
+use errors::{DeliveryError, Kind};
+use libc::funcs::posix88::unistd;
+
+/// Ask `question`, and return whether the answer was yes. Only `y` or
+/// `yes` count; anything else, including no answer at all, is no.
+pub fn ask<R: BufRead, W: Write>(input: &mut R, output: &mut W,
+                                 question: &str) -> Result<bool, DeliveryError> {
+    try!(write!(output, "{} [y/N]: ", question));
+    try!(output.flush());
+    let mut line = String::new();
+    try!(input.read_line(&mut line));
+    let answer = line.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+/// Whether stdin is a terminal, so there is someone to answer.
+pub fn stdin_is_tty() -> bool {
+    unsafe { unistd::isatty(0) == 1 }
+}
+
+/// Check the user wants to push `head` for review against `target`,
+/// unless `yes` says they already do. Saying no is
+/// `Kind::ReviewAbortedByUser`. Without a terminal to ask on
+/// (`interactive` false, as in CI) we don't take the missing answer
+/// for a no: that is `Kind::ConfirmationNeedsTerminal`, pointing at
+/// `--yes`.
+pub fn review<R: BufRead, W: Write>(input: &mut R, output: &mut W,
+                                    head: &str, target: &str,
+                                    yes: bool, interactive: bool) -> Result<(), DeliveryError> {
+    if yes {
+        return Ok(());
+    }
+    if !interactive {
+        return Err(DeliveryError{
+            kind: Kind::ConfirmationNeedsTerminal,
+            detail: Some(format!("Not asking whether to push {} for review against {}, as stdin isn't a terminal; pass --yes to push without asking",
+                                 head, target))
+        });
+    }
+    let question = format!("Push {} for review against {}?", head, target);
+    if try!(ask(input, output, &question)) {
+        Ok(())
+    } else {
+        Err(DeliveryError{
+            kind: Kind::ReviewAbortedByUser,
+            detail: Some("Nothing was pushed; pass --yes to skip this question".to_string())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Kind;
+    use std::io::Cursor;
+
+    fn answers(text: &str) -> Cursor<Vec<u8>> {
+        Cursor::new(text.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn review_confirmed() {
+        let mut output: Vec<u8> = Vec::new();
+        assert!(review(&mut answers("y\n"), &mut output, "feature", "master", false, true).is_ok());
+        assert_eq!("Push feature for review against master? [y/N]: ",
+                   String::from_utf8(output).unwrap());
+        assert!(review(&mut answers(" YES\n"), &mut Vec::new(), "feature", "master", false, true).is_ok());
+    }
+
+    #[test]
+    fn review_declined() {
+        for answer in ["n\n", "\n", "", "sure\n"].iter() {
+            match review(&mut answers(answer), &mut Vec::new(), "feature", "master", false, true) {
+                Err(e) => match e.kind {
+                    Kind::ReviewAbortedByUser => {},
+                    _ => panic!("Expected ReviewAbortedByUser, got {:?}", e)
+                },
+                Ok(_) => panic!("Expected {:?} to decline", answer)
+            }
+        }
+    }
+
+    #[test]
+    fn review_with_yes() {
+        let mut input = answers("n\n");
+        let mut output: Vec<u8> = Vec::new();
+        assert!(review(&mut input, &mut output, "feature", "master", true, false).is_ok());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn review_without_a_terminal() {
+        let mut output: Vec<u8> = Vec::new();
+        match review(&mut answers(""), &mut output, "feature", "master", false, false) {
+            Err(e) => match e.kind {
+                Kind::ConfirmationNeedsTerminal => assert!(e.detail.unwrap().ends_with("pass --yes to push without asking")),
+                _ => panic!("Expected ConfirmationNeedsTerminal, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected no push without a terminal")
+        }
+        assert!(output.is_empty());
+    }
+}
@@ -0,0 +1,109 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Check there is room on disk before we start writing a download, so
+//! we fail up front rather than halfway through.
+//!
+//! This will need a windows implementation; we ask `df`.
+
+use std::path::PathBuf;
+use std::process::Command;
+use errors::{DeliveryError, Kind};
+
+/// Bytes free on the filesystem holding `dir`, from `df -Pk`.
+pub fn available_bytes(dir: &PathBuf) -> Result<u64, DeliveryError> {
+    let result = try!(Command::new("df")
+                      .arg("-Pk")
+                      .arg(dir.to_str().unwrap())
+                      .output());
+    let stdout = String::from_utf8_lossy(&result.stdout).to_string();
+    if !result.status.success() {
+        let error = String::from_utf8_lossy(&result.stderr);
+        return Err(DeliveryError{ kind: Kind::FailedToExecute,
+                                  detail: Some(format!("Failed 'df -Pk'\nOUT: {}\nERR: {}", &stdout, &error)) });
+    }
+    // The second line is the filesystem; its fourth column is the
+    // free space in kilobytes.
+    let kb = stdout.lines_any().nth(1)
+        .and_then(|l| l.split(' ').filter(|c| !c.is_empty()).nth(3))
+        .and_then(|c| c.parse::<u64>().ok());
+    match kb {
+        Some(k) => Ok(k * 1024),
+        None => Err(DeliveryError{ kind: Kind::FailedToExecute,
+                                   detail: Some(format!("Unexpected 'df -Pk' output: {}", &stdout)) })
+    }
+}
+
+/// `Kind::InsufficientDiskSpace` if `needed` bytes won't fit in
+/// `available`. An unknown size always passes.
+pub fn check_space(dir: &PathBuf, needed: Option<u64>,
+                   available: u64) -> Result<(), DeliveryError> {
+    match needed {
+        Some(n) if n > available => Err(DeliveryError{
+            kind: Kind::InsufficientDiskSpace,
+            detail: Some(format!("Need {} bytes in {}, but only {} are free",
+                                 n, dir.display(), available))
+        }),
+        _ => Ok(())
+    }
+}
+
+/// Check that `needed` bytes will fit in `dir`. When the size is
+/// unknown we don't even look.
+pub fn preflight(dir: &PathBuf, needed: Option<u64>) -> Result<(), DeliveryError> {
+    if needed.is_none() {
+        return Ok(());
+    }
+    let available = try!(available_bytes(dir));
+    check_space(dir, needed, available)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Kind;
+    use std::path::PathBuf;
+
+    #[test]
+    fn check_space_sufficient() {
+        let dir = PathBuf::from("/var/opt/delivery/workspace");
+        assert!(check_space(&dir, Some(1024), 4096).is_ok());
+        assert!(check_space(&dir, Some(4096), 4096).is_ok());
+        assert!(check_space(&dir, None, 0).is_ok());
+    }
+
+    #[test]
+    fn check_space_insufficient() {
+        let dir = PathBuf::from("/var/opt/delivery/workspace");
+        match check_space(&dir, Some(4097), 4096) {
+            Err(e) => match e.kind {
+                Kind::InsufficientDiskSpace => {
+                    assert_eq!("Need 4097 bytes in /var/opt/delivery/workspace, but only 4096 are free",
+                               e.detail.unwrap());
+                },
+                _ => panic!("Expected InsufficientDiskSpace, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected the download not to fit")
+        }
+    }
+
+    #[test]
+    fn available_bytes_test() {
+        assert!(available_bytes(&PathBuf::from("/")).unwrap() > 0);
+    }
+}
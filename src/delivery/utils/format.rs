@@ -0,0 +1,244 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Output formatters for commands that list things.
+//!
+//! A command turns its result into a `Table` (named columns, and rows
+//! of string values) and hands it to the `Formatter` picked with
+//! `--format`. New formats only need a new `Formatter`.
+
+use errors::{DeliveryError, Kind};
+use rustc_serialize::json::Json;
+use std::collections::BTreeMap;
+
+pub const FORMATS: [&'static str; 4] = ["human", "json", "yaml", "tsv"];
+
+/// The shape every formatter consumes.
+#[derive(Debug, PartialEq)]
+pub struct Table {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>
+}
+
+pub trait Formatter {
+    fn render(&self, table: &Table) -> String;
+}
+
+impl Table {
+    /// Pick `columns` out of each JSON object in `items`. Missing
+    /// values are empty, and anything that isn't a string is written
+    /// as JSON.
+    pub fn from_json(items: &[Json], columns: &[&str]) -> Table {
+        let rows = items.iter().map(|item| {
+            columns.iter().map(|c| {
+                match item.find(c) {
+                    Some(&Json::String(ref s)) => s.clone(),
+                    Some(&Json::Null) | None => String::new(),
+                    Some(v) => v.to_string()
+                }
+            }).collect()
+        }).collect();
+        Table {
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            rows: rows
+        }
+    }
+}
+
+/// Columns padded to line up, for people.
+pub struct Human;
+
+/// An array of objects, one per row.
+pub struct JsonFormat;
+
+/// A YAML list of mappings, one per row.
+pub struct Yaml;
+
+/// A header line, then one tab separated line per row.
+pub struct Tsv;
+
+impl Formatter for Human {
+    fn render(&self, table: &Table) -> String {
+        let widths: Vec<usize> = table.columns.iter().enumerate().map(|(i, c)| {
+            table.rows.iter().fold(c.len(), |w, row| {
+                if row[i].len() > w { row[i].len() } else { w }
+            })
+        }).collect();
+        let line = |values: &Vec<String>| {
+            let cells: Vec<String> = values.iter().enumerate()
+                .map(|(i, v)| pad(v, widths[i]))
+                .collect();
+            format!("{}\n", cells.connect("  ").trim_right())
+        };
+        let header: Vec<String> = table.columns.iter().map(|c| c.to_uppercase()).collect();
+        let mut out = line(&header);
+        for row in table.rows.iter() {
+            out.push_str(&line(row));
+        }
+        out
+    }
+}
+
+impl Formatter for JsonFormat {
+    fn render(&self, table: &Table) -> String {
+        let items: Vec<Json> = table.rows.iter().map(|row| {
+            let mut obj = BTreeMap::new();
+            for (c, v) in table.columns.iter().zip(row.iter()) {
+                obj.insert(c.clone(), Json::String(v.clone()));
+            }
+            Json::Object(obj)
+        }).collect();
+        format!("{}\n", Json::Array(items).pretty())
+    }
+}
+
+impl Formatter for Yaml {
+    fn render(&self, table: &Table) -> String {
+        if table.rows.is_empty() {
+            return "[]\n".to_string();
+        }
+        let mut out = String::new();
+        for row in table.rows.iter() {
+            for (i, (c, v)) in table.columns.iter().zip(row.iter()).enumerate() {
+                let lead = if i == 0 { "- " } else { "  " };
+                out.push_str(&format!("{}{}: {}\n", lead, c, yaml_scalar(v)));
+            }
+        }
+        out
+    }
+}
+
+impl Formatter for Tsv {
+    fn render(&self, table: &Table) -> String {
+        let line = |values: &Vec<String>| {
+            let cells: Vec<String> = values.iter().map(|v| tsv_field(v)).collect();
+            format!("{}\n", cells.connect("\t"))
+        };
+        let mut out = line(&table.columns);
+        for row in table.rows.iter() {
+            out.push_str(&line(row));
+        }
+        out
+    }
+}
+
+/// The formatter for `--format=<name>`; empty means human.
+pub fn formatter(name: &str) -> Result<Box<Formatter>, DeliveryError> {
+    match name {
+        "" | "human" => Ok(Box::new(Human)),
+        "json" => Ok(Box::new(JsonFormat)),
+        "yaml" => Ok(Box::new(Yaml)),
+        "tsv" => Ok(Box::new(Tsv)),
+        _ => Err(DeliveryError{
+            kind: Kind::UnsupportedOutputFormat,
+            detail: Some(format!("Unknown format '{}'; use one of: {}", name, FORMATS.connect(", ")))
+        })
+    }
+}
+
+fn pad(value: &str, width: usize) -> String {
+    let mut out = value.to_string();
+    while out.len() < width {
+        out.push(' ');
+    }
+    out
+}
+
+/// Quote a YAML value unless it is plainly a string: no characters
+/// with special meaning, and nothing YAML would read as another type.
+fn yaml_scalar(value: &str) -> String {
+    let special = value.is_empty() ||
+        value.chars().any(|c| ":#{}[],&*!|>'\"%@`\n\t".chars().any(|s| s == c)) ||
+        value.starts_with(" ") || value.ends_with(" ") || value.starts_with("-") ||
+        ["true", "false", "yes", "no", "null", "~"].iter().any(|w| *w == value) ||
+        value.parse::<f64>().is_ok();
+    if special {
+        Json::String(value.to_string()).to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn tsv_field(value: &str) -> String {
+    value.replace("\\", "\\\\").replace("\t", "\\t").replace("\n", "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Kind;
+    use rustc_serialize::json::Json;
+
+    fn sample() -> Table {
+        let changes = Json::from_str(r#"[
+            {"id": "abc", "title": "Add widgets", "state": "open"},
+            {"id": "def", "title": "Fix: the widgets", "state": "merged", "extra": 1}
+        ]"#).unwrap();
+        Table::from_json(changes.as_array().unwrap(), &["id", "title", "state"])
+    }
+
+    fn render(name: &str) -> String {
+        formatter(name).unwrap().render(&sample())
+    }
+
+    #[test]
+    fn from_json_test() {
+        let json = Json::from_str(r#"[{"id": "abc", "count": 2, "gone": null}]"#).unwrap();
+        let table = Table::from_json(json.as_array().unwrap(), &["id", "count", "gone", "missing"]);
+        assert_eq!(vec![vec!["abc".to_string(), "2".to_string(), String::new(), String::new()]],
+                   table.rows);
+    }
+
+    #[test]
+    fn render_human() {
+        assert_eq!("ID   TITLE             STATE\n\
+                    abc  Add widgets       open\n\
+                    def  Fix: the widgets  merged\n", render("human"));
+        assert_eq!(render("human"), render(""));
+    }
+
+    #[test]
+    fn render_json() {
+        let json = Json::from_str(&render("json")).unwrap();
+        assert_eq!("Fix: the widgets", json.as_array().unwrap()[1].find("title").unwrap().as_string().unwrap());
+        assert_eq!(2, json.as_array().unwrap().len());
+    }
+
+    #[test]
+    fn render_yaml() {
+        assert_eq!("- id: abc\n  title: Add widgets\n  state: open\n\
+                    - id: def\n  title: \"Fix: the widgets\"\n  state: merged\n", render("yaml"));
+    }
+
+    #[test]
+    fn render_tsv() {
+        assert_eq!("id\ttitle\tstate\nabc\tAdd widgets\topen\ndef\tFix: the widgets\tmerged\n",
+                   render("tsv"));
+    }
+
+    #[test]
+    fn unknown_format() {
+        match formatter("xml") {
+            Err(e) => match e.kind {
+                Kind::UnsupportedOutputFormat => assert!(e.detail.unwrap().contains("human, json, yaml, tsv")),
+                _ => panic!("Expected UnsupportedOutputFormat, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected xml to be rejected")
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Write the outcome of a command as JUnit XML, which most CI systems
+//! know how to display. Each step of the command is a test case; a
+//! step that returned an error is a failure.
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::error::Error;
+use errors::DeliveryError;
+
+#[derive(Debug, Clone)]
+pub struct TestCase {
+    pub name: String,
+    pub failure: Option<String>
+}
+
+#[derive(Debug, Clone)]
+pub struct TestSuite {
+    pub name: String,
+    pub cases: Vec<TestCase>
+}
+
+impl TestSuite {
+    pub fn new(name: &str) -> TestSuite {
+        TestSuite { name: name.to_string(), cases: Vec::new() }
+    }
+
+    /// Record `result` as the test case `name`, and hand it back so
+    /// calls can be wrapped in `try!`.
+    pub fn step<T>(&mut self, name: &str,
+                   result: Result<T, DeliveryError>) -> Result<T, DeliveryError> {
+        let failure = match result {
+            Ok(_) => None,
+            Err(ref e) => Some(failure_message(e))
+        };
+        self.cases.push(TestCase { name: name.to_string(), failure: failure });
+        result
+    }
+
+    pub fn failures(&self) -> usize {
+        self.cases.iter().filter(|c| c.failure.is_some()).count()
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::from_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str(&format!("<testsuite name=\"{}\" tests=\"{}\" failures=\"{}\">\n",
+                              escape(&self.name), self.cases.len(), self.failures()));
+        for case in self.cases.iter() {
+            match case.failure {
+                Some(ref msg) => {
+                    xml.push_str(&format!("  <testcase classname=\"{0}\" name=\"{1}\">\n    <failure message=\"{2}\">{2}</failure>\n  </testcase>\n",
+                                          escape(&self.name), escape(&case.name), escape(msg)));
+                },
+                None => {
+                    xml.push_str(&format!("  <testcase classname=\"{}\" name=\"{}\"/>\n",
+                                          escape(&self.name), escape(&case.name)));
+                }
+            }
+        }
+        xml.push_str("</testsuite>\n");
+        xml
+    }
+
+    pub fn write(&self, path: &PathBuf) -> Result<(), DeliveryError> {
+        let mut f = try!(File::create(path));
+        try!(f.write_all(self.to_xml().as_bytes()));
+        Ok(())
+    }
+}
+
+fn failure_message(e: &DeliveryError) -> String {
+    match e.detail {
+        Some(ref d) => format!("{}: {}", e.description(), d),
+        None => e.description().to_string()
+    }
+}
+
+/// Escape the five characters XML cares about.
+pub fn escape(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(c)
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::{DeliveryError, Kind};
+
+    #[test]
+    fn passing_suite_xml() {
+        let mut suite = TestSuite::new("delivery review");
+        let _ = suite.step("find repository", Ok::<(), DeliveryError>(()));
+        let _ = suite.step("push review", Ok::<(), DeliveryError>(()));
+        assert_eq!(0, suite.failures());
+        assert_eq!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                    <testsuite name=\"delivery review\" tests=\"2\" failures=\"0\">\n  \
+                    <testcase classname=\"delivery review\" name=\"find repository\"/>\n  \
+                    <testcase classname=\"delivery review\" name=\"push review\"/>\n\
+                    </testsuite>\n",
+                   suite.to_xml());
+    }
+
+    #[test]
+    fn failing_suite_xml() {
+        let mut suite = TestSuite::new("delivery review");
+        let _ = suite.step("find repository", Ok::<(), DeliveryError>(()));
+        let result: Result<(), DeliveryError> = suite.step("push review", Err(DeliveryError{
+            kind: Kind::GitFailed,
+            detail: Some("remote said <no> & \"nope\"".to_string())
+        }));
+        assert!(result.is_err());
+        assert_eq!(1, suite.failures());
+        let xml = suite.to_xml();
+        assert!(xml.contains("tests=\"2\" failures=\"1\""));
+        assert!(xml.contains("<testcase classname=\"delivery review\" name=\"push review\">\n    <failure message=\""));
+        assert!(xml.contains("remote said &lt;no&gt; &amp; &quot;nope&quot;</failure>"));
+        assert!(xml.ends_with("</testcase>\n</testsuite>\n"));
+    }
+
+    #[test]
+    fn escape_test() {
+        assert_eq!("a &lt;b&gt; &amp; &apos;c&apos;", escape("a <b> & 'c'"));
+    }
+}
@@ -0,0 +1,204 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! A lock on a directory, so two `delivery` runs can't work in the
+//! same place at once.
+//!
+//! The lock is a `.delivery.lock` directory, since creating a
+//! directory either succeeds or fails atomically. Inside it we write
+//! the holder's PID, so the error can say who has it. `wait_for`
+//! waits for the holder to finish rather than failing at once, and
+//! throws away a lock whose holder has exited without releasing it.
+
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::thread;
+use std::time::duration::Duration;
+use libc;
+use libc::funcs::posix88::{signal, unistd};
+use time;
+use errors::{DeliveryError, Kind};
+use utils::say::sayln;
+
+pub const LOCK_NAME: &'static str = ".delivery.lock";
+
+/// Held until dropped, when the lock is released.
+#[derive(Debug)]
+pub struct Lock {
+    pub path: PathBuf
+}
+
+/// Take the lock on `dir`. If another run holds it, return
+/// `Kind::StaleLockfile` with its PID, unless `force` is set, in which
+/// case the old lock is thrown away first.
+pub fn acquire(dir: &PathBuf, force: bool) -> Result<Lock, DeliveryError> {
+    acquire_named(dir, LOCK_NAME, force)
+}
+
+/// Like `acquire`, for the lock called `name` in `dir`.
+pub fn acquire_named(dir: &PathBuf, name: &str, force: bool) -> Result<Lock, DeliveryError> {
+    let path = dir.join(name);
+    if force && fs::metadata(&path).is_ok() {
+        sayln("yellow", &format!("Removing lock held by PID {}", holder(&path)));
+        try!(fs::remove_dir_all(&path));
+    }
+    match fs::create_dir(&path) {
+        Ok(_) => {},
+        Err(_) => {
+            return Err(DeliveryError{
+                kind: Kind::StaleLockfile,
+                detail: Some(format!("{} is held by PID {}", path.display(), holder(&path)))
+            });
+        }
+    }
+    let pid = unsafe { unistd::getpid() };
+    let mut f = try!(File::create(&path.join("pid")));
+    try!(f.write_all(format!("{}\n", pid).as_bytes()));
+    Ok(Lock { path: path })
+}
+
+/// Take the lock called `name` in `dir`, waiting up to `wait_ms` for
+/// whoever holds it to let go. If they don't, that is
+/// `Kind::StaleLockfile`, as from `acquire`. A lock left behind by a
+/// process that is no longer running is taken over at once.
+pub fn wait_for(dir: &PathBuf, name: &str, wait_ms: u64) -> Result<Lock, DeliveryError> {
+    let deadline = time::precise_time_ns() + wait_ms * 1_000_000;
+    loop {
+        let abandoned = is_abandoned(&dir.join(name));
+        match acquire_named(dir, name, abandoned) {
+            Err(DeliveryError{ kind: Kind::StaleLockfile, .. }) if time::precise_time_ns() < deadline => {
+                thread::sleep(Duration::milliseconds(25));
+            },
+            result => return result
+        }
+    }
+}
+
+/// Whether the lock at `path` was left by a process that has exited,
+/// going by the PID in it. A lock with no PID yet may be being taken
+/// right now, so it isn't.
+fn is_abandoned(path: &PathBuf) -> bool {
+    let pid = match holder(path).parse::<libc::pid_t>() {
+        Ok(p) => p,
+        Err(_) => return false
+    };
+    if unsafe { signal::kill(pid, 0) } == 0 {
+        return false;
+    }
+    // EPERM means it is running, as someone else
+    io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+}
+
+/// The PID recorded in the lock at `path`, or "unknown".
+fn holder(path: &PathBuf) -> String {
+    let mut pid = String::new();
+    match File::open(&path.join("pid")).and_then(|mut f| f.read_to_string(&mut pid)) {
+        Ok(_) if !pid.trim().is_empty() => pid.trim().to_string(),
+        _ => String::from_str("unknown")
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        match fs::remove_dir_all(&self.path) {
+            Ok(_) => {},
+            Err(e) => debug!("Failed to release lock {}: {}", self.path.display(), e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Kind;
+    use std::fs::{self, File};
+    use std::io::prelude::*;
+    use std::thread;
+    use std::time::duration::Duration;
+    use tempdir::TempDir;
+    use utils::path_join_many::PathJoinMany;
+
+    #[test]
+    fn acquire_and_release() {
+        let tempdir = TempDir::new("lock").ok().expect("TempDir failed");
+        let dir = tempdir.path().to_path_buf();
+        {
+            let lock = acquire(&dir, false).unwrap();
+            assert!(fs::metadata(&lock.path.join("pid")).is_ok());
+        }
+        assert!(fs::metadata(&dir.join(LOCK_NAME)).is_err());
+        assert!(acquire(&dir, false).is_ok());
+    }
+
+    #[test]
+    fn acquire_held_lock() {
+        let tempdir = TempDir::new("lock").ok().expect("TempDir failed");
+        let dir = tempdir.path().to_path_buf();
+        // Simulate a run that crashed holding the lock
+        fs::create_dir(&dir.join(LOCK_NAME)).unwrap();
+        File::create(&dir.join(LOCK_NAME).join("pid")).unwrap().write_all(b"4242\n").unwrap();
+        match acquire(&dir, false) {
+            Err(e) => match e.kind {
+                Kind::StaleLockfile => assert!(e.detail.unwrap().ends_with("is held by PID 4242")),
+                _ => panic!("Expected StaleLockfile, got {:?}", e)
+            },
+            Ok(l) => panic!("Expected the lock to be held, got {:?}", l)
+        }
+        assert!(acquire(&dir, true).is_ok());
+    }
+
+    #[test]
+    fn wait_for_released_lock() {
+        let tempdir = TempDir::new("lock").ok().expect("TempDir failed");
+        let dir = tempdir.path().to_path_buf();
+        let held = acquire_named(&dir, ".refresh.lock", false).unwrap();
+        let waiting_dir = dir.clone();
+        let waiter = thread::spawn(move || {
+            wait_for(&waiting_dir, ".refresh.lock", 5000).map(|l| l.path.clone())
+        });
+        thread::sleep(Duration::milliseconds(100));
+        drop(held);
+        assert_eq!(dir.join(".refresh.lock"), waiter.join().unwrap().unwrap());
+    }
+
+    #[test]
+    fn wait_for_abandoned_lock() {
+        let tempdir = TempDir::new("lock").ok().expect("TempDir failed");
+        let dir = tempdir.path().to_path_buf();
+        // A PID past the kernel's limit can't be running
+        fs::create_dir(&dir.join(".refresh.lock")).unwrap();
+        File::create(&dir.join_many(&[".refresh.lock", "pid"])).unwrap().write_all(b"99999999\n").unwrap();
+        assert!(wait_for(&dir, ".refresh.lock", 0).is_ok());
+    }
+
+    #[test]
+    fn wait_for_held_lock_gives_up() {
+        let tempdir = TempDir::new("lock").ok().expect("TempDir failed");
+        let dir = tempdir.path().to_path_buf();
+        let _held = acquire_named(&dir, ".refresh.lock", false).unwrap();
+        match wait_for(&dir, ".refresh.lock", 100) {
+            Err(e) => match e.kind {
+                Kind::StaleLockfile => {},
+                _ => panic!("Expected StaleLockfile, got {:?}", e)
+            },
+            Ok(l) => panic!("Expected the lock to stay held, got {:?}", l)
+        }
+    }
+}
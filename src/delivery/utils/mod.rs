@@ -17,14 +17,26 @@
 
 use std::process::Command;
 use errors::{DeliveryError, Kind};
+#[cfg(not(target_os = "windows"))]
 use libc::funcs::posix88::unistd;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::convert::AsRef;
+use std::env;
+use std::error;
 use std::fs;
+#[cfg(target_os = "windows")]
+use utils::say::sayln;
 
 pub mod say;
 pub mod path_join_many;
 pub mod open;
+pub mod tar;
+pub mod junit;
+pub mod lock;
+pub mod disk;
+pub mod artifact_cache;
+pub mod format;
+pub mod confirm;
 
 // This will need a windows implementation
 pub fn copy_recursive<P: ?Sized>(f: &P, t: &P) -> Result<(), DeliveryError> where P: AsRef<Path> {
@@ -55,7 +67,13 @@ pub fn mkdir_recursive<P: ?Sized>(path: &P) -> Result<(), DeliveryError> where P
     Ok(())
 }
 
-// This will need a windows implementation
+// Owners and permission bits are Unix things. On Windows the helpers
+// below leave files as they are and say so, rather than fail.
+
+/// Why an ownership or permission change didn't happen on Windows.
+pub const PERMISSIONS_SKIPPED: &'static str = "Windows has no dbuild user or POSIX permissions, so files are left as they are";
+
+#[cfg(not(target_os = "windows"))]
 pub fn chmod<P: ?Sized>(path: &P, setting: &str) -> Result<(), DeliveryError> where P: AsRef<Path> {
     let result = try!(Command::new("chmod")
          .arg(setting)
@@ -67,9 +85,94 @@ pub fn chmod<P: ?Sized>(path: &P, setting: &str) -> Result<(), DeliveryError> wh
     Ok(())
 }
 
+#[cfg(target_os = "windows")]
+pub fn chmod<P: ?Sized>(path: &P, setting: &str) -> Result<(), DeliveryError> where P: AsRef<Path> {
+    debug!("Skipped chmod {} {}: {}", setting, path.as_ref().display(), PERMISSIONS_SKIPPED);
+    Ok(())
+}
+
+/// `chown -R owner` each of `paths`. Returns whether it ran.
+#[cfg(not(target_os = "windows"))]
+pub fn chown_recursive(paths: &[&Path], owner: &str) -> Result<bool, DeliveryError> {
+    let mut command = Command::new("chown");
+    command.arg("-R").arg(owner);
+    for p in paths.iter() {
+        command.arg(p.to_str().unwrap());
+    }
+    let output = match command.output() {
+        Ok(o) => o,
+        Err(e) => { return Err(DeliveryError{ kind: Kind::FailedToExecute, detail: Some(format!("failed to execute chown: {}", error::Error::description(&e)))}) },
+    };
+    if !output.status.success() {
+        return Err(DeliveryError{ kind: Kind::ChownFailed, detail: Some(format!("STDOUT: {}\nSTDERR: {}\n", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr)))});
+    }
+    debug!("chown stdout: {}", String::from_utf8_lossy(&output.stdout));
+    debug!("chown stderr: {}", String::from_utf8_lossy(&output.stderr));
+    Ok(true)
+}
+
+#[cfg(target_os = "windows")]
+pub fn chown_recursive(_paths: &[&Path], owner: &str) -> Result<bool, DeliveryError> {
+    sayln("yellow", &format!("Skipping chown to {}: {}", owner, PERMISSIONS_SKIPPED));
+    Ok(false)
+}
+
+#[cfg(not(target_os = "windows"))]
 pub fn privileged_process() -> bool {
     match unsafe { unistd::getuid() } {
         0 => true,
         _ => false
     }
 }
+
+/// There is no dbuild user to drop to on Windows, so we never run
+/// the job as root there.
+#[cfg(target_os = "windows")]
+pub fn privileged_process() -> bool {
+    false
+}
+
+/// The user's home directory: `HOME`, or `USERPROFILE` on Windows.
+#[cfg(not(target_os = "windows"))]
+pub fn home_dir() -> Option<PathBuf> {
+    env::home_dir()
+}
+
+#[cfg(target_os = "windows")]
+pub fn home_dir() -> Option<PathBuf> {
+    home_from_profile(env::var("USERPROFILE").ok()).or_else(env::home_dir)
+}
+
+/// The home directory from the value of `USERPROFILE`, if it has one.
+pub fn home_from_profile(profile: Option<String>) -> Option<PathBuf> {
+    match profile {
+        Some(ref p) if !p.trim().is_empty() => Some(PathBuf::from(p)),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Kind;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn home_from_profile_test() {
+        assert_eq!(Some(PathBuf::from("C:\\Users\\alice")), home_from_profile(Some("C:\\Users\\alice".to_string())));
+        assert_eq!(None, home_from_profile(Some("".to_string())));
+        assert_eq!(None, home_from_profile(None));
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn chown_recursive_missing_path() {
+        match chown_recursive(&[Path::new("/bogonista/workspace")], "root") {
+            Err(e) => match e.kind {
+                Kind::ChownFailed => {},
+                _ => panic!("Expected ChownFailed, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected chown of a missing path to fail")
+        }
+    }
+}
@@ -0,0 +1,463 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+//! Extract cookbook archives with `tar`.
+//!
+//! Before extracting we list the archive and refuse any entry that is
+//! absolute, climbs out of the destination with `..`, or is a link, so
+//! a hostile archive can't write outside the workspace.
+//!
+//! This will need a windows implementation, and probably won't work
+//! on non-gnu tar systems either.
+
+use std::cmp;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::thread;
+use errors::{DeliveryError, Kind};
+
+/// How many archives `extract_all` works on at once.
+pub const DEFAULT_WORKERS: usize = 4;
+
+/// The largest cookbook, in bytes, we'll download or unpack unless
+/// told otherwise.
+pub const DEFAULT_MAX_SIZE: u64 = 100 * 1024 * 1024;
+
+/// The member names in the gzipped tar at `archive`.
+pub fn list_entries(archive: &PathBuf) -> Result<Vec<String>, DeliveryError> {
+    let result = try!(Command::new("tar")
+                      .arg("tzf")
+                      .arg(archive.to_str().unwrap())
+                      .output());
+    if !result.status.success() {
+        let output = String::from_utf8_lossy(&result.stdout);
+        let error = String::from_utf8_lossy(&result.stderr);
+        return Err(DeliveryError{kind: Kind::TarFailed, detail: Some(format!("Failed 'tar tzf'\nOUT: {}\nERR: {}", &output, &error))});
+    }
+    let stdout = String::from_utf8_lossy(&result.stdout).to_string();
+    Ok(stdout.lines_any().map(|l| l.to_string()).collect())
+}
+
+/// Refuse entries that would land outside the destination.
+pub fn check_entries(entries: &[String]) -> Result<(), DeliveryError> {
+    for entry in entries.iter() {
+        let path = Path::new(entry);
+        let escapes = path.is_absolute() || path.components().any(|c| {
+            match c {
+                Component::ParentDir => true,
+                _ => false
+            }
+        });
+        if escapes {
+            return Err(DeliveryError{
+                kind: Kind::TarFailed,
+                detail: Some(format!("Refusing to extract '{}'; it is outside the destination", entry))
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Refuse link members. Unpacked, a symbolic or hard link can point
+/// outside the destination, and a later member could write through it.
+pub fn check_links(archive: &PathBuf) -> Result<(), DeliveryError> {
+    let mut link = None;
+    try!(scan(archive, |header| {
+        match header[156] {
+            b'1' | b'2' => {
+                link = Some(member_name(header));
+                Ok(false)
+            },
+            _ => Ok(true)
+        }
+    }));
+    match link {
+        Some(name) => Err(DeliveryError{
+            kind: Kind::TarFailed,
+            detail: Some(format!("Refusing to extract '{}'; it is a link", name))
+        }),
+        None => Ok(())
+    }
+}
+
+/// Call `each` with the header of every member of the gzipped tar at
+/// `archive`, reading it from `gzip -dc` as it streams rather than
+/// asking tar for a listing, whose format differs between tars.
+/// Reading stops early when `each` returns `false`.
+pub fn scan<F>(archive: &PathBuf, mut each: F) -> Result<(), DeliveryError>
+    where F: FnMut(&[u8]) -> Result<bool, DeliveryError> {
+    let mut child = try!(Command::new("gzip")
+                         .arg("-dc")
+                         .arg(archive.to_str().unwrap())
+                         .stdout(Stdio::piped())
+                         .stderr(Stdio::piped())
+                         .spawn());
+    let mut stdout = child.stdout.take().unwrap();
+    let mut header = [0u8; BLOCK];
+    let mut stopped = false;
+    loop {
+        if try!(read_block(&mut stdout, &mut header)) < BLOCK || header.iter().all(|b| *b == 0) {
+            break;
+        }
+        if !try!(each(&header)) {
+            stopped = true;
+            break;
+        }
+        let data = (member_size(&header) + BLOCK as u64 - 1) / BLOCK as u64 * BLOCK as u64;
+        try!(io::copy(&mut stdout.by_ref().take(data), &mut io::sink()));
+    }
+    drop(stdout);
+    if stopped {
+        let _ = child.kill();
+    }
+    let result = try!(child.wait_with_output());
+    if !stopped && !result.status.success() {
+        let error = String::from_utf8_lossy(&result.stderr);
+        return Err(DeliveryError{kind: Kind::TarFailed, detail: Some(format!("Failed 'gzip -dc {}'\nERR: {}", archive.display(), &error))});
+    }
+    Ok(())
+}
+
+/// Tar headers and member data come in blocks of this many bytes.
+const BLOCK: usize = 512;
+
+/// Fill `buf` from `r`, short only at the end of the stream.
+fn read_block<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<usize, DeliveryError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match try!(r.read(&mut buf[filled..])) {
+            0 => break,
+            n => filled = filled + n
+        }
+    }
+    Ok(filled)
+}
+
+/// The size field of a tar header: octal digits, or for members too
+/// big for those, a big-endian number flagged by the high bit.
+pub fn member_size(header: &[u8]) -> u64 {
+    let field = &header[124..136];
+    if field[0] & 0x80 != 0 {
+        return field[1..].iter().fold((field[0] & 0x7f) as u64, |n, b| (n << 8) | *b as u64);
+    }
+    let digits: String = field.iter()
+        .filter(|b| **b != 0 && **b != b' ')
+        .map(|b| *b as char)
+        .collect();
+    u64::from_str_radix(&digits, 8).unwrap_or(0)
+}
+
+/// The name field of a tar header, with the ustar prefix if there
+/// is one.
+fn member_name(header: &[u8]) -> String {
+    let field = |start: usize, len: usize| {
+        let bytes = &header[start..start + len];
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(len);
+        String::from_utf8_lossy(&bytes[..end]).into_owned()
+    };
+    let name = field(0, 100);
+    if &header[257..262] == &b"ustar"[..] && header[345] != 0 {
+        format!("{}/{}", field(345, 155), name)
+    } else {
+        name
+    }
+}
+
+/// Whether the member is a file with contents of its own. GNU long
+/// names and pax headers carry data too, but it is not unpacked.
+fn is_file(header: &[u8]) -> bool {
+    match header[156] {
+        0 | b'0' | b'7' => true,
+        _ => false
+    }
+}
+
+/// The total size, in bytes, of the files in the gzipped tar at
+/// `archive` once unpacked, summed while the archive streams. We stop
+/// reading as soon as the total passes `limit`, so a huge archive is
+/// never read to the end just to find out it is too big.
+pub fn unpacked_size(archive: &PathBuf, limit: u64) -> Result<u64, DeliveryError> {
+    let mut total = 0u64;
+    try!(scan(archive, |header| {
+        if is_file(header) {
+            total = total + member_size(header);
+        }
+        Ok(total <= limit)
+    }));
+    Ok(total)
+}
+
+/// Refuse an archive that is, or would unpack to, more than
+/// `max_size` bytes.
+pub fn check_size(archive: &PathBuf, max_size: u64) -> Result<(), DeliveryError> {
+    let packed = try!(fs::metadata(archive)).len();
+    let size = if packed > max_size { packed } else { try!(unpacked_size(archive, max_size)) };
+    if size > max_size {
+        return Err(DeliveryError{
+            kind: Kind::CookbookTooLarge,
+            detail: Some(format!("{} is {} bytes; the limit is {} bytes",
+                                 archive.display(), size, max_size))
+        });
+    }
+    Ok(())
+}
+
+/// Extract the gzipped tar at `archive` into `dest`, refusing it with
+/// `Kind::CookbookTooLarge` if it holds more than `max_size` bytes.
+pub fn extract_limited(archive: &PathBuf, dest: &PathBuf,
+                       max_size: u64) -> Result<(), DeliveryError> {
+    let entries = try!(list_entries(archive));
+    try!(check_entries(&entries));
+    try!(check_links(archive));
+    try!(check_size(archive, max_size));
+    let tar_result = try!(Command::new("tar")
+                          .arg("zxf")
+                          .arg(archive.to_str().unwrap())
+                          .current_dir(dest)
+                          .output());
+    if !tar_result.status.success() {
+        let output = String::from_utf8_lossy(&tar_result.stdout);
+        let error = String::from_utf8_lossy(&tar_result.stderr);
+        return Err(DeliveryError{kind: Kind::TarFailed, detail: Some(format!("Failed 'tar zxf'\nOUT: {}\nERR: {}", &output, &error))});
+    }
+    Ok(())
+}
+
+/// Extract each `(archive, dest)` pair using up to `workers` threads,
+/// each with the checks and `max_size` limit of `extract_limited`.
+/// Every archive is attempted. If one fails its error is returned,
+/// naming the archive; if more fail, a single `TarFailed` names each
+/// failed archive and why.
+pub fn extract_all(archives: Vec<(PathBuf, PathBuf)>, max_size: u64,
+                   workers: usize) -> Result<(), DeliveryError> {
+    let total = archives.len();
+    let queue = Arc::new(Mutex::new(archives));
+    let (tx, rx) = channel();
+    let mut handles = Vec::new();
+    for _ in 0..cmp::min(workers, total) {
+        let queue = queue.clone();
+        let tx = tx.clone();
+        handles.push(thread::spawn(move || {
+            loop {
+                let next = queue.lock().unwrap().pop();
+                match next {
+                    Some((archive, dest)) => {
+                        let result = extract_limited(&archive, &dest, max_size);
+                        let _ = tx.send((archive, result));
+                    },
+                    None => break
+                }
+            }
+        }));
+    }
+    drop(tx);
+    let mut failures: Vec<DeliveryError> = Vec::new();
+    for _ in 0..total {
+        match rx.recv() {
+            Ok((archive, Err(e))) => {
+                let detail = format!("{}: {}", archive.display(),
+                                     e.detail.unwrap_or(String::new()));
+                failures.push(DeliveryError{ kind: e.kind, detail: Some(detail) });
+            },
+            Ok((_, Ok(_))) => {},
+            Err(_) => break
+        }
+    }
+    for handle in handles.into_iter() {
+        let _ = handle.join();
+    }
+    if failures.len() < 2 {
+        return match failures.pop() {
+            Some(e) => Err(e),
+            None => Ok(())
+        };
+    }
+    let mut details: Vec<String> = failures.into_iter()
+        .map(|e| e.detail.unwrap_or(String::new()))
+        .collect();
+    details.sort();
+    Err(DeliveryError{ kind: Kind::TarFailed,
+                       detail: Some(details.connect("\n")) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::Kind;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+    use std::process::Command;
+    use tempdir::TempDir;
+    use utils::mkdir_recursive;
+
+    /// Tar up `member` (relative to `cwd`) into `archive`. `-P` keeps
+    /// any `..` in the member name, so we can build a hostile archive.
+    fn make_archive(cwd: &PathBuf, archive: &PathBuf, member: &str) {
+        let result = Command::new("tar").arg("czPf")
+            .arg(archive.to_str().unwrap()).arg(member)
+            .current_dir(cwd).output().unwrap();
+        assert!(result.status.success(), "tar czPf failed: {}",
+                String::from_utf8_lossy(&result.stderr));
+    }
+
+    fn write_file(path: &PathBuf) {
+        let mut f = File::create(path).unwrap();
+        f.write_all(b"I like cookies").unwrap();
+    }
+
+    #[test]
+    fn check_entries_test() {
+        assert!(check_entries(&["httpd/metadata.rb".to_string()]).is_ok());
+        assert!(check_entries(&["../httpd/metadata.rb".to_string()]).is_err());
+        assert!(check_entries(&["httpd/../../etc/passwd".to_string()]).is_err());
+        assert!(check_entries(&["/etc/passwd".to_string()]).is_err());
+    }
+
+    #[test]
+    fn extract_limited_under_limit() {
+        let tempdir = TempDir::new("tar").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        mkdir_recursive(&root.join("src").join("httpd")).unwrap();
+        write_file(&root.join("src").join("httpd").join("metadata.rb"));
+        let archive = root.join("httpd.tgz");
+        make_archive(&root.join("src"), &archive, "httpd");
+        assert_eq!(14, unpacked_size(&archive, DEFAULT_MAX_SIZE).unwrap());
+        let dest = root.join("dest");
+        mkdir_recursive(&dest).unwrap();
+        extract_limited(&archive, &dest, 1024).unwrap();
+        assert!(dest.join("httpd").join("metadata.rb").is_file());
+    }
+
+    #[test]
+    fn extract_limited_over_limit() {
+        let tempdir = TempDir::new("tar").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        mkdir_recursive(&root.join("src").join("httpd")).unwrap();
+        write_file(&root.join("src").join("httpd").join("metadata.rb"));
+        let archive = root.join("httpd.tgz");
+        make_archive(&root.join("src"), &archive, "httpd");
+        let dest = root.join("dest");
+        mkdir_recursive(&dest).unwrap();
+        match extract_limited(&archive, &dest, 10) {
+            Err(e) => {
+                match e.kind {
+                    Kind::CookbookTooLarge => {},
+                    _ => panic!("Expected CookbookTooLarge, got {:?}", e)
+                }
+                assert!(e.detail.unwrap().contains("is 14 bytes; the limit is 10 bytes"));
+            },
+            Ok(_) => panic!("Expected the archive to be too large")
+        }
+        assert!(!dest.join("httpd").is_dir());
+    }
+
+    #[test]
+    fn extract_limited_rejects_traversal_entry() {
+        let tempdir = TempDir::new("tar").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        let src = root.join("src");
+        mkdir_recursive(&src.join("httpd")).unwrap();
+        write_file(&root.join("evil.rb"));
+        let archive = root.join("evil.tgz");
+        make_archive(&src, &archive, "../evil.rb");
+        let dest = root.join("dest").join("build_cookbook");
+        mkdir_recursive(&dest).unwrap();
+        match extract_limited(&archive, &dest, DEFAULT_MAX_SIZE) {
+            Err(e) => match e.kind {
+                Kind::TarFailed => {
+                    assert_eq!("Refusing to extract '../evil.rb'; it is outside the destination", e.detail.unwrap());
+                },
+                _ => panic!("Expected TarFailed, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected the traversal entry to be refused")
+        }
+        assert!(!root.join("dest").join("evil.rb").exists());
+    }
+
+    #[test]
+    fn extract_limited_rejects_symlink() {
+        let tempdir = TempDir::new("tar").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        let src = root.join("src");
+        mkdir_recursive(&src.join("httpd")).unwrap();
+        write_file(&src.join("httpd").join("metadata.rb"));
+        let link = Command::new("ln").arg("-s").arg("/etc").arg("etc")
+            .current_dir(&src.join("httpd")).output().unwrap();
+        assert!(link.status.success());
+        let archive = root.join("httpd.tgz");
+        make_archive(&src, &archive, "httpd");
+        let dest = root.join("dest");
+        mkdir_recursive(&dest).unwrap();
+        match extract_limited(&archive, &dest, DEFAULT_MAX_SIZE) {
+            Err(e) => match e.kind {
+                Kind::TarFailed => {
+                    assert_eq!("Refusing to extract 'httpd/etc'; it is a link", e.detail.unwrap());
+                },
+                _ => panic!("Expected TarFailed, got {:?}", e)
+            },
+            Ok(_) => panic!("Expected the link to be refused")
+        }
+        assert!(!dest.join("httpd").exists());
+    }
+
+    #[test]
+    fn extract_all_rejects_malicious_archive() {
+        let tempdir = TempDir::new("tar").ok().expect("TempDir failed");
+        let root = tempdir.path().to_path_buf();
+        let src = root.join("src");
+        mkdir_recursive(&src).unwrap();
+        let mut archives = Vec::new();
+        for name in &["one", "two", "three"] {
+            mkdir_recursive(&src.join(name)).unwrap();
+            write_file(&src.join(name).join("metadata.rb"));
+            let archive = root.join(&format!("{}.tgz", name));
+            make_archive(&src, &archive, name);
+            let dest = root.join(&format!("dest-{}", name));
+            mkdir_recursive(&dest).unwrap();
+            archives.push((archive, dest));
+        }
+        write_file(&root.join("outside.txt"));
+        let evil = root.join("evil.tgz");
+        make_archive(&src, &evil, "../outside.txt");
+        let evil_dest = root.join("dest-evil");
+        mkdir_recursive(&evil_dest).unwrap();
+        archives.push((evil.clone(), evil_dest));
+
+        match extract_all(archives, DEFAULT_MAX_SIZE, DEFAULT_WORKERS) {
+            Err(e) => {
+                match e.kind {
+                    Kind::TarFailed => {},
+                    _ => panic!("Expected TarFailed, got {:?}", e)
+                }
+                let detail = e.detail.unwrap();
+                assert!(detail.contains(evil.to_str().unwrap()));
+                assert!(detail.contains("../outside.txt"));
+                assert!(!detail.contains("one.tgz"));
+            },
+            Ok(_) => panic!("Expected the hostile archive to be refused")
+        }
+        for name in &["one", "two", "three"] {
+            assert!(root.join(&format!("dest-{}", name)).join(name).join("metadata.rb").is_file());
+        }
+    }
+}
@@ -28,40 +28,62 @@ extern crate delivery;
 extern crate rustc_serialize;
 
 use std::env;
+use std::io;
+use std::io::prelude::*;
 use std::error::Error;
 use std::path::PathBuf;
 use delivery::utils::{self, privileged_process};
 // Allowing this, mostly just for testing.
 #[allow(unused_imports)]
 use delivery::utils::say::{self, say, sayln};
-use delivery::errors::{DeliveryError, Kind};
-use delivery::config::Config;
+use delivery::errors::{self, DeliveryError, Kind};
+use delivery::config::{Config, wizard, pipeline_from_branch};
 use delivery::delivery_config::DeliveryConfig;
 use delivery::git;
 use delivery::job::change::Change;
 use delivery::job::workspace::{Workspace, Privilege};
+use delivery::job::phase_env;
 use delivery::utils::path_join_many::PathJoinMany;
-use delivery::getpass;
 use delivery::token;
-use delivery::http::{self, APIClient, APIAuth};
+use delivery::token::device::DeviceCodeStore;
+use delivery::http::{self, APIClient};
 use delivery::project;
+use delivery::support;
+use delivery::cache;
+use delivery::utils::junit;
+use delivery::utils::lock;
+use delivery::utils::artifact_cache::ArtifactCache;
+use delivery::utils::format::{self, Table};
+use delivery::utils::confirm;
+use delivery::http::changes::CHANGE_COLUMNS;
+use delivery::http::webhook;
 
 docopt!(Args derive Debug, "
-Usage: delivery review [--for=<pipeline>] [--no-open]
-       delivery clone <project> [--user=<user>] [--server=<server>] [--ent=<ent>] [--org=<org>] [--git-url=<url>]
-       delivery checkout <change> [--for=<pipeline>] [--patchset=<number>]
-       delivery diff <change> [--for=<pipeline>] [--patchset=<number>] [--local]
-       delivery init [--user=<user>] [--server=<server>] [--ent=<ent>] [--org=<org>] [--project=<project>] [--type=<type>]
-       delivery setup [--user=<user>] [--server=<server>] [--ent=<ent>] [--org=<org>] [--config-path=<dir>] [--for=<pipeline>]
-       delivery job <stage> <phase> [--change=<change>] [--for=<pipeline>] [--job-root=<dir>] [--project=<project>] [--user=<user>] [--server=<server>] [--ent=<ent>] [--org=<org>] [--patchset=<number>] [--git-url=<url>] [--shasum=<gitsha>] [--change-id=<id>] [--no-spinner]
-       delivery pipeline [--for=<pipeline>] [--user=<user>] [--server=<server>] [--ent=<ent>] [--org=<org>] [--project=<project>] [--config-path=<dir>]
-       delivery api <method> <path> [--user=<user>] [--server=<server>] [--ent=<ent>] [--config-path=<dir>] [--data=<data>]
+Usage: delivery review [--for=<pipeline>] [--repo=<dir>] [--no-open] [--print-branch] [--junit-out=<file>] [--allow-dirty] [--require-signed] [--title=<title>] [--yes] [--show-git-output] [--git-path=<path>] [--no-help-links]
+       delivery clone <project> [--user=<user>] [--server=<server>] [--ent=<ent>] [--org=<org>] [--git-url=<url>] [--show-git-output] [--git-path=<path>] [--no-help-links]
+       delivery checkout <change> [--for=<pipeline>] [--repo=<dir>] [--patchset=<number>] [--show-git-output] [--git-path=<path>] [--no-help-links]
+       delivery diff <change> [--for=<pipeline>] [--repo=<dir>] [--patchset=<number>] [--local] [--show-git-output] [--git-path=<path>] [--no-help-links]
+       delivery init [--user=<user>] [--server=<server>] [--ent=<ent>] [--org=<org>] [--project=<project>] [--type=<type>] [--set-git-user] [--show-git-output] [--git-path=<path>] [--accept-new-cert] [--record-script=<file>] [--metrics-file=<file>] [--metrics-format=<format>] [--no-help-links]
+       delivery setup [--user=<user>] [--server=<server>] [--ent=<ent>] [--org=<org>] [--config-path=<dir>] [--for=<pipeline>] [--interactive] [--accept-new-cert] [--record-script=<file>] [--metrics-file=<file>] [--metrics-format=<format>] [--no-help-links]
+       delivery job <stage> <phase> [--change=<change>] [--for=<pipeline>] [--job-root=<dir>] [--project=<project>] [--user=<user>] [--server=<server>] [--ent=<ent>] [--org=<org>] [--patchset=<number>] [--git-url=<url>] [--shasum=<gitsha>] [--change-id=<id>] [--no-spinner] [--config-patch=<json>] [--skip-chown] [--force-unlock] [--no-artifact-cache] [--require-checksum] [--show-git-output] [--git-path=<path>] [--accept-new-cert] [--record-script=<file>] [--metrics-file=<file>] [--metrics-format=<format>] [--no-help-links]
+       delivery pipeline [--for=<pipeline>] [--user=<user>] [--server=<server>] [--ent=<ent>] [--org=<org>] [--project=<project>] [--config-path=<dir>] [--accept-new-cert] [--record-script=<file>] [--metrics-file=<file>] [--metrics-format=<format>] [--no-help-links]
+       delivery api <method> <path> [--user=<user>] [--server=<server>] [--ent=<ent>] [--config-path=<dir>] [--data=<data>] [--accept-new-cert] [--record-script=<file>] [--metrics-file=<file>] [--metrics-format=<format>] [--no-help-links]
        delivery --help
-       delivery token [--user=<user>] [--server=<server>] [--ent=<ent>]
+       delivery token [--user=<user>] [--server=<server>] [--ent=<ent>] [--check] [--token-endpoint=<path>] [--mfa=<code>] [--accept-new-cert] [--record-script=<file>] [--metrics-file=<file>] [--metrics-format=<format>] [--no-help-links]
+       delivery token --export=<file> [--no-help-links]
+       delivery token --import=<file> [--overwrite] [--no-help-links]
+       delivery support-bundle <dir> [--no-help-links]
+       delivery cache clear [--tokens] [--artifacts] [--responses] [--no-help-links]
+       delivery changes [--since=<date>] [--user=<user>] [--server=<server>] [--ent=<ent>] [--org=<org>] [--project=<project>] [--format=<format>] [--accept-new-cert] [--record-script=<file>] [--metrics-file=<file>] [--metrics-format=<format>] [--no-help-links]
 
 Options:
   -h, --help               Show this message.
   -f, --for=<pipeline>     A pipeline to target
+  --repo=<dir>             The git repository to work in, when more than one is found
+  --allow-dirty            Review even with uncommitted changes
+  --require-signed         Refuse to review unless every commit is GPG-signed
+  --title=<title>          The title of the review, instead of the newest commit's subject
+  -y, --yes                Push the review without asking first
   -P, --patchset=<number>  A patchset number [default: latest]
   -u, --user=<user>        A delivery username
   -s, --server=<server>    A delivery server
@@ -76,6 +98,33 @@ Options:
   -C, --change=<change>    A delivery change branch name
   -i, --change-id=<id>     A delivery change ID
   -n, --no-spinner         Turn off the delightful spinner :(
+  --print-branch           Print only the review branch name and exit
+  --junit-out=<file>       Also write the result as JUnit XML to this file
+  --config-patch=<json>    A JSON merge patch to apply to .delivery/config.json
+  --since=<date>           Only list changes submitted since this date (YYYY-MM-DD)
+  --format=<format>        How to print lists: human, json, yaml or tsv [default: human]
+  --skip-chown             Do not chown the workspace to the dbuild user
+  --no-artifact-cache      Download build cookbooks even if they are cached
+  --require-checksum       Refuse a build cookbook that has no checksum to verify
+  --force-unlock           Remove a lock left behind by a crashed job
+  --check                  Check the credentials work, without saving a token
+  --export=<file>          Copy the saved tokens to a file, readable only by you
+  --import=<file>          Add the tokens from a file made by --export
+  --overwrite              Let imported tokens replace ones we already have
+  --token-endpoint=<path>  Request tokens from this path or URL instead
+  --mfa=<code>             A multi-factor authentication code to send
+  --interactive            Prompt for each setup value
+  --set-git-user           Set a missing git user.name from the Delivery user, and ask for a missing user.email
+  --show-git-output        Print the full output of the git commands we run
+  --git-path=<path>        Run this git instead of the one on the PATH
+  --accept-new-cert        Trust the server's certificate even if it changed since we last saw it
+  --record-script=<file>  Write the API requests made to a shell script of curl commands
+  --metrics-file=<file>    On exit, write counts and timings of the API requests made to this file
+  --metrics-format=<format>  How to write --metrics-file: json or prometheus [default: json]
+  --tokens                 Clear saved API tokens
+  --artifacts              Clear downloaded build cookbooks
+  --responses              Clear configs fetched from the server
+  --no-help-links          Don't add links to the docs to error messages
   <change>                 A delivery change branch name
   <type>                   The type of project (currently supported: cookbook)
 ");
@@ -92,13 +141,51 @@ fn main() {
 
     let args: Args = Args::docopt().decode().unwrap_or_else(|e| e.exit());
     debug!("{:?}", args);
+    if args.flag_show_git_output {
+        git::turn_on_git_output();
+    }
+    if !args.flag_git_path.is_empty() {
+        git::use_git_binary(&args.flag_git_path);
+    }
+    if args.flag_accept_new_cert {
+        http::pins::turn_on_accept_new();
+    }
+    let metrics_format = match http::metrics::format(&args.flag_metrics_format) {
+        Ok(f) => f,
+        Err(e) => return exit_with(e)
+    };
+    if !args.flag_record_script.is_empty() {
+        let script = PathBuf::from(&args.flag_record_script);
+        match http::record::start(&script) {
+            Ok(_) => {},
+            Err(e) => return exit_with(e)
+        }
+    }
     let cmd_result = match args {
+        Args {
+            cmd_review: true,
+            flag_for: ref for_pipeline,
+            flag_print_branch: true,
+            ..
+        } => review_branch(&for_pipeline),
         Args {
             cmd_review: true,
             flag_for: ref for_pipeline,
             flag_no_open: ref no_open,
+            flag_junit_out: ref junit_out,
+            flag_allow_dirty: allow_dirty,
+            flag_require_signed: require_signed,
+            flag_title: ref title,
+            flag_yes: yes,
+            flag_repo: ref repo,
+            ..
+        } => review(&for_pipeline, &no_open, &junit_out, allow_dirty, require_signed, &title, yes, &repo),
+        Args {
+            cmd_setup: true,
+            flag_config_path: ref path,
+            flag_interactive: true,
             ..
-        } => review(&for_pipeline, &no_open),
+        } => setup_interactive(&path),
         Args {
             cmd_setup: true,
             flag_user: ref user,
@@ -117,23 +204,26 @@ fn main() {
             flag_org: ref org,
             flag_project: ref proj,
             flag_type: ref proj_type,
+            flag_set_git_user: set_git_user,
             ..
-        } => init(&user, &server, &ent, &org, &proj, &proj_type),
+        } => init(&user, &server, &ent, &org, &proj, &proj_type, set_git_user),
         Args {
             cmd_checkout: true,
             arg_change: ref change,
             flag_patchset: ref patchset,
             flag_for: ref pipeline,
+            flag_repo: ref repo,
             ..
-        } => checkout(&change, &patchset, &pipeline),
+        } => checkout(&change, &patchset, &pipeline, &repo),
         Args {
             cmd_diff: true,
             arg_change: ref change,
             flag_patchset: ref patchset,
             flag_for: ref pipeline,
             flag_local: ref local,
+            flag_repo: ref repo,
             ..
-        } => diff(&change, &patchset, &pipeline, local),
+        } => diff(&change, &patchset, &pipeline, local, &repo),
         Args {
             cmd_pipeline: true,
             flag_user: ref user,
@@ -184,24 +274,117 @@ fn main() {
             flag_git_url: ref git_url,
             flag_shasum: ref shasum,
             flag_no_spinner: no_spinner,
+            flag_config_patch: ref config_patch,
+            flag_skip_chown: skip_chown,
+            flag_force_unlock: force_unlock,
+            flag_no_artifact_cache: no_artifact_cache,
+            flag_require_checksum: require_checksum,
             ..
         } => {
             if no_spinner { say::turn_off_spinner() };
-            job(&stage, &phase, &change, &pipeline, &job_root, &project, &user, &server, &ent, &org, &patchset, &change_id, &git_url, &shasum)
+            job(&stage, &phase, &change, &pipeline, &job_root, &project, &user, &server, &ent, &org, &patchset, &change_id, &git_url, &shasum, &config_patch, skip_chown, force_unlock, no_artifact_cache, require_checksum)
         },
+        Args {
+            cmd_token: true,
+            flag_export: ref file,
+            ..
+        } if !file.is_empty() => export_tokens(&file),
+        Args {
+            cmd_token: true,
+            flag_import: ref file,
+            flag_overwrite: overwrite,
+            ..
+        } if !file.is_empty() => import_tokens(&file, overwrite),
+        Args {
+            cmd_token: true,
+            flag_server: ref server,
+            flag_ent: ref ent,
+            flag_user: ref user,
+            flag_check: true,
+            flag_token_endpoint: ref endpoint,
+            flag_mfa: ref mfa,
+            ..
+        } => check_token(&server, &ent, &user, &endpoint, &mfa),
         Args {
             cmd_token: true,
             flag_server: ref server,
             flag_ent: ref ent,
             flag_user: ref user,
+            flag_token_endpoint: ref endpoint,
+            flag_mfa: ref mfa,
+            ..
+        } => api_token(&server, &ent, &user, &endpoint, &mfa),
+        Args {
+            cmd_support_bundle: true,
+            arg_dir: ref dir,
+            ..
+        } => support_bundle(&dir),
+        Args {
+            cmd_cache: true,
+            cmd_clear: true,
+            flag_tokens: tokens,
+            flag_artifacts: artifacts,
+            flag_responses: responses,
+            ..
+        } => clear_cache(tokens, artifacts, responses),
+        Args {
+            cmd_changes: true,
+            flag_since: ref since,
+            flag_user: ref user,
+            flag_server: ref server,
+            flag_ent: ref ent,
+            flag_org: ref org,
+            flag_project: ref proj,
+            flag_format: ref output_format,
             ..
-        } => api_token(&server, &ent, &user),
+        } => list_changes(&since, &user, &server, &ent, &org, &proj, &output_format),
         _ => no_matching_command(),
     };
+    if !args.flag_metrics_file.is_empty() {
+        let path = PathBuf::from(&args.flag_metrics_file);
+        match http::metrics::write(&path, metrics_format) {
+            Ok(_) => {},
+            Err(e) => sayln("red", &format!("Unable to write metrics to {}: {}", path.display(), e))
+        }
+    }
     match cmd_result {
         Ok(_) => {},
-        Err(e) => exit_with(e, 1)
+        Err(e) => {
+            let link = if args.flag_no_help_links { None } else { help_link(&e) };
+            exit_with(e);
+            // After the error, which it explains
+            match link {
+                Some(url) => sayln("white", &format!("For help, see {}", url)),
+                None => {}
+            }
+        }
+    }
+}
+
+/// The docs page for `e`, if it has one.
+fn help_link(e: &DeliveryError) -> Option<String> {
+    let base = match Config::load_config(&project_dir()) {
+        Ok(c) => c.help_base_url.unwrap_or(errors::DEFAULT_HELP_BASE_URL.to_string()),
+        Err(_) => errors::DEFAULT_HELP_BASE_URL.to_string()
+    };
+    e.kind.help_url(&base)
+}
+
+fn export_tokens(file: &str) -> Result<(), DeliveryError> {
+    let tstore = try!(token::TokenStore::from_home());
+    let count = try!(tstore.export(&PathBuf::from(file)));
+    sayln("green", &format!("Exported {} token(s) to {}", count, file));
+    Ok(())
+}
+
+fn import_tokens(file: &str, overwrite: bool) -> Result<(), DeliveryError> {
+    let mut tstore = try!(token::TokenStore::from_home());
+    let summary = try!(tstore.import(&PathBuf::from(file), overwrite));
+    sayln("green", &format!("Imported {} new token(s), replaced {}", summary.added, summary.replaced));
+    for key in summary.skipped.iter() {
+        sayln("yellow", &format!("Skipped {}; we already have a different token for it (use --overwrite to replace it)", key));
     }
+    Ok(())
 }
 
 #[allow(dead_code)]
@@ -209,31 +392,78 @@ fn cwd() -> PathBuf {
     env::current_dir().unwrap()
 }
 
+/// Where to read the config from: the root of the project we are in,
+/// so commands work the same from any of its subdirectories, or the
+/// current directory outside a project.
+fn project_dir() -> PathBuf {
+    project::find_root(&cwd()).unwrap_or(cwd())
+}
+
+/// The project to use when `--project` may be empty: the one named by
+/// the `delivery` remote's URL if we can parse it, and the name of the
+/// current directory if not.
+fn default_project(proj: &str) -> String {
+    if !proj.is_empty() {
+        return proj.to_string();
+    }
+    match git::project_from_remote(&cwd()) {
+        Some(p) => p,
+        None => String::from_str(cwd().file_name().unwrap().to_str().unwrap())
+    }
+}
+
 #[allow(dead_code)]
 fn no_matching_command() -> Result<(), DeliveryError> {
     Err(DeliveryError { kind: Kind::NoMatchingCommand, detail: None })
 }
 
 #[allow(dead_code)]
-fn exit_with(e: DeliveryError, i: isize) {
-    sayln("red", e.description());
-    match e.detail() {
-        Some(deets) => sayln("red", &deets),
+fn exit_with(e: DeliveryError) {
+    // Tools that wrap us can ask for the error as JSON instead
+    if env::var("DELIVERY_ERROR_FORMAT").map(|f| f == "json").unwrap_or(false) {
+        let _ = writeln!(&mut io::stderr(), "{}", e.to_json());
+        return env::set_exit_status(e.exit_code());
+    }
+    match e.kind {
+        // Saying no isn't a failure, so don't make it look like one
+        Kind::ReviewAbortedByUser => {
+            sayln("yellow", &format!("{}", e));
+            return env::set_exit_status(e.exit_code());
+        },
+        _ => {}
+    }
+    sayln("red", &format!("{}", e));
+    match e.cause() {
+        Some(c) => sayln("red", &format!("caused by: {}", errors::chain(c))),
         None => {}
     }
-    let x = i as i32;
-    env::set_exit_status(x)
+    env::set_exit_status(e.exit_code())
 }
 
+/// Load the config from the nearest `cli.toml` at or above `path`.
 #[allow(dead_code)]
 fn load_config(path: &PathBuf) -> Result<Config, DeliveryError> {
     say("white", "Loading configuration from ");
     let msg = format!("{}", path.display());
     sayln("yellow", &msg);
-    let config = try!(Config::load_config(&cwd()));
+    let config = try!(Config::load_config(path));
+    // --git-path, which was set first, and DELIVERY_GIT win over cli.toml
+    match config.git_path {
+        Some(ref git) if env::var("DELIVERY_GIT").is_err() => git::use_git_binary(git),
+        _ => {}
+    }
     Ok(config)
 }
 
+/// Load the config under `path` as it is saved, leaving out the
+/// `DELIVERY_*` environment overrides, for commands that write it
+/// back to cli.toml.
+fn load_saved_config(path: &PathBuf) -> Result<Config, DeliveryError> {
+    say("white", "Loading configuration from ");
+    sayln("yellow", &format!("{}", path.display()));
+    Config::load_config_file(path)
+}
+
 #[allow(dead_code)]
 fn setup(user: &str, server: &str, ent: &str, org: &str, path: &str, pipeline: &str) -> Result<(), DeliveryError> {
     sayln("green", "Chef Delivery");
@@ -242,31 +472,54 @@ fn setup(user: &str, server: &str, ent: &str, org: &str, path: &str, pipeline: &
     } else {
         PathBuf::from(path)
     };
-    let mut config = try!(load_config(&config_path));
+    let mut config = try!(load_saved_config(&config_path));
     config = config.set_server(server)
         .set_user(user)
         .set_enterprise(ent)
         .set_organization(org)
         .set_pipeline(pipeline) ;
-    try!(config.write_file(&config_path));
+    let report = try!(project::setup(&config, &config_path));
+    report.report();
     Ok(())
 }
 
+/// Walk through the setup values one prompt at a time, check them by
+/// getting a token, then save both the token and the config.
 #[allow(dead_code)]
-fn init(user: &str, server: &str, ent: &str, org: &str, proj: &str, proj_type: &str) -> Result<(), DeliveryError> {
+fn setup_interactive(path: &str) -> Result<(), DeliveryError> {
     sayln("green", "Chef Delivery");
-    let mut config = try!(load_config(&cwd()));
-    // Since we wind up taking the filename as a reference, we need to
-    // have its scope be the entire method. Sadly, it means we call it
-    // whether we need to or not. We could probably abstract this into
-    // a function and get the lifetimes right, but.. meh :)
-    let cwd = try!(env::current_dir());
-    let filename = String::from_str(cwd.file_name().unwrap().to_str().unwrap());
-    let final_proj = if proj.is_empty() {
-        &filename[..]
+    let config_path = if path.is_empty() {
+        cwd()
     } else {
-        proj
+        PathBuf::from(path)
     };
+    let config = try!(load_saved_config(&config_path));
+    say::turn_off_spinner();
+    let stdin = io::stdin();
+    let config = try!(wizard::collect(config, &mut stdin.lock(), &mut io::stdout()));
+    let s = validate!(config, server);
+    let e = validate!(config, enterprise);
+    let u = validate!(config, user);
+    let client = try!(APIClient::from_config(&config));
+    let pass = try!(http::token::prompt_password());
+    say("white", "Checking we can log in to ");
+    sayln("magenta", &s);
+    let opts = http::token::TokenOptions::from_config(&config);
+    let token = try!(http::token::request_with_client(&client, &u, &pass, &opts));
+    let saved = try!(token::write_token(&s, &e, &u, &token));
+    sayln("green", &format!("saved API token to: {}", saved.display()));
+    try!(config.write_file(&config_path));
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn init(user: &str, server: &str, ent: &str, org: &str, proj: &str, proj_type: &str,
+        set_git_user: bool) -> Result<(), DeliveryError> {
+    sayln("green", "Chef Delivery");
+    let mut config = try!(load_config(&project_dir()));
+    let cwd = try!(env::current_dir());
+    let filename = default_project(proj);
+    let final_proj = &filename[..];
 
     config = config.set_user(user)
         .set_server(server)
@@ -279,52 +532,236 @@ fn init(user: &str, server: &str, ent: &str, org: &str, proj: &str, proj_type: &
     let o = validate!(config, organization);
     let p = validate!(config, project);
 
-    try!(project::import(&u, &s, &e, &o, &p, &cwd));
+    // committing the new config needs a git user, so make sure of one
+    // before anything is imported
+    if set_git_user {
+        let email = try!(ask_git_email(&u, &s, &cwd));
+        try!(git::set_git_user(&u, email.as_ref().map(|e| &e[..]), &cwd));
+    }
+    try!(git::check_git_user(&cwd));
+
+    let mut client = try!(APIClient::from_config(&config));
+    try!(token::ensure_valid(&mut client, &config, &s, &e, &u));
+    let pipeline = try!(project::import(&client, &u, &s, &e, &o, &p, &cwd));
 
     // now to adding the .delivery/config.json
     try!(DeliveryConfig::init(&cwd, proj_type));
     // if we got here, we've checked out a feature branch, added a
     // config file, and made a local commit. Let's create the review!
-    try!(review("master", &false));
+    // Asking first is for reviews of the user's own work, not this one,
+    // and the branch is ours to push too.
+    let head = try!(git::get_head());
+    try!(git::ensure_branch_pushed(&head, true, &cwd));
+    try!(review(&pipeline, &false, "", false, false, "", true, ""));
     Ok(())
 }
 
+/// The email `--set-git-user` should commit with, when git has none:
+/// we offer `user@server`, but only use what the user answers. With
+/// no terminal to ask on there is none, and `check_git_user` says
+/// what to set instead.
+fn ask_git_email(user: &str, server: &str, path: &PathBuf) -> Result<Option<String>, DeliveryError> {
+    match git::git_user(path) {
+        (_, Some(_)) => return Ok(None),
+        _ => {}
+    }
+    if !confirm::stdin_is_tty() {
+        return Ok(None);
+    }
+    say::turn_off_spinner();
+    let stdin = io::stdin();
+    let email = try!(wizard::ask(&mut stdin.lock(), &mut io::stdout(), "Git email for commits",
+                                 Some(format!("{}@{}", user, server)), wizard::validate_email));
+    Ok(Some(email))
+}
+
 #[allow(dead_code)]
-fn review(for_pipeline: &str, no_open: &bool) -> Result<(), DeliveryError> {
+fn review(for_pipeline: &str, no_open: &bool, junit_out: &str,
+          allow_dirty: bool, require_signed: bool, title: &str, yes: bool,
+          repo: &str) -> Result<(), DeliveryError> {
+    let mut suite = junit::TestSuite::new("delivery review");
+    let result = review_steps(for_pipeline, no_open, allow_dirty, require_signed, title, yes, repo, &mut suite);
+    if !junit_out.is_empty() {
+        try!(suite.write(&PathBuf::from(junit_out)));
+    }
+    notify_review(&result);
+    result.map(|_| ())
+}
+
+/// Call the `post_review_webhook` from cli.toml, if there is one,
+/// about the review in `result`.
+fn notify_review(result: &Result<webhook::ReviewNotice, DeliveryError>) {
+    let config = match Config::load_config(&project_dir()) {
+        Ok(c) => c,
+        Err(_) => return
+    };
+    let hook = match config.post_review_webhook {
+        Some(ref h) => h.clone(),
+        None => return
+    };
+    match APIClient::for_webhook(&config) {
+        Ok(client) => { webhook::after_review(&client, Some(&hook), result); },
+        Err(e) => sayln("yellow", &format!("Failed to call post_review_webhook: {}", e))
+    }
+}
+
+/// The work of `delivery review`, with each step recorded in `suite`.
+fn review_steps(for_pipeline: &str, no_open: &bool, allow_dirty: bool,
+                require_signed: bool, title: &str, yes: bool, repo: &str,
+                suite: &mut junit::TestSuite) -> Result<webhook::ReviewNotice, DeliveryError> {
     sayln("green", "Chef Delivery");
-    let mut config = try!(load_config(&cwd()));
-    config = config.set_pipeline(for_pipeline);
-    let target = validate!(config, pipeline);
-    say("white", "Review for change ");
-    let head = try!(git::get_head());
-    if &target == &head {
-        return Err(DeliveryError{ kind: Kind::CannotReviewSameBranch, detail: None })
+    let chosen = try!(suite.step("choose repository", use_repo(repo)));
+    let root = try!(suite.step("find project", project::find_root(&cwd())));
+    let config = try!(suite.step("load config", load_config(&root)));
+    if !chosen {
+        try!(suite.step("find repository", git::find_repo_root(&root)));
     }
+    let head = try!(suite.step("find current branch", git::get_head()));
+    let target = try!(suite.step("find pipeline",
+                                 find_pipeline(&config, for_pipeline, &head, &root)));
+    say("white", "Review for change ");
+    try!(suite.step("check branch", git::check_review_branches(&head, &target)));
     say("yellow", &head);
     say("white", " targeted for pipeline ");
     sayln("magenta", &target);
-    let review = try!(git::git_push_review(&head, &target));
+    if !allow_dirty {
+        try!(suite.step("check working tree", git::check_clean_working_tree(&root)));
+    }
+    try!(suite.step("check target",
+                    git::check_review_target(&head, &target, &root)));
+    if require_signed {
+        try!(suite.step("check signatures",
+                        git::check_signed_commits(&head, &target, &root)));
+    }
+    let review_title = try!(suite.step("find title",
+                                       git::review_title(&head, &root)
+                                       .and_then(|t| git::choose_review_title(title, t))));
+    say("white", "Titled ");
+    sayln("yellow", &review_title);
+    try!(suite.step("check description", check_description(&config, &head)));
+    if config.require_reviewers.unwrap_or(false) {
+        try!(suite.step("check reviewers", check_reviewers(&config)));
+    }
+    try!(suite.step("check remote",
+                    git::check_delivery_remote(&root, &config.allowed_servers)));
+    // Ask before anything is pushed, the branch itself included
+    let stdin = io::stdin();
+    try!(suite.step("confirm push",
+                    confirm::review(&mut stdin.lock(), &mut io::stdout(), &head, &target,
+                                    yes, confirm::stdin_is_tty())));
+    try!(suite.step("check branch is pushed",
+                    git::ensure_branch_pushed(&head, config.auto_push.unwrap_or(false), &root)));
+    let given_title = if title.is_empty() { None } else { Some(title) };
+    let review = try!(suite.step("push review", git::git_push_review(&head, &target, given_title)));
     for line in review.messages.iter() {
         sayln("white", line);
     }
     match review.url {
-        Some(url) => {
-            sayln("magenta", &url);
+        Some(ref url) => {
+            sayln("magenta", url);
             if !no_open {
-                try!(utils::open::item(&url));
+                try!(utils::open::item(url));
             }
         },
         None => {}
     };
+    Ok(webhook::ReviewNotice{
+        change_url: review.url.unwrap_or(String::new()),
+        title: review_title,
+        author: config.user.clone().unwrap_or(String::new())
+    })
+}
+
+/// The pipeline `--for` names, or the one `pipeline_branch_pattern`
+/// finds in `head`; with neither, the default branch of the delivery
+/// remote rather than always `master`.
+fn find_pipeline(config: &Config, for_pipeline: &str, head: &str,
+                 path: &PathBuf) -> Result<String, DeliveryError> {
+    let from_pattern = match config.pipeline_branch_pattern {
+        Some(ref pattern) if for_pipeline.is_empty() => try!(pipeline_from_branch(pattern, head)),
+        _ => None
+    };
+    if for_pipeline.is_empty() && from_pattern.is_none() {
+        let remote_head = try!(git::get_remote_head("delivery", path));
+        return config.clone().set_pipeline(&remote_head).pipeline();
+    }
+    config.clone().set_pipeline_for_branch(for_pipeline, head).and_then(|c| c.pipeline())
+}
+
+/// `pipeline`, or the default branch of the delivery remote when it is
+/// empty.
+fn pipeline_or_remote_head(pipeline: &str, path: &PathBuf) -> Result<String, DeliveryError> {
+    if pipeline.is_empty() {
+        git::get_remote_head("delivery", path)
+    } else {
+        Ok(pipeline.to_string())
+    }
+}
+
+/// Hold the description of the review for `head` to
+/// `review_description_max`, when that is set.
+fn check_description(config: &Config, head: &str) -> Result<(), DeliveryError> {
+    match try!(config.review_description_max_len()) {
+        Some(max) => {
+            let description = try!(git::review_description(head, &cwd()));
+            git::check_review_description(&description, max)
+        },
+        None => Ok(())
+    }
+}
+
+/// Make sure the server has someone to review changes to the
+/// project, for `require_reviewers`; `Kind::NoReviewersAvailable` if
+/// it has no one.
+fn check_reviewers(config: &Config) -> Result<(), DeliveryError> {
+    let given = config.project.clone().unwrap_or(String::new());
+    let config = config.clone().set_project(&default_project(&given));
+    let u = validate!(config, user);
+    let s = validate!(config, server);
+    let e = validate!(config, enterprise);
+    let o = validate!(config, organization);
+    let p = validate!(config, project);
+    let mut client = try!(APIClient::from_config(&config));
+    try!(token::ensure_valid(&mut client, &config, &s, &e, &u));
+    try!(client.reviewers(&o, &p));
+    Ok(())
+}
+
+/// Work in the repository `repo` names, for `--repo`, by moving into
+/// it. Whether there was one; without, the caller finds the
+/// repository itself, and may find more than one.
+fn use_repo(repo: &str) -> Result<bool, DeliveryError> {
+    if repo.is_empty() {
+        return Ok(false);
+    }
+    let dir = cwd().join(repo);
+    try!(git::check_repo_root(&dir));
+    try!(env::set_current_dir(&dir));
+    Ok(true)
+}
+
+/// Print the branch `delivery review` would push to, and nothing
+/// else, so scripts can consume it.
+#[allow(dead_code)]
+fn review_branch(for_pipeline: &str) -> Result<(), DeliveryError> {
+    say::turn_off_spinner();
+    let config = try!(Config::load_config(&project_dir()));
+    let head = try!(git::get_head());
+    let target = try!(find_pipeline(&config, for_pipeline, &head, &cwd()));
+    println!("{}", git::review_branch_name(&head, &target));
     Ok(())
 }
 
 #[allow(dead_code)]
-fn checkout(change: &str, patchset: &str, pipeline: &str) -> Result<(), DeliveryError> {
+fn checkout(change: &str, patchset: &str, pipeline: &str, repo: &str) -> Result<(), DeliveryError> {
     sayln("green", "Chef Delivery");
-    let mut config = try!(load_config(&cwd()));
-    config = config.set_pipeline(pipeline);
+    let chosen = try!(use_repo(repo));
+    let mut config = try!(load_config(&project_dir()));
+    config = config.set_pipeline(&try!(pipeline_or_remote_head(pipeline, &cwd())));
     let target = validate!(config, pipeline);
+    if !chosen {
+        try!(git::find_repo_root(&cwd()));
+    }
     say("white", "Checking out ");
     say("yellow", change);
     say("white", " targeted for pipeline ");
@@ -341,11 +778,16 @@ fn checkout(change: &str, patchset: &str, pipeline: &str) -> Result<(), Delivery
 }
 
 #[allow(dead_code)]
-fn diff(change: &str, patchset: &str, pipeline: &str, local: &bool) -> Result<(), DeliveryError> {
+fn diff(change: &str, patchset: &str, pipeline: &str, local: &bool,
+        repo: &str) -> Result<(), DeliveryError> {
     sayln("green", "Chef Delivery");
-    let mut config = try!(load_config(&cwd()));
-    config = config.set_pipeline(pipeline);
+    let chosen = try!(use_repo(repo));
+    let mut config = try!(load_config(&project_dir()));
+    config = config.set_pipeline(&try!(pipeline_or_remote_head(pipeline, &cwd())));
     let target = validate!(config, pipeline);
+    if !chosen {
+        try!(git::find_repo_root(&cwd()));
+    }
     say("white", "Showing diff for ");
     say("yellow", change);
    say("white", " targeted for pipeline ");
@@ -364,7 +806,7 @@ fn diff(change: &str, patchset: &str, pipeline: &str, local: &bool) -> Result<()
 #[allow(dead_code)]
 fn clone(project: &str, user: &str, server: &str, ent: &str, org: &str, git_url: &str) -> Result<(), DeliveryError> {
     sayln("green", "Chef Delivery");
-    let mut config = try!(load_config(&cwd()));
+    let mut config = try!(load_config(&project_dir()));
     config = config.set_user(user)
         .set_server(server)
         .set_enterprise(ent)
@@ -408,15 +850,15 @@ fn job(stage: &str,
        patchset: &str,
        change_id: &str,
        git_url: &str,
-       shasum: &str) ->
+       shasum: &str,
+       config_patch: &str,
+       skip_chown: bool,
+       force_unlock: bool,
+       no_artifact_cache: bool,
+       require_checksum: bool) ->
 Result<(), DeliveryError> { sayln("green", "Chef Delivery");
-    let mut config = try!(load_config(&cwd()));
-    config = if project.is_empty() {
-        let filename = String::from_str(cwd().file_name().unwrap().to_str().unwrap());
-        config.set_project(&filename)
-    } else {
-        config.set_project(project)
-    };
+    let mut config = try!(load_config(&project_dir()));
+    config = config.set_project(&default_project(project));
     config = config.set_pipeline(pipeline)
         .set_user(user)
         .set_server(server)
@@ -436,7 +878,7 @@ Result<(), DeliveryError> { sayln("green", "Chef Delivery");
         if privileged_process() {
             PathBuf::from("/var/opt/delivery/workspace").join_many(&[&s[..], &e, &o, &p, &pi, stage, phase])
         } else {
-            match env::home_dir() {
+            match utils::home_dir() {
                 Some(path) => path.join_many(&[".delivery", &s, &e, &o, &p, &pi, stage, phase]),
                 None => return Err(DeliveryError{ kind: Kind::NoHomedir, detail: None })
             }
@@ -444,8 +886,24 @@ Result<(), DeliveryError> { sayln("green", "Chef Delivery");
     } else {
         PathBuf::from(job_root)
     };
-    let ws = Workspace::new(&job_root_path);
+    let mut ws = Workspace::new(&job_root_path);
+    ws.skip_chown = skip_chown || config.skip_chown.unwrap_or(false);
+    ws.require_checksum = require_checksum;
+    match config.env {
+        Some(ref env) => {
+            try!(phase_env::check(env));
+            ws.phase_env = env.clone();
+        },
+        None => {}
+    }
+    if !no_artifact_cache {
+        ws.artifact_cache = ArtifactCache::default_dir();
+    }
     sayln("white", "Creating workspace");
+    // Lock before building, so a second run can't touch the
+    // workspace while this one holds it.
+    try!(utils::mkdir_recursive(&ws.root));
+    let _lock = try!(lock::acquire(&ws.root, force_unlock));
     try!(ws.build());
     say("white", "Cloning repository, and merging");
     let mut local = false;
@@ -468,7 +926,7 @@ Result<(), DeliveryError> { sayln("green", "Chef Delivery");
     sayln("magenta", &pi);
     let clone_url = if git_url.is_empty() {
         if local {
-            cwd().into_os_string().to_string_lossy().into_owned()
+            try!(project::find_root(&cwd())).into_os_string().to_string_lossy().into_owned()
         } else {
             git::delivery_ssh_url(&u, &s, &e, &o, &p)
         }
@@ -492,37 +950,85 @@ Result<(), DeliveryError> { sayln("green", "Chef Delivery");
         change_id: change_id.to_string(),
         patchset_number: patch.to_string()
     };
-    try!(ws.setup_chef_for_job(&u, &s, change));
+    try!(ws.setup_chef_for_job(&u, &s, change, config_patch, &config));
     sayln("white", "Running the job");
     if privileged_process() {
         sayln("yellow", "Setting up the builder");
-        try!(ws.run_job("default", Privilege::NoDrop));
+        try!(ws.run_job("default", Privilege::NoDrop, config_patch, &config));
         sayln("magenta", &format!("Running phase {}", phase));
-        try!(ws.run_job(phase, Privilege::Drop));
+        try!(ws.run_job(phase, Privilege::Drop, config_patch, &config));
     } else {
-        try!(ws.run_job(phase, Privilege::NoDrop));
+        try!(ws.run_job(phase, Privilege::NoDrop, config_patch, &config));
     }
     Ok(())
 }
 
 #[allow(dead_code)]
 fn api_token(server: &str, ent: &str,
-             user: &str) -> Result<(), DeliveryError> {
+             user: &str, endpoint: &str, mfa: &str) -> Result<(), DeliveryError> {
     sayln("green", "Chef Delivery");
-    let mut config = try!(load_config(&cwd()));
+    let mut config = try!(load_config(&project_dir()));
     config = config.set_server(server)
         .set_enterprise(ent)
-        .set_user(user);
+        .set_user(user)
+        .set_token_endpoint(endpoint);
     let s = validate!(config, server);
     let e = validate!(config, enterprise);
     let u = validate!(config, user);
 
-    let mut tstore = try!(token::TokenStore::from_home());
-    let pass = getpass::read("Delivery password: ");
-    let token = try!(http::token::request(&s, &e, &u, &pass));
+    let client = try!(APIClient::from_config(&config));
+    let opts = http::token::TokenOptions::from_config(&config).with_mfa(mfa);
+    let (token, saved) = match opts.device_endpoint {
+        Some(ref endpoint) => {
+            let mut codes = try!(DeviceCodeStore::from_home());
+            let t = try!(http::token::request_with_device_code(&client, &mut codes, &s, &e, &u,
+                                                               endpoint, &opts));
+            let path = try!(token::write_token(&s, &e, &u, &t));
+            (t, Some(path))
+        },
+        None => {
+            let pass = try!(http::token::prompt_password());
+            try!(http::token::fetch_token(&client, &try!(home()), true,
+                                          &s, &e, &u, &pass, &opts))
+        }
+    };
     sayln("magenta", &format!("token: {}", &token));
-    try!(tstore.write_token(&s, &e, &u, &token));
-    sayln("green", &format!("saved API token to: {}", tstore.path().display()));
+    match saved {
+        Some(path) => sayln("green", &format!("saved API token to: {}", path.display())),
+        None => {}
+    }
+    Ok(())
+}
+
+fn home() -> Result<PathBuf, DeliveryError> {
+    match utils::home_dir() {
+        Some(h) => Ok(h),
+        None => Err(DeliveryError{ kind: Kind::NoHomedir,
+                                   detail: Some("unable to find home dir".to_string()) })
+    }
+}
+
+/// Like `api_token`, but only reports whether the credentials work;
+/// the token store is never opened.
+#[allow(dead_code)]
+fn check_token(server: &str, ent: &str,
+               user: &str, endpoint: &str, mfa: &str) -> Result<(), DeliveryError> {
+    sayln("green", "Chef Delivery");
+    let mut config = try!(load_config(&project_dir()));
+    config = config.set_server(server)
+        .set_enterprise(ent)
+        .set_user(user)
+        .set_token_endpoint(endpoint);
+    let s = validate!(config, server);
+    let e = validate!(config, enterprise);
+    let u = validate!(config, user);
+
+    let client = try!(APIClient::from_config(&config));
+    let pass = try!(http::token::prompt_password());
+    let opts = http::token::TokenOptions::from_config(&config).with_mfa(mfa);
+    try!(http::token::fetch_token(&client, &try!(home()), false,
+                                  &s, &e, &u, &pass, &opts));
+    sayln("green", "Credentials are valid");
     Ok(())
 }
 
@@ -531,15 +1037,9 @@ fn init_pipeline(server: &str, user: &str,
                  ent: &str, org: &str, proj: &str,
                  pipeline: &str) -> Result<(), DeliveryError> {
     sayln("green", "Chef Delivery: baking a new pipeline");
-    let mut config = try!(Config::load_config(&cwd()));
-    let cwd = try!(env::current_dir());
-    let final_proj = if proj.is_empty() {
-        // let cwd_name = cwd.file_name().unwrap().to_str().unwrap();
-        // std::str::from_utf8(cwd_name.clone()).unwrap()
-        cwd.file_name().unwrap().to_str().unwrap()
-    } else {
-        proj
-    };
+    let mut config = try!(Config::load_config(&project_dir()));
+    let filename = default_project(proj);
+    let final_proj = &filename[..];
     config = config.set_user(user)
         .set_server(server)
         .set_enterprise(ent)
@@ -566,7 +1066,7 @@ fn init_pipeline(server: &str, user: &str,
 #[allow(dead_code)]
 fn api_req(method: &str, path: &str, data: &str,
            server: &str, ent: &str, user: &str) -> Result<(), DeliveryError> {
-    let mut config = try!(Config::load_config(&cwd()));
+    let mut config = try!(Config::load_config(&project_dir()));
     config = config.set_user(user)
         .set_server(server)
         .set_enterprise(ent);
@@ -574,15 +1074,13 @@ fn api_req(method: &str, path: &str, data: &str,
     let s = validate!(config, server);
     let e = validate!(config, enterprise);
 
-    let mut client = APIClient::new_https(&s, &e);
-
-    let tstore = try!(token::TokenStore::from_home());
-    
-    let auth = try!(APIAuth::from_token_store(tstore, &s, &e, &u));
-    client.set_auth(auth);
+    let mut client = try!(APIClient::from_config(&config));
+    try!(token::ensure_valid(&mut client, &config, &s, &e, &u));
     let result = match method {
         "get" => client.get(path),
         "post" => client.post(path, data),
+        "put" => client.put(path, data),
+        "delete" => client.delete(path),
         _ => return Err(DeliveryError{ kind: Kind::UnsupportedHttpMethod,
                                        detail: None })
     };
@@ -593,3 +1091,56 @@ fn api_req(method: &str, path: &str, data: &str,
     };
     Ok(())
 }
+
+#[allow(dead_code)]
+fn support_bundle(dir: &str) -> Result<(), DeliveryError> {
+    sayln("green", "Chef Delivery");
+    let config = try!(load_config(&project_dir()));
+    let written = try!(support::write_bundle(&PathBuf::from(dir), &config,
+                                             &cwd(), env::vars().collect(),
+                                             http::trace::log_path()));
+    for path in written.iter() {
+        sayln("white", &format!("wrote {}", path.display()));
+    }
+    sayln("green", "Secrets have been redacted; please review the files before sharing them.");
+    Ok(())
+}
+
+/// Remove the chosen caches from `~/.delivery`, or all of them.
+fn clear_cache(tokens: bool, artifacts: bool, responses: bool) -> Result<(), DeliveryError> {
+    sayln("green", "Chef Delivery");
+    let dot_delivery = match utils::home_dir() {
+        Some(home) => home.join(".delivery"),
+        None => return Err(DeliveryError{ kind: Kind::NoHomedir, detail: None })
+    };
+    let kinds = cache::selected(tokens, artifacts, responses);
+    let cleared = try!(cache::clear(&dot_delivery, &kinds));
+    for line in cache::report(&cleared).iter() {
+        sayln("white", line);
+    }
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn list_changes(since: &str, user: &str, server: &str, ent: &str,
+                org: &str, proj: &str, output_format: &str) -> Result<(), DeliveryError> {
+    let formatter = try!(format::formatter(output_format));
+    let mut config = try!(Config::load_config(&project_dir()));
+    config = config.set_project(&default_project(proj));
+    config = config.set_user(user)
+        .set_server(server)
+        .set_enterprise(ent)
+        .set_organization(org);
+    let u = validate!(config, user);
+    let s = validate!(config, server);
+    let e = validate!(config, enterprise);
+    let o = validate!(config, organization);
+    let p = validate!(config, project);
+
+    let mut client = try!(APIClient::from_config(&config));
+    try!(token::ensure_valid(&mut client, &config, &s, &e, &u));
+    let changes = try!(client.list_changes(&o, &p, since));
+    let table = Table::from_json(&changes, &CHANGE_COLUMNS);
+    print!("{}", formatter.render(&table));
+    Ok(())
+}
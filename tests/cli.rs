@@ -1,5 +1,5 @@
 use delivery::git::git_command;
-use delivery::utils::copy_recursive;
+use delivery::utils::{copy_recursive, mkdir_recursive};
 use delivery::utils::say;
 use std::io::prelude::*;
 use tempdir::TempDir;
@@ -90,11 +90,18 @@ fn setup_checkout_branch(tmpdir: &TempDir, branch: &str) {
     panic_on_error!(git_command(&["checkout", branch], tmpdir.path()));
 }
 
+/// Pushes the named branch to the delivery remote, which review wants
+/// to find there.
+fn setup_push_branch(tmpdir: &TempDir, branch: &str) {
+    panic_on_error!(git_command(&["push", "delivery", branch], tmpdir.path()));
+}
+
 /// Calls delivery review, and creates the two stub branches that the
 /// api would create (`_reviews/PIPELINE/BRANCH/1` and `_reviews/PIPELINE/BRANCH/latest`)
 fn delivery_review(local: &TempDir, remote: &TempDir, branch: &str, pipeline: &str) {
     panic_on_error!(git_command(&["checkout", branch], local.path()));
-    let result = panic_on_error!(delivery_cmd().arg("review").arg("--no-open").arg("--for").arg(pipeline).current_dir(&local.path()).output());
+    setup_push_branch(local, branch);
+    let result = panic_on_error!(delivery_cmd().arg("review").arg("--no-open").arg("--yes").arg("--for").arg(pipeline).current_dir(&local.path()).output());
     if ! result.status.success() {
         let output = String::from_utf8_lossy(&result.stdout);
         let error = String::from_utf8_lossy(&result.stderr);
@@ -136,6 +143,168 @@ test!(review {
     setup_checkout_branch(&delivery_project_git, "_for/master/rust/test");
 });
 
+// Tests `delivery init` on a project with no .delivery/config.json. It
+// commits one on a new branch, which nobody has pushed, and still has to
+// end with that branch under review. The server isn't there, which init
+// only warns about, so DELIVERY_TOKEN stands in for a token.
+test!(init_reviews_new_branch {
+    let delivery_project_git = TempDir::new("mock-delivery-remote").unwrap();
+    panic_on_error!(copy_recursive(&fixture_file("test_repo").join("README.md"), &delivery_project_git.path().to_path_buf()));
+    panic_on_error!(git_command(&["init", delivery_project_git.path().to_str().unwrap()], delivery_project_git.path()));
+    panic_on_error!(git_command(&["add", "."], delivery_project_git.path()));
+    panic_on_error!(git_command(&["commit", "-a", "-m", "Initial Commit"], delivery_project_git.path()));
+    let local_project = TempDir::new("local-project").unwrap();
+    panic_on_error!(git_command(&["clone",
+                                  delivery_project_git.path().to_str().unwrap(),
+                                  local_project.path().to_str().unwrap()
+                                 ], local_project.path()));
+    panic_on_error!(git_command(&["remote", "add", "delivery", delivery_project_git.path().to_str().unwrap()], local_project.path()));
+    panic_on_error!(git_command(&["config", "user.name", "Max Cavalera"], local_project.path()));
+    panic_on_error!(git_command(&["config", "user.email", "cavalera@example.com"], local_project.path()));
+    let result = panic_on_error!(delivery_cmd()
+                    .arg("init")
+                    .arg("--user").arg("cavalera")
+                    .arg("--server").arg("localhost")
+                    .arg("--ent").arg("family")
+                    .arg("--org").arg("sepultura")
+                    .arg("--project").arg("roots")
+                    .env("DELIVERY_TOKEN", "deadbeefcafe")
+                    .current_dir(local_project.path()).output());
+    if ! result.status.success() {
+        let output = String::from_utf8_lossy(&result.stdout);
+        let error = String::from_utf8_lossy(&result.stderr);
+        panic!("Failed 'delivery init'\nOUT: {}\nERR: {}\nPath: {}", &output, &error, local_project.path().to_str().unwrap());
+    }
+    setup_checkout_branch(&delivery_project_git, "add-delivery-config");
+    setup_checkout_branch(&delivery_project_git, "_for/master/add-delivery-config");
+});
+
+// Without a terminal to confirm on, review won't push unless given --yes.
+test!(review_without_terminal_needs_yes {
+    let delivery_project_git = setup_mock_delivery_project_git("path_config.json");
+    let local_project = setup_local_project_clone(&delivery_project_git);
+    setup_change(&local_project, "rust/test", "freaky");
+    setup_checkout_branch(&local_project, "rust/test");
+    let result = panic_on_error!(delivery_cmd().
+                                 arg("review").
+                                 arg("--no-open").
+                                 arg("--for").arg("master").
+                                 current_dir(local_project.path()).output());
+    assert_eq!(Some(2), result.status.code());
+    assert!(String::from_utf8_lossy(&result.stdout).contains("pass --yes"));
+    assert!(git_command(&["rev-parse", "--verify", "rust/test"], delivery_project_git.path()).is_err());
+    assert!(git_command(&["rev-parse", "--verify", "_for/master/rust/test"], delivery_project_git.path()).is_err());
+});
+
+test!(review_print_branch {
+    let delivery_project_git = setup_mock_delivery_project_git("path_config.json");
+    let local_project = setup_local_project_clone(&delivery_project_git);
+    setup_change(&local_project, "rust/test", "freaky");
+    setup_checkout_branch(&local_project, "rust/test");
+    let result = panic_on_error!(delivery_cmd().
+                                 arg("review").
+                                 arg("--for").arg("master").
+                                 arg("--print-branch").
+                                 current_dir(local_project.path()).output());
+    if ! result.status.success() {
+        let output = String::from_utf8_lossy(&result.stdout);
+        let error = String::from_utf8_lossy(&result.stderr);
+        panic!("Failed 'delivery review --print-branch'\nOUT: {}\nERR: {}\nPath: {}", &output, &error, local_project.path().to_str().unwrap());
+    }
+    assert_eq!("_for/master/rust/test\n", String::from_utf8_lossy(&result.stdout));
+});
+
+// Without --for, review targets the remote's default branch.
+test!(review_print_branch_for_remote_head {
+    let delivery_project_git = setup_mock_delivery_project_git("path_config.json");
+    let local_project = setup_local_project_clone(&delivery_project_git);
+    setup_change(&local_project, "rust/test", "freaky");
+    setup_checkout_branch(&local_project, "rust/test");
+    let result = panic_on_error!(delivery_cmd().
+                                 arg("review").
+                                 arg("--print-branch").
+                                 current_dir(local_project.path()).output());
+    assert!(result.status.success());
+    assert_eq!("_for/master/rust/test\n", String::from_utf8_lossy(&result.stdout));
+});
+
+test!(review_junit_out_on_failure {
+    let delivery_project_git = setup_mock_delivery_project_git("path_config.json");
+    let local_project = setup_local_project_clone(&delivery_project_git);
+    let junit = local_project.path().join("review.xml");
+    // Reviewing master against master fails
+    let result = panic_on_error!(delivery_cmd().
+                                 arg("review").
+                                 arg("--no-open").
+                                 arg("--for").arg("master").
+                                 arg("--junit-out").arg(junit.to_str().unwrap()).
+                                 current_dir(local_project.path()).output());
+    assert!(! result.status.success());
+    let mut xml = String::new();
+    panic_on_error!(panic_on_error!(File::open(&junit)).read_to_string(&mut xml));
+    assert!(xml.contains("tests=\"6\" failures=\"1\""));
+    assert!(xml.contains("<testcase classname=\"delivery review\" name=\"find repository\"/>"));
+    assert!(xml.contains("<testcase classname=\"delivery review\" name=\"check branch\">"));
+});
+
+test!(review_show_git_output {
+    let delivery_project_git = setup_mock_delivery_project_git("path_config.json");
+    let local_project = setup_local_project_clone(&delivery_project_git);
+    setup_change(&local_project, "rust/test", "freaky");
+    setup_change(&local_project, "rust/quiet", "quiet");
+    let raw_push = "refs/heads/rust/test:refs/heads/_for/master/rust/test";
+    setup_push_branch(&local_project, "rust/test");
+    setup_push_branch(&local_project, "rust/quiet");
+    setup_checkout_branch(&local_project, "rust/test");
+    let result = panic_on_error!(delivery_cmd().
+                                 arg("review").
+                                 arg("--no-open").
+                                 arg("--yes").
+                                 arg("--for").arg("master").
+                                 arg("--show-git-output").
+                                 current_dir(local_project.path()).output());
+    assert!(result.status.success());
+    assert!(String::from_utf8_lossy(&result.stdout).contains(raw_push));
+
+    setup_checkout_branch(&local_project, "rust/quiet");
+    let result = panic_on_error!(delivery_cmd().
+                                 arg("review").
+                                 arg("--no-open").
+                                 arg("--yes").
+                                 arg("--for").arg("master").
+                                 current_dir(local_project.path()).output());
+    assert!(result.status.success());
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    assert!(!stdout.contains("refs/heads/rust/quiet:refs/heads/_for/master/rust/quiet"));
+    assert!(!stdout.contains("STDOUT:"));
+});
+
+// `delivery setup --config-path` reads the config it starts from there,
+// not from the current directory.
+test!(setup_reads_config_from_config_path {
+    let config_dir = TempDir::new("config-path").unwrap();
+    let elsewhere = TempDir::new("elsewhere").unwrap();
+    panic_on_error!(mkdir_recursive(&config_dir.path().join(".delivery")));
+    {
+        let mut f = panic_on_error!(File::create(&config_dir.path().join_many(&[".delivery", "cli.toml"])));
+        panic_on_error!(f.write_all(b"git_port = \"2222\"\n"));
+    }
+    let result = panic_on_error!(delivery_cmd()
+                    .arg("setup")
+                    .arg("--user").arg("cavalera")
+                    .arg("--server").arg("localhost")
+                    .arg("--ent").arg("family")
+                    .arg("--org").arg("sepultura")
+                    .arg("--config-path").arg(config_dir.path().to_str().unwrap())
+                    .current_dir(elsewhere.path()).output());
+    assert!(result.status.success());
+    let mut toml = String::new();
+    panic_on_error!(panic_on_error!(File::open(&config_dir.path().join_many(&[".delivery", "cli.toml"]))).read_to_string(&mut toml));
+    assert!(toml.contains("2222"));
+    assert!(toml.contains("sepultura"));
+    assert!(!elsewhere.path().join(".delivery").exists());
+});
+
 test!(job_verify_unit_with_path_config {
     let delivery_project_git = setup_mock_delivery_project_git("path_config.json");
     let local_project = setup_local_project_clone(&delivery_project_git);